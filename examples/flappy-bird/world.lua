@@ -5,8 +5,8 @@ pipe = not_set
 started = false
 
 function _ready(self)
-    self.player = self:get_child(0):get_component("Player")
-    self.pipe = self:get_child(1):get_component("Pipe")
+    self.player = self:get_node("Player"):get_component("Player")
+    self.pipe = self:get_node("Pipe"):get_component("Pipe")
 end
 
 
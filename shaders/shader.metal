@@ -21,17 +21,23 @@ struct vs_mainOutput {
     metal::float4 clip_position [[position]];
     metal::float2 uv [[user(loc0), center_perspective]];
 };
+struct VsParams {
+    metal::float4x4 proj;
+    metal::float4 uv_rect;
+};
 vertex vs_mainOutput vs_main(
   vs_mainInput varyings [[stage_in]]
-, constant metal::float4x4& proj [[user(fake0)]]
+, constant VsParams& params [[user(fake0)]]
 ) {
     const VertexInput model = { varyings.position, varyings.texture_coord };
     VertexOutput out = {};
-    out.uv = model.texture_coord;
-    metal::float4x4 _e6 = proj;
-    out.clip_position = _e6 * metal::float4(model.position, 1.0);
-    VertexOutput _e11 = out;
-    const auto _tmp = _e11;
+    metal::float4 _e5 = params.uv_rect;
+    metal::float4 _e10 = params.uv_rect;
+    out.uv = _e5.xy + (model.texture_coord * _e10.zw);
+    metal::float4x4 _e16 = params.proj;
+    out.clip_position = _e16 * metal::float4(model.position, 1.0);
+    VertexOutput _e21 = out;
+    const auto _tmp = _e21;
     return vs_mainOutput { _tmp.clip_position, _tmp.uv };
 }
 
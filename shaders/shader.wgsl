@@ -8,15 +8,22 @@ struct VertexOutput {
     @location(0) uv: vec2<f32>,
 };
 
-@group(0) @binding(0) var<uniform> proj: mat4x4<f32>;
+struct VsParams {
+    proj: mat4x4<f32>,
+    // the part of the texture that is drawn
+    // as (x, y, width, height) in UV space
+    uv_rect: vec4<f32>,
+};
+
+@group(0) @binding(0) var<uniform> params: VsParams;
 
 @vertex
 fn vs_main(
     model: VertexInput,
 ) -> VertexOutput {
     var out: VertexOutput;
-    out.uv = model.texture_coord;
-    out.clip_position = proj * vec4(model.position, 1.0);
+    out.uv = params.uv_rect.xy + model.texture_coord * params.uv_rect.zw;
+    out.clip_position = params.proj * vec4(model.position, 1.0);
     return out;
 }
 
@@ -1,21 +1,67 @@
 pub mod texture;
+pub mod pixels;
+pub mod atlas;
+pub mod import;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use image::EncodableLayout;
+use image::{ColorType, EncodableLayout};
+use sokol::{app as sapp, gfx::{self as sg, ImageData}};
 use sti::{define_key, keyed::KVec};
-use texture::{Texture, TextureBuilder, TextureLoadType};
-use tracing::error;
+use import::{ImportSettings, TextureFilter};
+use pixels::PixelBuffer;
+use texture::{Texture, TextureBuilder, TextureLoadType, TextureUsage};
+use tracing::{error, info, trace, warn};
 
-use crate::{engine::Engine, script_manager::ScriptManager};
+use crate::{engine::Engine, file_system::FileSystem, script_manager::ScriptManager};
 
 define_key!(u32, pub TextureId);
 
 
+///
+/// An image that has been decoded on the CPU
+/// but not yet uploaded to the GPU
+///
+#[derive(Debug)]
+pub struct DecodedImage {
+    pub width: usize,
+    pub height: usize,
+    /// The data is RGBA32F if true, otherwise it's RGBA8
+    pub is_hdr: bool,
+    /// Whether the colours are sRGB encoded
+    pub srgb: bool,
+    pub premultiplied: bool,
+    pub filter: TextureFilter,
+    pub data: Box<[u8]>,
+}
+
+
+///
+/// Options for how an image is turned into a texture
+///
+#[derive(Debug, Clone, Copy)]
+pub struct TextureImport {
+    /// Multiplies the colour channels by the alpha channel
+    /// on import, the texture is then drawn with premultiplied
+    /// blending which avoids dark fringes around transparent edges
+    pub premultiply_alpha: bool,
+    /// Whether the image holds sRGB encoded colours,
+    /// which is true for most colour textures
+    pub srgb: bool,
+    pub filter: TextureFilter,
+    /// The size of the frames the image is split into,
+    /// frame `n` of `sheet.png` is loaded as `sheet.png#n`
+    pub frames: Option<[usize; 2]>,
+}
+
+
 #[derive(Debug)]
 pub struct AssetManager {
     textures: KVec<TextureId, Texture>,
     path_to_texture: HashMap<String, TextureId>,
+    /// The atlas page and UV rectangle of every packed image
+    atlas_regions: HashMap<String, (String, [f32; 4])>,
+    texture_memory: usize,
 }
 
 
@@ -24,6 +70,8 @@ impl AssetManager {
         Self {
             textures: KVec::new(),
             path_to_texture: HashMap::new(),
+            atlas_regions: HashMap::new(),
+            texture_memory: 0,
         }
     }
 
@@ -38,47 +86,442 @@ impl AssetManager {
             .build(self);
 
         assert_eq!(blank, TextureId::WHITE);
+
+        self.load_atlases(Engine::file_system());
+    }
+
+
+    /// Reads the atlas manifests so that packed images
+    /// resolve to a region of their atlas
+    pub fn load_atlases(&mut self, file_system: &FileSystem) {
+        for manifest in atlas::load_manifests(file_system) {
+            for (path, region) in manifest.regions.iter() {
+                let page = manifest.pages[region.page].image.clone();
+                self.atlas_regions.insert(path.clone(), (page, manifest.uv(region)));
+            }
+        }
+
+        info!("{} images are packed into atlases", self.atlas_regions.len());
+    }
+
+
+    /// Returns the path of the image that holds the pixels
+    /// of `path`, which is its atlas page if it's packed
+    pub fn source_image<'a>(&'a self, path: &'a str) -> &'a str {
+        match self.atlas_regions.get(path) {
+            Some((page, _)) => page,
+            None => path,
+        }
     }
 
 
+    /// Loads the image at `path` using the
+    /// import settings next to it
     pub fn from_image(&mut self, path: &str) -> Option<TextureId> {
         if let Some(texture) = self.path_to_texture.get(path) { return Some(*texture) }
 
-        let Ok(img) = image::ImageReader::open(path)
-        else { error!("unable to read image at '{path}'"); return None };
+        let (image_path, _) = split_frame(path);
+        let import = ImportSettings::load(Engine::file_system(), image_path).texture();
+        self.from_image_with(path, import)
+    }
+
+
+    /// Loads the image at `path` with the given import options.
+    ///
+    /// Images are cached by path so the options of the
+    /// first load of a path are the ones that stick
+    pub fn from_image_with(&mut self, path: &str, import: TextureImport) -> Option<TextureId> {
+        if let Some(texture) = self.path_to_texture.get(path) { return Some(*texture) }
+
+        if let (image_path, Some(frame)) = split_frame(path) {
+            let frames = self.frames(image_path, import)?;
+            let Some(texture) = frames.get(frame)
+            else {
+                error!("'{image_path}' has {} frames, frame '{frame}' doesn't exist", frames.len());
+                return None;
+            };
 
-        let Ok(img) = img.decode()
+            return Some(*texture);
+        }
+
+        if let Some((page, uv)) = self.atlas_regions.get(path).cloned() {
+            let atlas = self.from_image_with(&page, import)?;
+            return Some(self.atlas_region(path, atlas, uv));
+        }
+
+        let image = Self::decode_image(Engine::file_system(), path, import)?;
+        Some(self.upload_image(path, image))
+    }
+
+
+    /// Reads and decodes the image at `path`.
+    ///
+    /// 8-bit images stay 8-bit, only images that are
+    /// stored as floats are decoded as floats.
+    ///
+    /// This doesn't touch the GPU so it can be
+    /// called from any thread
+    pub fn decode_image(file_system: &FileSystem, path: &str, import: TextureImport) -> Option<DecodedImage> {
+        let img = match file_system.read(path) {
+            Ok(v) => v,
+            Err(e) => { error!("unable to read image at '{path}': {e}"); return None },
+        };
+
+        let Ok(img) = image::load_from_memory(&img)
         else { error!("image at '{path}' is an unsupported format"); return None };
 
-        let image = img.into_rgba32f();
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let is_hdr = matches!(img.color(), ColorType::Rgb32F | ColorType::Rgba32F);
+
+        let data = if is_hdr {
+            let mut image = img.into_rgba32f();
+            if import.premultiply_alpha {
+                for pixel in image.pixels_mut() {
+                    let [r, g, b, a] = pixel.0;
+                    pixel.0 = [r * a, g * a, b * a, a];
+                }
+            }
+
+            image.as_bytes().to_vec().into_boxed_slice()
+
+        } else {
+            let is_16_bit = matches!(img.color(), ColorType::L16 | ColorType::La16
+                                                  | ColorType::Rgb16 | ColorType::Rgba16);
+
+            // 16-bit images are brought down to 8-bit on their
+            // own so the channels are rounded instead of cut off
+            let to_rgba8 = |pixel: [f32; 4]| {
+                if import.premultiply_alpha { premultiply(pixel, import.srgb) }
+                else { pixel.map(|c| (c * 255.0).round() as u8) }
+            };
+
+            if is_16_bit {
+                img.into_rgba16().pixels()
+                    .flat_map(|pixel| to_rgba8(pixel.0.map(|c| c as f32 / 65535.0)))
+                    .collect()
+
+            } else if import.premultiply_alpha {
+                img.into_rgba8().pixels()
+                    .flat_map(|pixel| to_rgba8(pixel.0.map(|c| c as f32 / 255.0)))
+                    .collect()
+
+            } else {
+                img.into_rgba8().into_raw().into_boxed_slice()
+            }
+        };
+
+        Some(DecodedImage {
+            width,
+            height,
+            is_hdr,
+            srgb: import.srgb && !is_hdr,
+            premultiplied: import.premultiply_alpha,
+            filter: import.filter,
+            data,
+        })
+    }
+
+
+    /// Uploads a decoded image to the GPU and caches it
+    /// under `path`, must be called from the main thread
+    pub fn upload_image(&mut self, path: &str, image: DecodedImage) -> TextureId {
+        if let Some(texture) = self.path_to_texture.get(path) { return *texture }
+
+        let colour_format = if image.is_hdr { texture::ColourFormat::RGBA32F }
+                            else if image.srgb && Self::supports_srgb() { texture::ColourFormat::SRGB8A8 }
+                            else { texture::ColourFormat::RGBA8 };
+
         let texture = texture::TextureBuilder::new()
             .label(path)
-            .width(image.width() as usize)
-            .height(image.height() as usize)
-            .colour_format(texture::ColourFormat::RGBA32F)
-            .data(image.to_vec().as_bytes().to_vec().into_boxed_slice())
+            .width(image.width)
+            .height(image.height)
+            .colour_format(colour_format)
+            .premultiplied(image.premultiplied)
+            .filter(image.filter)
+            .data(image.data)
             .build(self);
 
         self.textures.get_mut(texture).unwrap().texture_load_type = TextureLoadType::Image(path.to_string());
         self.path_to_texture.insert(path.to_string(), texture);
 
-        Some(texture)
+        texture
+    }
+
+
+    /// Creates a texture for the packed image at `path`
+    fn atlas_region(&mut self, path: &str, atlas: TextureId, uv: [f32; 4]) -> TextureId {
+        trace!("'{path}' is packed into '{atlas:?}'");
+
+        let mut texture = Texture::region(atlas, &self.textures[atlas], uv);
+        texture.texture_load_type = TextureLoadType::Image(path.to_string());
+
+        let texture = self.textures.push(texture);
+        self.path_to_texture.insert(path.to_string(), texture);
+        texture
+    }
+
+
+    /// Splits the image at `path` into frames of the size
+    /// given by `import.frames`, left to right, top to bottom.
+    ///
+    /// Returns `None` if the image failed to load or isn't
+    /// split into frames
+    pub fn frames(&mut self, path: &str, import: TextureImport) -> Option<Vec<TextureId>> {
+        let Some([frame_width, frame_height]) = import.frames
+        else {
+            error!("'{path}' isn't split into frames, set 'texture.frames' \
+                   in its import settings");
+            return None;
+        };
+
+        if frame_width == 0 || frame_height == 0 {
+            error!("the frames of '{path}' can't be {frame_width}x{frame_height}");
+            return None;
+        }
+
+        let sheet = self.from_image_with(path, import)?;
+        let sheet_texture = &self.textures[sheet];
+        let (width, height) = (sheet_texture.width(), sheet_texture.height());
+        let [u, v, uv_width, uv_height] = sheet_texture.uv();
+        let atlas = sheet_texture.region_of().map(|x| x.atlas).unwrap_or(sheet);
+
+        let (columns, rows) = (width / frame_width, height / frame_height);
+        let mut frames = Vec::with_capacity(columns * rows);
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let frame_path = format!("{path}#{}", frames.len());
+                if let Some(texture) = self.path_to_texture.get(&frame_path) {
+                    frames.push(*texture);
+                    continue;
+                }
+
+                let uv = [
+                    u + (column * frame_width) as f32 / width as f32 * uv_width,
+                    v + (row * frame_height) as f32 / height as f32 * uv_height,
+                    frame_width as f32 / width as f32 * uv_width,
+                    frame_height as f32 / height as f32 * uv_height,
+                ];
+
+                frames.push(self.atlas_region(&frame_path, atlas, uv));
+            }
+        }
+
+        Some(frames)
+    }
+
+
+    /// Whether sRGB textures can be used.
+    ///
+    /// Sampling an sRGB texture gives back linear colours so
+    /// they only come out right if the swapchain encodes them
+    /// back to sRGB. Otherwise the 8-bit data is uploaded as is
+    /// and everything stays in gamma space.
+    pub fn supports_srgb() -> bool {
+        sapp::color_format() == sg::PixelFormat::Srgb8a8 as i32
+            && texture::ColourFormat::SRGB8A8.info().filter
+    }
+
+
+    /// The amount of GPU memory used by textures, in bytes
+    pub fn texture_memory(&self) -> usize {
+        self.texture_memory
+    }
+
+
+    pub fn is_loaded(&self, path: &str) -> bool {
+        self.path_to_texture.contains_key(path)
     }
 
 
     pub fn from_script(engine: &mut Engine, path: &str) -> Option<TextureId> {
         let script = ScriptManager::from_path(engine, path);
-        let engine = engine.get();
-        let script = engine.script_manager.script(script);
+        let functions = engine.get().script_manager.script(script).functions.clone();
+
+        let texture = functions.texture(path)?;
 
-        let texture = script.functions.texture(script.path())?;
+        let mut engine = engine.get_mut();
+        let texture_ref = engine.asset_manager.textures.get_mut(texture).unwrap();
+        if let TextureLoadType::Runtime = texture_ref.texture_load_type {
+            texture_ref.texture_load_type = TextureLoadType::Script(path.to_string());
+        }
 
         Some(texture)
     }
 
 
-    pub fn texture(&self, script: TextureId) -> &Texture {
-        &self.textures[script]
+    /// Creates a texture out of the pixels of `pixels`.
+    ///
+    /// Textures that aren't immutable can be updated with
+    /// new pixels using `update_texture`
+    pub fn from_pixels(&mut self, label: &str, pixels: &PixelBuffer, usage: TextureUsage) -> TextureId {
+        let colour_format = if Self::supports_srgb() { texture::ColourFormat::SRGB8A8 }
+                            else { texture::ColourFormat::RGBA8 };
+
+        let texture = texture::TextureBuilder::new()
+            .label(label)
+            .width(pixels.width())
+            .height(pixels.height())
+            .colour_format(colour_format)
+            .usage(usage)
+            .data(pixels.data().to_vec().into_boxed_slice())
+            .build(self);
+
+        if usage != TextureUsage::Immutable {
+            self.update_texture(texture, pixels.data());
+        }
+
+        texture
+    }
+
+
+    /// Replaces the pixels of a texture that isn't immutable.
+    ///
+    /// sokol only allows a texture to be updated
+    /// once per frame
+    pub fn update_texture(&mut self, id: TextureId, data: &[u8]) {
+        let texture = &self.textures[id];
+        if !texture.is_loaded() {
+            warn!("tried to update '{}' after it was unloaded", texture.name());
+            return;
+        }
+
+        if texture.usage() == TextureUsage::Immutable {
+            error!("'{}' is immutable and can't be updated", texture.name());
+            return;
+        }
+
+        if data.len() != texture.size() {
+            error!("tried to update '{}' with {} bytes but it is {} bytes",
+                   texture.name(), data.len(), texture.size());
+            return;
+        }
+
+        let mut image_data = ImageData::new();
+        image_data.subimage[0][0] = sg::Range {
+            ptr: data.as_ptr().cast(),
+            size: data.len(),
+        };
+
+        sg::update_image(texture.inner(), &image_data);
+    }
+
+
+    /// Returns the texture behind `id`, or the white
+    /// texture if it has been unloaded
+    pub fn texture(&self, id: TextureId) -> &Texture {
+        let texture = &self.textures[id];
+        if !texture.is_loaded() {
+            trace!("texture '{id:?}' has been unloaded, using white instead");
+            return &self.textures[TextureId::WHITE];
+        }
+
+        texture
+    }
+
+
+    /// Adds a reference to the texture, keeping it
+    /// loaded until the reference is released
+    pub fn acquire(&mut self, id: TextureId) {
+        let texture = &mut self.textures[id];
+        texture.refs += 1;
+        trace!("acquired '{}', {} references", texture.name(), texture.refs);
+    }
+
+
+    pub fn release(&mut self, id: TextureId) {
+        let texture = &mut self.textures[id];
+        if texture.refs == 0 {
+            warn!("released '{}' more times than it was acquired", texture.name());
+            return;
+        }
+
+        texture.refs -= 1;
+        trace!("released '{}', {} references", texture.name(), texture.refs);
+    }
+
+
+    /// Frees every texture that doesn't have a reference and
+    /// isn't used by the scene tree or any of the cached templates.
+    ///
+    /// A texture that a script got from somewhere other than
+    /// `Texture.load`, like `node.sprite`, is freed once no node
+    /// uses it unless the script calls `Texture.acquire` on it
+    pub fn collect_garbage(engine: &mut Engine) {
+        engine.with(|engine| {
+            let used = engine.scene_manager.used_textures();
+            engine.asset_manager.collect(&used);
+        });
+    }
+
+
+    fn collect(&mut self, used: &HashSet<TextureId>) {
+        info!("collecting unused textures");
+
+        let garbage = self.garbage(used);
+
+        let mut freed_memory = 0;
+        for id in garbage.iter() {
+            let texture = &mut self.textures[*id];
+            trace!("freeing '{}'", texture.name());
+            texture.destroy();
+
+            if let TextureLoadType::Image(path) = &texture.texture_load_type {
+                self.path_to_texture.remove(path);
+            }
+
+            freed_memory += texture.size();
+        }
+
+        self.texture_memory -= freed_memory;
+        info!("freed {} textures, {} KB", garbage.len(), freed_memory / 1024);
+    }
+
+
+    /// The loaded textures that `collect` frees, the ones
+    /// without a reference that aren't in `used` and aren't
+    /// the atlas of a texture that is
+    fn garbage(&self, used: &HashSet<TextureId>) -> Vec<TextureId> {
+        let mut keep = used.clone();
+        for (id, texture) in self.textures.iter() {
+            if texture.refs > 0 { keep.insert(id); }
+        }
+
+        // the atlases of the regions that are kept
+        let atlases = keep.iter()
+            .filter_map(|id| self.textures[*id].region_of())
+            .map(|region| region.atlas)
+            .collect::<Vec<_>>();
+        keep.extend(atlases);
+
+        self.textures.iter()
+            .filter(|(id, texture)| *id != TextureId::WHITE
+                                    && texture.is_loaded()
+                                    && !keep.contains(id))
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+
+    /// Frees every texture, reporting the ones
+    /// that still have references as leaks
+    pub fn shutdown(&mut self) {
+        info!("shutting down the asset manager");
+
+        for (id, texture) in self.textures.iter_mut() {
+            if !texture.is_loaded() { continue }
+
+            if id != TextureId::WHITE && texture.refs > 0 {
+                warn!("leaked texture '{}' with {} references ({} KB), \
+                      'Texture.unload' was never called on it",
+                      texture.name(), texture.refs, texture.size() / 1024);
+            }
+
+            texture.destroy();
+        }
+
+        self.path_to_texture.clear();
+        self.texture_memory = 0;
     }
 }
 
@@ -86,3 +529,118 @@ impl AssetManager {
 impl TextureId {
     pub const WHITE : Self = Self(0);
 }
+
+
+/// Splits `sheet.png#3` into `("sheet.png", Some(3))`
+pub fn split_frame(path: &str) -> (&str, Option<usize>) {
+    let Some((image_path, frame)) = path.rsplit_once('#')
+    else { return (path, None) };
+
+    match frame.parse() {
+        Ok(frame) => (image_path, Some(frame)),
+        Err(_) => (path, None),
+    }
+}
+
+
+impl Default for TextureImport {
+    fn default() -> Self {
+        Self {
+            premultiply_alpha: false,
+            srgb: true,
+            filter: TextureFilter::default(),
+            frames: None,
+        }
+    }
+}
+
+
+///
+/// Multiplies the colour of an RGBA pixel with its alpha and
+/// rounds it to 8 bits, the channels go from 0 to 1.
+///
+/// Blending happens on linear colours so sRGB colours are
+/// decoded before they're multiplied and encoded after
+///
+fn premultiply(pixel: [f32; 4], srgb: bool) -> [u8; 4] {
+    let [r, g, b, a] = pixel;
+
+    let mul = |c: f32| {
+        let c = if srgb { srgb_to_linear(c) * a } else { c * a };
+        let c = if srgb { linear_to_srgb(c) } else { c };
+        (c.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+
+    [mul(r), mul(g), mul(b), (a.clamp(0.0, 1.0) * 255.0).round() as u8]
+}
+
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 }
+    else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 { c * 12.92 }
+    else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+
+#[cfg(test)]
+impl AssetManager {
+    /// Registers a texture that isn't backed by anything as
+    /// the image at `path`, for tests that can't use the GPU
+    pub(crate) fn placeholder_image(&mut self, path: &str) -> TextureId {
+        let mut texture = Texture::placeholder(None);
+        texture.texture_load_type = TextureLoadType::Image(path.to_string());
+
+        let id = self.textures.push(texture);
+        self.path_to_texture.insert(path.to_string(), id);
+        id
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use texture::TextureRegion;
+
+    use super::*;
+
+
+    #[test]
+    fn asset_manager_garbage() {
+        let mut asset_manager = AssetManager::new();
+        let white = asset_manager.textures.push(Texture::placeholder(None));
+        let used = asset_manager.textures.push(Texture::placeholder(None));
+        let unused = asset_manager.textures.push(Texture::placeholder(None));
+        let acquired = asset_manager.textures.push(Texture::placeholder(None));
+        let atlas = asset_manager.textures.push(Texture::placeholder(None));
+        let region = TextureRegion { atlas, uv: [0.0, 0.0, 0.5, 0.5] };
+        let frame = asset_manager.textures.push(Texture::placeholder(Some(region)));
+        asset_manager.acquire(acquired);
+
+        let garbage = asset_manager.garbage(&HashSet::from([used, frame]));
+        assert_eq!(garbage, vec![unused]);
+        assert!(!garbage.contains(&white));
+
+        asset_manager.release(acquired);
+        let garbage = asset_manager.garbage(&HashSet::from([used]));
+        assert_eq!(garbage, vec![unused, acquired, atlas, frame]);
+    }
+
+
+    #[test]
+    fn premultiply_alpha() {
+        // opaque and fully transparent pixels are the same either way
+        assert_eq!(premultiply([0.2, 0.4, 1.0, 1.0], true), [51, 102, 255, 255]);
+        assert_eq!(premultiply([0.2, 0.4, 1.0, 0.0], true), [0, 0, 0, 0]);
+
+        assert_eq!(premultiply([1.0, 0.5, 0.0, 0.5], false), [128, 64, 0, 128]);
+
+        // half of sRGB white is brighter than half
+        // of the encoded value once it's encoded again
+        assert_eq!(premultiply([1.0, 1.0, 1.0, 0.5], true), [188, 188, 188, 128]);
+    }
+}
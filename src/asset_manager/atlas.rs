@@ -0,0 +1,330 @@
+use std::{collections::BTreeMap, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::file_system::{normalize, FileSystem};
+
+use super::pixels::PixelBuffer;
+
+
+/// The extension of atlas manifests, every manifest
+/// in the file system is loaded at startup
+pub const ATLAS_MANIFEST_EXTENSION : &str = ".atlas.toml";
+
+/// The largest width and height of an atlas page
+const MAX_PAGE_SIZE : usize = 2048;
+
+/// The space left around every image, it's filled with the
+/// edge pixels of the image so filtering doesn't pick up
+/// the neighbours or fade into transparency
+const PADDING : usize = 2;
+
+const PACKED_EXTENSIONS : &[&str] = &["png", "jpg", "jpeg", "bmp", "tga", "gif"];
+
+
+///
+/// Describes where the images of a folder
+/// ended up after being packed
+///
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AtlasManifest {
+    pub pages: Vec<AtlasPage>,
+    /// The original path of every packed image
+    pub regions: BTreeMap<String, AtlasRegion>,
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AtlasPage {
+    pub image: String,
+    pub width: usize,
+    pub height: usize,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AtlasRegion {
+    pub page: usize,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+
+///
+/// A packed atlas that hasn't been written anywhere yet
+///
+#[derive(Debug)]
+pub struct Atlas {
+    /// The path the manifest should be written to
+    pub manifest_path: String,
+    pub manifest: AtlasManifest,
+    pub pages: Vec<PixelBuffer>,
+}
+
+
+impl AtlasManifest {
+    pub fn parse(path: &str, data: &str) -> Option<Self> {
+        match toml::from_str(data) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                error!("unable to parse the atlas manifest '{path}': {e}");
+                None
+            },
+        }
+    }
+
+
+    /// The UV rectangle of a region as
+    /// `(x, y, width, height)` from 0 to 1
+    pub fn uv(&self, region: &AtlasRegion) -> [f32; 4] {
+        let page = &self.pages[region.page];
+        let (width, height) = (page.width as f32, page.height as f32);
+
+        [region.x as f32 / width, region.y as f32 / height,
+         region.width as f32 / width, region.height as f32 / height]
+    }
+}
+
+
+impl Atlas {
+    ///
+    /// Packs every image inside of `folder` into as few
+    /// pages as possible.
+    ///
+    /// The pages are named `<folder>.atlas<n>.png` and
+    /// the manifest `<folder>.atlas.toml`, both next to
+    /// the folder. Images that don't fit on a page are
+    /// left out and keep being loaded on their own.
+    ///
+    pub fn build(file_system: &FileSystem, folder: &str) -> io::Result<Self> {
+        let folder = normalize(folder);
+        info!("packing the images in '{folder}' into an atlas");
+
+        let prefix = format!("{folder}/");
+        let mut images = vec![];
+        for path in file_system.files() {
+            if !path.starts_with(&prefix) || !is_packable(&path) { continue }
+
+            let bytes = file_system.read(&path)?;
+            let pixels = match PixelBuffer::decode(&bytes) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("skipping '{path}', unable to decode it: {e}");
+                    continue;
+                },
+            };
+
+            images.push((path, pixels));
+        }
+
+
+        let sizes = images.iter()
+            .map(|(_, pixels)| (pixels.width(), pixels.height()))
+            .collect::<Vec<_>>();
+
+        let (placements, page_sizes) = pack(&sizes, MAX_PAGE_SIZE, PADDING);
+
+        let mut pages = page_sizes.iter()
+            .map(|(width, height)| PixelBuffer::new(*width, *height))
+            .collect::<Vec<_>>();
+
+        let mut manifest = AtlasManifest::default();
+        manifest.pages = page_sizes.iter().enumerate()
+            .map(|(i, (width, height))| AtlasPage {
+                image: format!("{folder}.atlas{i}.png"),
+                width: *width,
+                height: *height,
+            })
+            .collect();
+
+        for ((path, pixels), placement) in images.iter().zip(placements) {
+            let Some(region) = placement
+            else {
+                warn!("'{path}' is larger than an atlas page, it won't be packed");
+                continue;
+            };
+
+            let page = &mut pages[region.page];
+            page.blit(pixels, region.x as i64, region.y as i64);
+            page.extrude(region.x as i64, region.y as i64, region.width, region.height, PADDING);
+            manifest.regions.insert(path.clone(), region);
+        }
+
+        info!("packed {} images into {} pages", manifest.regions.len(), pages.len());
+
+        Ok(Self {
+            manifest_path: format!("{folder}{ATLAS_MANIFEST_EXTENSION}"),
+            manifest,
+            pages,
+        })
+    }
+
+
+    /// Encodes the manifest and pages into
+    /// `(path, data)` pairs ready to be written
+    pub fn files(&self) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let mut files = vec![];
+
+        let manifest = toml::to_string(&self.manifest)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        files.push((self.manifest_path.clone(), manifest.into_bytes()));
+
+        for (page, pixels) in self.manifest.pages.iter().zip(self.pages.iter()) {
+            let png = pixels.encode_png()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            files.push((page.image.clone(), png));
+        }
+
+        Ok(files)
+    }
+
+
+    /// Writes the manifest and pages into the `project` directory
+    pub fn write(&self, project: &Path) -> io::Result<()> {
+        for (path, data) in self.files()? {
+            info!("writing '{path}'");
+            std::fs::write(project.join(path), data)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Reads every atlas manifest in the file system
+pub fn load_manifests(file_system: &FileSystem) -> Vec<AtlasManifest> {
+    file_system.files().into_iter()
+        .filter(|path| path.ends_with(ATLAS_MANIFEST_EXTENSION))
+        .filter_map(|path| {
+            let data = match file_system.read_to_string(&path) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("unable to read the atlas manifest '{path}': {e}");
+                    return None;
+                },
+            };
+
+            AtlasManifest::parse(&path, &data)
+        })
+        .collect()
+}
+
+
+fn is_packable(path: &str) -> bool {
+    // don't pack previously generated pages
+    if path.contains(".atlas") { return false }
+
+    let Some((_, ext)) = path.rsplit_once('.')
+    else { return false };
+
+    PACKED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+}
+
+
+///
+/// Packs rectangles into pages using shelves, tallest first.
+///
+/// Returns where each rectangle ended up, in the same order
+/// as `sizes`, and the size of every page. Rectangles that
+/// don't fit on an empty page are `None`.
+///
+pub fn pack(sizes: &[(usize, usize)], max_size: usize, padding: usize)
+    -> (Vec<Option<AtlasRegion>>, Vec<(usize, usize)>) {
+
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|i| core::cmp::Reverse((sizes[*i].1, sizes[*i].0)));
+
+    let mut placements = vec![None; sizes.len()];
+    let mut pages : Vec<(usize, usize)> = vec![];
+
+    // the cursor on the current page
+    let mut x = 0;
+    let mut y = 0;
+    let mut shelf_height = 0;
+
+    for i in order {
+        let (width, height) = sizes[i];
+        let (padded_width, padded_height) = (width + padding * 2, height + padding * 2);
+        if padded_width > max_size || padded_height > max_size { continue }
+
+        if pages.is_empty() {
+            pages.push((0, 0));
+        }
+
+        // start a new shelf
+        if x + padded_width > max_size {
+            x = 0;
+            y += shelf_height;
+            shelf_height = 0;
+        }
+
+        // start a new page
+        if y + padded_height > max_size {
+            pages.push((0, 0));
+            x = 0;
+            y = 0;
+            shelf_height = 0;
+        }
+
+        let page = pages.len() - 1;
+        placements[i] = Some(AtlasRegion { page, x: x + padding, y: y + padding, width, height });
+
+        x += padded_width;
+        shelf_height = shelf_height.max(padded_height);
+
+        let size = &mut pages[page];
+        size.0 = size.0.max(x);
+        size.1 = size.1.max(y + shelf_height);
+    }
+
+    (placements, pages)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn overlaps(a: &AtlasRegion, b: &AtlasRegion) -> bool {
+        a.page == b.page
+            && a.x < b.x + b.width && b.x < a.x + a.width
+            && a.y < b.y + b.height && b.y < a.y + a.height
+    }
+
+
+    #[test]
+    fn atlas_pack_no_overlap() {
+        let sizes = [(30, 20), (64, 64), (10, 50), (100, 8), (64, 64), (33, 33), (1, 1)];
+        let (placements, pages) = pack(&sizes, 128, 1);
+
+        let regions = placements.iter().map(|x| x.unwrap()).collect::<Vec<_>>();
+        for (i, a) in regions.iter().enumerate() {
+            assert_eq!((a.width, a.height), sizes[i]);
+
+            let (page_width, page_height) = pages[a.page];
+            assert!(a.x + a.width < page_width + 1 && a.y + a.height < page_height + 1);
+
+            for b in regions[i+1..].iter() {
+                assert!(!overlaps(a, b), "{a:?} overlaps {b:?}");
+            }
+        }
+    }
+
+
+    #[test]
+    fn atlas_pack_spills_into_pages() {
+        let sizes = [(60, 60); 5];
+        let (placements, pages) = pack(&sizes, 128, 0);
+
+        assert_eq!(pages.len(), 2);
+        assert!(placements.iter().all(|x| x.is_some()));
+
+        let (placements, pages) = pack(&[(200, 10)], 128, 0);
+        assert_eq!(placements, vec![None]);
+        assert!(pages.is_empty());
+    }
+}
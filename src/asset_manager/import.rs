@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use tracing::{error, trace};
+
+use crate::file_system::{normalize, FileSystem};
+
+use super::TextureImport;
+
+
+/// The extension of per asset import settings,
+/// `hero.png` is configured by `hero.png.import.toml`
+pub const IMPORT_SIDECAR_EXTENSION : &str = ".import.toml";
+
+/// The name of the file that holds the default import
+/// settings of every asset in a folder and its subfolders
+pub const FOLDER_IMPORT_FILE : &str = "import.toml";
+
+
+///
+/// How an asset should be imported, read from the
+/// sidecar files next to it.
+///
+/// Every option is optional so that a sidecar only has to
+/// mention what differs from the folder defaults. Settings
+/// closer to the asset win: the sidecar over the folder
+/// the asset is in over the folders above it.
+///
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ImportSettings {
+    #[serde(default)]
+    pub texture: TextureImportSettings,
+}
+
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct TextureImportSettings {
+    pub filter: Option<TextureFilter>,
+    /// Splits the image into frames of `[width, height]` pixels
+    pub frames: Option<[usize; 2]>,
+    pub premultiply_alpha: Option<bool>,
+    pub srgb: Option<bool>,
+}
+
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureFilter {
+    /// Keeps pixels sharp, for pixel art
+    #[default]
+    Nearest,
+    Linear,
+}
+
+
+impl ImportSettings {
+    /// Reads and merges every import file that applies to `path`
+    pub fn load(file_system: &FileSystem, path: &str) -> Self {
+        let mut settings = Self::default();
+
+        for file in Self::files(path) {
+            if !file_system.exists(&file) { continue }
+
+            trace!("applying the import settings in '{file}' to '{path}'");
+
+            let data = match file_system.read_to_string(&file) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("unable to read the import settings '{file}': {e}");
+                    continue;
+                },
+            };
+
+            match toml::from_str::<ImportSettings>(&data) {
+                Ok(v) => settings.merge(&v),
+                Err(e) => error!("unable to parse the import settings '{file}': {e}"),
+            }
+        }
+
+        settings
+    }
+
+
+    /// Every file that can hold import settings for `path`,
+    /// in the order they're applied in
+    pub fn files(path: &str) -> Vec<String> {
+        let path = normalize(path);
+        let mut files = vec![FOLDER_IMPORT_FILE.to_string()];
+
+        let mut folder = String::new();
+        let components = path.split('/').collect::<Vec<_>>();
+        for component in components.iter().take(components.len().saturating_sub(1)) {
+            folder.push_str(component);
+            folder.push('/');
+            files.push(format!("{folder}{FOLDER_IMPORT_FILE}"));
+        }
+
+        files.push(format!("{path}{IMPORT_SIDECAR_EXTENSION}"));
+        files
+    }
+
+
+    /// Overwrites the settings that `other` sets
+    pub fn merge(&mut self, other: &ImportSettings) {
+        let (texture, other_texture) = (&mut self.texture, &other.texture);
+        texture.filter = other_texture.filter.or(texture.filter);
+        texture.frames = other_texture.frames.or(texture.frames);
+        texture.premultiply_alpha = other_texture.premultiply_alpha.or(texture.premultiply_alpha);
+        texture.srgb = other_texture.srgb.or(texture.srgb);
+    }
+
+
+    /// Resolves the texture options, using the
+    /// defaults for anything that isn't set
+    pub fn texture(&self) -> TextureImport {
+        let default = TextureImport::default();
+        TextureImport {
+            premultiply_alpha: self.texture.premultiply_alpha.unwrap_or(default.premultiply_alpha),
+            srgb: self.texture.srgb.unwrap_or(default.srgb),
+            filter: self.texture.filter.unwrap_or(default.filter),
+            frames: self.texture.frames.or(default.frames),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn import_settings_files() {
+        assert_eq!(ImportSettings::files("./sprites/hero/idle.png"), vec![
+            "import.toml",
+            "sprites/import.toml",
+            "sprites/hero/import.toml",
+            "sprites/hero/idle.png.import.toml",
+        ]);
+    }
+
+
+    #[test]
+    fn import_settings_merge() {
+        let mut folder : ImportSettings = toml::from_str("[texture]\nfilter = \"nearest\"\nframes = [16, 16]").unwrap();
+        let sidecar : ImportSettings = toml::from_str("[texture]\nframes = [32, 8]\npremultiply_alpha = true").unwrap();
+        folder.merge(&sidecar);
+
+        let texture = folder.texture();
+        assert_eq!(texture.filter, TextureFilter::Nearest);
+        assert_eq!(texture.frames, Some([32, 8]));
+        assert!(texture.premultiply_alpha);
+        assert!(texture.srgb);
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::Cursor;
+
+use image::{ImageFormat, RgbaImage};
+
+use crate::math::vector::Colour;
+
+
+///
+/// A CPU side RGBA8 image that can be edited pixel
+/// by pixel and turned into a texture.
+///
+/// Coordinates start at the top left corner and anything
+/// that falls outside of the image is ignored.
+///
+#[derive(Debug, Clone)]
+pub struct PixelBuffer {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+
+impl PixelBuffer {
+    /// Creates a fully transparent image
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; width * height * 4],
+        }
+    }
+
+
+    /// Decodes an image file of any of the supported formats
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let image = image::load_from_memory(bytes)
+            .map_err(|e| e.to_string())?
+            .into_rgba8();
+
+        Ok(Self {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            data: image.into_raw(),
+        })
+    }
+
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, String> {
+        let image = RgbaImage::from_raw(self.width as u32, self.height as u32, self.data.clone())
+            .ok_or_else(|| String::from("the pixel data doesn't match the size of the image"))?;
+
+        let mut png = Cursor::new(vec![]);
+        image.write_to(&mut png, ImageFormat::Png)
+            .map_err(|e| e.to_string())?;
+
+        Ok(png.into_inner())
+    }
+
+
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
+    pub fn data(&self) -> &[u8] { &self.data }
+
+
+    pub fn get_pixel(&self, x: i64, y: i64) -> Option<Colour> {
+        let index = self.index(x, y)?;
+        let [r, g, b, a] = [0, 1, 2, 3].map(|i| self.data[index + i] as f32 / 255.0);
+        Some(Colour::new(r, g, b, a))
+    }
+
+
+    pub fn set_pixel(&mut self, x: i64, y: i64, colour: Colour) {
+        let Some(index) = self.index(x, y)
+        else { return };
+
+        self.data[index..index + 4].copy_from_slice(&to_rgba8(colour));
+    }
+
+
+    pub fn fill(&mut self, colour: Colour) {
+        let colour = to_rgba8(colour);
+        for pixel in self.data.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&colour);
+        }
+    }
+
+
+    /// Copies `src` into this image with its top left
+    /// corner at `(x, y)`, replacing the pixels under it
+    pub fn blit(&mut self, src: &PixelBuffer, x: i64, y: i64) {
+        for src_y in 0..src.height {
+            let dst_y = y + src_y as i64;
+            if dst_y < 0 || dst_y >= self.height as i64 { continue }
+
+            // clip the row to the bounds of this image
+            let start = (-x).max(0) as usize;
+            let end = (self.width as i64 - x).min(src.width as i64);
+            if end <= start as i64 { return }
+            let end = end as usize;
+
+            let src_index = (src_y * src.width + start) * 4;
+            let dst_index = (dst_y as usize * self.width + (x + start as i64) as usize) * 4;
+            let len = (end - start) * 4;
+
+            self.data[dst_index..dst_index + len]
+                .copy_from_slice(&src.data[src_index..src_index + len]);
+        }
+    }
+
+
+    /// Repeats the edge pixels of the `width`x`height` rectangle
+    /// at `(x, y)` outwards by `border` pixels, corners included
+    pub fn extrude(&mut self, x: i64, y: i64, width: usize, height: usize, border: usize) {
+        if width == 0 || height == 0 { return }
+
+        let border = border as i64;
+        let (right, bottom) = (x + width as i64 - 1, y + height as i64 - 1);
+
+        for dst_y in y - border..=bottom + border {
+            for dst_x in x - border..=right + border {
+                let is_inside = (x..=right).contains(&dst_x) && (y..=bottom).contains(&dst_y);
+                if is_inside { continue }
+
+                let src = self.index(dst_x.clamp(x, right), dst_y.clamp(y, bottom));
+                let (Some(src), Some(dst)) = (src, self.index(dst_x, dst_y))
+                else { continue };
+
+                self.data.copy_within(src..src + 4, dst);
+            }
+        }
+    }
+
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return None;
+        }
+
+        Some((y as usize * self.width + x as usize) * 4)
+    }
+}
+
+
+fn to_rgba8(colour: Colour) -> [u8; 4] {
+    [colour.x, colour.y, colour.z, colour.w]
+        .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn pixel_buffer_blit_clips() {
+        let red = Colour::new(1.0, 0.0, 0.0, 1.0);
+        let blue = Colour::new(0.0, 0.0, 1.0, 1.0);
+
+        let mut dst = PixelBuffer::new(4, 4);
+        dst.fill(blue);
+
+        let mut src = PixelBuffer::new(3, 3);
+        src.fill(red);
+
+        dst.blit(&src, 2, -1);
+
+        assert_eq!(dst.get_pixel(2, 0), Some(red));
+        assert_eq!(dst.get_pixel(3, 1), Some(red));
+        assert_eq!(dst.get_pixel(1, 0), Some(blue));
+        assert_eq!(dst.get_pixel(2, 2), Some(blue));
+        assert_eq!(dst.get_pixel(4, 0), None);
+
+        dst.set_pixel(-1, 0, red);
+        dst.set_pixel(0, 3, red);
+        assert_eq!(dst.get_pixel(0, 3), Some(red));
+    }
+
+
+    #[test]
+    fn pixel_buffer_extrude() {
+        let red = Colour::new(1.0, 0.0, 0.0, 1.0);
+        let blue = Colour::new(0.0, 0.0, 1.0, 1.0);
+
+        let mut src = PixelBuffer::new(2, 2);
+        src.fill(blue);
+        src.set_pixel(0, 0, red);
+
+        let mut dst = PixelBuffer::new(6, 6);
+        dst.blit(&src, 2, 2);
+        dst.extrude(2, 2, 2, 2, 2);
+
+        // the corner and the edges next to the red pixel are red
+        assert_eq!(dst.get_pixel(0, 0), Some(red));
+        assert_eq!(dst.get_pixel(2, 0), Some(red));
+        assert_eq!(dst.get_pixel(0, 2), Some(red));
+        assert_eq!(dst.get_pixel(3, 0), Some(blue));
+        assert_eq!(dst.get_pixel(5, 5), Some(blue));
+        assert_eq!(dst.get_pixel(2, 2), Some(red));
+    }
+}
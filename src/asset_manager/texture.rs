@@ -5,12 +5,35 @@ use tracing::{info, trace};
 
 use crate::{clamp_to_i32, to_cstring};
 
-use super::{AssetManager, TextureId};
+use super::{import::TextureFilter, AssetManager, TextureId};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Texture {
     image: u32,
+    width: usize,
+    height: usize,
+    filter: TextureFilter,
     pub(super) texture_load_type: TextureLoadType,
+    /// Whether the colour channels have already
+    /// been multiplied by the alpha channel
+    premultiplied: bool,
+    /// The size of the texture in GPU memory, in bytes
+    size: usize,
+    usage: TextureUsage,
+    /// The references held outside of scenes, textures
+    /// with references are never garbage collected
+    pub(super) refs: u32,
+    /// Set if the texture is a part of an atlas, the
+    /// image is then shared with the atlas
+    region: Option<TextureRegion>,
+}
+
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct TextureRegion {
+    pub atlas: TextureId,
+    /// `(x, y, width, height)` in UV space
+    pub uv: [f32; 4],
 }
 
 
@@ -30,6 +53,8 @@ pub struct TextureBuilder {
     height: usize,
     usage: TextureUsage,
     sample_count: usize,
+    premultiplied: bool,
+    filter: TextureFilter,
     #[ignore]
     data : Box<[u8]>,
     #[ignore]
@@ -58,6 +83,10 @@ pub enum ColourFormat {
 
     #[default]
     BGRA8,
+    RGBA8,
+    /// RGBA8 where the colour channels are sRGB encoded,
+    /// the GPU decodes them to linear when sampling
+    SRGB8A8,
 
     RGB8UI,
     RGBA8UI,
@@ -83,7 +112,7 @@ pub struct ColourFormatInfo {
 }
 
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, Deserialize, Serialize)]
 pub enum TextureUsage {
     ///
     /// the resource will never be updated with
@@ -120,6 +149,8 @@ impl TextureBuilder {
             height: 0,
             usage: TextureUsage::default(),
             sample_count: 0,
+            premultiplied: false,
+            filter: TextureFilter::default(),
             data: vec![].into(),
             label: String::from("undeclared"),
         }
@@ -149,22 +180,30 @@ impl TextureBuilder {
         info!("- height: {}", self.height);
         info!("- usage: {:?}", self.usage);
         info!("- sample_count: {}", self.sample_count);
+        info!("- premultiplied: {}", self.premultiplied);
+        info!("- filter: {:?}", self.filter);
         info!("- len(data): {}", self.data.len());
 
 
+        let pixel = self.colour_format.info();
+        let size = self.width * self.height * pixel.bytes_per_pixel as usize;
+
         if self.usage == TextureUsage::Immutable {
-            let pixel = self.colour_format.info();
-            assert_eq!(self.data.len(), self.width * self.height * pixel.bytes_per_pixel as usize,
+            assert_eq!(self.data.len(), size,
                     "texture usage pattern `immutable` requires the texture data \
                     to be initialised at the start. but `data.len()`({}) != `width * height * bytes_per_pixel`({}x{}x{} = {})",
                     self.data.len(), self.width, self.height, pixel.bytes_per_pixel, self.width * self.height * pixel.bytes_per_pixel as usize);
         }
 
+        // only immutable textures can be given data on creation,
+        // the rest have to be filled in with `update_texture`
         let mut image_data = ImageData::new();
-        image_data.subimage[0][0] = sg::Range {
-            ptr: self.data.as_ptr().cast(),
-            size: self.data.len(),
-        };
+        if self.usage == TextureUsage::Immutable {
+            image_data.subimage[0][0] = sg::Range {
+                ptr: self.data.as_ptr().cast(),
+                size: self.data.len(),
+            };
+        }
 
         let label = to_cstring("texture label", self.label);
         let image_desc = sg::ImageDesc {
@@ -186,24 +225,148 @@ impl TextureBuilder {
 
         let image = sg::make_image(&image_desc);
 
+        asset_manager.texture_memory += size;
         asset_manager.textures.push(Texture {
             image: image.id,
+            width: self.width,
+            height: self.height,
+            filter: self.filter,
             texture_load_type: TextureLoadType::Runtime,
+            premultiplied: self.premultiplied,
+            size,
+            usage: self.usage,
+            refs: 0,
+            region: None,
         })
     }
 }
 
 
 impl Texture {
+    /// Creates a texture that views a part of `atlas`
+    pub(super) fn region(atlas_id: TextureId, atlas: &Texture, uv: [f32; 4]) -> Self {
+        Self {
+            image: atlas.image,
+            width: (uv[2] * atlas.width as f32).round() as usize,
+            height: (uv[3] * atlas.height as f32).round() as usize,
+            filter: atlas.filter,
+            texture_load_type: TextureLoadType::Runtime,
+            premultiplied: atlas.premultiplied,
+            // the memory belongs to the atlas
+            size: 0,
+            usage: TextureUsage::Immutable,
+            refs: 0,
+            region: Some(TextureRegion { atlas: atlas_id, uv }),
+        }
+    }
+
+
     pub fn inner(&self) -> sg::Image {
         sg::Image{ id: self.image }
     }
 
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+
+    pub fn filter(&self) -> TextureFilter {
+        self.filter
+    }
+
+
+    pub fn region_of(&self) -> Option<TextureRegion> {
+        self.region
+    }
+
+
+    /// The part of the image that the texture covers as
+    /// `(x, y, width, height)` in UV space
+    pub fn uv(&self) -> [f32; 4] {
+        match self.region {
+            Some(region) => region.uv,
+            None => [0.0, 0.0, 1.0, 1.0],
+        }
+    }
+
+
     pub fn load_type(&self) -> &TextureLoadType {
         &self.texture_load_type
     }
 
+
+    pub fn is_premultiplied(&self) -> bool {
+        self.premultiplied
+    }
+
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+
+    pub fn usage(&self) -> TextureUsage {
+        self.usage
+    }
+
+
+    pub fn refs(&self) -> u32 {
+        self.refs
+    }
+
+
+    /// A loaded texture that isn't backed by anything
+    #[cfg(test)]
+    pub(super) fn placeholder(region: Option<TextureRegion>) -> Self {
+        Self {
+            image: 1,
+            width: 1,
+            height: 1,
+            filter: TextureFilter::Nearest,
+            texture_load_type: TextureLoadType::Runtime,
+            premultiplied: false,
+            size: 4,
+            usage: TextureUsage::Immutable,
+            refs: 0,
+            region,
+        }
+    }
+
+
+    pub fn is_loaded(&self) -> bool {
+        self.image != sg::INVALID_ID
+    }
+
+
+    /// Frees the GPU side of the texture, the texture
+    /// can't be drawn afterwards
+    pub(super) fn destroy(&mut self) {
+        if !self.is_loaded() { return }
+
+        // the atlas owns the image
+        if self.region.is_none() {
+            sg::destroy_image(self.inner());
+        }
+
+        self.image = sg::INVALID_ID;
+    }
+
+
+    /// A human readable name for logs
+    pub fn name(&self) -> &str {
+        match &self.texture_load_type {
+            TextureLoadType::Image(v) => v,
+            TextureLoadType::Script(v) => v,
+            TextureLoadType::Runtime => "<runtime>",
+        }
+    }
+
 }
 
 
@@ -212,6 +375,8 @@ impl ColourFormat {
         match self {
             ColourFormat::None => sg::PixelFormat::None,
             ColourFormat::BGRA8 => sg::PixelFormat::Bgra8,
+            ColourFormat::RGBA8 => sg::PixelFormat::Rgba8,
+            ColourFormat::SRGB8A8 => sg::PixelFormat::Srgb8a8,
             ColourFormat::RGB8UI => sg::PixelFormat::Rgba8ui,
             ColourFormat::RGBA8UI => sg::PixelFormat::Rgba8ui,
             ColourFormat::RGB16UI => sg::PixelFormat::Rgba16ui,
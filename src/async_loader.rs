@@ -0,0 +1,545 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::{mpsc, Arc, Mutex}, thread};
+
+use sti::{define_key, keyed::KVec};
+use tracing::{error, info, trace};
+
+use crate::{asset_manager::{import::ImportSettings, split_frame, AssetManager, DecodedImage}, deserialize::scene_file::SceneSource, engine::Engine, file_system::FileSystem, scene_manager::{scene_template::TemplateScene, SceneManager, TemplateId}};
+
+define_key!(u32, pub LoadId);
+
+
+///
+/// Loads scenes in the background.
+///
+/// Reading files, decoding images and parsing scene files
+/// happens on worker threads while uploading textures and
+/// resolving scripts happens on the main thread in `poll`.
+///
+/// Loaded templates are retained since the script that
+/// asked for them is holding on to them, they're released
+/// along with the job.
+///
+/// The slot of a job is reused once its `LoadHandle` is
+/// dropped and the job isn't loading anymore.
+///
+#[derive(Debug)]
+pub struct AsyncLoader {
+    jobs: KVec<LoadId, LoadJob>,
+    free_jobs: Vec<LoadId>,
+    /// The jobs whose handles were dropped since the last `poll`
+    released: Rc<RefCell<Vec<LoadId>>>,
+    /// The jobs waiting on an image that is being decoded
+    images_in_flight: HashMap<String, Vec<LoadId>>,
+    workers: Option<Workers>,
+}
+
+
+///
+/// What scripts hold on to while a scene loads.
+///
+/// Dropping it, which happens when Lua collects it, only
+/// queues the job to be released as the engine could be
+/// borrowed at that point
+///
+#[derive(Debug)]
+pub struct LoadHandle {
+    pub id: LoadId,
+    released: Rc<RefCell<Vec<LoadId>>>,
+}
+
+
+#[derive(Debug)]
+pub struct LoadJob {
+    pub path: String,
+    pub state: LoadState,
+    total_steps: usize,
+    done_steps: usize,
+    pending_images: usize,
+    source: Option<SceneSource>,
+    /// Set when the handle is dropped while the job is
+    /// still loading, the job is freed once it finishes
+    released: bool,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadState {
+    Loading,
+    Done(TemplateId),
+    Failed,
+}
+
+
+#[derive(Debug)]
+struct Workers {
+    tasks: mpsc::Sender<Task>,
+    results: mpsc::Receiver<TaskResult>,
+}
+
+
+#[derive(Debug)]
+enum Task {
+    ParseScene { job: LoadId, path: String },
+    DecodeImage { path: String },
+}
+
+
+#[derive(Debug)]
+enum TaskResult {
+    Scene { job: LoadId, source: Option<SceneSource> },
+    Image { path: String, image: Option<DecodedImage> },
+}
+
+
+impl AsyncLoader {
+    pub fn new() -> Self {
+        Self {
+            jobs: KVec::new(),
+            free_jobs: vec![],
+            released: Rc::new(RefCell::new(vec![])),
+            images_in_flight: HashMap::new(),
+            workers: None,
+        }
+    }
+
+
+    /// Starts loading the scene at `path` in the background
+    pub fn load_scene(engine: &mut Engine, path: &str) -> LoadId {
+        info!("loading '{path}' in the background");
+
+        let mut engine = engine.get_mut();
+        let engine = &mut *engine;
+
+        let cached = engine.scene_manager.path_to_template.get(path).copied();
+        let loader = &mut engine.async_loader;
+
+        let mut job = LoadJob::new(path);
+
+        if let Some(template) = cached {
+            trace!("the scene is already loaded");
+            engine.scene_manager.retain_template(template);
+            job.state = LoadState::Done(template);
+            job.done_steps = job.total_steps;
+            return loader.push(job);
+        }
+
+        // the template ids aren't handed out until then
+        if !engine.scene_manager.is_initialized() {
+            error!("unable to load '{path}' in the background before the scene manager initialized");
+            job.state = LoadState::Failed;
+            return loader.push(job);
+        }
+
+        let id = loader.push(job);
+        loader.workers().send(Task::ParseScene { job: id, path: path.to_string() });
+        id
+    }
+
+
+    pub fn job(&self, id: LoadId) -> &LoadJob {
+        &self.jobs[id]
+    }
+
+
+    /// Hands out the handle of `id` for a script to hold on to,
+    /// there should only ever be one handle per job
+    pub fn handle(&self, id: LoadId) -> LoadHandle {
+        LoadHandle { id, released: self.released.clone() }
+    }
+
+
+    fn push(&mut self, job: LoadJob) -> LoadId {
+        match self.free_jobs.pop() {
+            Some(id) => {
+                self.jobs[id] = job;
+                id
+            },
+            None => self.jobs.push(job),
+        }
+    }
+
+
+    /// Frees the jobs whose handles were dropped, the ones that
+    /// are still loading are freed by `finish`.
+    ///
+    /// Returns the templates the freed jobs retained
+    fn collect_released(&mut self) -> Vec<TemplateId> {
+        let released = core::mem::take(&mut *self.released.borrow_mut());
+
+        let mut templates = vec![];
+        for id in released {
+            let job = &mut self.jobs[id];
+            if job.state == LoadState::Loading {
+                job.released = true;
+                continue;
+            }
+
+            templates.extend(self.free(id));
+        }
+
+        templates
+    }
+
+
+    /// Returns the template the job retained, if it had one
+    fn free(&mut self, id: LoadId) -> Option<TemplateId> {
+        let job = core::mem::replace(&mut self.jobs[id], LoadJob::new(""));
+        self.free_jobs.push(id);
+
+        match job.state {
+            LoadState::Done(template) => Some(template),
+            _ => None,
+        }
+    }
+
+
+    /// Makes the job wait on `images`, returns the ones that
+    /// need to be decoded as nobody else is decoding them yet
+    fn wait_for_images(&mut self, job_id: LoadId, images: Vec<String>) -> Vec<String> {
+        let job = &mut self.jobs[job_id];
+        job.done_steps += 1;
+        job.total_steps += images.len();
+        job.pending_images = images.len();
+
+        let mut to_decode = vec![];
+        for image in images {
+            let waiting = self.images_in_flight.entry(image.clone()).or_default();
+            waiting.push(job_id);
+
+            // somebody else is already decoding it
+            if waiting.len() > 1 { continue }
+
+            to_decode.push(image);
+        }
+
+        to_decode
+    }
+
+
+    /// Counts the image as done for every job that was
+    /// waiting on it, returns the jobs that are ready
+    fn image_done(&mut self, path: &str) -> Vec<LoadId> {
+        let waiting = self.images_in_flight.remove(path)
+            .unwrap_or_default();
+
+        let mut ready = vec![];
+        for job_id in waiting {
+            let job = &mut self.jobs[job_id];
+            job.done_steps += 1;
+            job.pending_images -= 1;
+
+            if job.pending_images == 0 {
+                ready.push(job_id);
+            }
+        }
+
+        ready
+    }
+
+
+    /// Counts the last step and frees the job if its handle
+    /// was dropped while it was loading, returns the template
+    /// the job retained if it was freed
+    fn set_finished(&mut self, job_id: LoadId, state: LoadState) -> Option<TemplateId> {
+        let job = &mut self.jobs[job_id];
+        job.done_steps = job.total_steps;
+        job.state = state;
+
+        if !job.released { return None }
+        self.free(job_id)
+    }
+
+
+    /// Processes the work the worker threads finished,
+    /// must be called from the main thread
+    pub fn poll(engine: &mut Engine) {
+        engine.with(|engine| {
+            for template in engine.async_loader.collect_released() {
+                engine.scene_manager.release_template(template);
+            }
+        });
+
+        loop {
+            let result = engine.with(|engine| {
+                engine.async_loader.workers.as_ref()
+                    .map(|workers| workers.results.try_recv().ok())
+                    .flatten()
+            });
+
+            let Some(result) = result
+            else { break };
+
+            match result {
+                TaskResult::Scene { job, source } => Self::on_scene_parsed(engine, job, source),
+                TaskResult::Image { path, image } => Self::on_image_decoded(engine, path, image),
+            }
+        }
+    }
+
+
+    fn on_scene_parsed(engine: &mut Engine, job_id: LoadId, source: Option<SceneSource>) {
+        let is_ready = engine.with(|engine| {
+            let asset_manager = &engine.asset_manager;
+            let loader = &mut engine.async_loader;
+
+            let Some(source) = source
+            else {
+                error!("failed to load '{}' in the background", loader.jobs[job_id].path);
+                loader.set_finished(job_id, LoadState::Failed);
+                return false;
+            };
+
+
+            let mut images = vec![];
+            for texture in source.textures() {
+                let Some(path) = texture.strip_prefix("image:")
+                else { continue };
+
+                // frames and packed images are loaded
+                // through the image they're a part of
+                let (path, _) = split_frame(path);
+                let path = asset_manager.source_image(path);
+
+                if asset_manager.is_loaded(path) || images.iter().any(|x| x == path) { continue }
+                images.push(path.to_string());
+            }
+
+
+            loader.jobs[job_id].source = Some(source);
+
+            for image in loader.wait_for_images(job_id, images) {
+                loader.workers().send(Task::DecodeImage { path: image });
+            }
+
+
+            loader.jobs[job_id].pending_images == 0
+        });
+
+        if is_ready {
+            Self::finish(engine, job_id);
+        }
+    }
+
+
+    fn on_image_decoded(engine: &mut Engine, path: String, image: Option<DecodedImage>) {
+        let finished = engine.with(|engine| {
+            if let Some(image) = image {
+                engine.asset_manager.upload_image(&path, image);
+            }
+
+            engine.async_loader.image_done(&path)
+        });
+
+        for job in finished {
+            Self::finish(engine, job);
+        }
+    }
+
+
+    /// Builds the template once all of the
+    /// images of the scene are on the GPU
+    fn finish(engine: &mut Engine, job_id: LoadId) {
+        let (path, source) = engine.with(|engine| {
+            let job = &mut engine.async_loader.jobs[job_id];
+            (job.path.clone(), job.source.take().unwrap())
+        });
+
+        let span = tracing::span!(tracing::Level::ERROR, "deserialize ", path);
+        let _handle = span.entered();
+
+        let scene = TemplateScene::from_source(engine, &path, &source);
+        let state = if scene.len() == 0 {
+            LoadState::Failed
+        } else {
+            let template = SceneManager::insert_template(engine, &path, scene);
+            engine.get_mut().scene_manager.retain_template(template);
+            LoadState::Done(template)
+        };
+
+        engine.with(|engine| {
+            if let Some(template) = engine.async_loader.set_finished(job_id, state) {
+                engine.scene_manager.release_template(template);
+            }
+        });
+
+        info!("finished loading '{path}' in the background");
+    }
+
+
+    fn workers(&mut self) -> &Workers {
+        self.workers.get_or_insert_with(|| Workers::spawn(Engine::file_system()))
+    }
+}
+
+
+impl LoadJob {
+    fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            state: LoadState::Loading,
+            total_steps: 2,
+            done_steps: 0,
+            pending_images: 0,
+            source: None,
+            released: false,
+        }
+    }
+
+
+    /// How much of the job is done, from 0 to 1
+    pub fn progress(&self) -> f32 {
+        self.done_steps as f32 / self.total_steps as f32
+    }
+}
+
+
+impl Drop for LoadHandle {
+    fn drop(&mut self) {
+        self.released.borrow_mut().push(self.id);
+    }
+}
+
+
+impl Workers {
+    fn spawn(file_system: &'static FileSystem) -> Self {
+        let (task_sender, task_receiver) = mpsc::channel::<Task>();
+        let (result_sender, result_receiver) = mpsc::channel();
+        let task_receiver = Arc::new(Mutex::new(task_receiver));
+
+        let count = thread::available_parallelism()
+            .map(|x| x.get())
+            .unwrap_or(1)
+            .clamp(1, 4);
+
+        info!("spawning {count} loader threads");
+
+        for i in 0..count {
+            let tasks = task_receiver.clone();
+            let results = result_sender.clone();
+
+            let spawned = thread::Builder::new()
+                .name(format!("loader {i}"))
+                .spawn(move || {
+                    loop {
+                        let task = tasks.lock().unwrap().recv();
+                        let Ok(task) = task
+                        else { return };
+
+                        let result = match task {
+                            Task::ParseScene { job, path } => {
+                                let source = TemplateScene::read_source(file_system, &path);
+                                TaskResult::Scene { job, source }
+                            },
+
+
+                            Task::DecodeImage { path } => {
+                                let import = ImportSettings::load(file_system, &path).texture();
+                                let image = AssetManager::decode_image(file_system, &path, import);
+                                TaskResult::Image { path, image }
+                            },
+                        };
+
+                        if results.send(result).is_err() { return }
+                    }
+                });
+
+            if let Err(e) = spawned {
+                error!("unable to spawn a loader thread: {e}");
+            }
+        }
+
+        Self {
+            tasks: task_sender,
+            results: result_receiver,
+        }
+    }
+
+
+    fn send(&self, task: Task) {
+        if self.tasks.send(task).is_err() {
+            error!("all of the loader threads have stopped");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{test_dir, test_engine};
+
+    use super::*;
+
+
+    #[test]
+    fn async_loader_steps() {
+        let mut loader = AsyncLoader::new();
+        let a = loader.push(LoadJob::new("a.scn"));
+        let b = loader.push(LoadJob::new("b.scn"));
+        assert_eq!(loader.job(a).progress(), 0.0);
+
+        // the shared image is only decoded once
+        let decode = loader.wait_for_images(a, vec!["pipe.png".to_string(), "bird.png".to_string()]);
+        assert_eq!(decode, vec!["pipe.png", "bird.png"]);
+        let decode = loader.wait_for_images(b, vec!["pipe.png".to_string()]);
+        assert!(decode.is_empty());
+
+        // parsing, two images and building the template
+        assert_eq!(loader.job(a).total_steps, 4);
+        assert_eq!(loader.job(a).progress(), 0.25);
+
+        assert_eq!(loader.image_done("pipe.png"), vec![b]);
+        assert_eq!(loader.job(a).progress(), 0.5);
+        assert_eq!(loader.job(b).progress(), 2.0 / 3.0);
+
+        assert_eq!(loader.image_done("bird.png"), vec![a]);
+        loader.set_finished(a, LoadState::Failed);
+        assert_eq!(loader.job(a).progress(), 1.0);
+        assert_eq!(loader.job(a).state, LoadState::Failed);
+    }
+
+
+    #[test]
+    fn async_loader_recycles_jobs() {
+        let mut loader = AsyncLoader::new();
+        let done = loader.push(LoadJob::new("a.scn"));
+        let loading = loader.push(LoadJob::new("b.scn"));
+        loader.set_finished(done, LoadState::Failed);
+
+        drop(loader.handle(done));
+        drop(loader.handle(loading));
+        loader.collect_released();
+
+        // the loading job is kept until it finishes
+        assert_eq!(loader.push(LoadJob::new("c.scn")), done);
+        assert_eq!(loader.job(loading).path, "b.scn");
+
+        loader.set_finished(loading, LoadState::Failed);
+        assert_eq!(loader.push(LoadJob::new("d.scn")), loading);
+    }
+
+
+    #[test]
+    fn async_loader_releases_templates() {
+        let (_guard, mut engine) = test_engine();
+        std::fs::write(test_dir().join("async.scene"), "version = 2\n\n[root]\nname = \"Async\"").unwrap();
+
+        let template = SceneManager::template_from_file(&mut engine, "async.scene");
+        let id = AsyncLoader::load_scene(&mut engine, "async.scene");
+        let handle = engine.get().async_loader.handle(id);
+        assert_eq!(engine.get().async_loader.job(id).state, LoadState::Done(template));
+
+        let is_cached = |engine: &mut Engine| {
+            engine.with(|engine| {
+                engine.scene_manager.evict_templates();
+                engine.scene_manager.path_to_template.contains_key("async.scene")
+            })
+        };
+
+        AsyncLoader::poll(&mut engine);
+        assert!(is_cached(&mut engine));
+
+        drop(handle);
+        AsyncLoader::poll(&mut engine);
+        assert!(!is_cached(&mut engine));
+    }
+}
@@ -0,0 +1,352 @@
+pub mod sound;
+pub mod mixer;
+pub mod bus;
+pub mod effect;
+pub mod stream;
+pub mod synth;
+
+use std::{collections::HashMap, ffi::c_void, sync::{Arc, Mutex, MutexGuard, Weak}};
+
+use bus::BusId;
+use mixer::{Mixer, PlayOptions, VoiceId};
+use sokol::audio as saudio;
+use sound::Sound;
+use stream::{LoopPoints, Stream, Streamer};
+use synth::Tone;
+use tracing::{error, info, trace, warn};
+
+use crate::{file_system::FileSystem, math::vector::Vec2, settings::AudioSettings};
+
+
+/// The sample rate used until the device tells us its own
+const DEFAULT_SAMPLE_RATE : u32 = 44100;
+
+
+///
+/// Owns the decoded sounds and the mixer that
+/// feeds the sokol audio stream.
+///
+/// The mixer is shared with the audio thread, so every
+/// access to it goes through a short lived lock.
+///
+#[derive(Debug)]
+pub struct AudioManager {
+    mixer: Arc<Mutex<Mixer>>,
+    sounds: HashMap<String, Arc<Sound>>,
+    streamer: Streamer,
+    /// The music track that's playing, it isn't tied to
+    /// a scene so it keeps playing across scene changes
+    music: Option<Music>,
+    is_running: bool,
+}
+
+
+#[derive(Debug)]
+struct Music {
+    path: String,
+    voice: VoiceId,
+    stream: Weak<Stream>,
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct MusicOptions {
+    pub volume: f32,
+    /// How long it takes the track to reach
+    /// its volume, in seconds
+    pub fade_in: f32,
+    /// `None` if the track only plays once
+    pub looping: Option<LoopPoints>,
+}
+
+
+impl Default for MusicOptions {
+    fn default() -> Self {
+        Self { volume: 1.0, fade_in: 0.0, looping: Some(LoopPoints::default()) }
+    }
+}
+
+
+impl AudioManager {
+    pub fn new(settings: &AudioSettings) -> Self {
+        let mut mixer = Mixer::new(DEFAULT_SAMPLE_RATE);
+        mixer.set_buses(settings);
+
+        Self {
+            mixer: Arc::new(Mutex::new(mixer)),
+            sounds: HashMap::new(),
+            streamer: Streamer::new(),
+            music: None,
+            is_running: false,
+        }
+    }
+
+
+    /// Opens the audio device, the game keeps
+    /// running silently if there isn't one
+    pub fn init(&mut self) {
+        info!("starting the audio device");
+
+        saudio::setup(&saudio::Desc {
+            sample_rate: DEFAULT_SAMPLE_RATE as i32,
+            num_channels: 2,
+            stream_userdata_cb: Some(stream),
+            // the mixer outlives the device, it's only
+            // dropped after `shutdown` stops the stream
+            user_data: Arc::as_ptr(&self.mixer) as *mut c_void,
+            logger: saudio::Logger {
+                func: Some(sokol::log::slog_func),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        if !saudio::isvalid() {
+            error!("unable to start the audio device, no sound will be played");
+            return;
+        }
+
+        let sample_rate = saudio::sample_rate() as u32;
+        info!("audio device running at {sample_rate}hz with {} channels", saudio::channels());
+
+        self.mixer().set_sample_rate(sample_rate);
+        self.is_running = true;
+    }
+
+
+    pub fn shutdown(&mut self) {
+        self.streamer.shutdown();
+        if !self.is_running { return }
+
+        info!("stopping the audio device");
+        saudio::shutdown();
+        self.is_running = false;
+    }
+
+
+    /// Locks the mixer, the audio thread waits
+    /// until the guard is dropped
+    pub fn mixer(&self) -> MutexGuard<'_, Mixer> {
+        // a panic on the audio thread can't leave the
+        // mixer in a state worse than a skipped buffer
+        self.mixer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+
+    /// Decodes the sound at `path`, or returns
+    /// the cached one if it was already loaded
+    pub fn load(&mut self, file_system: &FileSystem, path: &str) -> Option<Arc<Sound>> {
+        if let Some(sound) = self.sounds.get(path) {
+            return Some(sound.clone());
+        }
+
+        trace!("loading sound '{path}'");
+
+        let bytes = match file_system.read(path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("unable to read the sound '{path}': {e}");
+                return None;
+            },
+        };
+
+        let sound = match Sound::decode(&bytes) {
+            Ok(v) => Arc::new(v),
+            Err(e) => {
+                error!("unable to decode the sound '{path}': {e}");
+                return None;
+            },
+        };
+
+        if sound.channels > 2 {
+            warn!("'{path}' has {} channels, only the first two will be played", sound.channels);
+        }
+
+        self.sounds.insert(path.to_string(), sound.clone());
+        Some(sound)
+    }
+
+
+    pub fn play(&mut self, file_system: &FileSystem, path: &str, options: PlayOptions) -> Option<VoiceId> {
+        let sound = self.load(file_system, path)?;
+        Some(self.mixer().play(sound, options))
+    }
+
+
+    pub fn stop(&self, voice: VoiceId) {
+        self.mixer().stop(voice);
+    }
+
+
+    pub fn is_playing(&self, voice: VoiceId) -> bool {
+        self.mixer().is_playing(voice)
+    }
+
+
+    pub fn set_volume(&self, voice: VoiceId, volume: f32) {
+        if let Some(options) = self.mixer().options_mut(voice) {
+            options.volume = volume;
+        }
+    }
+
+
+    pub fn set_pan(&self, voice: VoiceId, pan: f32) {
+        if let Some(options) = self.mixer().options_mut(voice) {
+            options.pan = pan;
+        }
+    }
+
+
+    /// Renders `tone` at the device's sample rate and plays it
+    pub fn play_tone(&self, tone: &Tone, options: PlayOptions) -> VoiceId {
+        // the mixer isn't locked while rendering
+        // so the audio thread never waits on it
+        let sample_rate = self.mixer().sample_rate();
+        let sound = tone.render(sample_rate);
+        self.mixer().play(Arc::new(sound), options)
+    }
+
+
+    ///
+    /// Plays a stream that's fed by pushing samples into it,
+    /// see `Stream::queue`.
+    ///
+    /// Returns the voice along with the stream to push into.
+    ///
+    pub fn play_queue(&self, sample_rate: u32, channels: usize,
+                      options: PlayOptions) -> Result<(VoiceId, Arc<Stream>), String> {
+        let stream = Arc::new(Stream::queue(sample_rate, channels)?);
+        let voice = self.mixer().play_stream(stream.clone(), options);
+        Ok((voice, stream))
+    }
+
+
+    ///
+    /// Streams the track at `path` on the `music` bus,
+    /// replacing the current one.
+    ///
+    /// The current track fades out over `crossfade` seconds
+    /// while the new one fades in over `options.fade_in`.
+    ///
+    pub fn play_music(&mut self, file_system: &FileSystem, path: &str,
+                      options: MusicOptions, crossfade: f32) -> bool {
+        trace!("streaming music '{path}'");
+
+        // nothing is decoded up front and only
+        // the header of a WAV file is read
+        let file = match file_system.open(path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("unable to open the music '{path}': {e}");
+                return false;
+            },
+        };
+
+        let stream = match Stream::open(file, options.looping) {
+            Ok(v) => Arc::new(v),
+            Err(e) => {
+                error!("unable to stream the music '{path}': {e}");
+                return false;
+            },
+        };
+
+        self.stop_music(crossfade);
+        self.streamer.add(&stream);
+
+        let mut mixer = self.mixer();
+        let bus = mixer.bus_id("music").unwrap_or(BusId::MASTER);
+        let voice = mixer.play_stream(stream.clone(), PlayOptions {
+            volume: options.volume,
+            bus,
+            ..Default::default()
+        });
+
+        mixer.fade(voice, Some(0.0), 1.0, options.fade_in, false);
+        drop(mixer);
+
+        self.music = Some(Music { path: path.to_string(), voice, stream: Arc::downgrade(&stream) });
+        true
+    }
+
+
+    /// Fades the current track out over `fade_out` seconds
+    pub fn stop_music(&mut self, fade_out: f32) {
+        let Some(music) = self.music.take()
+        else { return };
+
+        self.mixer().fade(music.voice, None, 0.0, fade_out, true);
+    }
+
+
+    /// The path of the track that's playing
+    pub fn music(&self) -> Option<&str> {
+        let music = self.music.as_ref()?;
+        if !self.is_playing(music.voice) { return None }
+
+        Some(&music.path)
+    }
+
+
+    /// Where the current track is, in seconds
+    pub fn music_position(&self) -> Option<f32> {
+        let music = self.music.as_ref()?;
+        Some(music.stream.upgrade()?.position())
+    }
+}
+
+
+///
+/// The `(gain, pan)` of a sound at `position` heard
+/// by a listener at `listener`.
+///
+/// The sound fades out until it can't be heard past
+/// `max_distance`, `attenuation` is the exponent of the
+/// fade so 1 is linear and higher values fade out faster.
+/// The pan reaches a side once the sound is `half_width`
+/// away from the listener horizontally. A `max_distance`
+/// of 0 or less means the sound isn't positional.
+///
+pub fn positional(listener: Vec2, half_width: f32, position: Vec2,
+                  max_distance: f32, attenuation: f32) -> (f32, f32) {
+    if max_distance <= 0.0 { return (1.0, 0.0) }
+
+    let (dx, dy) = (position.x - listener.x, position.y - listener.y);
+    let distance = (dx * dx + dy * dy).sqrt();
+
+    let gain = (1.0 - distance / max_distance).clamp(0.0, 1.0).powf(attenuation.max(0.0));
+    let pan = if half_width > 0.0 { (dx / half_width).clamp(-1.0, 1.0) } else { 0.0 };
+
+    (gain, pan)
+}
+
+
+/// Called by sokol on the audio thread whenever
+/// the device needs more samples
+extern "C" fn stream(buffer: *mut f32, num_frames: i32, num_channels: i32, user_data: *mut c_void) {
+    let len = (num_frames * num_channels) as usize;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer, len) };
+    let mixer = unsafe { &*(user_data as *const Mutex<Mixer>) };
+
+    let mut mixer = mixer.lock().unwrap_or_else(|e| e.into_inner());
+    mixer.mix(buffer, num_channels as usize);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn audio_positional() {
+        let listener = Vec2::new(10.0, 0.0);
+
+        assert_eq!(positional(listener, 5.0, Vec2::new(10.0, 0.0), 20.0, 1.0), (1.0, 0.0));
+        assert_eq!(positional(listener, 5.0, Vec2::new(20.0, 0.0), 20.0, 1.0), (0.5, 1.0));
+        assert_eq!(positional(listener, 5.0, Vec2::new(8.0, 0.0), 4.0, 2.0), (0.25, -0.4));
+        assert_eq!(positional(listener, 5.0, Vec2::new(10.0, -30.0), 20.0, 1.0).0, 0.0);
+
+        // not positional at all
+        assert_eq!(positional(listener, 5.0, Vec2::new(100.0, 0.0), 0.0, 1.0), (1.0, 0.0));
+    }
+}
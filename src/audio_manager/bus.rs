@@ -0,0 +1,129 @@
+use tracing::warn;
+
+use crate::settings::AudioSettings;
+
+use super::effect::Effect;
+
+
+/// The buses that always exist, in this order
+pub const DEFAULT_BUSES : &[&str] = &["master", "music", "sfx", "ui"];
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BusId(pub(super) usize);
+
+
+impl BusId {
+    pub const MASTER : BusId = BusId(0);
+}
+
+
+///
+/// A group of voices that share a volume and effects,
+/// every bus ends up mixed into `master`
+///
+#[derive(Debug)]
+pub struct Bus {
+    pub name: String,
+    pub volume: f32,
+    pub muted: bool,
+    pub effects: Vec<Effect>,
+    parent: Option<BusId>,
+    /// The interleaved stereo samples mixed into this bus
+    pub(super) buffer: Vec<f32>,
+}
+
+
+impl Bus {
+    /// The bus this one is mixed into, only `master` has none
+    pub fn parent(&self) -> Option<BusId> { self.parent }
+
+
+    /// The gain applied to everything on this bus
+    pub fn gain(&self) -> f32 {
+        if self.muted { 0.0 } else { self.volume }
+    }
+}
+
+
+///
+/// Creates the buses declared in `settings` along with
+/// the default ones.
+///
+/// Returns the buses, with `master` first, and the order
+/// they have to be processed in so that every bus is done
+/// before its parent.
+///
+pub fn build(settings: &AudioSettings) -> (Vec<Bus>, Vec<BusId>) {
+    let mut names = DEFAULT_BUSES.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    for name in settings.buses.keys() {
+        if !names.contains(name) { names.push(name.clone()) }
+    }
+
+    let mut buses = names.iter()
+        .map(|name| {
+            let bus = settings.buses.get(name).cloned().unwrap_or_default();
+            Bus {
+                name: name.clone(),
+                volume: bus.volume,
+                muted: bus.muted,
+                effects: bus.effects.into_iter().map(Effect::new).collect(),
+                parent: None,
+                buffer: vec![],
+            }
+        })
+        .collect::<Vec<_>>();
+
+
+    for (i, name) in names.iter().enumerate().skip(1) {
+        let parent = settings.buses.get(name).and_then(|x| x.parent.as_ref());
+        let parent = match parent {
+            Some(parent) => match names.iter().position(|x| x == parent) {
+                Some(v) if v != i => v,
+                _ => {
+                    warn!("the bus '{name}' can't be mixed into '{parent}', using 'master' instead");
+                    0
+                },
+            },
+            None => 0,
+        };
+
+        buses[i].parent = Some(BusId(parent));
+    }
+
+
+    // break cycles by mixing the bus that closes them into master
+    for i in 0..buses.len() {
+        let mut path = vec![i];
+        let mut current = i;
+        while let Some(parent) = buses[current].parent {
+            if path.contains(&parent.0) {
+                warn!("the bus '{}' ends up mixed into itself, using 'master' instead", buses[current].name);
+                buses[current].parent = Some(BusId::MASTER);
+                break;
+            }
+
+            path.push(parent.0);
+            current = parent.0;
+        }
+    }
+
+
+    let depths = (0..buses.len())
+        .map(|i| {
+            let mut depth = 0;
+            let mut current = i;
+            while let Some(parent) = buses[current].parent {
+                depth += 1;
+                current = parent.0;
+            }
+
+            depth
+        })
+        .collect::<Vec<_>>();
+
+    let mut order = (0..buses.len()).map(BusId).collect::<Vec<_>>();
+    order.sort_by_key(|x| core::cmp::Reverse(depths[x.0]));
+
+    (buses, order)
+}
@@ -0,0 +1,352 @@
+use core::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+
+///
+/// An effect as declared in the project settings
+/// or created from a script
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EffectSettings {
+    /// Removes the frequencies above `cutoff` hz,
+    /// makes everything sound muffled
+    LowPass {
+        cutoff: f32,
+        #[serde(default = "default_q")]
+        q: f32,
+    },
+
+    /// Removes the frequencies below `cutoff` hz,
+    /// makes everything sound thin
+    HighPass {
+        cutoff: f32,
+        #[serde(default = "default_q")]
+        q: f32,
+    },
+
+    Reverb {
+        /// From 0 to 1, how long the tail is
+        #[serde(default = "default_half")]
+        room_size: f32,
+        /// From 0 to 1, how quickly the high frequencies fade
+        #[serde(default = "default_half")]
+        damping: f32,
+        /// From 0 (dry) to 1 (only the reverb)
+        #[serde(default = "default_mix")]
+        mix: f32,
+    },
+
+    /// Turns down anything louder than `threshold`
+    Compressor {
+        /// In decibels
+        #[serde(default = "default_threshold")]
+        threshold: f32,
+        #[serde(default = "default_ratio")]
+        ratio: f32,
+        /// In seconds
+        #[serde(default = "default_attack")]
+        attack: f32,
+        /// In seconds
+        #[serde(default = "default_release")]
+        release: f32,
+        /// The gain applied after compressing, in decibels
+        #[serde(default)]
+        makeup: f32,
+    },
+}
+
+
+///
+/// An effect on a bus along with the state it
+/// needs to carry over from one buffer to the next
+///
+#[derive(Debug)]
+pub struct Effect {
+    pub settings: EffectSettings,
+    pub enabled: bool,
+    state: EffectState,
+    /// The sample rate the state was built for
+    sample_rate: u32,
+}
+
+
+#[derive(Debug)]
+enum EffectState {
+    Biquad([Biquad; 2]),
+    Reverb(Box<[Reverb; 2]>),
+    Compressor { envelope: f32 },
+}
+
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Biquad {
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+
+#[derive(Debug)]
+struct Reverb {
+    combs: Vec<DelayLine>,
+    allpasses: Vec<DelayLine>,
+}
+
+
+#[derive(Debug)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    index: usize,
+    /// The low-pass state of comb filters
+    store: f32,
+}
+
+
+/// The delay lengths from freeverb, tuned for 44100hz
+const COMB_LENGTHS : [usize; 4] = [1116, 1188, 1277, 1356];
+const ALLPASS_LENGTHS : [usize; 2] = [556, 441];
+/// How much longer the delays of the right channel are
+const STEREO_SPREAD : usize = 23;
+
+
+impl Effect {
+    pub fn new(settings: EffectSettings) -> Self {
+        Self {
+            settings,
+            enabled: true,
+            state: EffectState::new(&settings, 0),
+            sample_rate: 0,
+        }
+    }
+
+
+    /// Processes interleaved stereo samples in place
+    pub fn process(&mut self, buffer: &mut [f32], sample_rate: u32) {
+        if !self.enabled { return }
+
+        if self.sample_rate != sample_rate || !self.state.fits(&self.settings) {
+            self.state = EffectState::new(&self.settings, sample_rate);
+            self.sample_rate = sample_rate;
+        }
+
+        let sample_rate = sample_rate as f32;
+        match (&self.settings, &mut self.state) {
+            (EffectSettings::LowPass { cutoff, q }, EffectState::Biquad(filters)) => {
+                let coefficients = biquad_coefficients(*cutoff, *q, sample_rate, false);
+                process_biquad(filters, coefficients, buffer);
+            },
+
+
+            (EffectSettings::HighPass { cutoff, q }, EffectState::Biquad(filters)) => {
+                let coefficients = biquad_coefficients(*cutoff, *q, sample_rate, true);
+                process_biquad(filters, coefficients, buffer);
+            },
+
+
+            (EffectSettings::Reverb { room_size, damping, mix }, EffectState::Reverb(reverbs)) => {
+                let feedback = 0.7 + room_size.clamp(0.0, 1.0) * 0.28;
+                let damping = damping.clamp(0.0, 1.0) * 0.4;
+                let mix = mix.clamp(0.0, 1.0);
+
+                for frame in buffer.chunks_exact_mut(2) {
+                    let input = (frame[0] + frame[1]) * 0.015;
+                    for (sample, reverb) in frame.iter_mut().zip(reverbs.iter_mut()) {
+                        let wet = reverb.process(input, feedback, damping);
+                        *sample = *sample * (1.0 - mix) + wet * mix;
+                    }
+                }
+            },
+
+
+            (EffectSettings::Compressor { threshold, ratio, attack, release, makeup },
+             EffectState::Compressor { envelope }) => {
+                let attack = (-1.0 / (attack.max(0.0001) * sample_rate)).exp();
+                let release = (-1.0 / (release.max(0.0001) * sample_rate)).exp();
+                let slope = 1.0 - 1.0 / ratio.max(1.0);
+                let (threshold, makeup) = (*threshold, *makeup);
+
+                for frame in buffer.chunks_exact_mut(2) {
+                    let level = frame[0].abs().max(frame[1].abs());
+                    let coefficient = if level > *envelope { attack } else { release };
+                    *envelope = coefficient * *envelope + (1.0 - coefficient) * level;
+
+                    let over = 20.0 * envelope.max(0.000001).log10() - threshold;
+                    let reduction = if over > 0.0 { -over * slope } else { 0.0 };
+                    let gain = 10f32.powf((reduction + makeup) / 20.0);
+
+                    frame[0] *= gain;
+                    frame[1] *= gain;
+                }
+            },
+
+
+            _ => unreachable!(),
+        }
+    }
+}
+
+
+impl EffectState {
+    fn new(settings: &EffectSettings, sample_rate: u32) -> Self {
+        match settings {
+            EffectSettings::LowPass { .. }
+            | EffectSettings::HighPass { .. } => Self::Biquad([Biquad::default(); 2]),
+
+            EffectSettings::Reverb { .. } => {
+                let scale = sample_rate as f32 / 44100.0;
+                let reverb = |spread: usize| Reverb {
+                    combs: COMB_LENGTHS.iter()
+                        .map(|x| DelayLine::new(((x + spread) as f32 * scale) as usize))
+                        .collect(),
+                    allpasses: ALLPASS_LENGTHS.iter()
+                        .map(|x| DelayLine::new(((x + spread) as f32 * scale) as usize))
+                        .collect(),
+                };
+
+                Self::Reverb(Box::new([reverb(0), reverb(STEREO_SPREAD)]))
+            },
+
+            EffectSettings::Compressor { .. } => Self::Compressor { envelope: 0.0 },
+        }
+    }
+
+
+    /// Whether this state belongs to `settings`, scripts
+    /// can swap an effect for another kind at any time
+    fn fits(&self, settings: &EffectSettings) -> bool {
+        matches!((self, settings),
+            (Self::Biquad(_), EffectSettings::LowPass { .. } | EffectSettings::HighPass { .. })
+            | (Self::Reverb(_), EffectSettings::Reverb { .. })
+            | (Self::Compressor { .. }, EffectSettings::Compressor { .. }))
+    }
+}
+
+
+impl Reverb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let mut output = 0.0;
+        for comb in self.combs.iter_mut() {
+            let delayed = comb.buffer[comb.index];
+            comb.store = delayed * (1.0 - damping) + comb.store * damping;
+            comb.buffer[comb.index] = input + comb.store * feedback;
+            comb.index = (comb.index + 1) % comb.buffer.len();
+            output += delayed;
+        }
+
+        for allpass in self.allpasses.iter_mut() {
+            let delayed = allpass.buffer[allpass.index];
+            allpass.buffer[allpass.index] = output + delayed * 0.5;
+            allpass.index = (allpass.index + 1) % allpass.buffer.len();
+            output = delayed - output;
+        }
+
+        output
+    }
+}
+
+
+impl DelayLine {
+    fn new(length: usize) -> Self {
+        Self { buffer: vec![0.0; length.max(1)], index: 0, store: 0.0 }
+    }
+}
+
+
+/// The normalised `[b0, b1, b2, a1, a2]` coefficients
+/// of a low or high pass filter
+fn biquad_coefficients(cutoff: f32, q: f32, sample_rate: f32, high_pass: bool) -> [f32; 5] {
+    let cutoff = cutoff.clamp(10.0, sample_rate * 0.45);
+    let w0 = 2.0 * PI * cutoff / sample_rate;
+    let (sin, cos) = w0.sin_cos();
+    let alpha = sin / (2.0 * q.max(0.01));
+
+    let (b0, b1, b2) = if high_pass { ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0) }
+                       else { ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0) };
+
+    let a0 = 1.0 + alpha;
+    [b0 / a0, b1 / a0, b2 / a0, -2.0 * cos / a0, (1.0 - alpha) / a0]
+}
+
+
+fn process_biquad(filters: &mut [Biquad; 2], [b0, b1, b2, a1, a2]: [f32; 5], buffer: &mut [f32]) {
+    for frame in buffer.chunks_exact_mut(2) {
+        for (sample, filter) in frame.iter_mut().zip(filters.iter_mut()) {
+            let x = *sample;
+            let y = b0 * x + b1 * filter.x1 + b2 * filter.x2 - a1 * filter.y1 - a2 * filter.y2;
+
+            filter.x2 = filter.x1;
+            filter.x1 = x;
+            filter.y2 = filter.y1;
+            filter.y1 = y;
+            *sample = y;
+        }
+    }
+}
+
+
+fn default_q() -> f32 { core::f32::consts::FRAC_1_SQRT_2 }
+fn default_half() -> f32 { 0.5 }
+fn default_mix() -> f32 { 0.3 }
+fn default_threshold() -> f32 { -12.0 }
+fn default_ratio() -> f32 { 4.0 }
+fn default_attack() -> f32 { 0.01 }
+fn default_release() -> f32 { 0.1 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn peak(buffer: &[f32]) -> f32 {
+        buffer.iter().fold(0.0, |acc, x| acc.max(x.abs()))
+    }
+
+
+    #[test]
+    fn effect_filters() {
+        // the highest frequency possible, +1 -1 +1 ...
+        let high = (0..2000).map(|i| if (i / 2) % 2 == 0 { 1.0 } else { -1.0 }).collect::<Vec<f32>>();
+        // a constant signal has no frequency at all
+        let constant = vec![0.5; 2000];
+
+        let mut low_pass = Effect::new(EffectSettings::LowPass { cutoff: 500.0, q: default_q() });
+        let mut buffer = high.clone();
+        low_pass.process(&mut buffer, 44100);
+        assert!(peak(&buffer[1000..]) < 0.01);
+
+        let mut buffer = constant.clone();
+        low_pass.process(&mut buffer, 44100);
+        assert!((buffer[1999] - 0.5).abs() < 0.01);
+
+        let mut high_pass = Effect::new(EffectSettings::HighPass { cutoff: 500.0, q: default_q() });
+        let mut buffer = constant.clone();
+        high_pass.process(&mut buffer, 44100);
+        assert!(peak(&buffer[1000..]) < 0.01);
+
+        high_pass.enabled = false;
+        let mut buffer = high.clone();
+        high_pass.process(&mut buffer, 44100);
+        assert_eq!(buffer, high);
+    }
+
+
+    #[test]
+    fn effect_compressor() {
+        let settings : EffectSettings = toml::from_str("type = \"compressor\"\nthreshold = -20.0").unwrap();
+        assert_eq!(settings, EffectSettings::Compressor {
+            threshold: -20.0, ratio: 4.0, attack: 0.01, release: 0.1, makeup: 0.0,
+        });
+
+        let mut compressor = Effect::new(settings);
+        let mut buffer = vec![1.0; 44100];
+        compressor.process(&mut buffer, 44100);
+
+        // 20db over the threshold at 4:1 leaves 5db over it
+        let expected = 10f32.powf(-15.0 / 20.0);
+        assert!((buffer[44099] - expected).abs() < 0.01);
+    }
+}
@@ -0,0 +1,517 @@
+use std::sync::Arc;
+
+use crate::settings::AudioSettings;
+
+use super::{bus::{self, Bus, BusId}, sound::Sound, stream::Stream};
+
+
+///
+/// Identifies a playing sound, stays valid after the voice
+/// finishes but every operation on it is then ignored
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VoiceId(u64);
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct PlayOptions {
+    pub volume: f32,
+    /// The playback speed, 2.0 plays an octave higher
+    pub pitch: f32,
+    /// From -1 (left) to 1 (right)
+    pub pan: f32,
+    /// Ignored by streams, they loop on their own
+    pub looping: bool,
+    pub bus: BusId,
+}
+
+
+impl Default for PlayOptions {
+    fn default() -> Self {
+        Self { volume: 1.0, pitch: 1.0, pan: 0.0, looping: false, bus: BusId::MASTER }
+    }
+}
+
+
+#[derive(Debug)]
+struct Voice {
+    id: VoiceId,
+    source: Source,
+    options: PlayOptions,
+    /// The position in the sound, in frames of the sound. For
+    /// streams it's the position in the first buffered frame
+    position: f64,
+    fade: Fade,
+}
+
+
+#[derive(Debug)]
+enum Source {
+    Sound(Arc<Sound>),
+    Stream(Arc<Stream>),
+}
+
+
+#[derive(Debug, Clone, Copy)]
+struct Fade {
+    gain: f32,
+    target: f32,
+    /// How much the gain moves every frame
+    speed: f32,
+    /// Whether the voice stops once the target is reached
+    stop: bool,
+}
+
+
+impl Voice {
+    /// Adds the next `buffer.len() / 2` frames to the
+    /// stereo `buffer`, returns false once it's finished
+    fn mix(&mut self, buffer: &mut [f32], output_rate: f64) -> bool {
+        let sound = match &self.source {
+            Source::Sound(v) => v,
+            Source::Stream(stream) => {
+                let step = self.options.pitch.max(0.0) as f64 * stream.sample_rate as f64 / output_rate;
+                return stream.mix(buffer, step, self.gains(), &mut self.position);
+            },
+        };
+
+        let length = sound.frames();
+        if length == 0 { return false }
+
+        let step = self.options.pitch.max(0.0) as f64 * sound.sample_rate as f64 / output_rate;
+        let (left_gain, right_gain) = self.gains();
+
+        for frame in buffer.chunks_exact_mut(2) {
+            if self.position >= length as f64 {
+                if !self.options.looping { return false }
+                self.position %= length as f64;
+            }
+
+            // linearly interpolate between the two closest frames
+            let index = self.position as usize;
+            let t = (self.position - index as f64) as f32;
+            let next = if index + 1 < length { Some(index + 1) }
+                       else if self.options.looping { Some(0) }
+                       else { None };
+
+            let (l0, r0) = sound.frame(index);
+            let (l1, r1) = next.map(|i| sound.frame(i)).unwrap_or((0.0, 0.0));
+            frame[0] += (l0 + (l1 - l0) * t) * left_gain;
+            frame[1] += (r0 + (r1 - r0) * t) * right_gain;
+
+            self.position += step;
+        }
+
+        self.options.looping || self.position < length as f64
+    }
+
+
+    /// The volume of each side after panning
+    fn gains(&self) -> (f32, f32) {
+        let volume = self.options.volume;
+        let pan = self.options.pan.clamp(-1.0, 1.0);
+        (volume * (1.0 - pan).min(1.0), volume * (1.0 + pan).min(1.0))
+    }
+}
+
+
+impl Fade {
+    const NONE : Fade = Fade { gain: 1.0, target: 1.0, speed: 0.0, stop: false };
+
+
+    /// Moves the gain one frame closer to the target
+    fn next(&mut self) -> f32 {
+        let gain = self.gain;
+        if self.gain < self.target {
+            self.gain = (self.gain + self.speed).min(self.target);
+        } else if self.gain > self.target {
+            self.gain = (self.gain - self.speed).max(self.target);
+        }
+
+        gain
+    }
+
+
+    fn has_stopped(&self) -> bool {
+        self.stop && self.gain == self.target
+    }
+}
+
+
+///
+/// Mixes every playing voice into its bus, and every
+/// bus into its parent until it all ends up in a
+/// stereo stream.
+///
+/// The mixer doesn't know about the audio device, the
+/// stream callback asks it for samples but it can just
+/// as well render into a buffer on its own.
+///
+#[derive(Debug)]
+pub struct Mixer {
+    sample_rate: u32,
+    voices: Vec<Voice>,
+    next_id: u64,
+    buses: Vec<Bus>,
+    /// The buses ordered so children come before their parent
+    bus_order: Vec<BusId>,
+    /// Where each voice is mixed before being faded
+    scratch: Vec<f32>,
+}
+
+
+impl Mixer {
+    pub fn new(sample_rate: u32) -> Self {
+        let (buses, bus_order) = bus::build(&AudioSettings::default());
+        Self {
+            sample_rate,
+            voices: vec![],
+            next_id: 0,
+            buses,
+            bus_order,
+            scratch: vec![],
+        }
+    }
+
+
+    /// Replaces the buses with the ones in `settings`, voices
+    /// on buses that no longer exist are moved to `master`
+    pub fn set_buses(&mut self, settings: &AudioSettings) {
+        let (buses, bus_order) = bus::build(settings);
+        for voice in self.voices.iter_mut() {
+            let name = &self.buses[voice.options.bus.0].name;
+            voice.options.bus = buses.iter().position(|x| &x.name == name)
+                .map(BusId)
+                .unwrap_or(BusId::MASTER);
+        }
+
+        self.buses = buses;
+        self.bus_order = bus_order;
+    }
+
+
+    pub fn bus_id(&self, name: &str) -> Option<BusId> {
+        self.buses.iter().position(|x| x.name == name).map(BusId)
+    }
+
+
+    pub fn bus(&self, id: BusId) -> &Bus { &self.buses[id.0] }
+    pub fn bus_mut(&mut self, id: BusId) -> &mut Bus { &mut self.buses[id.0] }
+    pub fn buses(&self) -> &[Bus] { &self.buses }
+
+
+    pub fn sample_rate(&self) -> u32 { self.sample_rate }
+    pub fn set_sample_rate(&mut self, sample_rate: u32) { self.sample_rate = sample_rate }
+
+
+    pub fn play(&mut self, sound: Arc<Sound>, options: PlayOptions) -> VoiceId {
+        self.play_source(Source::Sound(sound), options)
+    }
+
+
+    /// Plays a stream, it's decoded on whatever
+    /// thread calls `Stream::fill`
+    pub fn play_stream(&mut self, stream: Arc<Stream>, options: PlayOptions) -> VoiceId {
+        self.play_source(Source::Stream(stream), options)
+    }
+
+
+    fn play_source(&mut self, source: Source, options: PlayOptions) -> VoiceId {
+        let id = VoiceId(self.next_id);
+        self.next_id += 1;
+
+        self.voices.push(Voice { id, source, options, position: 0.0, fade: Fade::NONE });
+        id
+    }
+
+
+    ///
+    /// Fades the volume of a voice from `from`, or wherever
+    /// it currently is, to `to` over `seconds`.
+    ///
+    /// The fade multiplies the volume of the voice so
+    /// `set_volume` still works during it. If `stop` is
+    /// true the voice stops once the fade is over.
+    ///
+    pub fn fade(&mut self, id: VoiceId, from: Option<f32>, to: f32, seconds: f32, stop: bool) {
+        let sample_rate = self.sample_rate as f32;
+        let Some(voice) = self.voices.iter_mut().find(|voice| voice.id == id)
+        else { return };
+
+        let fade = &mut voice.fade;
+        fade.gain = from.unwrap_or(fade.gain);
+        fade.target = to;
+        fade.stop = stop;
+        fade.speed = match seconds > 0.0 {
+            true => (to - fade.gain).abs() / (seconds * sample_rate),
+            false => f32::INFINITY,
+        };
+    }
+
+
+    pub fn stop(&mut self, id: VoiceId) {
+        self.voices.retain(|voice| voice.id != id);
+    }
+
+
+    pub fn stop_all(&mut self) {
+        self.voices.clear();
+    }
+
+
+    pub fn is_playing(&self, id: VoiceId) -> bool {
+        self.voices.iter().any(|voice| voice.id == id)
+    }
+
+
+    /// The options of a voice that's still playing,
+    /// changes are heard on the next mix
+    pub fn options_mut(&mut self, id: VoiceId) -> Option<&mut PlayOptions> {
+        self.voices.iter_mut()
+            .find(|voice| voice.id == id)
+            .map(|voice| &mut voice.options)
+    }
+
+
+    /// The stream a voice is playing, if it's playing one
+    pub fn stream(&self, id: VoiceId) -> Option<&Arc<Stream>> {
+        self.voices.iter()
+            .find(|voice| voice.id == id)
+            .and_then(|voice| match &voice.source {
+                Source::Stream(stream) => Some(stream),
+                Source::Sound(_) => None,
+            })
+    }
+
+
+    pub fn voice_count(&self) -> usize {
+        self.voices.len()
+    }
+
+
+    ///
+    /// Mixes the next `out.len() / channels` frames into `out`,
+    /// interleaved with `channels` channels.
+    ///
+    /// Mono outputs get both sides averaged and any
+    /// channel past the second one is left silent.
+    ///
+    pub fn mix(&mut self, out: &mut [f32], channels: usize) {
+        out.fill(0.0);
+        if channels == 0 { return }
+
+        let frames = out.len() / channels;
+        for bus in self.buses.iter_mut() {
+            bus.buffer.clear();
+            bus.buffer.resize(frames * 2, 0.0);
+        }
+
+        let output_rate = self.sample_rate as f64;
+        let buses = &mut self.buses;
+        let scratch = &mut self.scratch;
+        scratch.resize(frames * 2, 0.0);
+
+        self.voices.retain_mut(|voice| {
+            scratch.fill(0.0);
+            let is_playing = voice.mix(scratch, output_rate);
+
+            let bus = voice.options.bus.0.min(buses.len() - 1);
+            for (dst, src) in buses[bus].buffer.chunks_exact_mut(2).zip(scratch.chunks_exact(2)) {
+                let gain = voice.fade.next();
+                dst[0] += src[0] * gain;
+                dst[1] += src[1] * gain;
+            }
+
+            is_playing && !voice.fade.has_stopped()
+        });
+
+
+        for id in self.bus_order.iter() {
+            let bus = &mut self.buses[id.0];
+            let mut buffer = core::mem::take(&mut bus.buffer);
+
+            let gain = bus.gain();
+            if gain != 0.0 {
+                for effect in bus.effects.iter_mut() {
+                    effect.process(&mut buffer, self.sample_rate);
+                }
+            }
+
+            match bus.parent() {
+                Some(parent) => {
+                    let parent = &mut self.buses[parent.0].buffer;
+                    for (dst, src) in parent.iter_mut().zip(buffer.iter()) {
+                        *dst += src * gain;
+                    }
+                },
+
+                // only master has no parent
+                None => {
+                    for (out, frame) in out.chunks_exact_mut(channels).zip(buffer.chunks_exact(2)) {
+                        let (left, right) = ((frame[0] * gain).clamp(-1.0, 1.0),
+                                             (frame[1] * gain).clamp(-1.0, 1.0));
+                        if channels == 1 {
+                            out[0] = (left + right) * 0.5;
+                        } else {
+                            out[0] = left;
+                            out[1] = right;
+                        }
+                    }
+                },
+            }
+
+            self.buses[id.0].buffer = buffer;
+        }
+    }
+
+
+    /// Renders the next `frames` frames as interleaved stereo,
+    /// without needing an audio device
+    pub fn render(&mut self, frames: usize) -> Vec<f32> {
+        let mut buffer = vec![0.0; frames * 2];
+        self.mix(&mut buffer, 2);
+        buffer
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn sound(sample_rate: u32, samples: Vec<f32>) -> Arc<Sound> {
+        Arc::new(Sound { sample_rate, channels: 1, samples })
+    }
+
+
+    #[test]
+    fn mixer_renders_and_finishes() {
+        let mut mixer = Mixer::new(100);
+        let voice = mixer.play(sound(100, vec![0.5; 10]), PlayOptions::default());
+        mixer.play(sound(100, vec![0.25; 4]), PlayOptions { pan: -1.0, ..Default::default() });
+
+        let buffer = mixer.render(8);
+        // both voices on the left, only the first on the right
+        assert_eq!(&buffer[0..2], &[0.75, 0.5]);
+        assert_eq!(&buffer[8..10], &[0.5, 0.5]);
+        assert_eq!(mixer.voice_count(), 1);
+
+        mixer.render(8);
+        assert!(!mixer.is_playing(voice));
+        assert!(mixer.render(4).iter().all(|x| *x == 0.0));
+    }
+
+
+    #[test]
+    fn mixer_pitch_and_looping() {
+        let mut mixer = Mixer::new(100);
+        let samples = vec![0.0, 0.1, 0.2, 0.3];
+        let voice = mixer.play(sound(100, samples), PlayOptions { pitch: 2.0, looping: true, ..Default::default() });
+
+        let buffer = mixer.render(4);
+        let left = buffer.iter().step_by(2).copied().collect::<Vec<_>>();
+        assert_eq!(left, vec![0.0, 0.2, 0.0, 0.2]);
+        assert!(mixer.is_playing(voice));
+
+        mixer.options_mut(voice).unwrap().volume = 0.0;
+        assert!(mixer.render(4).iter().all(|x| *x == 0.0));
+
+        mixer.stop(voice);
+        assert_eq!(mixer.voice_count(), 0);
+        assert!(mixer.options_mut(voice).is_none());
+    }
+
+
+    #[test]
+    fn mixer_resamples() {
+        // a sound at half the output rate lasts twice as long
+        let mut mixer = Mixer::new(200);
+        mixer.play(sound(100, vec![0.0, 1.0]), PlayOptions::default());
+
+        let buffer = mixer.render(4);
+        let left = buffer.iter().step_by(2).copied().collect::<Vec<_>>();
+        assert_eq!(left, vec![0.0, 0.5, 1.0, 0.5]);
+        assert_eq!(mixer.voice_count(), 0);
+    }
+
+
+    #[test]
+    fn mixer_buses() {
+        let settings : AudioSettings = toml::from_str(r#"
+            [buses.music]
+            volume = 0.5
+
+            [buses.voice]
+            parent = "music"
+            volume = 0.5
+
+            [buses.loop_a]
+            parent = "loop_b"
+
+            [buses.loop_b]
+            parent = "loop_a"
+        "#).unwrap();
+
+        let mut mixer = Mixer::new(100);
+        mixer.set_buses(&settings);
+
+        let names = mixer.buses().iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["master", "music", "sfx", "ui", "loop_a", "loop_b", "voice"]);
+
+        // one of the buses in the cycle is moved to master
+        let loop_a = mixer.bus_id("loop_a").unwrap();
+        let loop_b = mixer.bus_id("loop_b").unwrap();
+        assert!(mixer.bus(loop_a).parent() == Some(BusId::MASTER)
+                || mixer.bus(loop_b).parent() == Some(BusId::MASTER));
+
+        let bus = mixer.bus_id("voice").unwrap();
+        mixer.play(sound(100, vec![1.0; 4]), PlayOptions { bus, ..Default::default() });
+        assert_eq!(&mixer.render(1)[..], &[0.25, 0.25]);
+
+        let music = mixer.bus_id("music").unwrap();
+        mixer.bus_mut(music).muted = true;
+        assert_eq!(&mixer.render(1)[..], &[0.0, 0.0]);
+
+        mixer.bus_mut(music).muted = false;
+        mixer.bus_mut(BusId::MASTER).volume = 2.0;
+        assert_eq!(&mixer.render(1)[..], &[0.5, 0.5]);
+    }
+
+
+    #[test]
+    fn mixer_fades() {
+        let mut mixer = Mixer::new(4);
+        let voice = mixer.play(sound(4, vec![1.0; 64]), PlayOptions::default());
+
+        mixer.fade(voice, Some(0.0), 1.0, 1.0, false);
+        let left = mixer.render(6).iter().step_by(2).copied().collect::<Vec<_>>();
+        assert_eq!(left, vec![0.0, 0.25, 0.5, 0.75, 1.0, 1.0]);
+
+        mixer.fade(voice, None, 0.0, 0.5, true);
+        let left = mixer.render(3).iter().step_by(2).copied().collect::<Vec<_>>();
+        assert_eq!(left, vec![1.0, 0.5, 0.0]);
+        assert!(!mixer.is_playing(voice));
+    }
+
+
+    #[test]
+    fn mixer_queued_streams() {
+        let mut mixer = Mixer::new(4);
+        let stream = Arc::new(Stream::queue(4, 1).unwrap());
+        let voice = mixer.play_stream(stream.clone(), PlayOptions::default());
+
+        stream.push(&[0.5, 0.25]);
+        assert_eq!(stream.queued(), 0.5);
+        assert_eq!(&mixer.render(1)[..], &[0.5, 0.5]);
+
+        // waits for more samples instead of ending
+        assert_eq!(&mixer.render(2)[..], &[0.25, 0.25, 0.0, 0.0]);
+        assert!(mixer.is_playing(voice));
+
+        stream.push(&[1.0]);
+        stream.finish();
+        assert_eq!(&mixer.render(2)[..], &[1.0, 1.0, 0.0, 0.0]);
+        assert!(!mixer.is_playing(voice));
+    }
+}
@@ -0,0 +1,348 @@
+use std::{io::Cursor, ops::Range};
+
+use lewton::inside_ogg::OggStreamReader;
+
+
+///
+/// A fully decoded sound, its samples are
+/// interleaved and range from -1 to 1
+///
+pub struct Sound {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>,
+}
+
+
+impl Sound {
+    /// Decodes a WAV or OGG Vorbis file, the
+    /// format is detected from its contents
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.starts_with(b"RIFF") {
+            return decode_wav(bytes);
+        }
+
+        if bytes.starts_with(b"OggS") {
+            return decode_ogg(bytes);
+        }
+
+        Err(String::from("unknown audio format, only WAV and OGG Vorbis are supported"))
+    }
+
+
+    /// The amount of samples per channel
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+
+    /// The length of the sound in seconds
+    pub fn duration(&self) -> f32 {
+        self.frames() as f32 / self.sample_rate as f32
+    }
+
+
+    /// The left and right sample of a frame, mono
+    /// sounds are played on both sides and any channel
+    /// past the second one is ignored
+    pub fn frame(&self, index: usize) -> (f32, f32) {
+        let index = index * self.channels;
+        match self.channels {
+            1 => (self.samples[index], self.samples[index]),
+            _ => (self.samples[index], self.samples[index + 1]),
+        }
+    }
+}
+
+
+impl core::fmt::Debug for Sound {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Sound({} channels, {}hz, {:.2}s)", self.channels, self.sample_rate, self.duration())
+    }
+}
+
+
+/// The layout of the samples in a WAV file
+#[derive(Debug, Clone)]
+pub(super) struct WavFormat {
+    tag: u16,
+    bits: u16,
+    pub channels: usize,
+    pub sample_rate: u32,
+    /// Where the samples are in the file
+    pub data: Range<usize>,
+}
+
+
+impl WavFormat {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        Self::parse_header(bytes, bytes.len())
+    }
+
+
+    /// Parses the first `bytes` of a file that's `len` bytes
+    /// long, the data chunk can reach past them but everything
+    /// before it has to be included
+    pub fn parse_header(bytes: &[u8], len: usize) -> Result<Self, String> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(String::from("not a WAVE file"));
+        }
+
+        let u16_at = |data: &[u8], i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at = |data: &[u8], i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+        let mut format = None;
+        let mut data = None;
+
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let id = &bytes[offset..offset + 4];
+            let size = u32_at(bytes, offset + 4) as usize;
+            let start = offset + 8;
+            let end = (start + size).min(len);
+            let chunk = &bytes[start..end.min(bytes.len())];
+
+            match id {
+                b"fmt " => {
+                    if chunk.len() < 16 { return Err(String::from("the format chunk is too short")) }
+
+                    let mut tag = u16_at(chunk, 0);
+                    // WAVE_FORMAT_EXTENSIBLE keeps the real
+                    // format at the start of the sub format guid
+                    if tag == 0xFFFE && chunk.len() >= 26 {
+                        tag = u16_at(chunk, 24);
+                    }
+
+                    let channels = u16_at(chunk, 2) as usize;
+                    let sample_rate = u32_at(chunk, 4);
+                    let bits = u16_at(chunk, 14);
+                    format = Some((tag, channels, sample_rate, bits));
+                },
+
+                b"data" => data = Some(start..end),
+
+                _ => (),
+            }
+
+            // chunks are padded to an even size
+            offset = start + size + (size & 1);
+        }
+
+
+        let Some((tag, channels, sample_rate, bits)) = format
+        else { return Err(String::from("the file has no format chunk")) };
+
+        let Some(data) = data
+        else { return Err(String::from("the file has no data chunk")) };
+
+        if channels == 0 || sample_rate == 0 {
+            return Err(format!("invalid format, {channels} channels at {sample_rate}hz"));
+        }
+
+        if !matches!((tag, bits), (1, 8) | (1, 16) | (1, 24) | (1, 32) | (3, 32) | (3, 64)) {
+            return Err(format!("unsupported sample format {tag} with {bits} bits per sample"));
+        }
+
+        Ok(Self { tag, bits, channels, sample_rate, data })
+    }
+
+
+    pub fn bytes_per_frame(&self) -> usize {
+        self.bits as usize / 8 * self.channels
+    }
+
+
+    /// The amount of frames in the file
+    pub fn frames(&self) -> usize {
+        self.data.len() / self.bytes_per_frame()
+    }
+
+
+    /// Converts the samples in `data`, which
+    /// must be whole frames, to floats
+    pub fn convert(&self, data: &[u8]) -> Vec<f32> {
+        match (self.tag, self.bits) {
+            (1, 8) => data.iter().map(|x| (*x as f32 - 128.0) / 128.0).collect(),
+
+            (1, 16) => data.chunks_exact(2)
+                .map(|x| i16::from_le_bytes([x[0], x[1]]) as f32 / 32768.0)
+                .collect(),
+
+            (1, 24) => data.chunks_exact(3)
+                .map(|x| i32::from_le_bytes([0, x[0], x[1], x[2]]) as f32 / 2147483648.0)
+                .collect(),
+
+            (1, 32) => data.chunks_exact(4)
+                .map(|x| i32::from_le_bytes([x[0], x[1], x[2], x[3]]) as f32 / 2147483648.0)
+                .collect(),
+
+            (3, 32) => data.chunks_exact(4)
+                .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                .collect(),
+
+            (3, 64) => data.chunks_exact(8)
+                .map(|x| f64::from_le_bytes(x.try_into().unwrap()) as f32)
+                .collect(),
+
+            _ => unreachable!(),
+        }
+    }
+}
+
+
+fn decode_wav(bytes: &[u8]) -> Result<Sound, String> {
+    let format = WavFormat::parse(bytes)?;
+
+    // drop a trailing partial frame
+    let len = format.frames() * format.bytes_per_frame();
+    let samples = format.convert(&bytes[format.data.start..format.data.start + len]);
+
+    Ok(Sound { sample_rate: format.sample_rate, channels: format.channels, samples })
+}
+
+
+fn decode_ogg(bytes: &[u8]) -> Result<Sound, String> {
+    let mut reader = OggStreamReader::new(Cursor::new(bytes))
+        .map_err(|e| e.to_string())?;
+
+    let sample_rate = reader.ident_hdr.audio_sample_rate;
+    let channels = reader.ident_hdr.audio_channels as usize;
+
+    let mut samples = vec![];
+    while let Some(packet) = reader.read_dec_packet_itl().map_err(|e| e.to_string())? {
+        samples.extend(packet.into_iter().map(|x| x as f32 / 32768.0));
+    }
+
+    Ok(Sound { sample_rate, channels, samples })
+}
+
+
+/// A 16-bit WAV file holding `samples` for the tests
+#[cfg(test)]
+pub(super) fn wav(channels: u16, sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+    let data = samples.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+
+    let mut bytes = vec![];
+    bytes.extend(b"RIFF");
+    bytes.extend((36 + data.len() as u32).to_le_bytes());
+    bytes.extend(b"WAVE");
+
+    bytes.extend(b"fmt ");
+    bytes.extend(16u32.to_le_bytes());
+    bytes.extend(1u16.to_le_bytes());
+    bytes.extend(channels.to_le_bytes());
+    bytes.extend(sample_rate.to_le_bytes());
+    bytes.extend((sample_rate * channels as u32 * 2).to_le_bytes());
+    bytes.extend((channels * 2).to_le_bytes());
+    bytes.extend(16u16.to_le_bytes());
+
+    bytes.extend(b"data");
+    bytes.extend((data.len() as u32).to_le_bytes());
+    bytes.extend(data);
+    bytes
+}
+
+
+///
+/// A silent OGG Vorbis file for the tests.
+///
+/// Both block sizes are 64 so every audio packet holds 32
+/// frames, except for the first one which only primes the
+/// decoder and holds none
+///
+#[cfg(test)]
+pub(super) fn ogg(channels: u8, sample_rate: u32, packets: usize) -> Vec<u8> {
+    let mut ident = vec![1];
+    ident.extend(b"vorbis");
+    ident.extend(0u32.to_le_bytes());
+    ident.push(channels);
+    ident.extend(sample_rate.to_le_bytes());
+    ident.extend([0; 12]);
+    ident.extend([0x66, 1]);
+
+    let mut comment = vec![3];
+    comment.extend(b"vorbis");
+    comment.extend([0; 8]);
+    comment.push(1);
+
+    // the least a decoder accepts, a codebook, a floor with
+    // no partitions, a residue, a mapping and a mode
+    let fields : &[(u32, u32)] = &[
+        (5, 8), (0x76, 8), (0x6f, 8), (0x72, 8), (0x62, 8), (0x69, 8), (0x73, 8),
+        (0, 8), (0x564342, 24), (1, 16), (2, 24), (0, 1), (0, 1), (0, 5), (0, 5), (0, 4),
+        (0, 6), (0, 16),
+        (0, 6), (1, 16), (0, 5), (0, 2), (5, 4),
+        (0, 6), (0, 16), (0, 24), (0, 24), (0, 24), (0, 6), (0, 8), (0, 3), (0, 1),
+        (0, 6), (0, 16), (0, 1), (0, 1), (0, 2), (0, 8), (0, 8), (0, 8),
+        (0, 6), (0, 1), (0, 16), (0, 16), (0, 8),
+        (1, 1),
+    ];
+
+    let mut setup = vec![];
+    let mut bit = 0;
+    for (value, bits) in fields.iter().copied() {
+        for i in 0..bits {
+            if bit % 8 == 0 { setup.push(0) }
+            *setup.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (bit % 8);
+            bit += 1;
+        }
+    }
+
+    // an audio packet whose channels have no floor is silent
+    let mut pages = vec![(vec![ident], 0), (vec![comment, setup], 0)];
+    pages.push((vec![vec![0]; packets], packets.saturating_sub(1) as u64 * 32));
+
+    let mut bytes = vec![];
+    let last = pages.len() - 1;
+    for (sequence, (packets, granule)) in pages.into_iter().enumerate() {
+        let mut page = vec![];
+        page.extend(b"OggS");
+        page.push(0);
+        page.push(match sequence { 0 => 2, x if x == last => 4, _ => 0 });
+        page.extend(granule.to_le_bytes());
+        page.extend(1u32.to_le_bytes());
+        page.extend((sequence as u32).to_le_bytes());
+        page.extend([0; 4]);
+
+        let lacing = packets.iter()
+            .flat_map(|x| core::iter::repeat_n(255, x.len() / 255).chain([(x.len() % 255) as u8]))
+            .collect::<Vec<_>>();
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(packets.concat());
+
+        let mut crc = 0u32;
+        for byte in page.iter() {
+            crc ^= (*byte as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+            }
+        }
+
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        bytes.extend(page);
+    }
+
+    bytes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+
+    #[test]
+    fn sound_decode_wav() {
+        let sound = Sound::decode(&wav(2, 22050, &[0, 16384, -32768, 32767])).unwrap();
+
+        assert_eq!(sound.sample_rate, 22050);
+        assert_eq!(sound.channels, 2);
+        assert_eq!(sound.frames(), 2);
+        assert_eq!(sound.frame(0), (0.0, 0.5));
+        assert_eq!(sound.frame(1).0, -1.0);
+
+        assert!(Sound::decode(b"not a sound").is_err());
+    }
+}
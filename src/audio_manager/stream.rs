@@ -0,0 +1,574 @@
+use std::{collections::VecDeque, io::{Cursor, Read, Seek, SeekFrom}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, MutexGuard, Weak}, thread::JoinHandle, time::Duration};
+
+use lewton::inside_ogg::OggStreamReader;
+use tracing::{error, info};
+
+use super::sound::WavFormat;
+
+
+/// How much audio is decoded ahead of playback
+const BUFFERED_SECONDS : f32 = 1.0;
+
+/// The most frames decoded at once from a WAV file
+const WAV_CHUNK_FRAMES : usize = 4096;
+
+/// How much of the start of a file is read to find its format
+const HEADER_BYTES : u64 = 64 * 1024;
+
+
+///
+/// Where a streamed track loops, in seconds
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoopPoints {
+    pub start: f32,
+    /// The end of the track if `None`
+    pub end: Option<f32>,
+}
+
+
+///
+/// A sound that is decoded a little at a time while
+/// it plays instead of all at once.
+///
+/// The streamer thread keeps the buffer topped up and
+/// the mixer drains it. WAV files are read from their
+/// source as they play and only the encoded file is kept
+/// in memory for OGG Vorbis, which is much smaller than
+/// the decoded samples.
+///
+pub struct Stream {
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// `None` if the track doesn't loop, otherwise
+    /// the frames it loops between
+    looping: Option<(usize, Option<usize>)>,
+    /// `None` if the samples are pushed by a script
+    decoder: Option<Mutex<Decoder>>,
+    buffer: Mutex<StreamBuffer>,
+}
+
+
+struct StreamBuffer {
+    samples: VecDeque<f32>,
+    /// Every frame has been decoded and queued
+    ended: bool,
+    /// The amount of frames the mixer has played
+    played: u64,
+    /// The frame the track loops at, either from the loop
+    /// points or the length of the track once it's known
+    loop_end: Option<usize>,
+}
+
+
+/// Anything a stream can read its file from
+trait Source: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Source for T {}
+
+
+enum Decoder {
+    Wav {
+        source: Box<dyn Source>,
+        format: WavFormat,
+        frame: usize,
+    },
+
+    Ogg {
+        bytes: Arc<[u8]>,
+        reader: Box<OggStreamReader<Cursor<Arc<[u8]>>>>,
+        frame: usize,
+        /// Decoded samples that didn't fit in the last read
+        pending: Vec<f32>,
+    },
+}
+
+
+///
+/// Owns the thread that decodes every playing stream
+///
+#[derive(Debug)]
+pub struct Streamer {
+    streams: Arc<Mutex<Vec<Weak<Stream>>>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+
+impl Stream {
+    /// Prepares `bytes`, a WAV or OGG Vorbis file,
+    /// for streaming without decoding any of it
+    pub fn new(bytes: Vec<u8>, looping: Option<LoopPoints>) -> Result<Self, String> {
+        Self::open(Cursor::new(bytes), looping)
+    }
+
+
+    ///
+    /// Prepares a WAV or OGG Vorbis file for streaming
+    /// without decoding any of it.
+    ///
+    /// Only the header of a WAV file is read, the samples
+    /// are read from `source` as they're needed. OGG Vorbis
+    /// files are read into memory since they're compressed.
+    ///
+    pub fn open(mut source: impl Read + Seek + Send + 'static, looping: Option<LoopPoints>) -> Result<Self, String> {
+        let mut header = vec![];
+        (&mut source).take(HEADER_BYTES).read_to_end(&mut header)
+            .map_err(|e| e.to_string())?;
+
+        let (decoder, sample_rate, channels, length) = if header.starts_with(b"RIFF") {
+            let len = source.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+            let format = WavFormat::parse_header(&header, len as usize)?;
+            let (sample_rate, channels, length) = (format.sample_rate, format.channels, format.frames());
+            (Decoder::Wav { source: Box::new(source), format, frame: 0 }, sample_rate, channels, Some(length))
+
+        } else if header.starts_with(b"OggS") {
+            let mut bytes = header;
+            source.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+            let bytes : Arc<[u8]> = bytes.into();
+
+            let reader = OggStreamReader::new(Cursor::new(bytes.clone()))
+                .map_err(|e| e.to_string())?;
+            let sample_rate = reader.ident_hdr.audio_sample_rate;
+            let channels = reader.ident_hdr.audio_channels as usize;
+            (Decoder::Ogg { bytes, reader: Box::new(reader), frame: 0, pending: vec![] }, sample_rate, channels, None)
+
+        } else {
+            return Err(String::from("unknown audio format, only WAV and OGG Vorbis are supported"));
+        };
+
+        if channels == 0 || sample_rate == 0 {
+            return Err(format!("invalid format, {channels} channels at {sample_rate}hz"));
+        }
+
+        let to_frames = |seconds: f32| (seconds.max(0.0) * sample_rate as f32) as usize;
+        let looping = looping.map(|x| (to_frames(x.start), x.end.map(to_frames)));
+        let loop_end = looping.and_then(|(_, end)| end).or(length);
+
+        Ok(Self {
+            sample_rate,
+            channels,
+            looping,
+            decoder: Some(Mutex::new(decoder)),
+            buffer: Mutex::new(StreamBuffer {
+                samples: VecDeque::new(),
+                ended: false,
+                played: 0,
+                loop_end,
+            }),
+        })
+    }
+
+
+    ///
+    /// A stream with no file behind it, it plays
+    /// whatever samples are `push`ed into it.
+    ///
+    /// Running out of samples pauses it until more
+    /// are pushed or it's `finish`ed.
+    ///
+    pub fn queue(sample_rate: u32, channels: usize) -> Result<Self, String> {
+        if channels == 0 || sample_rate == 0 {
+            return Err(format!("invalid format, {channels} channels at {sample_rate}hz"));
+        }
+
+        Ok(Self {
+            sample_rate,
+            channels,
+            looping: None,
+            decoder: None,
+            buffer: Mutex::new(StreamBuffer {
+                samples: VecDeque::new(),
+                ended: false,
+                played: 0,
+                loop_end: None,
+            }),
+        })
+    }
+
+
+    /// Queues interleaved samples after the ones not played yet,
+    /// a trailing incomplete frame is dropped
+    pub fn push(&self, samples: &[f32]) {
+        let samples = &samples[..samples.len() - samples.len() % self.channels];
+        self.buffer().samples.extend(samples);
+    }
+
+
+    /// Lets the stream end once what's queued has been played
+    pub fn finish(&self) {
+        self.buffer().ended = true;
+    }
+
+
+    /// How many seconds of audio are waiting to be played
+    pub fn queued(&self) -> f32 {
+        let frames = self.buffer().samples.len() / self.channels;
+        frames as f32 / self.sample_rate as f32
+    }
+
+
+    ///
+    /// Decodes until a second of audio is buffered.
+    ///
+    /// Returns false once every frame has been
+    /// decoded and the stream needs nothing more.
+    ///
+    pub fn fill(&self) -> bool {
+        let Some(decoder) = &self.decoder
+        else { return !self.buffer().ended };
+
+        let target = (self.sample_rate as f32 * BUFFERED_SECONDS) as usize * self.channels;
+
+        loop {
+            {
+                let buffer = self.buffer();
+                if buffer.ended { return false }
+                if buffer.samples.len() >= target { return true }
+            }
+
+            // the buffer isn't locked while decoding
+            // so the mixer never waits on the decoder
+            let mut decoder = lock(decoder);
+            let frame = decoder.frame();
+            let loop_end = self.looping.and_then(|(_, end)| end);
+            let max_frames = loop_end.map(|end| end.saturating_sub(frame)).unwrap_or(usize::MAX);
+
+            let samples = match decoder.read(max_frames, self.channels) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("unable to decode the stream: {e}");
+                    vec![]
+                },
+            };
+
+            if !samples.is_empty() {
+                drop(decoder);
+                self.buffer().samples.extend(samples);
+                continue;
+            }
+
+
+            // reached the end of the track or its loop,
+            // a loop with nothing in it would never end
+            let loop_start = self.looping
+                .map(|(start, _)| start)
+                .filter(|start| *start < frame);
+
+            {
+                let mut buffer = self.buffer();
+                if buffer.loop_end.is_none() {
+                    buffer.loop_end = Some(frame);
+                }
+
+                if loop_start.is_none() {
+                    buffer.ended = true;
+                    return false;
+                }
+            }
+
+            if let Err(e) = decoder.seek(loop_start.unwrap(), self.channels) {
+                error!("unable to loop the stream: {e}");
+                self.buffer().ended = true;
+                return false;
+            }
+        }
+    }
+
+
+    /// Where playback is in the track, in seconds
+    pub fn position(&self) -> f32 {
+        let buffer = self.buffer();
+        let played = buffer.played as usize;
+
+        let frame = match (self.looping, buffer.loop_end) {
+            (Some((start, _)), Some(end)) if played >= end && end > start => {
+                start + (played - end) % (end - start)
+            },
+
+            _ => played,
+        };
+
+        frame as f32 / self.sample_rate as f32
+    }
+
+
+    ///
+    /// Adds the next frames of the stream to the stereo `out`.
+    ///
+    /// `offset` is how far into the first buffered frame
+    /// playback is and `step` is how far it advances every
+    /// output frame. Returns false once the stream is over.
+    ///
+    pub(super) fn mix(&self, out: &mut [f32], step: f64, gains: (f32, f32), offset: &mut f64) -> bool {
+        let mut buffer = self.buffer();
+        let channels = self.channels;
+
+        for frame in out.chunks_exact_mut(2) {
+            // drop the frames that have been played
+            let available = buffer.samples.len() / channels;
+            let played = (*offset as usize).min(available);
+            if played > 0 {
+                buffer.samples.drain(..played * channels);
+                buffer.played += played as u64;
+                *offset -= played as f64;
+            }
+
+            let available = buffer.samples.len() / channels;
+            if available == 0 {
+                // otherwise the decoder is late, wait for it
+                return !buffer.ended;
+            }
+
+            let t = offset.fract() as f32;
+            let (l0, r0) = buffer.frame(0, channels);
+            let (l1, r1) = if available > 1 { buffer.frame(1, channels) } else { (l0, r0) };
+            frame[0] += (l0 + (l1 - l0) * t) * gains.0;
+            frame[1] += (r0 + (r1 - r0) * t) * gains.1;
+
+            *offset += step;
+        }
+
+        true
+    }
+
+
+    fn buffer(&self) -> MutexGuard<'_, StreamBuffer> {
+        lock(&self.buffer)
+    }
+}
+
+
+impl core::fmt::Debug for Stream {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Stream({} channels, {}hz)", self.channels, self.sample_rate)
+    }
+}
+
+
+impl StreamBuffer {
+    fn frame(&self, index: usize, channels: usize) -> (f32, f32) {
+        let index = index * channels;
+        match channels {
+            1 => (self.samples[index], self.samples[index]),
+            _ => (self.samples[index], self.samples[index + 1]),
+        }
+    }
+}
+
+
+impl Decoder {
+    fn frame(&self) -> usize {
+        match self {
+            Decoder::Wav { frame, .. } => *frame,
+            Decoder::Ogg { frame, .. } => *frame,
+        }
+    }
+
+
+    /// Decodes up to `max_frames` frames, returns
+    /// nothing once the end has been reached
+    fn read(&mut self, max_frames: usize, channels: usize) -> Result<Vec<f32>, String> {
+        match self {
+            Decoder::Wav { source, format, frame } => {
+                let frames = (format.frames() - (*frame).min(format.frames()))
+                    .min(max_frames)
+                    .min(WAV_CHUNK_FRAMES);
+
+                if frames == 0 { return Ok(vec![]) }
+
+                let start = format.data.start + *frame * format.bytes_per_frame();
+                let mut data = vec![0; frames * format.bytes_per_frame()];
+                source.seek(SeekFrom::Start(start as u64)).map_err(|e| e.to_string())?;
+                source.read_exact(&mut data).map_err(|e| e.to_string())?;
+                *frame += frames;
+
+                Ok(format.convert(&data))
+            },
+
+
+            Decoder::Ogg { reader, frame, pending, .. } => {
+                // packets can be empty, the first one always is
+                while pending.is_empty() {
+                    match reader.read_dec_packet_itl().map_err(|e| e.to_string())? {
+                        Some(packet) => pending.extend(packet.into_iter().map(|x| x as f32 / 32768.0)),
+                        None => return Ok(vec![]),
+                    }
+                }
+
+                let frames = (pending.len() / channels).min(max_frames);
+                *frame += frames;
+                Ok(pending.drain(..frames * channels).collect())
+            },
+        }
+    }
+
+
+    /// Moves the decoder to `target`
+    fn seek(&mut self, target: usize, channels: usize) -> Result<(), String> {
+        match self {
+            Decoder::Wav { frame, .. } => *frame = target,
+
+
+            Decoder::Ogg { bytes, reader, frame, pending } => {
+                // vorbis can only seek to pages, so start
+                // over and skip to the exact frame instead
+                **reader = OggStreamReader::new(Cursor::new(bytes.clone()))
+                    .map_err(|e| e.to_string())?;
+                *frame = 0;
+                pending.clear();
+
+                while *frame < target {
+                    let Some(packet) = reader.read_dec_packet_itl().map_err(|e| e.to_string())?
+                    else { break };
+
+                    let frames = packet.len() / channels;
+                    if *frame + frames > target {
+                        let skip = (target - *frame) * channels;
+                        pending.extend(packet[skip..].iter().map(|x| *x as f32 / 32768.0));
+                        *frame = target;
+                        break;
+                    }
+
+                    *frame += frames;
+                }
+            },
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Streamer {
+    pub fn new() -> Self {
+        Self {
+            streams: Arc::new(Mutex::new(vec![])),
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+
+
+    /// Starts decoding `stream` in the background, the
+    /// stream is dropped by the streamer once nothing
+    /// else holds on to it
+    pub fn add(&mut self, stream: &Arc<Stream>) {
+        lock(&self.streams).push(Arc::downgrade(stream));
+
+        // the mixer plays silence until
+        // the first fill is done
+        if let Some(thread) = &self.thread {
+            thread.thread().unpark();
+            return;
+        }
+
+        info!("starting the audio streaming thread");
+        self.running.store(true, Ordering::Relaxed);
+
+        let streams = self.streams.clone();
+        let running = self.running.clone();
+        let thread = std::thread::Builder::new()
+            .name(String::from("audio streamer"))
+            .spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    let alive = {
+                        let mut streams = lock(&streams);
+                        streams.retain(|x| x.strong_count() > 0);
+                        streams.iter().filter_map(|x| x.upgrade()).collect::<Vec<_>>()
+                    };
+
+                    for stream in alive {
+                        stream.fill();
+                    }
+
+                    std::thread::park_timeout(Duration::from_millis(10));
+                }
+            });
+
+        match thread {
+            Ok(v) => self.thread = Some(v),
+            Err(e) => error!("unable to start the audio streaming thread: {e}"),
+        }
+    }
+
+
+    pub fn shutdown(&mut self) {
+        let Some(thread) = self.thread.take()
+        else { return };
+
+        self.running.store(false, Ordering::Relaxed);
+        if thread.join().is_err() {
+            error!("the audio streaming thread panicked");
+        }
+    }
+}
+
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_manager::sound::{ogg, wav};
+
+
+    fn left(stream: &Stream, frames: usize, offset: &mut f64) -> Vec<f32> {
+        let mut out = vec![0.0; frames * 2];
+        stream.mix(&mut out, 1.0, (1.0, 1.0), offset);
+        out.iter().step_by(2).copied().collect()
+    }
+
+
+    #[test]
+    fn stream_loop_points() {
+        // 10 frames at 10hz, looping from 0.2s to 0.5s
+        let samples = (0..10).map(|x| x * 1000).collect::<Vec<i16>>();
+        let looping = LoopPoints { start: 0.2, end: Some(0.5) };
+        let stream = Stream::new(wav(1, 10, &samples), Some(looping)).unwrap();
+        assert_eq!(stream.queued(), 0.0);
+
+        assert!(stream.fill());
+
+        let mut offset = 0.0;
+        let played = left(&stream, 8, &mut offset).iter()
+            .map(|x| (x * 32768.0 / 1000.0).round() as i32)
+            .collect::<Vec<_>>();
+        assert_eq!(played, vec![0, 1, 2, 3, 4, 2, 3, 4]);
+
+        // the mixer drops a frame once it's past it
+        left(&stream, 1, &mut offset);
+        assert!((stream.position() - 0.2).abs() < 0.001);
+    }
+
+
+    #[test]
+    fn stream_ends() {
+        let stream = Stream::new(wav(1, 10, &[1000; 4]), None).unwrap();
+        assert!(!stream.fill());
+
+        let mut offset = 0.0;
+        let mut out = vec![0.0; 6 * 2];
+        assert!(!stream.mix(&mut out, 1.0, (1.0, 1.0), &mut offset));
+        assert!(out[8..].iter().all(|x| *x == 0.0));
+        assert!(Stream::new(b"not a sound".to_vec(), None).is_err());
+    }
+
+
+    #[test]
+    fn stream_ogg() {
+        // the first packet is empty, the other four hold 32 frames
+        let stream = Stream::new(ogg(2, 1000, 5), None).unwrap();
+        assert_eq!((stream.channels, stream.sample_rate), (2, 1000));
+        assert!(!stream.fill());
+        assert_eq!(stream.queued(), 0.128);
+
+        // looping starts the decoder over on its empty packet
+        let looping = Stream::new(ogg(1, 200, 5), Some(LoopPoints::default())).unwrap();
+        assert!(looping.fill());
+        assert!(looping.queued() >= BUFFERED_SECONDS);
+    }
+}
@@ -0,0 +1,263 @@
+use core::f32::consts::TAU;
+
+use super::sound::Sound;
+
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    #[default]
+    Square,
+    Sine,
+    Triangle,
+    Saw,
+    /// A new random value every period, lower
+    /// frequencies sound rougher
+    Noise,
+}
+
+
+///
+/// How the volume of a tone changes over time, the
+/// times are in seconds and `sustain` is the level held
+/// until the tone is released
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+
+///
+/// A generated sound effect, in the spirit of sfxr
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    /// In hz
+    pub frequency: f32,
+    /// The frequency the tone slides to by the time it's released
+    pub slide: Option<f32>,
+    /// How much of a period a square wave is up, from 0 to 1
+    pub duty: f32,
+    /// How long the tone is held before it's released, in seconds
+    pub duration: f32,
+    pub volume: f32,
+    pub envelope: Envelope,
+    /// Two tones with the same seed make the same noise
+    pub seed: u32,
+}
+
+
+///
+/// Produces one waveform sample at a time, the
+/// frequency can change between samples
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Oscillator {
+    pub waveform: Waveform,
+    pub duty: f32,
+    /// Where in the period the oscillator is, from 0 to 1
+    phase: f32,
+    noise: f32,
+    rng: u32,
+}
+
+
+impl Waveform {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "square" => Self::Square,
+            "sine" => Self::Sine,
+            "triangle" => Self::Triangle,
+            "saw" => Self::Saw,
+            "noise" => Self::Noise,
+            _ => return None,
+        })
+    }
+}
+
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self { attack: 0.01, decay: 0.0, sustain: 1.0, release: 0.05 }
+    }
+}
+
+
+impl Envelope {
+    /// The volume `time` seconds into a
+    /// tone released after `held` seconds
+    pub fn gain(&self, time: f32, held: f32) -> f32 {
+        let sustain = self.sustain.clamp(0.0, 1.0);
+        let level = |time: f32| {
+            if time < self.attack { return time / self.attack }
+
+            let time = time - self.attack;
+            if time < self.decay { return 1.0 - (1.0 - sustain) * time / self.decay }
+
+            sustain
+        };
+
+        if time < held { return level(time) }
+
+        // release from wherever the tone was, even mid-attack
+        let time = time - held;
+        if time >= self.release { return 0.0 }
+        level(held) * (1.0 - time / self.release)
+    }
+}
+
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::default(),
+            frequency: 440.0,
+            slide: None,
+            duty: 0.5,
+            duration: 0.2,
+            volume: 0.5,
+            envelope: Envelope::default(),
+            seed: 1,
+        }
+    }
+}
+
+
+impl Tone {
+    /// The longest a tone can be including its release, in
+    /// seconds, since the whole tone is rendered at once
+    pub const MAX_LENGTH : f32 = 10.0;
+
+    /// The highest sample rate a tone can be rendered at
+    pub const MAX_SAMPLE_RATE : u32 = 192_000;
+
+
+    /// The length of the tone including its release, in seconds
+    pub fn length(&self) -> f32 {
+        self.duration.max(0.0) + self.envelope.release.max(0.0)
+    }
+
+
+    /// Renders the tone into mono samples
+    pub fn samples(&self, sample_rate: u32) -> Vec<f32> {
+        let rate = sample_rate as f32;
+        let frames = (self.length() * rate) as usize;
+        let held = self.duration.max(0.0);
+
+        let mut oscillator = Oscillator::new(self.waveform, self.seed);
+        oscillator.duty = self.duty;
+
+        (0..frames)
+            .map(|i| {
+                let time = i as f32 / rate;
+                let frequency = match self.slide {
+                    Some(slide) if held > 0.0 => {
+                        let t = (time / held).min(1.0);
+                        self.frequency + (slide - self.frequency) * t
+                    },
+
+                    _ => self.frequency,
+                };
+
+                oscillator.next(frequency, rate) * self.envelope.gain(time, held) * self.volume
+            })
+            .collect()
+    }
+
+
+    pub fn render(&self, sample_rate: u32) -> Sound {
+        Sound { sample_rate, channels: 1, samples: self.samples(sample_rate) }
+    }
+}
+
+
+impl Oscillator {
+    pub fn new(waveform: Waveform, seed: u32) -> Self {
+        // xorshift gets stuck on 0
+        let rng = seed.max(1);
+        Self { waveform, duty: 0.5, phase: 0.0, noise: 0.0, rng }
+    }
+
+
+    /// The next sample, from -1 to 1
+    pub fn next(&mut self, frequency: f32, sample_rate: f32) -> f32 {
+        let phase = self.phase;
+        let sample = match self.waveform {
+            Waveform::Square => if phase < self.duty.clamp(0.0, 1.0) { 1.0 } else { -1.0 },
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Noise => self.noise,
+        };
+
+        self.phase += frequency.max(0.0) / sample_rate;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            self.noise = self.random();
+        }
+
+        sample
+    }
+
+
+    /// A random value from -1 to 1
+    fn random(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn synth_envelope() {
+        let envelope = Envelope { attack: 1.0, decay: 1.0, sustain: 0.5, release: 2.0 };
+
+        assert_eq!(envelope.gain(0.0, 10.0), 0.0);
+        assert_eq!(envelope.gain(0.5, 10.0), 0.5);
+        assert_eq!(envelope.gain(1.5, 10.0), 0.75);
+        assert_eq!(envelope.gain(5.0, 10.0), 0.5);
+        assert_eq!(envelope.gain(11.0, 10.0), 0.25);
+        assert_eq!(envelope.gain(12.0, 10.0), 0.0);
+
+        // released halfway through the attack
+        assert_eq!(envelope.gain(0.5, 0.5), 0.5);
+        assert_eq!(envelope.gain(1.5, 0.5), 0.25);
+    }
+
+
+    #[test]
+    fn synth_tones() {
+        let envelope = Envelope { attack: 0.0, decay: 0.0, sustain: 1.0, release: 0.0 };
+        let tone = Tone { frequency: 2.0, duration: 1.0, volume: 1.0, envelope, ..Default::default() };
+
+        let square = tone.samples(8);
+        assert_eq!(square, vec![1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0]);
+
+        let saw = Tone { waveform: Waveform::Saw, ..tone }.samples(8);
+        assert_eq!(saw, vec![-1.0, -0.5, 0.0, 0.5, -1.0, -0.5, 0.0, 0.5]);
+
+        let triangle = Tone { waveform: Waveform::Triangle, ..tone }.samples(8);
+        assert_eq!(triangle, vec![-1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0]);
+
+        let noise = Tone { waveform: Waveform::Noise, frequency: 100.0, ..tone };
+        let samples = noise.samples(1000);
+        assert_eq!(samples, noise.samples(1000));
+        assert!(samples.iter().all(|x| (-1.0..=1.0).contains(x)));
+        assert_ne!(samples, Tone { seed: 2, ..noise }.samples(1000));
+
+        let sound = Tone { duration: 0.5, envelope: Envelope::default(), ..tone }.render(100);
+        assert_eq!(sound.frames(), 55);
+        assert_eq!(sound.channels, 1);
+    }
+}
@@ -1,11 +1,14 @@
 use std::collections::{HashMap, HashSet};
 
+use mlua::ChunkMode;
 use sti::keyed::KVec;
 use tracing::{error, info, trace};
 
-use crate::{engine::Engine, script_manager::{fields::{Field, FieldValue}, Script, ScriptId, ScriptManager}};
+use crate::{engine::Engine, script_manager::{fields::{Field, FieldId, FieldValue}, Script, ScriptId, ScriptManager}};
 
 pub mod template_scene;
+pub mod scene_file;
+pub mod scene_binary;
 
 
 impl ScriptManager {
@@ -28,27 +31,142 @@ impl ScriptManager {
         }
 
 
-        let Ok(file) = std::fs::read(path)
+        drop(engine_ref);
+
+        let Some(lua) = Self::read_script(path)
+        else { return ScriptId::EMPTY };
+
+        engine.get_mut().script_manager.watcher.watch(path);
+
+        Self::from_lua(engine, path, &lua)
+    }
+
+
+    /// Re-executes the script at `path` and hot swaps the result
+    /// into the already loaded script with the same path.
+    ///
+    /// Live components keep the field values they already have,
+    /// templates only keep the values that the scene file overrides.
+    /// If the script fails to execute the old version is kept.
+    pub fn reload(engine: &mut Engine, path: &str) {
+        let span = tracing::span!(tracing::Level::ERROR, "reloading script ", path);
+        let _handle = span.entered();
+
+        let script_id = engine.get().script_manager.path_to_script.get(path).copied();
+        let Some(script_id) = script_id
         else {
-            error!("unable to read '{path}'");
-            return ScriptId::EMPTY;
+            error!("the script isn't loaded, there's nothing to reload");
+            return;
         };
 
+        let Some(lua) = Self::read_script(path)
+        else { return };
 
-        let Ok(lua) = std::str::from_utf8(&file)
+        let Some((fields, default_fields)) = Self::execute_lua(&lua)
         else {
-            error!("'{path}' is not a valid utf-8 string");
-            return ScriptId::EMPTY;
+            error!("keeping the old version of the script");
+            return;
         };
 
 
-        drop(engine_ref);
+        let mut engine = engine.get_mut();
+        let engine = &mut *engine;
+        let sm = &mut engine.script_manager;
+
+        let old = sm.scripts[script_id].hot_swap(fields, default_fields);
+        let script = &sm.scripts[script_id];
+
+        if old.name != script.name {
+            info!("class name changed from '{}' to '{}'", old.name, script.name);
+
+            if sm.path_to_script.get(&old.name) == Some(&script_id) {
+                sm.path_to_script.remove(&old.name);
+            }
+
+            if let Some(binded) = sm.path_to_script.get(&script.name) {
+                let name_scr = sm.scripts.get(*binded).unwrap();
+                error!("the name '{:?}' is already binded to '{}'", script.name, name_scr.path());
+            } else {
+                sm.path_to_script.insert(script.name.clone(), script_id);
+            }
+        }
+
+
+        let tree = &mut engine.scene_manager.tree;
+        let handles = tree.map.iter().collect::<Vec<_>>();
+        let mut component_count = 0;
+
+        for handle in handles {
+            let node = tree.map.get_mut(handle).unwrap();
+
+            for (_, comp) in node.components.iter_mut() {
+                if comp.script != script_id { continue }
+
+                comp.fields = script.migrate_fields(&old, &comp.fields, |_, _| true);
+                component_count += 1;
+            }
+        }
+
+
+        for (_, template) in engine.scene_manager.templates.iter_mut() {
+            template.migrate_script_fields(script_id, script, &old);
+        }
+
+        info!("reloaded, patched {component_count} live components");
+    }
+
+
+    fn read_script(path: &str) -> Option<Vec<u8>> {
+        match Engine::file_system().read(path) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                error!("unable to read '{path}': {e}");
+                None
+            },
+        }
+    }
+
+
+    /// Executes `lua_file`, which is either Luau source code or
+    /// bytecode produced by the export step, as a script
+    pub fn from_lua(engine: &mut Engine, path: &str, lua_file: &[u8]) -> ScriptId {
+        let Some((fields, default_fields)) = Self::execute_lua(lua_file)
+        else { return ScriptId::EMPTY };
+
+
+        let script = Script::new(
+            path.to_string(),
+            fields,
+            default_fields,
+        );
+
+        let name = script.name.clone();
 
-        Self::from_lua(engine, path, lua)
+        
+        let mut engine = engine.get_mut();
+        let sm = &mut engine.script_manager;
+
+        let id = sm.scripts.push(script);
+
+        if let Some(binded) = sm.path_to_script.get(&name) {
+            let name_scr = sm.scripts.get(*binded).unwrap();
+            error!("the name '{:?}' is already binded to '{}'", name, name_scr.path());
+
+        } else {
+            sm.path_to_script.insert(name.clone(), id);
+
+        }
+
+        sm.path_to_script.insert(path.to_string(), id);
+
+
+        ScriptId::EMPTY
     }
 
 
-    pub fn from_lua(engine: &mut Engine, path: &str, lua_file: &str) -> ScriptId {
+    /// Executes a script and collects the globals it
+    /// defines, removing them from the global environment
+    fn execute_lua(lua_file: &[u8]) -> Option<(HashMap<String, FieldId>, KVec<FieldId, Field>)> {
         // we save the environment so we can diff it
         let environment = {
             let mut hashset = HashSet::new();
@@ -63,12 +181,18 @@ impl ScriptManager {
         };
 
 
-        let lua_chunk = Engine::lua().load(lua_file);
+        // luau bytecode starts with its version number
+        // while source code can't start with a control
+        // character
+        let is_bytecode = lua_file.first().is_some_and(|x| *x < b'\t');
+        let mode = if is_bytecode { ChunkMode::Binary } else { ChunkMode::Text };
+
+        let lua_chunk = Engine::lua().load(lua_file).set_mode(mode);
         let lua_result = lua_chunk.call::<mlua::Value>(());
 
         if let Err(e) = lua_result {
             error!("while executing the script: \n{e}");
-            return ScriptId::EMPTY
+            return None
         }
 
 
@@ -97,32 +221,6 @@ impl ScriptManager {
         }
 
 
-        let script = Script::new(
-            path.to_string(),
-            fields,
-            default_fields,
-        );
-
-        let name = script.name.clone();
-
-        
-        let mut engine = engine.get_mut();
-        let sm = &mut engine.script_manager;
-
-        let id = sm.scripts.push(script);
-
-        if let Some(binded) = sm.path_to_script.get(&name) {
-            let name_scr = sm.scripts.get(*binded).unwrap();
-            error!("the name '{:?}' is already binded to '{}'", name, name_scr.path());
-
-        } else {
-            sm.path_to_script.insert(name.clone(), id);
-
-        }
-
-        sm.path_to_script.insert(path.to_string(), id);
-
-
-        ScriptId::EMPTY
+        Some((fields, default_fields))
     }
 }
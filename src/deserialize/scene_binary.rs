@@ -0,0 +1,905 @@
+use std::collections::HashMap;
+
+use sti::keyed::KVec;
+use tracing::{error, warn};
+
+use crate::{engine::Engine, file_system::FileSystem, math::vector::{Vec2, Vec4}, scene_manager::{node::NodeProperties, scene_template::{TemplateComponent, TemplateComponents, TemplateNode, TemplateNodeId, TemplateScene}, scene_tree::is_valid_name}, script_manager::{fields::FieldValue, ScriptManager}};
+
+use super::scene_file::{upgrade, SceneNode, SceneSource};
+
+
+///
+/// A scene flattened into a list of nodes by the export
+/// step so that it loads without any parsing, see `compile`.
+///
+/// The layout of a binary scene is:
+///   - the magic bytes `BUTRSCNE`
+///   - the format version as a u32
+///   - the string count as a u32
+///   - for each string, its length as a u32 and then its utf-8
+///   - the node count as a u32
+///   - for each node:
+///       - the index of the parent
+///       - the name
+///       - the group count as a u32 and then each group
+///       - the position, scale, rotation and modulate as f32s
+///       - the texture
+///       - the component count as a u32
+///       - for each component:
+///           - the path of the script
+///           - the field count as a u32
+///           - for each field, its name and a value
+///
+/// Strings are u32 indices into the strings and optional
+/// indices are `u32::MAX` when there's nothing. A value is a
+/// u8 tag followed by a string, an i64, an f64, a u8 boolean
+/// or three f32s for a vector. All numbers are little endian.
+///
+/// Nodes come parents first so node 0 is the root.
+///
+#[derive(Debug, Default)]
+pub struct BinaryScene {
+    pub nodes: Vec<BinaryNode>,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct BinaryNode {
+    pub parent: Option<u32>,
+    pub name: Option<String>,
+    pub groups: Vec<String>,
+    /// Textures are resolved when the scene is
+    /// loaded so this one never has any
+    pub properties: NodeProperties,
+    pub texture: Option<String>,
+    pub components: Vec<BinaryComponent>,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct BinaryComponent {
+    /// The path of the script, never its class name
+    pub script: String,
+    /// Only the fields the scene sets, the
+    /// rest keep the script's defaults
+    pub fields: Vec<(String, BinaryValue)>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BinaryValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Vector(f32, f32, f32),
+}
+
+
+const NONE : u32 = u32::MAX;
+
+
+impl BinaryScene {
+    pub const MAGIC : &[u8; 8] = b"BUTRSCNE";
+    pub const VERSION : u32 = 1;
+
+
+    /// Whether `bytes` are a binary scene rather than a text one
+    pub fn is_binary(bytes: &[u8]) -> bool {
+        bytes.starts_with(Self::MAGIC)
+    }
+
+
+    ///
+    /// Flattens the text scene at `path` into a binary one.
+    ///
+    /// The scenes it instances are expanded in place and
+    /// components are resolved from their class name to the
+    /// path of their script through `class_names`.
+    ///
+    pub fn compile(file_system: &FileSystem, path: &str,
+                   class_names: &HashMap<String, String>) -> Result<Self, String> {
+        let mut compiler = Compiler { file_system, class_names, loading: vec![] };
+        let nodes = compiler.scene(path)?;
+        Ok(Self { nodes })
+    }
+
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+
+        writer.u32(self.nodes.len() as u32);
+        for node in self.nodes.iter() {
+            writer.u32(node.parent.unwrap_or(NONE));
+            writer.optional_string(node.name.as_deref());
+
+            writer.u32(node.groups.len() as u32);
+            for group in node.groups.iter() {
+                writer.string(group);
+            }
+
+            let properties = node.properties;
+            for value in [properties.position.x, properties.position.y,
+                          properties.scale.x, properties.scale.y,
+                          properties.rotation,
+                          properties.modulate.x, properties.modulate.y,
+                          properties.modulate.z, properties.modulate.w] {
+                writer.bytes.extend(value.to_le_bytes());
+            }
+
+            writer.optional_string(node.texture.as_deref());
+
+            writer.u32(node.components.len() as u32);
+            for component in node.components.iter() {
+                writer.string(&component.script);
+
+                writer.u32(component.fields.len() as u32);
+                for (name, value) in component.fields.iter() {
+                    writer.string(name);
+                    writer.value(value);
+                }
+            }
+        }
+
+
+        let mut bytes = Vec::with_capacity(writer.bytes.len() + 16);
+        bytes.extend(Self::MAGIC);
+        bytes.extend(Self::VERSION.to_le_bytes());
+
+        bytes.extend((writer.strings.len() as u32).to_le_bytes());
+        for string in writer.strings.iter() {
+            bytes.extend((string.len() as u32).to_le_bytes());
+            bytes.extend(string.as_bytes());
+        }
+
+        bytes.extend(writer.bytes);
+        bytes
+    }
+
+
+    /// Reads and validates a binary scene, nothing
+    /// past the header is trusted
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes, offset: 0, strings: vec![] };
+
+        if reader.take(Self::MAGIC.len())? != Self::MAGIC {
+            return Err(String::from("the file isn't a binary scene"));
+        }
+
+        let version = reader.u32()?;
+        if version != Self::VERSION {
+            return Err(format!("the binary scene version is '{version}' \
+                               but only '{}' is supported", Self::VERSION));
+        }
+
+        let string_count = reader.u32()?;
+        for _ in 0..string_count {
+            let len = reader.u32()?;
+            let string = reader.take(len as usize)?;
+            let Ok(string) = core::str::from_utf8(string)
+            else { return Err(String::from("a string isn't valid utf-8")) };
+
+            reader.strings.push(string);
+        }
+
+
+        let node_count = reader.u32()?;
+        let mut nodes = Vec::with_capacity((node_count as usize).min(bytes.len()));
+
+        for index in 0..node_count {
+            let parent = match reader.u32()? {
+                NONE => None,
+                v => Some(v),
+            };
+
+            match parent {
+                None if index != 0 => return Err(format!("the node '{index}' has no parent")),
+                Some(_) if index == 0 => return Err(String::from("the root has a parent")),
+                Some(parent) if parent >= index => {
+                    return Err(format!("the parent of the node '{index}' doesn't come before it"));
+                },
+
+                _ => (),
+            }
+
+            let name = reader.optional_string()?;
+            if let Some(name) = &name {
+                if !is_valid_name(name) {
+                    return Err(format!("'{name}' isn't a valid name"));
+                }
+            }
+
+            let group_count = reader.u32()?;
+            let mut groups = vec![];
+            for _ in 0..group_count {
+                groups.push(reader.string()?);
+            }
+
+            let mut floats = [0.0; 9];
+            for float in floats.iter_mut() {
+                *float = reader.f32()?;
+            }
+
+            let [px, py, sx, sy, rotation, r, g, b, a] = floats;
+            let properties = NodeProperties::new(Vec2::new(px, py), Vec4::new(r, g, b, a),
+                                                 Vec2::new(sx, sy), rotation, None);
+
+            let texture = reader.optional_string()?;
+
+            let component_count = reader.u32()?;
+            let mut components = vec![];
+            for _ in 0..component_count {
+                let script = reader.string()?;
+
+                let field_count = reader.u32()?;
+                let mut fields = vec![];
+                for _ in 0..field_count {
+                    fields.push((reader.string()?, reader.value()?));
+                }
+
+                components.push(BinaryComponent { script, fields });
+            }
+
+            nodes.push(BinaryNode { parent, name, groups, properties, texture, components });
+        }
+
+
+        if reader.offset != bytes.len() {
+            return Err(format!("there are {} bytes after the last node", bytes.len() - reader.offset));
+        }
+
+        if nodes.is_empty() {
+            return Err(String::from("the scene has no nodes"));
+        }
+
+        Ok(Self { nodes })
+    }
+
+
+    /// The textures the nodes of the scene use
+    pub fn textures(&self) -> impl Iterator<Item=&str> {
+        self.nodes.iter().filter_map(|x| x.texture.as_deref())
+    }
+}
+
+
+impl TemplateScene {
+    /// Builds a template out of a binary scene, only
+    /// the scripts and textures are left to resolve
+    pub fn from_binary(engine: &mut Engine, scene: &BinaryScene) -> TemplateScene {
+        let mut template = TemplateScene::new();
+        let mut has_errored = false;
+
+        for (index, node) in scene.nodes.iter().enumerate() {
+            let span = tracing::span!(tracing::Level::ERROR, "", node = index);
+            let _handle = span.entered();
+
+            let mut properties = node.properties;
+            if let Some(texture) = &node.texture {
+                properties.texture = NodeProperties::texture_from_str(engine, "", texture);
+                has_errored |= properties.texture.is_none();
+            }
+
+            let mut components = KVec::with_cap(node.components.len());
+            for component in node.components.iter() {
+                let script_id = ScriptManager::from_path(engine, &component.script);
+                let engine = engine.get();
+                let script = engine.script_manager.script(script_id);
+
+                let mut fields = KVec::with_cap(script.default_fields.len());
+                for (_, field) in script.default_fields.iter() {
+                    fields.push(field.value.clone());
+                }
+
+                for (name, value) in component.fields.iter() {
+                    let Some(field_id) = script.fields.get(name)
+                    else {
+                        warn!("the component '{}' has no field '{name}'", component.script);
+                        continue;
+                    };
+
+                    fields[*field_id] = value.to_field_value();
+                }
+
+                components.push(TemplateComponent::new(script_id, fields));
+            }
+
+            template.inner_mut().push(TemplateNode {
+                name: node.name.clone(),
+                groups: node.groups.clone(),
+                properties,
+                parent: node.parent.map(TemplateNodeId::new_unck),
+                components: TemplateComponents::new(components),
+            });
+        }
+
+
+        if has_errored {
+            error!("the binary scene references textures that failed to load");
+            return TemplateScene::new();
+        }
+
+        template
+    }
+}
+
+
+impl BinaryValue {
+    /// The value `FieldValue::from_toml` would read
+    /// out of `value`, `None` if it can't
+    pub fn from_toml(value: &toml::Value) -> Option<Self> {
+        Some(match value {
+            toml::Value::String(v) => Self::String(v.clone()),
+            toml::Value::Integer(v) => Self::Integer(*v),
+            toml::Value::Float(v) => Self::Float(*v),
+            toml::Value::Boolean(v) => Self::Boolean(*v),
+
+            toml::Value::Table(map) => {
+                if map.get("is_table").and_then(|x| x.as_bool()).unwrap_or(false) {
+                    return None;
+                }
+
+                let axis = |name| map.get(name).map(|x| x.as_float()).unwrap_or(Some(0.0));
+                Self::Vector(axis("x")? as f32, axis("y")? as f32, axis("z")? as f32)
+            },
+
+            toml::Value::Datetime(_) => return None,
+            toml::Value::Array(_) => return None,
+        })
+    }
+
+
+    pub fn to_field_value(&self) -> FieldValue {
+        let value = match self {
+            BinaryValue::String(v) => mlua::Value::String(Engine::lua().create_string(v).unwrap()),
+            BinaryValue::Integer(v) => mlua::Value::Integer(*v as i32),
+            BinaryValue::Float(v) => mlua::Value::Number(*v),
+            BinaryValue::Boolean(v) => mlua::Value::Boolean(*v),
+            BinaryValue::Vector(x, y, z) => mlua::Value::Vector(mlua::Vector::new(*x, *y, *z)),
+        };
+
+        FieldValue::new(value)
+    }
+}
+
+
+impl Default for BinaryNode {
+    fn default() -> Self {
+        Self {
+            parent: None,
+            name: None,
+            groups: vec![],
+            properties: NodeProperties::identity(),
+            texture: None,
+            components: vec![],
+        }
+    }
+}
+
+
+struct Compiler<'a> {
+    file_system: &'a FileSystem,
+    class_names: &'a HashMap<String, String>,
+    /// The scenes being compiled, to catch
+    /// the ones that instance themselves
+    loading: Vec<String>,
+}
+
+
+impl Compiler<'_> {
+    fn scene(&mut self, path: &str) -> Result<Vec<BinaryNode>, String> {
+        if self.loading.iter().any(|x| x == path) {
+            return Err(format!("'{path}' ends up instancing itself: {} -> {path}", self.loading.join(" -> ")));
+        }
+
+        let bytes = self.file_system.read(path)
+            .map_err(|e| format!("unable to read '{path}': {e}"))?;
+
+        let source = SceneSource::from_bytes(bytes)
+            .map_err(|e| format!("unable to parse '{path}': {e}"))?;
+
+        // the upgrader is the only thing that
+        // knows the old format without an engine
+        let source = match source {
+            SceneSource::V1(table) => SceneSource::parse(upgrade(&table)?)?,
+            _ => source,
+        };
+
+        let root = match &source {
+            SceneSource::V2 { file, .. } => file.root.get_ref(),
+            SceneSource::Binary(scene) => return Ok(scene.nodes.clone()),
+            SceneSource::V1(_) => unreachable!(),
+        };
+
+        self.loading.push(path.to_string());
+        let mut nodes = vec![];
+        let result = self.node(&mut nodes, None, None, root);
+        self.loading.pop();
+
+        result.map_err(|e| format!("in '{path}': {e}"))?;
+        Ok(nodes)
+    }
+
+
+    fn node(&mut self, nodes: &mut Vec<BinaryNode>, parent: Option<u32>,
+            name: Option<&str>, entry: &SceneNode) -> Result<(), String> {
+        let index = nodes.len() as u32;
+
+        match &entry.instance {
+            Some(instance) => {
+                let instanced = self.scene(instance.get_ref())?;
+
+                nodes.extend(instanced.into_iter().map(|mut node| {
+                    node.parent = match node.parent {
+                        Some(parent) => Some(parent + index),
+                        None => parent,
+                    };
+                    node
+                }));
+            },
+
+            None => nodes.push(BinaryNode { parent, ..Default::default() }),
+        }
+
+
+        let is_instance = entry.instance.is_some();
+        let node = &mut nodes[index as usize];
+        if let Some(name) = name {
+            node.name = Some(name.to_string());
+        }
+
+        let properties = [
+            ("name", &entry.name),
+            ("groups", &entry.groups),
+            ("position", &entry.position),
+            ("scale", &entry.scale),
+            ("modulate", &entry.modulate),
+            ("rotation", &entry.rotation),
+            ("texture", &entry.texture),
+            ("components", &entry.components),
+        ];
+
+        for (key, value) in properties {
+            let Some(value) = value
+            else { continue };
+
+            self.apply(node, key, value.get_ref(), !is_instance)?;
+        }
+
+
+        for (path, overrides) in entry.overrides.iter() {
+            if !is_instance {
+                return Err(format!("only instances can override nodes, '{path}' can't be overridden"));
+            }
+
+            let Some(target) = find(nodes, index, path)
+            else { return Err(format!("there's no node at '{path}' to override")) };
+
+            for (key, value) in overrides.get_ref().iter() {
+                self.apply(&mut nodes[target as usize], key, value, false)?;
+            }
+        }
+
+
+        for (name, child) in entry.children.iter() {
+            if !is_valid_name(name) {
+                return Err(format!("'{name}' isn't a valid name"));
+            }
+
+            self.node(nodes, Some(index), Some(name), child.get_ref())?;
+        }
+
+        Ok(())
+    }
+
+
+    /// Applies one property of a scene file to `node`,
+    /// components are only added if `add_components` and
+    /// otherwise override the ones the node already has
+    fn apply(&self, node: &mut BinaryNode, key: &str, value: &toml::Value,
+             add_components: bool) -> Result<(), String> {
+        let properties = &mut node.properties;
+
+        match key {
+            "name" => {
+                let Some(name) = value.as_str().filter(|x| is_valid_name(x))
+                else { return Err(format!("'{value}' isn't a valid name")) };
+
+                node.name = Some(name.to_string());
+            },
+
+
+            "groups" => {
+                let groups = value.as_array()
+                    .and_then(|x| x.iter().map(|x| x.as_str().map(String::from)).collect::<Option<Vec<_>>>());
+
+                let Some(groups) = groups
+                else { return Err(String::from("the groups aren't an array of strings")) };
+
+                node.groups = groups;
+            },
+
+
+            "position" | "scale" => {
+                let Some(vector) = value.as_table().and_then(|x| Vec2::from_table(key, x))
+                else { return Err(format!("unable to read '{key}'")) };
+
+                match key {
+                    "position" => properties.position = vector,
+                    _ => properties.scale = vector,
+                }
+            },
+
+
+            "modulate" => {
+                let Some(modulate) = value.as_table().and_then(|x| Vec4::from_table(key, x))
+                else { return Err(String::from("unable to read 'modulate'")) };
+
+                properties.modulate = modulate;
+            },
+
+
+            "rotation" => {
+                let Some(rotation) = value.as_float()
+                else { return Err(String::from("'rotation' isn't a float")) };
+
+                properties.rotation = rotation as f32;
+            },
+
+
+            "texture" => {
+                let Some(texture) = value.as_str()
+                else { return Err(String::from("'texture' isn't a string")) };
+
+                node.texture = Some(texture.to_string());
+            },
+
+
+            "components" => {
+                let Some(components) = value.as_table()
+                else { return Err(String::from("the components entry isn't a table")) };
+
+                for (class, fields) in components.iter() {
+                    let Some(fields) = fields.as_table()
+                    else { return Err(format!("the fields of the component '{class}' aren't a table")) };
+
+                    let script = self.class_names.get(class).cloned()
+                        .unwrap_or_else(|| class.clone());
+
+                    let component = match add_components {
+                        true => {
+                            node.components.push(BinaryComponent { script, fields: vec![] });
+                            node.components.last_mut().unwrap()
+                        },
+
+                        false => {
+                            let component = node.components.iter_mut().find(|x| x.script == script);
+                            let Some(component) = component
+                            else { return Err(format!("the node doesn't have a '{class}' component to override")) };
+
+                            component
+                        },
+                    };
+
+                    for (field, value) in fields.iter() {
+                        let Some(value) = BinaryValue::from_toml(value)
+                        else {
+                            warn!("the field '{field}' of '{class}' is an unsupported toml value");
+                            continue;
+                        };
+
+                        match component.fields.iter_mut().find(|(name, _)| name == field) {
+                            Some((_, v)) => *v = value,
+                            None => component.fields.push((field.clone(), value)),
+                        }
+                    }
+                }
+            },
+
+
+            _ => return Err(format!("'{key}' isn't a property")),
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Finds a node by its path the same way `TemplateScene::get_node` does
+fn find(nodes: &[BinaryNode], from: u32, path: &str) -> Option<u32> {
+    let mut current = from;
+
+    for segment in path.split('/') {
+        current = match segment {
+            "" | "." => current,
+            ".." => nodes.get(current as usize)?.parent?,
+            _ => {
+                let children = (0..nodes.len() as u32)
+                    .filter(|x| nodes[*x as usize].parent == Some(current))
+                    .collect::<Vec<_>>();
+
+                let by_name = children.iter()
+                    .find(|x| nodes[**x as usize].name.as_deref() == Some(segment));
+
+                let by_index = || segment.strip_prefix('#')
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .and_then(|x| children.get(x));
+
+                *by_name.or_else(by_index)?
+            },
+        };
+    }
+
+    Some(current)
+}
+
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+    strings: Vec<String>,
+    lookup: HashMap<String, u32>,
+}
+
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+
+    fn string(&mut self, string: &str) {
+        let index = match self.lookup.get(string) {
+            Some(index) => *index,
+            None => {
+                let index = self.strings.len() as u32;
+                self.strings.push(string.to_string());
+                self.lookup.insert(string.to_string(), index);
+                index
+            },
+        };
+
+        self.u32(index);
+    }
+
+
+    fn optional_string(&mut self, string: Option<&str>) {
+        match string {
+            Some(string) => self.string(string),
+            None => self.u32(NONE),
+        }
+    }
+
+
+    fn value(&mut self, value: &BinaryValue) {
+        match value {
+            BinaryValue::String(v) => {
+                self.bytes.push(0);
+                self.string(v);
+            },
+
+            BinaryValue::Integer(v) => {
+                self.bytes.push(1);
+                self.bytes.extend(v.to_le_bytes());
+            },
+
+            BinaryValue::Float(v) => {
+                self.bytes.push(2);
+                self.bytes.extend(v.to_le_bytes());
+            },
+
+            BinaryValue::Boolean(v) => {
+                self.bytes.push(3);
+                self.bytes.push(*v as u8);
+            },
+
+            BinaryValue::Vector(x, y, z) => {
+                self.bytes.push(4);
+                for v in [x, y, z] {
+                    self.bytes.extend(v.to_le_bytes());
+                }
+            },
+        }
+    }
+}
+
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    strings: Vec<&'a str>,
+}
+
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.checked_add(len).filter(|x| *x <= self.bytes.len());
+        let Some(end) = end
+        else { return Err(String::from("the file ends early")) };
+
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+
+    fn string(&mut self) -> Result<String, String> {
+        let index = self.u32()?;
+        match self.strings.get(index as usize) {
+            Some(string) => Ok(string.to_string()),
+            None => Err(format!("the string '{index}' is out of bounds")),
+        }
+    }
+
+
+    fn optional_string(&mut self) -> Result<Option<String>, String> {
+        let index = self.u32()?;
+        if index == NONE { return Ok(None) }
+
+        self.offset -= 4;
+        self.string().map(Some)
+    }
+
+
+    fn value(&mut self) -> Result<BinaryValue, String> {
+        Ok(match self.array::<1>()?[0] {
+            0 => BinaryValue::String(self.string()?),
+            1 => BinaryValue::Integer(i64::from_le_bytes(self.array()?)),
+            2 => BinaryValue::Float(f64::from_le_bytes(self.array()?)),
+            3 => BinaryValue::Boolean(self.array::<1>()?[0] != 0),
+            4 => BinaryValue::Vector(self.f32()?, self.f32()?, self.f32()?),
+            tag => return Err(format!("'{tag}' isn't a value type")),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn scene() -> BinaryScene {
+        let pipe = BinaryNode {
+            name: Some(String::from("Pipe")),
+            groups: vec![String::from("pipes")],
+            components: vec![BinaryComponent {
+                script: String::from("pipe/pipe.lua"),
+                fields: vec![(String::from("speed"), BinaryValue::Float(2.5))],
+            }],
+            ..Default::default()
+        };
+
+        let mut top = BinaryNode {
+            parent: Some(0),
+            name: Some(String::from("Top")),
+            texture: Some(String::from("image:pipe/pipe.png")),
+            components: vec![BinaryComponent {
+                script: String::from("collider.lua"),
+                fields: vec![
+                    (String::from("kind"), BinaryValue::String(String::from("kinematic"))),
+                    (String::from("layer"), BinaryValue::Integer(-3)),
+                    (String::from("sensor"), BinaryValue::Boolean(true)),
+                    (String::from("scale_mult"), BinaryValue::Vector(0.8, 0.95, 1.0)),
+                ],
+            }],
+            ..Default::default()
+        };
+        top.properties.rotation = 3.5;
+        top.properties.position = Vec2::new(0.0, 15.0);
+
+        BinaryScene { nodes: vec![pipe, top] }
+    }
+
+
+    #[test]
+    fn scene_binary_round_trip() {
+        let bytes = scene().encode();
+        assert!(BinaryScene::is_binary(&bytes));
+
+        let decoded = BinaryScene::decode(&bytes).unwrap();
+        assert_eq!(decoded.encode(), bytes);
+
+        let top = &decoded.nodes[1];
+        assert_eq!(top.parent, Some(0));
+        assert_eq!(top.name.as_deref(), Some("Top"));
+        assert_eq!(top.texture.as_deref(), Some("image:pipe/pipe.png"));
+        assert_eq!(top.properties.rotation, 3.5);
+        assert_eq!(top.components[0].fields[3].1, BinaryValue::Vector(0.8, 0.95, 1.0));
+        assert_eq!(decoded.textures().collect::<Vec<_>>(), vec!["image:pipe/pipe.png"]);
+    }
+
+
+    #[test]
+    fn scene_binary_validation() {
+        let bytes = scene().encode();
+
+        assert!(BinaryScene::decode(b"BUTRPACK").is_err());
+        assert!(BinaryScene::decode(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(BinaryScene::decode(&trailing).is_err());
+
+        let mut version = bytes.clone();
+        version[8] = 2;
+        assert!(BinaryScene::decode(&version).is_err());
+
+        let mut orphan = scene();
+        orphan.nodes[1].parent = None;
+        assert!(BinaryScene::decode(&orphan.encode()).is_err());
+
+        let mut forward = scene();
+        forward.nodes[1].parent = Some(1);
+        assert!(BinaryScene::decode(&forward.encode()).is_err());
+    }
+
+
+    #[test]
+    fn scene_binary_compile() {
+        let dir = std::env::temp_dir().join(format!("butter-scenes-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("pipe")).unwrap();
+
+        std::fs::write(dir.join("pipe/pipe.scene"), r#"
+            version = 2
+
+            [root]
+            name = "Pipe"
+            components = { Pipe = { speed = 1.0 } }
+
+            [root.children.Top]
+            rotation = 3.0
+        "#).unwrap();
+
+        std::fs::write(dir.join("world.scene"), r#"
+            [0]
+            position = { x = 0.0, y = 0.0 }
+            modulate = { x = 1.0, y = 1.0, z = 1.0, w = 1.0 }
+            scale = { x = 1.0, y = 1.0 }
+            rotation = 0.0
+
+            [1]
+            parent = 0
+            instance = "pipe/pipe.scene"
+            position = { x = 5.0, y = 0.0 }
+            components = { Pipe = { speed = 2.0 } }
+            overrides = { Top = { rotation = 1.0 } }
+        "#).unwrap();
+
+        let mut file_system = FileSystem::new();
+        file_system.mount_directory(&dir);
+
+        let class_names = HashMap::from([(String::from("Pipe"), String::from("pipe/pipe.lua"))]);
+        let scene = BinaryScene::compile(&file_system, "world.scene", &class_names);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let scene = scene.unwrap();
+        assert_eq!(scene.nodes.len(), 3);
+
+        let pipe = &scene.nodes[1];
+        assert_eq!(pipe.parent, Some(0));
+        assert_eq!(pipe.name.as_deref(), Some("Pipe"));
+        assert_eq!(pipe.properties.position, Vec2::new(5.0, 0.0));
+        assert_eq!(pipe.components[0].script, "pipe/pipe.lua");
+        assert_eq!(pipe.components[0].fields, vec![(String::from("speed"), BinaryValue::Float(2.0))]);
+
+        let top = &scene.nodes[2];
+        assert_eq!(top.parent, Some(1));
+        assert_eq!(top.properties.rotation, 1.0);
+    }
+}
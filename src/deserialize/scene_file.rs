@@ -0,0 +1,648 @@
+use std::{collections::HashSet, fmt::Write, io, ops::Range, path::Path, str::FromStr};
+
+use serde::Deserialize;
+use sti::keyed::KVec;
+use toml::Spanned;
+use tracing::{error, info, Level};
+
+use crate::{deserialize::scene_binary::BinaryScene, engine::Engine, file_system::FileSystem, scene_manager::{node::NodeProperties, scene_template::{TemplateComponents, TemplateNode, TemplateNodeId, TemplateScene}, scene_tree::is_valid_name}};
+
+
+/// The version of the scene format the engine writes,
+/// files without a `version` are version 1
+pub const SCENE_VERSION : i64 = 2;
+
+
+/// A scene file that was read and parsed but whose
+/// scripts and textures aren't resolved yet
+#[derive(Debug)]
+pub enum SceneSource {
+    /// Nodes keyed by their index along with
+    /// the index of their parent
+    V1(toml::Table),
+
+    /// The text is kept to turn spans into lines
+    V2 { text: String, file: SceneFile },
+
+    /// Produced by the export step
+    Binary(BinaryScene),
+}
+
+
+///
+/// A version 2 scene file
+///
+/// ```toml
+/// version = 2
+///
+/// [root]
+/// name = "World"
+/// components = { World = {} }
+///
+/// [root.children.Player]
+/// texture = "image:character/midflap.png"
+/// position = { x = -3.0, y = 0.0 }
+/// components = { Player = { jump = 7.5 } }
+///
+/// [root.children.Pipe]
+/// instance = "pipe/pipe.scene"
+/// overrides = { Top = { rotation = 3.14 } }
+/// ```
+///
+/// Every property is optional, children are named
+/// by their key and keep the order they're written in.
+///
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    pub version: i64,
+    pub root: Spanned<SceneNode>,
+}
+
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneNode {
+    /// Only the root has a name entry, the rest
+    /// are named by their key in `children`
+    pub name: Option<Spanned<toml::Value>>,
+    /// The path of the scene this node is an instance of
+    pub instance: Option<Spanned<String>>,
+    pub groups: Option<Spanned<toml::Value>>,
+    pub position: Option<Spanned<toml::Value>>,
+    pub scale: Option<Spanned<toml::Value>>,
+    pub modulate: Option<Spanned<toml::Value>>,
+    pub rotation: Option<Spanned<toml::Value>>,
+    pub texture: Option<Spanned<toml::Value>>,
+    /// On an instance these override the fields
+    /// of the components it already has
+    pub components: Option<Spanned<toml::Value>>,
+    /// Overrides of the nodes of an instance, keyed
+    /// by their path from the instance
+    #[serde(default)]
+    pub overrides: toml::map::Map<String, Spanned<toml::Table>>,
+    #[serde(default)]
+    pub children: toml::map::Map<String, Spanned<SceneNode>>,
+}
+
+
+/// A node of a scene that's being written, see `nest`
+#[derive(Debug)]
+pub struct FlatNode {
+    pub parent: Option<usize>,
+    pub table: toml::Table,
+}
+
+
+impl SceneSource {
+    /// Parses a scene file that's either text or binary
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, String> {
+        if BinaryScene::is_binary(&bytes) {
+            return BinaryScene::decode(&bytes).map(Self::Binary);
+        }
+
+        match String::from_utf8(bytes) {
+            Ok(text) => Self::parse(text),
+            Err(e) => Err(format!("the file isn't valid utf-8: {e}")),
+        }
+    }
+
+
+    /// Parses the text of a scene file of any version
+    pub fn parse(text: String) -> Result<Self, String> {
+        let table = toml::Table::from_str(&text).map_err(|e| e.to_string())?;
+
+        let Some(version) = table.get("version")
+        else { return Ok(Self::V1(table)) };
+
+        match version.as_integer() {
+            Some(SCENE_VERSION) => {
+                let file = toml::from_str(&text).map_err(|e| e.to_string())?;
+                Ok(Self::V2 { text, file })
+            },
+
+            _ => Err(format!("the version '{version}' isn't supported, \
+                             the newest one is '{SCENE_VERSION}'")),
+        }
+    }
+
+
+    /// The textures the nodes of the scene use, the ones
+    /// of the scenes it instances aren't included
+    pub fn textures(&self) -> Vec<&str> {
+        let mut textures = vec![];
+
+        match self {
+            SceneSource::V1(table) => {
+                let iter = table.values().filter_map(|x| x.get("texture")?.as_str());
+                textures.extend(iter);
+            },
+
+
+            SceneSource::V2 { file, .. } => {
+                let mut stack = vec![file.root.get_ref()];
+                while let Some(node) = stack.pop() {
+                    textures.extend(node.texture.as_ref().and_then(|x| x.get_ref().as_str()));
+                    stack.extend(node.children.values().map(|x| x.get_ref()));
+                }
+            },
+
+
+            SceneSource::Binary(scene) => textures.extend(scene.textures()),
+        }
+
+        textures
+    }
+}
+
+
+impl TemplateScene {
+    ///
+    /// Builds a template out of a version 2 scene file.
+    ///
+    /// Every error in the file is reported along with its
+    /// line and column instead of stopping at the first one,
+    /// the scene is empty if there were any.
+    ///
+    pub fn from_scene_file(engine: &mut Engine, path: &str, text: &str, file: &SceneFile) -> TemplateScene {
+        let mut loader = Loader { path, text, errors: 0 };
+        let mut scene = TemplateScene::new();
+
+        loader.node(engine, &mut scene, None, None, &file.root);
+
+        if loader.errors > 0 {
+            error!("found {} errors in the scene", loader.errors);
+            return TemplateScene::new();
+        }
+
+        if !scene.has_unique_names() {
+            return TemplateScene::new();
+        }
+
+        scene
+    }
+}
+
+
+struct Loader<'a> {
+    path: &'a str,
+    text: &'a str,
+    errors: usize,
+}
+
+
+impl Loader<'_> {
+    /// A span that tags the errors logged in
+    /// it with `path:line:column`
+    fn at(&self, span: Range<usize>) -> tracing::Span {
+        let (line, column) = line_column(self.text, span.start);
+        let location = format!("{}:{line}:{column}", self.path);
+        tracing::span!(Level::ERROR, "", at = %location)
+    }
+
+
+    fn node(&mut self, engine: &mut Engine, scene: &mut TemplateScene,
+            parent: Option<TemplateNodeId>, name: Option<&str>, node: &Spanned<SceneNode>) {
+        let _handle = self.at(node.span()).entered();
+        let entry = node.get_ref();
+
+        let id = match &entry.instance {
+            Some(instance) => {
+                let _handle = self.at(instance.span()).entered();
+
+                // the properties are applied one by one
+                // below so they get their own location
+                let id = scene.expand_instance(engine, parent, instance.get_ref(), &toml::Table::new());
+                let Some(id) = id
+                else {
+                    self.errors += 1;
+                    return;
+                };
+
+                id
+            },
+
+
+            None => {
+                let mut components = TemplateComponents::new(KVec::new());
+                if let Some(value) = &entry.components {
+                    let _handle = self.at(value.span()).entered();
+
+                    match value.get_ref().as_table() {
+                        Some(table) => match TemplateComponents::from_table(engine, table) {
+                            Some(v) => components = v,
+                            None => self.errors += 1,
+                        },
+
+                        None => {
+                            error!("the components entry exists but it's not a table");
+                            self.errors += 1;
+                        },
+                    }
+                }
+
+                let id = TemplateNodeId::new_unck(scene.len() as u32);
+                scene.inner_mut().push(TemplateNode {
+                    name: None,
+                    groups: vec![],
+                    properties: NodeProperties::identity(),
+                    parent,
+                    components,
+                });
+
+                id
+            },
+        };
+
+
+        if let Some(name) = name {
+            scene.get_mut(id).unwrap().name = Some(name.to_string());
+        }
+
+        let properties = [
+            ("name", &entry.name),
+            ("groups", &entry.groups),
+            ("position", &entry.position),
+            ("scale", &entry.scale),
+            ("modulate", &entry.modulate),
+            ("rotation", &entry.rotation),
+            ("texture", &entry.texture),
+        ];
+
+        for (key, value) in properties {
+            let Some(value) = value
+            else { continue };
+
+            self.apply(engine, scene, id, key, value);
+        }
+
+
+        if let Some(instance) = &entry.instance {
+            if let Some(components) = &entry.components {
+                self.apply(engine, scene, id, "components", components);
+            }
+
+            for (path, overrides) in entry.overrides.iter() {
+                let _handle = self.at(overrides.span()).entered();
+
+                let Some(node) = scene.get_node(id, path)
+                else {
+                    error!("there's no node at '{path}' in '{}' to override", instance.get_ref());
+                    self.errors += 1;
+                    continue;
+                };
+
+                if !scene.get_mut(node).unwrap().apply_overrides(engine, overrides.get_ref()) {
+                    self.errors += 1;
+                }
+            }
+
+        } else if let Some((path, overrides)) = entry.overrides.iter().next() {
+            let _handle = self.at(overrides.span()).entered();
+            error!("only instances can override nodes, '{path}' isn't in this scene's instances");
+            self.errors += 1;
+        }
+
+
+        for (name, child) in entry.children.iter() {
+            if !is_valid_name(name) {
+                let _handle = self.at(child.span()).entered();
+                error!("'{name}' isn't a valid name, names can't be \
+                       empty, contain a '/' or be '.' or '..'");
+                self.errors += 1;
+                continue;
+            }
+
+            if let Some(value) = &child.get_ref().name {
+                let _handle = self.at(value.span()).entered();
+                error!("children are named by their key, '{name}' can't have a name entry");
+                self.errors += 1;
+                continue;
+            }
+
+            self.node(engine, scene, Some(id), Some(name), child);
+        }
+    }
+
+
+    fn apply(&mut self, engine: &mut Engine, scene: &mut TemplateScene,
+             node: TemplateNodeId, key: &str, value: &Spanned<toml::Value>) {
+        let _handle = self.at(value.span()).entered();
+
+        let mut table = toml::Table::new();
+        table.insert(key.to_string(), value.get_ref().clone());
+
+        if !scene.get_mut(node).unwrap().apply_overrides(engine, &table) {
+            self.errors += 1;
+        }
+    }
+}
+
+
+/// The line and column, both starting
+/// at 1, of the byte `offset` in `text`
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map(|x| x + 1).unwrap_or(0);
+
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+
+/// Removes the properties that are the same
+/// as the defaults of a version 2 scene
+pub fn strip_defaults(table: &mut toml::Table) {
+    let identity = NodeProperties::identity();
+    let defaults : [(&str, toml::Value); 4] = [
+        ("position", identity.position.to_table().into()),
+        ("modulate", identity.modulate.to_table().into()),
+        ("scale", identity.scale.to_table().into()),
+        ("rotation", identity.rotation.into()),
+    ];
+
+    for (key, value) in defaults {
+        if table.get(key) == Some(&value) {
+            table.remove(key);
+        }
+    }
+}
+
+
+///
+/// Nests nodes that are listed parents first into the root
+/// table of a version 2 scene, `nodes[0]` is the root.
+///
+/// Children are keyed by the `name` of their table, the
+/// ones without a name are named after their first component.
+///
+pub fn nest(nodes: Vec<FlatNode>) -> toml::Table {
+    let mut children = vec![vec![]; nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        if let Some(parent) = node.parent {
+            children[parent].push(index);
+        }
+    }
+
+    let mut tables = nodes.into_iter().map(|x| Some(x.table)).collect::<Vec<_>>();
+    nest_node(0, &children, &mut tables)
+}
+
+
+fn nest_node(index: usize, children: &[Vec<usize>], tables: &mut [Option<toml::Table>]) -> toml::Table {
+    let mut table = tables[index].take().unwrap();
+    if children[index].is_empty() { return table }
+
+    let names = children[index].iter()
+        .map(|x| tables[*x].as_mut().unwrap().remove("name"))
+        .map(|x| x.and_then(|x| x.as_str().map(String::from)))
+        .collect::<Vec<_>>();
+
+    let mut taken = names.iter().flatten().cloned().collect::<HashSet<_>>();
+    let mut children_table = toml::Table::new();
+
+    for (&child, name) in children[index].iter().zip(names) {
+        let name = name.unwrap_or_else(|| {
+            let components = tables[child].as_ref().unwrap().get("components");
+            let base = components.and_then(|x| x.as_table()?.keys().next().cloned())
+                .filter(|x| is_valid_name(x))
+                .unwrap_or_else(|| String::from("Node"));
+
+            let name = match taken.contains(&base) {
+                false => base,
+                true => (2..).map(|i| format!("{base}{i}"))
+                    .find(|x| !taken.contains(x))
+                    .unwrap(),
+            };
+
+            taken.insert(name.clone());
+            name
+        });
+
+        let child_table = nest_node(child, children, tables);
+        children_table.insert(name, child_table.into());
+    }
+
+    table.insert("children".to_string(), children_table.into());
+    table
+}
+
+
+/// Writes the root table of a version 2 scene
+/// as text, every node gets its own section
+pub fn to_text(root: &toml::Table) -> String {
+    let mut text = format!("version = {SCENE_VERSION}\n");
+    write_node(&mut text, "root", root);
+    text
+}
+
+
+fn write_node(text: &mut String, header: &str, table: &toml::Table) {
+    let _ = write!(text, "\n[{header}]\n");
+    for (key, value) in table.iter().filter(|(key, _)| *key != "children") {
+        let _ = writeln!(text, "{} = {value}", toml_key(key));
+    }
+
+    let Some(children) = table.get("children").and_then(|x| x.as_table())
+    else { return };
+
+    for (name, child) in children.iter() {
+        let Some(child) = child.as_table()
+        else { continue };
+
+        write_node(text, &format!("{header}.children.{}", toml_key(name)), child);
+    }
+}
+
+
+/// Quotes `key` unless it can be written bare
+fn toml_key(key: &str) -> String {
+    let is_bare = !key.is_empty()
+        && key.chars().all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-');
+
+    if is_bare { return key.to_string() }
+    toml::Value::String(key.to_string()).to_string()
+}
+
+
+/// Converts a version 1 scene into the
+/// text of a version 2 one
+pub fn upgrade(table: &toml::Table) -> Result<String, String> {
+    let mut nodes = Vec::with_capacity(table.len());
+
+    for (key, value) in table.iter() {
+        let index = key.parse::<usize>()
+            .map_err(|e| format!("'{key}' isn't a node index: {e}"))?;
+
+        let Some(value) = value.as_table()
+        else { return Err(format!("the node '{key}' isn't a table")) };
+
+        let mut value = value.clone();
+        let parent = match value.remove("parent") {
+            Some(parent) => {
+                let parent = parent.as_integer()
+                    .and_then(|x| usize::try_from(x).ok())
+                    .filter(|x| *x < index);
+
+                let Some(parent) = parent
+                else { return Err(format!("the parent of '{key}' must be the index of a node before it")) };
+
+                Some(parent)
+            },
+
+            None => None,
+        };
+
+        if parent.is_none() != (index == 0) {
+            return Err(format!("the node '{key}' must have a parent unless it's the root, node 0"));
+        }
+
+        strip_defaults(&mut value);
+        nodes.push((index, FlatNode { parent, table: value }));
+    }
+
+
+    nodes.sort_by_key(|(index, _)| *index);
+    if nodes.is_empty() || nodes.iter().enumerate().any(|(i, (index, _))| i != *index) {
+        return Err(String::from("the node indices must count up from 0 without any gaps"));
+    }
+
+    let root = nest(nodes.into_iter().map(|(_, node)| node).collect());
+    Ok(to_text(&root))
+}
+
+
+/// Upgrades every version 1 scene in the project at `project`
+/// to the current version in place and returns how many it did
+pub fn upgrade_scenes(project: &Path) -> io::Result<usize> {
+    info!("upgrading the scenes of '{}'", project.to_string_lossy());
+
+    let mut file_system = FileSystem::new();
+    file_system.mount_directory(project);
+
+    let mut upgraded = 0;
+    let mut failed = 0;
+
+    for path in file_system.files().iter().filter(|x| x.ends_with(".scene")) {
+        let text = file_system.read_to_string(path)?;
+
+        let table = match SceneSource::parse(text) {
+            Ok(SceneSource::V1(table)) => table,
+            Ok(_) => continue,
+            Err(e) => {
+                error!("unable to parse '{path}': {e}");
+                failed += 1;
+                continue;
+            },
+        };
+
+        let text = match upgrade(&table) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("unable to upgrade '{path}': {e}");
+                failed += 1;
+                continue;
+            },
+        };
+
+        let Some(real_path) = file_system.write_path(path)
+        else { continue };
+
+        std::fs::write(real_path, text)?;
+        info!("upgraded '{path}'");
+        upgraded += 1;
+    }
+
+
+    if failed > 0 {
+        return Err(io::Error::other(format!("{failed} scenes couldn't be upgraded")));
+    }
+
+    Ok(upgraded)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn scene_file_line_column() {
+        let text = "version = 2\n\n[root]\nnamé = 1";
+
+        assert_eq!(line_column(text, 0), (1, 1));
+        assert_eq!(line_column(text, 10), (1, 11));
+        assert_eq!(line_column(text, 13), (3, 1));
+        assert_eq!(line_column(text, text.find('=').unwrap()), (1, 9));
+        assert_eq!(line_column(text, text.rfind('=').unwrap()), (4, 6));
+    }
+
+
+    #[test]
+    fn scene_file_upgrade() {
+        let v1 = toml::Table::from_str(r#"
+            [0]
+            name = "World"
+            position = { x = 0.0, y = 0.0 }
+            modulate = { x = 1.0, y = 1.0, z = 1.0, w = 1.0 }
+            scale = { x = 1.0, y = 1.0 }
+            rotation = 0.0
+            components = { World = {} }
+
+            [1]
+            parent = 0
+            position = { x = 2.0, y = 0.0 }
+            modulate = { x = 1.0, y = 1.0, z = 1.0, w = 1.0 }
+            scale = { x = 1.0, y = 1.0 }
+            rotation = 0.0
+            components = { Player = { jump = 7.5 } }
+
+            [2]
+            parent = 0
+            instance = "pipe/pipe.scene"
+            name = "Pipe"
+
+            [3]
+            parent = 1
+            position = { x = 0.0, y = 0.0 }
+            modulate = { x = 1.0, y = 1.0, z = 1.0, w = 1.0 }
+            scale = { x = 1.0, y = 1.0 }
+            rotation = 0.0
+        "#).unwrap();
+
+        let v2 = upgrade(&v1).unwrap();
+        assert_eq!(v2, "version = 2\n\
+                        \n[root]\nname = \"World\"\ncomponents = { World = {} }\n\
+                        \n[root.children.Player]\nposition = { x = 2.0, y = 0.0 }\ncomponents = { Player = { jump = 7.5 } }\n\
+                        \n[root.children.Player.children.Node]\n\
+                        \n[root.children.Pipe]\ninstance = \"pipe/pipe.scene\"\n");
+
+        let Ok(SceneSource::V2 { file, .. }) = SceneSource::parse(v2)
+        else { panic!("the upgraded scene isn't version 2") };
+
+        let root = file.root.get_ref();
+        assert_eq!(root.children.keys().collect::<Vec<_>>(), vec!["Player", "Pipe"]);
+        assert!(root.children["Player"].get_ref().children.contains_key("Node"));
+
+
+        let mut gap = v1.clone();
+        gap.remove("2");
+        assert!(upgrade(&gap).is_err());
+
+        let mut orphan = v1.clone();
+        orphan["3"].as_table_mut().unwrap().remove("parent");
+        assert!(upgrade(&orphan).is_err());
+    }
+
+
+    #[test]
+    fn scene_file_errors() {
+        assert!(matches!(SceneSource::parse(String::from("[0]\nrotation = 0.0")), Ok(SceneSource::V1(_))));
+        assert!(SceneSource::parse(String::from("version = 3\n[root]")).is_err());
+
+        let error = SceneSource::parse(String::from("version = 2\n\n[root]\npositon = { x = 1.0, y = 0.0 }"))
+            .unwrap_err();
+        assert!(error.contains("line"), "{error}");
+        assert!(error.contains("positon"), "{error}");
+    }
+}
@@ -1,54 +1,87 @@
-use std::str::FromStr;
+use std::collections::HashSet;
 
 use sti::keyed::KVec;
 use tracing::{error, info, trace, warn, Level};
 
-use crate::{engine::Engine, math::vector::Vec3, scene_manager::{node::NodeProperties, scene_template::{TemplateComponent, TemplateComponents, TemplateNode, TemplateNodeId, TemplateScene}}, script_manager::{fields::{Field, FieldValue}, ScriptManager}};
+use crate::{deserialize::scene_file::SceneSource, engine::Engine, file_system::FileSystem, math::vector::{Vec2, Vec3, Vec4}, scene_manager::{node::NodeProperties, SceneManager, scene_tree::is_valid_name, scene_template::{TemplateComponent, TemplateComponents, TemplateNode, TemplateNodeId, TemplateScene}}, script_manager::{fields::{Field, FieldValue}, ScriptManager}};
 
 impl TemplateScene {
     /// Loads a file as a 'TemplateScene'
     /// Returns an empty 'TemplateScene' if an error occurs
-    pub fn from_file<A>(engine: &mut Engine, path: A) -> TemplateScene
-    where A: AsRef<std::path::Path> {
-        let path = path.as_ref();
-        let path_str = path.to_string_lossy();
+    pub fn from_file(engine: &mut Engine, path: &str) -> TemplateScene {
+        let Some(source) = Self::read_source(Engine::file_system(), path)
+        else { return TemplateScene::new() };
 
-        let span = tracing::span!(Level::ERROR, "deserialize ", 
-                                  path = &*path_str);
+        let span = tracing::span!(Level::ERROR, "deserialize ", path);
         let _handle = span.entered();
 
-        info!("reading scene '{}'", path_str);
+        TemplateScene::from_source(engine, path, &source)
+    }
 
 
-        let Ok(scene_data) = std::fs::read_to_string(path)
-        else {
-            error!("unable to read");
-            return TemplateScene::new();
-        };
+    /// Reads and parses a scene file without resolving
+    /// any of its scripts or textures.
+    ///
+    /// This doesn't touch the engine so it can be
+    /// called from any thread
+    pub fn read_source(file_system: &FileSystem, path: &str) -> Option<SceneSource> {
+        let span = tracing::span!(Level::ERROR, "deserialize ", path);
+        let _handle = span.entered();
+
+        info!("reading scene '{}'", path);
 
-        let toml_table = match toml::Table::from_str(&scene_data) {
+
+        let scene_data = match file_system.read(path) {
             Ok(v) => v,
             Err(e) => {
-                error!("unable to parse the file as a toml table: {e}");
-                return TemplateScene::new();
+                error!("unable to read: {e}");
+                return None;
+            },
+        };
+
+        match SceneSource::from_bytes(scene_data) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                error!("unable to parse the scene file: \n{e}");
+                None
             }
+        }
+    }
+
+
+    /// Loads a parsed scene file as a 'TemplateScene'
+    /// Returns an empty 'TemplateScene' if an error occurs
+    ///
+    /// `path` is the path of the scene being loaded, it's
+    /// used to catch scenes that end up instancing themselves
+    pub fn from_source(engine: &mut Engine, path: &str, source: &SceneSource) -> TemplateScene {
+        engine.get_mut().scene_manager.loading_scenes.push(path.to_string());
+
+        let scene = match source {
+            SceneSource::V1(table) => {
+                warn!("the scene uses the version 1 format, \
+                      `butter upgrade <project>` converts it");
+                Self::from_table(engine, table)
+            },
+
+            SceneSource::V2 { text, file } => Self::from_scene_file(engine, path, text, file),
+            SceneSource::Binary(scene) => Self::from_binary(engine, scene),
         };
 
-        TemplateScene::from_table(engine, &toml_table)
+        engine.get_mut().scene_manager.loading_scenes.pop();
+        scene
     }
 
 
-    /// Loads a file as a 'TemplateScene'
+    /// Loads a version 1 scene, where nodes are keyed
+    /// by their index, as a 'TemplateScene'
     /// Returns an empty 'TemplateScene' if an error occurs
     pub fn from_table(engine: &mut Engine, table: &toml::Table) -> TemplateScene {
-        let mut scene = TemplateScene::new();
-        let inner_scene = scene.inner_mut();
-        
         // saved scenes must be trimmed down to their bare minimums
         // this provides us an easy way to check out of bounds nodes
         let expected_scene_size = table.len() as u32;
 
-        let mut largest_node_index = 0;
+        let mut entries = Vec::with_capacity(table.len());
         let mut has_errored = false; 
 
 
@@ -65,37 +98,41 @@ impl TemplateScene {
                 },
             };
 
-            largest_node_index = largest_node_index.max(node_index);
-
-            let node = TemplateNode::from_entry(engine, value);
-            let Some(node) = node
+            let entry = SceneEntry::from_entry(engine, value);
+            let Some(entry) = entry
             else {
                 has_errored = true;
                 continue;
             };
 
 
-            if let Some(parent) = node.parent {
-                if parent.inner() >= expected_scene_size {
-                    error!("the parent '{}' is out of bounds", parent.inner());
+            if let Some(parent) = entry.parent() {
+                if parent >= expected_scene_size {
+                    error!("the parent '{parent}' is out of bounds");
                     has_errored = true;
                     continue;
                 }
 
 
-                if parent.inner() as u32 == node_index {
+                if parent == node_index {
                     error!("a node can't be a parent of itself");
                     has_errored = true;
                     continue;
                 }
-            }
 
 
-            if inner_scene.len() > node_index as usize {
-                inner_scene.insert(node_index as usize, node);
-            } else {
-                inner_scene.push(node);
+                // nodes are instantiated in order so the
+                // parent has to exist by the time its
+                // children are created
+                if parent > node_index {
+                    error!("the parent '{parent}' must come before its children");
+                    has_errored = true;
+                    continue;
+                }
             }
+
+
+            entries.push((node_index, entry));
         }
 
 
@@ -112,17 +149,194 @@ impl TemplateScene {
         }
 
 
+        entries.sort_by_key(|(index, _)| *index);
+
         // we do `largest_node_index + 1` as node indexes start from 0
-        if inner_scene.len() != largest_node_index as usize + 1 {
+        let largest_node_index = entries.last().unwrap().0;
+        if entries.len() != largest_node_index as usize + 1 {
             error!("the scene file must be compacted down. the largest index \
                    is '{}' while the item count is '{}'",
-                   largest_node_index + 1, scene.len());
+                   largest_node_index + 1, entries.len());
+            return TemplateScene::new();
+        }
+
+
+        // instances expand into many nodes so the indices in
+        // the file don't match the ones of the template anymore
+        let mut scene = TemplateScene::new();
+        let mut ids : Vec<TemplateNodeId> = Vec::with_capacity(entries.len());
+
+        for (index, entry) in entries {
+            let span = tracing::span!(Level::ERROR, "", node = index);
+            let _handle = span.entered();
+
+            let parent = entry.parent().map(|x| ids[x as usize]);
+
+            let id = match entry {
+                SceneEntry::Node(mut node) => {
+                    node.parent = parent;
+                    let id = TemplateNodeId::new_unck(scene.len() as u32);
+                    scene.inner_mut().push(node);
+                    id
+                },
+
+
+                SceneEntry::Instance { path, table, .. } => {
+                    let Some(id) = scene.expand_instance(engine, parent, path, table)
+                    else { return TemplateScene::new() };
+
+                    id
+                },
+            };
+
+            ids.push(id);
+        }
+
+
+        if !scene.has_unique_names() {
             return TemplateScene::new();
         }
 
 
         scene
     }
+
+
+    /// Whether no two siblings share a name,
+    /// the first duplicate is reported
+    pub(super) fn has_unique_names(&self) -> bool {
+        let mut names = HashSet::new();
+        for (_, node) in self.iter() {
+            let Some(name) = &node.name
+            else { continue };
+
+            if !names.insert((node.parent, name)) {
+                error!("there's more than one node named '{name}' under the same parent");
+                return false;
+            }
+        }
+
+        true
+    }
+
+
+    ///
+    /// Copies the nodes of the scene at `path` to the end of
+    /// this one, under `parent`, and returns the root of the copy.
+    ///
+    /// `table` is the entry that instanced the scene, its
+    /// properties override the ones of the root and its
+    /// `overrides` table overrides the nodes at the paths
+    /// it's keyed by.
+    ///
+    pub(super) fn expand_instance(&mut self, engine: &mut Engine, parent: Option<TemplateNodeId>,
+                       path: &str, table: &toml::Table) -> Option<TemplateNodeId> {
+        info!("instancing '{path}'");
+
+        let loading = engine.get().scene_manager.loading_scenes.clone();
+        if loading.iter().any(|x| x == path) {
+            error!("'{path}' ends up instancing itself: {} -> {path}", loading.join(" -> "));
+            return None;
+        }
+
+        let template = SceneManager::instanced_template(engine, path);
+        let offset = self.len() as u32;
+
+        {
+            let engine = engine.get();
+            let template = &engine.scene_manager.templates[template];
+            if template.len() == 0 {
+                error!("the instanced scene '{path}' is empty or failed to load");
+                return None;
+            }
+
+            for (_, node) in template.iter() {
+                let mut node = node.clone();
+                node.parent = match node.parent {
+                    Some(parent) => Some(TemplateNodeId::new_unck(parent.inner() + offset)),
+                    None => parent,
+                };
+
+                self.inner_mut().push(node);
+            }
+        }
+
+
+        let root = TemplateNodeId::new_unck(offset);
+        if !self.get_mut(root).unwrap().apply_overrides(engine, table) {
+            return None;
+        }
+
+        let Some(overrides) = table.get("overrides")
+        else { return Some(root) };
+
+        let Some(overrides) = overrides.as_table()
+        else {
+            error!("the overrides of the instance of '{path}' aren't a table");
+            return None;
+        };
+
+        for (node_path, value) in overrides.iter() {
+            let Some(node) = self.get_node(root, node_path)
+            else {
+                error!("there's no node at '{node_path}' in '{path}' to override");
+                return None;
+            };
+
+            let Some(value) = value.as_table()
+            else {
+                error!("the overrides of '{node_path}' aren't a table");
+                return None;
+            };
+
+            if !self.get_mut(node).unwrap().apply_overrides(engine, value) {
+                return None;
+            }
+        }
+
+        Some(root)
+    }
+}
+
+
+/// A node in a scene file, either a node of its own
+/// or another scene that's instanced in its place
+enum SceneEntry<'a> {
+    Node(TemplateNode),
+
+    Instance {
+        path: &'a str,
+        parent: Option<u32>,
+        table: &'a toml::Table,
+    },
+}
+
+
+impl<'a> SceneEntry<'a> {
+    fn from_entry(engine: &mut Engine, value: &'a toml::Value) -> Option<Self> {
+        let instance = value.as_table()
+            .and_then(|table| Some((table, table.get("instance")?)));
+
+        let Some((table, path)) = instance
+        else { return TemplateNode::from_entry(engine, value).map(Self::Node) };
+
+        let Some(path) = path.as_str()
+        else {
+            error!("failed to parse the instance: not a path string");
+            return None;
+        };
+
+        Some(Self::Instance { path, parent: read_parent(table)?, table })
+    }
+
+
+    /// The index of the parent in the file
+    fn parent(&self) -> Option<u32> {
+        match self {
+            SceneEntry::Node(node) => node.parent.map(|x| x.inner()),
+            SceneEntry::Instance { parent, .. } => *parent,
+        }
+    }
 }
 
 
@@ -136,29 +350,42 @@ impl TemplateNode {
 
         let properties = NodeProperties::from_table(engine, &table);
 
-        let parent = 'me: {
-            let Some(parent) = table.get("parent")
-            else { break 'me None };
+        let name = 'me: {
+            let Some(name) = table.get("name")
+            else { break 'me Some(None) };
 
-            let Some(node_id) = parent.as_integer()
+            let Some(name) = name.as_str()
             else {
-                error!("failed to parse the parent as \
-                       a node index: not an integer");
+                error!("failed to parse the name: not a string");
                 break 'me None;
             };
 
+            if !is_valid_name(name) {
+                error!("'{name}' isn't a valid name, names can't be \
+                       empty, contain a '/' or be '.' or '..'");
+                break 'me None;
+            }
 
-            let node_id : u32 = match node_id.try_into() {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("failed to parse the parent as a node index: {e}");
-                    break 'me None;
-                },
-            };
+            Some(Some(name.to_string()))
+        };
+
+
+        let groups = 'me: {
+            let Some(groups) = table.get("groups")
+            else { break 'me Some(vec![]) };
+
+            let groups = groups.as_array()
+                .and_then(|x| x.iter().map(|x| x.as_str().map(String::from)).collect::<Option<Vec<_>>>());
 
-            Some(node_id)
+            if groups.is_none() {
+                error!("failed to parse the groups: not an array of strings");
+            }
+
+            groups
         };
 
+        let parent = read_parent(table);
+
 
         let components = 'me: {
             let Some(components) = table.get("components")
@@ -177,8 +404,10 @@ impl TemplateNode {
 
 
         Some(TemplateNode {
+            name: name?,
+            groups: groups?,
             properties: properties?,
-            parent: parent.map(|x| TemplateNodeId::new_unck(x)),
+            parent: parent?.map(|x| TemplateNodeId::new_unck(x)),
             components: components?,
         })
     }
@@ -186,6 +415,167 @@ impl TemplateNode {
 
 
 
+impl TemplateNode {
+    ///
+    /// Overrides whatever `table` has out of the name, groups,
+    /// properties and component fields, the rest is kept.
+    ///
+    /// Components are picked by their script and have
+    /// to already be on the node.
+    ///
+    pub(super) fn apply_overrides(&mut self, engine: &mut Engine, table: &toml::Table) -> bool {
+        if let Some(name) = table.get("name") {
+            let Some(name) = name.as_str().filter(|x| is_valid_name(x))
+            else {
+                error!("failed to read the name, '{name}' isn't a valid name");
+                return false;
+            };
+
+            self.name = Some(name.to_string());
+        }
+
+        if let Some(groups) = table.get("groups") {
+            let groups = groups.as_array()
+                .and_then(|x| x.iter().map(|x| x.as_str().map(String::from)).collect::<Option<Vec<_>>>());
+
+            let Some(groups) = groups
+            else {
+                error!("failed to read the groups: not an array of strings");
+                return false;
+            };
+
+            self.groups = groups;
+        }
+
+
+        let properties = &mut self.properties;
+        if let Some(position) = table.get("position") {
+            let Some(position) = position.as_table().and_then(|x| Vec2::from_table("position", x))
+            else {
+                error!("failed to read 'position'");
+                return false;
+            };
+
+            properties.position = position;
+        }
+
+        if let Some(scale) = table.get("scale") {
+            let Some(scale) = scale.as_table().and_then(|x| Vec2::from_table("scale", x))
+            else {
+                error!("failed to read 'scale'");
+                return false;
+            };
+
+            properties.scale = scale;
+        }
+
+        if let Some(modulate) = table.get("modulate") {
+            let Some(modulate) = modulate.as_table().and_then(|x| Vec4::from_table("modulate", x))
+            else {
+                error!("failed to read 'modulate'");
+                return false;
+            };
+
+            properties.modulate = modulate;
+        }
+
+        if let Some(rotation) = table.get("rotation") {
+            let Some(rotation) = rotation.as_float()
+            else {
+                error!("failed to read 'rotation', it isn't a float");
+                return false;
+            };
+
+            properties.rotation = rotation as f32;
+        }
+
+        if let Some(texture) = table.get("texture") {
+            let Some(texture) = NodeProperties::texture_from_toml(engine, "", texture)
+            else { return false };
+
+            properties.texture = Some(texture);
+        }
+
+
+        let Some(components) = table.get("components")
+        else { return true };
+
+        let Some(components) = components.as_table()
+        else {
+            error!("the components entry exists but it's not a table");
+            return false;
+        };
+
+        for (name, fields) in components.iter() {
+            let span = tracing::span!(Level::ERROR, "", component = name);
+            let _handle = span.entered();
+
+            let Some(fields) = fields.as_table()
+            else {
+                error!("the field value of the component '{name}' isn't a table");
+                return false;
+            };
+
+            let script_id = ScriptManager::from_path(engine, name);
+            let component = self.components.iter_mut()
+                .find(|(_, comp)| comp.script() == script_id);
+
+            let Some((_, component)) = component
+            else {
+                error!("the node doesn't have a '{name}' component to override");
+                return false;
+            };
+
+            let engine = engine.get();
+            let script = engine.script_manager.script(script_id);
+
+            for (field, value) in fields.iter() {
+                let Some(field_id) = script.fields.get(field)
+                else {
+                    warn!("the component '{name}' has no field '{field}'");
+                    continue;
+                };
+
+                let Some(value) = FieldValue::from_toml(value)
+                else {
+                    warn!("'{value}' is an unsupported toml value");
+                    continue;
+                };
+
+                component.fields_mut()[*field_id] = value;
+            }
+        }
+
+        true
+    }
+}
+
+
+/// Reads the index of the parent of a node, `Some(None)`
+/// if it has none and `None` if it's invalid
+fn read_parent(table: &toml::Table) -> Option<Option<u32>> {
+    let Some(parent) = table.get("parent")
+    else { return Some(None) };
+
+    let Some(node_id) = parent.as_integer()
+    else {
+        error!("failed to parse the parent as \
+               a node index: not an integer");
+        return None;
+    };
+
+
+    match node_id.try_into() {
+        Ok(v) => Some(Some(v)),
+        Err(e) => {
+            error!("failed to parse the parent as a node index: {e}");
+            None
+        },
+    }
+}
+
+
+
 impl TemplateComponents {
     pub fn from_table(engine: &mut Engine, table: &toml::Table) -> Option<Self> {
         let mut vec = KVec::with_cap(table.len());
@@ -296,3 +686,122 @@ impl FieldValue {
         Some(Self::new(value))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{test_dir, test_engine};
+
+    use super::*;
+
+
+    fn write(path: &str, contents: &str) {
+        let path = test_dir().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+
+    #[test]
+    fn template_scene_expand_instance() {
+        let (_guard, mut engine) = test_engine();
+        ScriptManager::from_lua(&mut engine, "spinner.lua", b"class_name = \"Spinner\"\nspeed = 1.5");
+
+        write("expand/pipe.scene", r#"
+            version = 2
+
+            [root]
+            name = "Pipe"
+            position = { x = 1.0, y = 0.0 }
+            components = { Spinner = {} }
+
+            [root.children.Top]
+
+            [root.children.Top.children.Cap]
+        "#);
+
+        write("expand/level.scene", r#"
+            version = 2
+
+            [root]
+            name = "Level"
+
+            [root.children.Left]
+            instance = "expand/pipe.scene"
+            position = { x = -2.0, y = 0.0 }
+            components = { Spinner = { speed = 3.0 } }
+            overrides = { "Top/Cap" = { rotation = 1.5 }, Top = { groups = ["tops"] } }
+
+            [root.children.Right]
+            instance = "expand/pipe.scene"
+        "#);
+
+        let level = SceneManager::template_from_file(&mut engine, "expand/level.scene");
+
+        let engine = engine.get();
+        let sm = &engine.scene_manager;
+        let template = &sm.templates[level];
+
+        let nodes = template.iter().map(|(_, node)| (node.name.as_deref().unwrap(), node.parent.map(|x| x.inner())))
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, vec![("Level", None), ("Left", Some(0)), ("Top", Some(1)), ("Cap", Some(2)),
+                               ("Right", Some(0)), ("Top", Some(4)), ("Cap", Some(5))]);
+
+        let node = |index: u32| template.get(TemplateNodeId::new_unck(index)).unwrap();
+        assert_eq!(node(1).properties.position, Vec2::new(-2.0, 0.0));
+        assert_eq!(node(4).properties.position, Vec2::new(1.0, 0.0));
+        assert_eq!(node(3).properties.rotation, 1.5);
+        assert_eq!(node(6).properties.rotation, 0.0);
+        assert_eq!(node(2).groups, vec!["tops"]);
+        assert!(node(5).groups.is_empty());
+
+        let spinner = engine.script_manager.path_to_script["Spinner"];
+        let speed = engine.script_manager.script(spinner).fields["speed"];
+        let speed_of = |index: u32| node(index).components.iter().next().unwrap().1.fields()[speed].value().clone();
+        assert_eq!(speed_of(1), mlua::Value::Number(3.0));
+        assert_eq!(speed_of(4), mlua::Value::Number(1.5));
+
+        // the instanced template itself is left as it was
+        let pipe = &sm.templates[sm.path_to_template["expand/pipe.scene"]];
+        assert_eq!(pipe.len(), 3);
+        assert_eq!(pipe.get(TemplateNodeId::new_unck(0)).unwrap().properties.position, Vec2::new(1.0, 0.0));
+    }
+
+
+    #[test]
+    fn template_scene_instance_cycles() {
+        let (_guard, mut engine) = test_engine();
+
+        write("cycle/self.scene", "version = 2\n\n[root]\n\n[root.children.Again]\ninstance = \"cycle/self.scene\"");
+        write("cycle/a.scene", "version = 2\n\n[root]\n\n[root.children.B]\ninstance = \"cycle/b.scene\"");
+        write("cycle/b.scene", "version = 2\n\n[root]\n\n[root.children.A]\ninstance = \"cycle/a.scene\"");
+
+        let this = SceneManager::template_from_file(&mut engine, "cycle/self.scene");
+        let a = SceneManager::template_from_file(&mut engine, "cycle/a.scene");
+
+        let engine = engine.get();
+        let sm = &engine.scene_manager;
+        assert_eq!(sm.templates[this].len(), 0);
+        assert_eq!(sm.templates[a].len(), 0);
+        assert_eq!(sm.templates[sm.path_to_template["cycle/b.scene"]].len(), 0);
+        assert!(sm.loading_scenes.is_empty());
+    }
+
+
+    #[test]
+    fn template_scene_failed_instance() {
+        let (_guard, mut engine) = test_engine();
+
+        write("failed/prefab.scene", "this isn't toml");
+        write("failed/level.scene", "version = 2\n\n[root]\n\n[root.children.Prefab]\ninstance = \"failed/prefab.scene\"");
+
+        let level = SceneManager::template_from_file(&mut engine, "failed/level.scene");
+        assert_eq!(engine.get().scene_manager.templates[level].len(), 0);
+
+        // the failed template is cached, it isn't
+        // read again every time it's instanced
+        write("failed/prefab.scene", "version = 2\n\n[root]");
+        let prefab = SceneManager::instanced_template(&mut engine, "failed/prefab.scene");
+        assert_eq!(engine.get().scene_manager.templates[prefab].len(), 0);
+    }
+}
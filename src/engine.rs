@@ -4,7 +4,7 @@ use mlua::{Compiler, Function};
 use sokol::{debugtext as sdtx, app as sapp, time as stime};
 use tracing::{error, info, trace, Level};
 
-use crate::{asset_manager::AssetManager, event_manager::{EventManager, Keycode}, input_manager::InputManager, lua::{self}, math::vector::{Colour, Vec2, Vec3, Vec4}, physics::PhysicsServer, renderer::Renderer, scene_manager::{node::NodeProperties, scene_template::TemplateScene, scene_tree::SceneTree, SceneManager}, script_manager::ScriptManager, settings::ProjectSettings, Camera};
+use crate::{asset_manager::AssetManager, audio_manager::AudioManager, async_loader::AsyncLoader, event_manager::{EventManager, Keycode}, file_system::FileSystem, input_manager::InputManager, lua::{self, node::NodeUserData}, math::vector::{Colour, Vec2, Vec3, Vec4}, physics::PhysicsServer, renderer::Renderer, scene_manager::{scene_template::TemplateScene, scene_tree::SceneTree, SceneManager}, script_manager::ScriptManager, settings::ProjectSettings, Camera};
 
 
 static mut ENGINE : *const EngineStatic = null();
@@ -13,6 +13,7 @@ static mut ENGINE : *const EngineStatic = null();
 pub struct EngineStatic {
     engine: RefCell<ManagerManager>,
     project_settings: ProjectSettings,
+    file_system: FileSystem,
     lua: mlua::Lua,
 }
 
@@ -26,7 +27,9 @@ pub struct ManagerManager {
     pub input_manager: InputManager,
     pub script_manager: ScriptManager,
     pub asset_manager: AssetManager,
+    pub audio_manager: AudioManager,
     pub scene_manager: SceneManager,
+    pub async_loader: AsyncLoader,
 
     pub renderer: Renderer,
 
@@ -60,7 +63,7 @@ pub struct Timers {
 
 
 impl Engine {
-    pub fn new(project_settings: ProjectSettings) {
+    pub fn new(project_settings: ProjectSettings, file_system: FileSystem) {
         info!("creating engine");
         if !unsafe { ENGINE.is_null() } { 
             error!("there already is an engine instance");
@@ -72,7 +75,9 @@ impl Engine {
             script_manager: ScriptManager::new(),
             input_manager: InputManager::new(),
             asset_manager: AssetManager::new(),
+            audio_manager: AudioManager::new(&project_settings.audio),
             scene_manager: SceneManager::new(project_settings.world.gravity),
+            async_loader: AsyncLoader::new(),
             renderer: Renderer::new(&project_settings),
             camera: Camera::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 25.0),
 
@@ -87,6 +92,7 @@ impl Engine {
             engine: slf.into(),
             lua: mlua::Lua::new(),
             project_settings,
+            file_system,
         };
 
         slf.lua.globals().set("not_set", 0).unwrap();
@@ -108,19 +114,72 @@ impl Engine {
     }
 
 
+    pub fn file_system() -> &'static FileSystem {
+        assert!(unsafe { !ENGINE.is_null() });
+        unsafe { &(*ENGINE).file_system }
+    }
+
+
     pub fn lua() -> &'static mlua::Lua {
         assert!(unsafe { !ENGINE.is_null() });
         unsafe { &(*ENGINE).lua }
     }
 
 
-    pub fn change_scene(engine: &mut Engine, scene: &str) {
+    /// Swaps the current scene for `scene` right away, if
+    /// `keep_persistent` is set the persistent nodes of the
+    /// old scene move to the new root. If `scene` fails to
+    /// load the old scene is kept.
+    ///
+    /// Scripts go through `SceneManager::queue_change` instead
+    /// so the old scene isn't freed in the middle of a frame
+    pub fn change_scene(engine: &mut Engine, scene: &str, keep_persistent: bool) {
         let template_id = SceneManager::template_from_file(engine, scene);
 
         let Some(node) = TemplateScene::instantiate(engine, template_id)
-        else { return };
+        else {
+            error!("unable to change the scene to '{scene}', keeping the current one");
+            return
+        };
 
+        let persistent = engine.with(|engine| {
+            engine.scene_manager.current_scene = Some(scene.to_string());
+
+            let tree = &engine.scene_manager.tree;
+            match tree.root() {
+                Some(root) if keep_persistent => tree.persistent_nodes(root),
+                _ => vec![],
+            }
+        });
+
+        // to their scripts the persistent nodes never left
+        // the tree, they don't exit the old scene or enter
+        // the new one
+        engine.with(|engine| {
+            for persistent in persistent.iter() {
+                engine.scene_manager.tree.set_parent(*persistent, Some(node));
+            }
+        });
+
+        // the music belongs to the audio manager
+        // so it keeps playing into the new scene
         SceneTree::set_root(engine, node);
+        SceneManager::enter_tree_except(engine, node, &persistent);
+
+        // free whatever the previous scene used
+        // that the new one doesn't
+        engine.get_mut().scene_manager.evict_templates();
+        AssetManager::collect_garbage(engine);
+    }
+
+
+    /// Called once the window closes, before sokol shuts down
+    pub fn shutdown(engine: &mut Engine) {
+        info!("shutting down engine");
+        engine.with(|engine| {
+            engine.audio_manager.shutdown();
+            engine.asset_manager.shutdown();
+        });
     }
 
 
@@ -135,17 +194,18 @@ impl Engine {
 
         engine.with(|engine| {
             engine.asset_manager.init();
+            engine.audio_manager.init();
             engine.scene_manager.physics.init();
             let fps = Engine::project_settings()
                 .world.physics_framerate;
             engine.scene_manager.physics.set_framerate(fps);
         });
 
-        ScriptManager::load_current_dir(engine);
+        ScriptManager::load_all(engine);
 
         SceneManager::init_templates(engine);
 
-        Engine::change_scene(engine, &Engine::project_settings().world.entry_scene);
+        Engine::change_scene(engine, &Engine::project_settings().world.entry_scene, true);
     }
 
 
@@ -176,44 +236,19 @@ impl Engine {
             engine.timers.io_event_time = timer.elapsed();
         });
 
-        let nodes = engine.with(|engine| {
-            engine.scene_manager.tree.iter_vec_root()
-        });
+
+        AsyncLoader::poll(engine);
+
+        if Engine::project_settings().engine.hot_reload {
+            ScriptManager::hot_reload(engine);
+            SceneManager::hot_reload(engine);
+        }
 
         {
             trace!("update all nodes");
 
             let timer = Instant::now();
-            
-            for node in nodes.iter().copied() {
-                let comps = {
-                    let mut engine = engine.get_mut();
-                    let node = engine.scene_manager.tree.get_mut(node);
-                    node.components.iter()
-                };
-                
-                for comp in comps {
-                    let (functions, userdata, path) = {
-                        let mut engine = engine.get_mut();
-                        let node = engine.scene_manager.tree.get_mut(node);
-                        let userdata = node.userdata_of(comp).clone();
-
-                        let component = node.components.get(comp);
-                        let script = component.script;
-                        let script = engine.script_manager.script(script);
-
-                        (
-                            script.functions.clone(),
-                            userdata,
-                            script.path(),
-                        )
-                    };
-
-
-                    functions.update(path, userdata);
-                }
-
-            }
+            Self::update_nodes(engine);
 
             trace!("updated");
             engine.with(|engine|
@@ -236,18 +271,12 @@ impl Engine {
         }
 
 
-        {
-            engine.with(|engine| {
-                trace!("actually freeing nodes that were queue freed");
-                for handle in nodes.iter().copied() {
-                    let node = engine.scene_manager.tree.map.get(handle.0).unwrap();
-                    if node.queued_free {
-                        engine.scene_manager.tree.map.remove(handle.0).unwrap();
-                    }
-                }
-                trace!("finished actually freeing nodes that were queue freed");
-            });
-        }
+        SceneManager::process_change(engine);
+
+
+        trace!("actually freeing nodes that were queue freed");
+        SceneTree::free_queued(engine);
+        trace!("finished actually freeing nodes that were queue freed");
 
 
         engine.with(|engine| {
@@ -266,6 +295,46 @@ impl Engine {
     }
 
 
+    /// Calls `_update` on every component in the tree
+    fn update_nodes(engine: &mut Engine) {
+        let nodes = engine.with(|engine| {
+            engine.scene_manager.tree.iter_vec_root()
+        });
+
+        for node in nodes.iter().copied() {
+            let comps = engine.with(|engine| {
+                engine.scene_manager.tree.get_mut(node).components_userdata()
+            });
+
+            for userdata in comps {
+                // a script earlier in the pass could've removed
+                // it, the userdata follows the components that
+                // moved down to fill the gap
+                let Ok(NodeUserData(_, comp)) = userdata.borrow::<NodeUserData>()
+                    .map(|this| *this)
+                    .and_then(NodeUserData::alive)
+                else { continue };
+
+                let (functions, path) = {
+                    let engine = engine.get();
+                    let node = engine.scene_manager.tree.get(node);
+
+                    let script = node.components.get(comp).script;
+                    let script = engine.script_manager.script(script);
+
+                    (
+                        script.functions.clone(),
+                        script.path(),
+                    )
+                };
+
+
+                functions.update(path, userdata);
+            }
+        }
+    }
+
+
     pub fn render(engine: &mut Engine) {
         let span = tracing::span!(Level::TRACE, "render");
         let _handle = span.entered();
@@ -291,7 +360,9 @@ impl Engine {
             let timer = Instant::now();
 
             let mut stack = vec![];
-            let mut property_stack = vec![(1, NodeProperties::identity())];
+            // modulate still multiplies down the tree, the
+            // transforms come from the tree's cache
+            let mut modulate_stack = vec![(1, Colour::new(1.0, 1.0, 1.0, 1.0))];
 
             engine.with(|engine|
                 if let Some(root) = engine.scene_manager.tree.root() {
@@ -307,15 +378,17 @@ impl Engine {
                     let mut engine = engine.get_mut();
                     let engine = &mut *engine;
 
+                    let transform = engine.scene_manager.tree.global_transform(node);
                     let node = engine.scene_manager.tree.get(node);
-                    let parent_properties = {
-                        let props = property_stack.last_mut().unwrap();
-                        props.0 -= 1;
-                        if props.0 == 0 { property_stack.pop().unwrap().1 }
-                        else { props.1 }
+                    let parent_modulate = {
+                        let modulate = modulate_stack.last_mut().unwrap();
+                        modulate.0 -= 1;
+                        if modulate.0 == 0 { modulate_stack.pop().unwrap().1 }
+                        else { modulate.1 }
                     };
 
-                    let properties = node.properties.merge(parent_properties);
+                    let properties = &node.properties;
+                    let modulate = properties.modulate * parent_modulate;
 
                     // add children to the render queue
                     if node.children.len() != 0 {
@@ -323,14 +396,12 @@ impl Engine {
                                node.children.len());
 
                         stack.extend_from_slice(&node.children);
-                        property_stack.push((node.children.len(), properties));
+                        modulate_stack.push((node.children.len(), modulate));
                     }
 
                     let model = engine.renderer.draw_quad()
-                        .position(properties.position)
-                        .scale(properties.scale)
-                        .rotation(properties.rotation)
-                        .modulate(properties.modulate);
+                        .transform(transform)
+                        .modulate(modulate);
 
                     let mvp = if let Some(texture) = properties.texture {
                         let model = model.texture(texture);
@@ -354,25 +425,26 @@ impl Engine {
                 lua::draw::Draw::register();
 
 
-                let comps = {
-                    let mut engine = engine.get_mut();
-                    let node = engine.scene_manager.tree.get_mut(node);
-                    node.components.iter()
-                };
+                let comps = engine.with(|engine| {
+                    engine.scene_manager.tree.get_mut(node).components_userdata()
+                });
 
-                for comp in comps {
-                    let (functions, userdata, path) = {
-                        let mut engine = engine.get_mut();
-                        let node = engine.scene_manager.tree.get_mut(node);
-                        let userdata = node.userdata_of(comp);
+                for userdata in comps {
+                    // same as in `update_nodes`
+                    let Ok(NodeUserData(_, comp)) = userdata.borrow::<NodeUserData>()
+                        .map(|this| *this)
+                        .and_then(NodeUserData::alive)
+                    else { continue };
 
-                        let component = node.components.get(comp);
-                        let script = component.script;
+                    let (functions, path) = {
+                        let engine = engine.get();
+                        let node = engine.scene_manager.tree.get(node);
+
+                        let script = node.components.get(comp).script;
                         let script = engine.script_manager.script(script);
 
                         (
                             script.functions.clone(),
-                            userdata,
                             script.path(),
                         )
                     };
@@ -415,6 +487,13 @@ impl Engine {
             }
         });
 
+        // cover the screen while the scene changes
+        engine.with(|engine| {
+            if let Some(change) = &engine.scene_manager.queue_change {
+                change.draw(&mut engine.renderer, &engine.asset_manager);
+            }
+        });
+
         engine.with(|engine|
                      engine.timers.frame_render_time = timer.elapsed());
 
@@ -476,6 +555,8 @@ impl Engine {
         sdtx::crlf();
         sdtx::puts(&format!("COLLIDER COUNT: {}", engine.scene_manager.physics.collider_set.len()));
         sdtx::crlf();
+        sdtx::puts(&format!("TEXTURE MEMORY: {} KB", engine.asset_manager.texture_memory() / 1024));
+        sdtx::crlf();
 
         engine.renderer.end_frame();
     }
@@ -506,3 +587,194 @@ impl Engine {
     }
 }
 
+
+
+///
+/// The engine the tests share, it's created by the first
+/// test that needs it with `test_dir` mounted and the
+/// script, asset and scene managers and the async loader
+/// are reset every call, the scene manager is initialized
+/// right away.
+///
+/// The engine is global so these tests take turns, the
+/// returned guard has to be held for the whole test.
+///
+#[cfg(test)]
+pub(crate) fn test_engine() -> (std::sync::MutexGuard<'static, ()>, Engine) {
+    static LOCK : std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    if unsafe { ENGINE.is_null() } {
+        std::fs::create_dir_all(test_dir()).unwrap();
+        let mut file_system = FileSystem::new();
+        file_system.mount_directory(test_dir());
+
+        let settings = format!("[engine]\nversion = \"{}\"\n\n[window]\n\n[world]\nentry_scene = \"main.scn\"",
+                               crate::settings::engine_version::EngineVersion::CURRENT);
+        Engine::new(ProjectSettings::new(&settings).unwrap(), file_system);
+        lua::setup_lua_environment(Engine::lua());
+    }
+
+    let mut engine = Engine::generate();
+    engine.with(|engine| {
+        engine.script_manager = ScriptManager::new();
+        engine.asset_manager = AssetManager::new();
+        engine.scene_manager = SceneManager::new(Engine::project_settings().world.gravity);
+        engine.async_loader = AsyncLoader::new();
+    });
+
+    SceneManager::init_templates(&mut engine);
+    (guard, engine)
+}
+
+
+/// The directory the test engine reads its files from
+#[cfg(test)]
+pub(crate) fn test_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("butter-engine-{}", std::process::id()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sti::keyed::KVec;
+
+    use crate::scene_manager::{node::NodeProperties, NodeId};
+
+    use super::*;
+
+
+    #[test]
+    fn engine_change_scene_keeps_persistent_nodes() {
+        let (_guard, mut engine) = test_engine();
+        ScriptManager::from_lua(&mut engine, "tracker.lua", b"
+            class_name = \"Tracker\"
+            entered = 0
+            exited = 0
+            function _enter_tree(self) self.entered = self.entered + 1 end
+            function _exit_tree(self) self.exited = self.exited + 1 end
+        ");
+
+        std::fs::create_dir_all(test_dir().join("change")).unwrap();
+        std::fs::write(test_dir().join("change/first.scene"), "version = 2\n\n[root]\nname = \"First\"").unwrap();
+        std::fs::write(test_dir().join("change/second.scene"), "version = 2\n\n[root]\nname = \"Second\"").unwrap();
+
+        Engine::change_scene(&mut engine, "change/first.scene", true);
+
+        let (first, player) = engine.with(|engine| {
+            let sm = &mut engine.scene_manager;
+            let tracker = engine.script_manager.path_to_script["Tracker"];
+            let mut fields = KVec::new();
+            for (_, field) in engine.script_manager.script(tracker).default_fields.iter() {
+                fields.push(field.value.clone());
+            }
+
+            let player = sm.tree.create(NodeProperties { position: Vec2::new(3.0, 4.0), ..NodeProperties::identity() });
+            sm.tree.get_mut(player).components.push(tracker, fields);
+            sm.tree.get_mut(player).persistent = true;
+            (sm.tree.root().unwrap(), player)
+        });
+
+        SceneManager::add_child(&mut engine, first, player).unwrap();
+        Engine::change_scene(&mut engine, "change/second.scene", true);
+        SceneTree::free_queued(&mut engine);
+
+        let engine = engine.get();
+        let tree = &engine.scene_manager.tree;
+        let second = tree.root().unwrap();
+        assert!(!tree.exists(first));
+        assert_eq!(tree.get(second).name.as_deref(), Some("Second"));
+        assert_eq!(tree.get(player).parent, Some(second));
+        assert_eq!(tree.get(player).properties.position, Vec2::new(3.0, 4.0));
+
+        // it entered the first scene and never left
+        let tracker = engine.script_manager.path_to_script["Tracker"];
+        let script = engine.script_manager.script(tracker);
+        let component = tree.get(player).components.get_index(0);
+        assert_eq!(component.fields[script.fields["entered"]].value().as_f64(), Some(1.0));
+        assert_eq!(component.fields[script.fields["exited"]].value().as_f64(), Some(0.0));
+    }
+
+
+    #[test]
+    fn engine_change_scene_fails() {
+        let (_guard, mut engine) = test_engine();
+        std::fs::create_dir_all(test_dir().join("change")).unwrap();
+        std::fs::write(test_dir().join("change/kept.scene"), "version = 2\n\n[root]\nname = \"Kept\"").unwrap();
+
+        Engine::change_scene(&mut engine, "change/kept.scene", true);
+        let root = engine.get().scene_manager.tree.root().unwrap();
+
+        Engine::change_scene(&mut engine, "change/missing.scene", true);
+        SceneTree::free_queued(&mut engine);
+
+        let engine = engine.get();
+        let sm = &engine.scene_manager;
+        assert_eq!(sm.tree.root(), Some(root));
+        assert!(sm.tree.is_inside_tree(root));
+        assert_eq!(sm.current_scene.as_deref(), Some("change/kept.scene"));
+    }
+
+    #[test]
+    fn engine_update_removed_components() {
+        let (_guard, mut engine) = test_engine();
+        ScriptManager::from_lua(&mut engine, "remove_self.lua", b"
+            class_name = \"RemoveSelf\"
+            function _update(self) self:remove_component(self) end
+        ");
+        ScriptManager::from_lua(&mut engine, "remove_counter.lua", b"
+            class_name = \"RemoveCounter\"
+            function _update(self) self:remove_component(\"Counter\") end
+        ");
+        ScriptManager::from_lua(&mut engine, "counter.lua", b"
+            class_name = \"Counter\"
+            updates = 0
+            function _update(self) self.updates = self.updates + 1 end
+        ");
+
+        let (first, second) = engine.with(|engine| {
+            let root = engine.scene_manager.tree.create(NodeProperties::identity());
+            let first = engine.scene_manager.tree.create(NodeProperties::identity());
+            let second = engine.scene_manager.tree.create(NodeProperties::identity());
+            engine.scene_manager.tree.set_parent(first, Some(root));
+            engine.scene_manager.tree.set_parent(second, Some(root));
+
+            let mut component = |node: NodeId, class: &str| {
+                let script = engine.script_manager.path_to_script[class];
+                let mut fields = KVec::new();
+                for (_, field) in engine.script_manager.script(script).default_fields.iter() {
+                    fields.push(field.value.clone());
+                }
+
+                engine.scene_manager.tree.get_mut(node).components.push(script, fields);
+            };
+
+            component(first, "RemoveSelf");
+            component(first, "Counter");
+            component(second, "RemoveCounter");
+            component(second, "Counter");
+            (first, second)
+        });
+
+        let root = engine.get().scene_manager.tree.get(first).parent.unwrap();
+        SceneTree::set_root(&mut engine, root);
+
+        Engine::update_nodes(&mut engine);
+
+        let engine = engine.get();
+        let tree = &engine.scene_manager.tree;
+        let counter = engine.script_manager.path_to_script["Counter"];
+        let updates = engine.script_manager.script(counter).fields["updates"];
+
+        // the counter moved into the removed slot and still updated
+        let first = &tree.get(first).components;
+        assert_eq!(first.len(), 1);
+        assert_eq!(first.get_index(0).script, counter);
+        assert_eq!(first.get_index(0).fields[updates].value().as_f64(), Some(1.0));
+
+        // the counter was removed before its turn
+        let second = &tree.get(second).components;
+        assert_eq!(second.len(), 1);
+        assert_ne!(second.get_index(0).script, counter);
+    }
+}
@@ -0,0 +1,372 @@
+use std::{collections::{BTreeSet, HashMap, HashSet}, io, path::Path, str::FromStr};
+
+use mlua::Compiler;
+use tracing::{error, info, trace, warn};
+
+use crate::{asset_manager::{atlas::Atlas, import::ImportSettings, split_frame}, deserialize::scene_binary::BinaryScene, file_system::{archive::ArchiveWriter, normalize, FileSystem}, script_manager::BUILTIN_SCRIPTS, settings::ProjectSettings, PROJECT_SETTINGS_FILE};
+
+
+/// The name of the archive an exported game is packed into,
+/// the runtime mounts it if no project is given to it
+pub const GAME_ARCHIVE : &str = "game.pack";
+
+
+/// The extensions of the files that scenes and
+/// scripts can reference
+const ASSET_EXTENSIONS : &[&str] = &["png", "jpg", "jpeg", "bmp", "tga", "gif",
+                                      "scene", "lua", "wav", "ogg", "toml"];
+
+
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    /// The amount of files packed into the archive
+    pub packed: usize,
+    /// `(referenced by, path)` pairs of files that are
+    /// referenced but don't exist
+    pub missing: Vec<(String, String)>,
+    /// Files in the project that nothing references
+    pub unused: Vec<String>,
+}
+
+
+///
+/// Builds a distributable version of the project at `project`
+/// into the `out` directory.
+///
+/// Every asset that is reachable from the entry scene and the
+/// scripts is packed into a single archive next to a copy of
+/// the runtime. Scripts are precompiled to Luau bytecode,
+/// scenes are flattened into binary scenes and the folders
+/// listed in `assets.atlases` are packed into texture atlases.
+///
+pub fn export(project: &Path, out: &Path) -> io::Result<ExportReport> {
+    info!("exporting '{}' to '{}'", project.to_string_lossy(), out.to_string_lossy());
+
+    let mut file_system = FileSystem::new();
+    file_system.mount_directory(project);
+
+    let settings = file_system.read_to_string(PROJECT_SETTINGS_FILE)?;
+    let settings = match ProjectSettings::new(&settings) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("corrupt '{PROJECT_SETTINGS_FILE}': {e}");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+        },
+    };
+
+
+    let files = file_system.files();
+    let scripts = files.iter()
+        .filter(|x| x.ends_with(".lua"))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut class_names = scripts.iter()
+        .filter_map(|path| {
+            let source = file_system.read_to_string(path).ok()?;
+            Some((class_name(&source)?, path.clone()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    // builtin scripts are a part of the runtime
+    for (path, source) in BUILTIN_SCRIPTS {
+        if let Some(name) = class_name(source) {
+            class_names.entry(name).or_insert_with(|| path.to_string());
+        }
+    }
+
+
+    // every script is loaded at startup so they're all
+    // used, the rest is found by following references
+    let mut report = ExportReport::default();
+    let mut used = BTreeSet::new();
+    let mut stack = vec![(String::from(PROJECT_SETTINGS_FILE), normalize(&settings.world.entry_scene))];
+    stack.extend(scripts.iter().map(|x| (String::from("<startup>"), x.clone())));
+
+    while let Some((referenced_by, path)) = stack.pop() {
+        if used.contains(&path) { continue }
+
+        let Ok(data) = file_system.read(&path)
+        else {
+            report.missing.push((referenced_by, path));
+            continue;
+        };
+
+        trace!("found '{path}'");
+
+        let references = if path.ends_with(".scene") {
+            scene_references(&path, &data, &class_names, &mut report)
+        } else if path.ends_with(".lua") {
+            script_references(&data)
+        } else {
+            vec![]
+        };
+
+        stack.extend(references.into_iter().map(|x| (path.clone(), x)));
+
+        // the import settings are needed to load the asset the same way
+        if !path.ends_with(".toml") {
+            let import_files = ImportSettings::files(&path).into_iter()
+                .filter(|x| file_system.exists(x));
+            stack.extend(import_files.map(|x| (path.clone(), x)));
+        }
+
+        used.insert(path);
+    }
+
+
+    // packed images only ship as a part of their atlas
+    let mut packed = HashSet::new();
+    let mut atlas_files = vec![];
+    for folder in settings.assets.atlases.iter() {
+        let atlas = Atlas::build(&file_system, folder)?;
+        packed.extend(atlas.manifest.regions.keys().cloned());
+        atlas_files.extend(atlas.files()?);
+    }
+
+
+    let mut archive = ArchiveWriter::new();
+    archive.add(PROJECT_SETTINGS_FILE, file_system.read(PROJECT_SETTINGS_FILE)?);
+
+    let compiler = Compiler::new().set_debug_level(2);
+    for path in used.iter() {
+        if packed.contains(path) { continue }
+
+        let data = file_system.read(path)?;
+
+        if path.ends_with(".scene") {
+            match BinaryScene::compile(&file_system, path, &class_names) {
+                Ok(scene) => archive.add(path, scene.encode()),
+                Err(e) => {
+                    // the text version reports where the error is
+                    error!("unable to compile '{path}': {e}");
+                    archive.add(path, data);
+                },
+            }
+
+            continue;
+        }
+
+        if !path.ends_with(".lua") {
+            archive.add(path, data);
+            continue;
+        }
+
+        match compiler.compile(&data) {
+            Ok(bytecode) => archive.add(path, bytecode),
+            Err(e) => {
+                // ship the source so the error shows up
+                // in the same way it would in development
+                error!("unable to compile '{path}': {e}");
+                archive.add(path, data);
+            },
+        }
+    }
+
+
+    for (path, data) in atlas_files.iter() {
+        archive.add(path, data.clone());
+    }
+
+
+    for file in files.iter() {
+        if used.contains(file) || file == PROJECT_SETTINGS_FILE { continue }
+
+        let is_generated = atlas_files.iter().any(|(path, _)| path == file);
+        if is_generated { continue }
+
+        let is_hidden = file.split('/').any(|x| x.starts_with('.'));
+        if is_hidden { continue }
+
+        report.unused.push(file.clone());
+    }
+
+    report.packed = archive.len();
+
+
+    std::fs::create_dir_all(out)?;
+    archive.write(&out.join(GAME_ARCHIVE))?;
+
+    let runtime = std::env::current_exe()?;
+    let runtime_name = format!("{}{}", settings.window.title.replace(['/', '\\'], "_"),
+                               std::env::consts::EXE_SUFFIX);
+    std::fs::copy(runtime, out.join(runtime_name))?;
+
+
+    for (referenced_by, path) in report.missing.iter() {
+        warn!("missing '{path}', referenced by '{referenced_by}'");
+    }
+
+    for path in report.unused.iter() {
+        warn!("unused '{path}'");
+    }
+
+    info!("exported {} files, {} missing, {} unused",
+          report.packed, report.missing.len(), report.unused.len());
+
+    Ok(report)
+}
+
+
+/// Finds the assets a scene references and reports
+/// components that don't have a script
+fn scene_references(path: &str,
+                    data: &[u8],
+                    class_names: &HashMap<String, String>,
+                    report: &mut ExportReport) -> Vec<String> {
+    let Ok(data) = std::str::from_utf8(data)
+    else { return vec![] };
+
+    let table = match toml::Table::from_str(data) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("unable to parse '{path}': {e}");
+            return vec![];
+        },
+    };
+
+
+    let mut references = vec![];
+    walk_scene_value(path, &toml::Value::Table(table), class_names, report, &mut references);
+    references
+}
+
+
+fn walk_scene_value(path: &str,
+                    value: &toml::Value,
+                    class_names: &HashMap<String, String>,
+                    report: &mut ExportReport,
+                    references: &mut Vec<String>) {
+    match value {
+        toml::Value::String(string) => {
+            let string = string.split_once(':')
+                .filter(|(ty, _)| matches!(*ty, "image" | "script"))
+                .map(|(_, path)| path)
+                .unwrap_or(string);
+
+            // frames are a part of the image they're cut from
+            let (string, _) = split_frame(string);
+            if is_asset_path(string) {
+                references.push(normalize(string));
+            }
+        },
+
+
+        toml::Value::Array(vec) => {
+            for value in vec.iter() {
+                walk_scene_value(path, value, class_names, report, references);
+            }
+        },
+
+
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter() {
+                if key == "components" {
+                    let components = value.as_table().into_iter().flat_map(|x| x.keys());
+                    for component in components {
+                        if class_names.contains_key(component) { continue }
+
+                        report.missing.push((path.to_string(), format!("component '{component}'")));
+                    }
+                }
+
+                walk_scene_value(path, value, class_names, report, references);
+            }
+        },
+
+
+        _ => (),
+    }
+}
+
+
+/// Finds the assets a script references by looking
+/// at the string literals in it
+fn script_references(data: &[u8]) -> Vec<String> {
+    let Ok(data) = std::str::from_utf8(data)
+    else { return vec![] };
+
+    let mut references = vec![];
+    let mut chars = data.char_indices();
+
+    while let Some((start, c)) = chars.next() {
+        if c != '"' && c != '\'' { continue }
+
+        let mut end = None;
+        while let Some((i, next)) = chars.next() {
+            if next == '\\' { chars.next(); continue }
+            if next == '\n' { break }
+            if next == c { end = Some(i); break }
+        }
+
+        let Some(end) = end
+        else { continue };
+
+        let string = &data[start + 1..end];
+        let (string, _) = split_frame(string);
+        if is_asset_path(string) {
+            references.push(normalize(string));
+        }
+    }
+
+    references
+}
+
+
+fn is_asset_path(string: &str) -> bool {
+    let Some((_, ext)) = string.rsplit_once('.')
+    else { return false };
+
+    !string.contains(char::is_whitespace)
+        && ASSET_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+}
+
+
+/// Reads the class name of a script without executing it
+fn class_name(source: &str) -> Option<String> {
+    for line in source.lines() {
+        let Some(rest) = line.trim().strip_prefix("class_name")
+        else { continue };
+
+        let Some(rest) = rest.trim_start().strip_prefix('=')
+        else { continue };
+
+        let rest = rest.trim();
+        let quote = rest.chars().next()?;
+        if quote != '"' && quote != '\'' { return None }
+
+        let rest = &rest[1..];
+        let end = rest.find(quote)?;
+        return Some(rest[..end].to_string());
+    }
+
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn export_script_references() {
+        let script = r#"
+            local upflap = Texture.from_rgbaf32("character/upflap.png")
+            local pipe = SceneManager.load('./pipe/pipe.scene')
+            print("not a path")
+            print("hello.world with spaces.png")
+        "#;
+
+        let references = script_references(script.as_bytes());
+        assert_eq!(references, vec!["character/upflap.png", "pipe/pipe.scene"]);
+    }
+
+
+    #[test]
+    fn export_class_name() {
+        assert_eq!(class_name("class_name = \"Player\"\nspeed = 1"), Some("Player".to_string()));
+        assert_eq!(class_name("speed = 1\nclass_name='Pipe'"), Some("Pipe".to_string()));
+        assert_eq!(class_name("speed = 1"), None);
+    }
+}
@@ -0,0 +1,330 @@
+pub mod archive;
+
+use std::{collections::BTreeSet, fs::File, io::{self, Read, Seek, SeekFrom}, path::{Path, PathBuf}, time::SystemTime};
+
+use archive::Archive;
+use tracing::{error, info, trace};
+
+
+///
+/// A read-only virtual file system that every loader goes
+/// through.
+///
+/// Paths are relative, '/' separated and are looked up in
+/// the mounted directories and archives, the most recently
+/// mounted one first. This lets a shipped game be a single
+/// archive while development happens in a loose folder.
+///
+#[derive(Debug)]
+pub struct FileSystem {
+    mounts: Vec<Mount>,
+}
+
+
+///
+/// A file opened for reading a piece at a time, either a
+/// whole file of a directory or one entry of an archive
+///
+#[derive(Debug)]
+pub struct FileReader {
+    file: File,
+    /// Where the file starts in `file`
+    start: u64,
+    len: u64,
+    position: u64,
+}
+
+
+#[derive(Debug)]
+enum Mount {
+    Directory(PathBuf),
+    Archive(Archive),
+}
+
+
+impl FileSystem {
+    pub fn new() -> Self {
+        Self { mounts: vec![] }
+    }
+
+
+    /// Mounts either a directory or an archive
+    /// depending on what `path` points to
+    pub fn mount(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            self.mount_directory(path);
+            return Ok(());
+        }
+
+        self.mount_archive(path)
+    }
+
+
+    pub fn mount_directory(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        info!("mounting directory '{}'", path.to_string_lossy());
+        self.mounts.push(Mount::Directory(path.to_path_buf()));
+    }
+
+
+    pub fn mount_archive(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        info!("mounting archive '{}'", path.to_string_lossy());
+
+        let archive = match Archive::open(path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("unable to mount '{}': {e}", path.to_string_lossy());
+                return Err(e);
+            },
+        };
+
+        info!("- {} files", archive.len());
+        self.mounts.push(Mount::Archive(archive));
+        Ok(())
+    }
+
+
+    pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let path = normalize(path);
+        trace!("reading '{path}'");
+
+        for mount in self.mounts.iter().rev() {
+            match mount {
+                Mount::Directory(dir) => {
+                    let full_path = dir.join(&path);
+                    if full_path.is_file() {
+                        return std::fs::read(full_path);
+                    }
+                },
+
+
+                Mount::Archive(archive) => {
+                    if archive.contains(&path) {
+                        return archive.read(&path);
+                    }
+                },
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::NotFound,
+                           format!("'{path}' doesn't exist in any of the mounts")))
+    }
+
+
+    /// Opens a file without reading any of it, for files
+    /// that are too large to keep in memory
+    pub fn open(&self, path: &str) -> io::Result<FileReader> {
+        let path = normalize(path);
+        trace!("opening '{path}'");
+
+        for mount in self.mounts.iter().rev() {
+            match mount {
+                Mount::Directory(dir) => {
+                    let full_path = dir.join(&path);
+                    if full_path.is_file() {
+                        let file = File::open(full_path)?;
+                        let len = file.metadata()?.len();
+                        return Ok(FileReader::new(file, 0, len));
+                    }
+                },
+
+
+                Mount::Archive(archive) => {
+                    if archive.contains(&path) {
+                        return archive.open_entry(&path);
+                    }
+                },
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::NotFound,
+                           format!("'{path}' doesn't exist in any of the mounts")))
+    }
+
+
+    pub fn read_to_string(&self, path: &str) -> io::Result<String> {
+        let file = self.read(path)?;
+        String::from_utf8(file)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("'{path}' is not a valid utf-8 string")))
+    }
+
+
+    pub fn exists(&self, path: &str) -> bool {
+        let path = normalize(path);
+
+        self.mounts.iter().any(|mount| match mount {
+            Mount::Directory(dir) => dir.join(&path).is_file(),
+            Mount::Archive(archive) => archive.contains(&path),
+        })
+    }
+
+
+    /// Returns the modification time of a file if it
+    /// lives in a mounted directory.
+    ///
+    /// Files inside of archives never change so this
+    /// returns `None` for them
+    pub fn modified(&self, path: &str) -> Option<SystemTime> {
+        let path = normalize(path);
+
+        for mount in self.mounts.iter().rev() {
+            match mount {
+                Mount::Directory(dir) => {
+                    let full_path = dir.join(&path);
+                    if !full_path.is_file() { continue }
+
+                    return std::fs::metadata(full_path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok();
+                },
+
+
+                Mount::Archive(archive) => {
+                    if archive.contains(&path) { return None }
+                },
+            }
+        }
+
+        None
+    }
+
+
+    /// Returns the path of every file in every mount, sorted
+    pub fn files(&self) -> Vec<String> {
+        let mut files = BTreeSet::new();
+
+        for mount in self.mounts.iter() {
+            match mount {
+                Mount::Directory(dir) => walk_directory(dir, &mut files),
+                Mount::Archive(archive) => files.extend(archive.files().map(String::from)),
+            }
+        }
+
+        files.into_iter().collect()
+    }
+
+
+    /// Returns the real path that `path` should be written to,
+    /// which is inside the most recently mounted directory.
+    ///
+    /// Returns `None` if no directories are mounted
+    pub fn write_path(&self, path: &str) -> Option<PathBuf> {
+        let path = normalize(path);
+
+        self.mounts.iter().rev().find_map(|mount| match mount {
+            Mount::Directory(dir) => Some(dir.join(&path)),
+            Mount::Archive(_) => None,
+        })
+    }
+}
+
+
+impl FileReader {
+    pub(crate) fn new(file: File, start: u64, len: u64) -> Self {
+        Self { file, start, len, position: 0 }
+    }
+
+
+    /// The size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+}
+
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let len = (buf.len() as u64).min(remaining) as usize;
+        if len == 0 { return Ok(0) }
+
+        self.file.seek(SeekFrom::Start(self.start + self.position))?;
+        let read = self.file.read(&mut buf[..len])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.len.checked_add_signed(x),
+            SeekFrom::Current(x) => self.position.checked_add_signed(x),
+        };
+
+        let Some(position) = position
+        else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "seeked before the start of the file"));
+        };
+
+        self.position = position;
+        Ok(position)
+    }
+}
+
+
+/// Turns a path into the form used by the file system,
+/// relative, '/' separated and without any '.' or '..'
+pub fn normalize(path: &str) -> String {
+    let mut components : Vec<&str> = vec![];
+
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            ".." => { components.pop(); },
+            _ => components.push(component),
+        }
+    }
+
+    components.join("/")
+}
+
+
+fn walk_directory(root: &Path, files: &mut BTreeSet<String>) {
+    let mut stack = vec![root.to_path_buf()];
+
+    while let Some(dir) = stack.pop() {
+        let read_dir = match dir.read_dir() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("unable to read directory '{}': {}", dir.to_string_lossy(), e);
+                continue;
+            },
+        };
+
+        for item in read_dir {
+            let item = match item {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("unable to read an item: {}", e);
+                    continue;
+                },
+            };
+
+            let path = item.path();
+            let metadata = match item.metadata() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("unable to retrieve metadata of '{}': {}", path.to_string_lossy(), e);
+                    continue;
+                },
+            };
+
+            if metadata.file_type().is_dir() {
+                stack.push(path);
+                continue
+            }
+
+            let Ok(relative) = path.strip_prefix(root)
+            else { continue };
+
+            files.insert(normalize(&relative.to_string_lossy()));
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use std::{collections::HashMap, fs::File, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+
+use tracing::info;
+
+use super::{normalize, FileReader};
+
+
+///
+/// A packed archive of files.
+///
+/// The layout of an archive is:
+///   - the magic bytes `BUTRPACK`
+///   - the format version as a u32
+///   - the entry count as a u32
+///   - for each entry:
+///       - the length of the path as a u32
+///       - the path as utf-8
+///       - the offset of the data from the start of the file as a u64
+///       - the size of the data as a u64
+///   - the data of the entries
+///
+/// All integers are little endian.
+///
+/// Only the table of contents is kept in memory, the
+/// data is read from the file when it's requested.
+///
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    entries: HashMap<String, ArchiveEntry>,
+}
+
+
+/// The smallest an entry can be in the table of
+/// contents, the path length, offset and size
+const ENTRY_LEN : u64 = 4 + 8 + 8;
+
+
+#[derive(Debug, Clone, Copy)]
+struct ArchiveEntry {
+    offset: u64,
+    size: u64,
+}
+
+
+///
+/// Collects files in memory and writes them out
+/// as an archive that `Archive` can read
+///
+#[derive(Debug, Default)]
+pub struct ArchiveWriter {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+
+impl Archive {
+    pub const MAGIC : &[u8; 8] = b"BUTRPACK";
+    pub const VERSION : u32 = 1;
+
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != Self::MAGIC {
+            return Err(invalid_data("the file isn't an archive"));
+        }
+
+        let version = read_u32(&mut file)?;
+        if version != Self::VERSION {
+            return Err(invalid_data(&format!("the archive version is '{version}' \
+                                             but only '{}' is supported", Self::VERSION)));
+        }
+
+        let file_len = file.metadata()?.len();
+        let entry_count = read_u32(&mut file)?;
+
+        // the counts and lengths come from the file so they're checked
+        // against its length before anything is allocated for them
+        let header_len = (Self::MAGIC.len() + 4 + 4) as u64;
+        if entry_count as u64 * ENTRY_LEN > file_len - header_len {
+            return Err(invalid_data(&format!("the archive claims to have '{entry_count}' \
+                                             entries but it's too short for that")));
+        }
+
+        let mut entries = HashMap::with_capacity(entry_count as usize);
+
+        for _ in 0..entry_count {
+            let path_len = read_u32(&mut file)?;
+            let position = file.stream_position()?;
+            if path_len as u64 > file_len.saturating_sub(position) {
+                return Err(invalid_data("an entry path goes past the end of the archive"));
+            }
+
+            let mut path = vec![0; path_len as usize];
+            file.read_exact(&mut path)?;
+
+            let Ok(path) = String::from_utf8(path)
+            else { return Err(invalid_data("an entry path isn't valid utf-8")) };
+
+            let offset = read_u64(&mut file)?;
+            let size = read_u64(&mut file)?;
+
+            if offset.checked_add(size).map(|end| end > file_len).unwrap_or(true) {
+                return Err(invalid_data(&format!("the entry '{path}' is out of bounds")));
+            }
+
+            entries.insert(path, ArchiveEntry { offset, size });
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.entries.contains_key(path)
+    }
+
+
+    pub fn files(&self) -> impl Iterator<Item=&str> {
+        self.entries.keys().map(|x| x.as_str())
+    }
+
+
+    pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut reader = self.open_entry(path)?;
+
+        let mut data = vec![0; reader.size() as usize];
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+
+    /// Opens an entry to be read a piece at a time
+    pub fn open_entry(&self, path: &str) -> io::Result<FileReader> {
+        let Some(entry) = self.entries.get(path)
+        else {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("'{path}' isn't in the archive")));
+        };
+
+        // every entry opens the file on its own so
+        // archives can be read from multiple threads
+        let file = File::open(&self.path)?;
+
+        // the file could have been replaced since it was opened
+        let file_len = file.metadata()?.len();
+        if entry.offset.saturating_add(entry.size) > file_len {
+            return Err(invalid_data(&format!("the entry '{path}' is out of bounds")));
+        }
+
+        Ok(FileReader::new(file, entry.offset, entry.size))
+    }
+}
+
+
+impl ArchiveWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+
+    /// Adds a file to the archive, replacing any
+    /// previously added file with the same path
+    pub fn add(&mut self, path: &str, data: Vec<u8>) {
+        let path = normalize(path);
+        self.files.retain(|(other, _)| *other != path);
+        self.files.push((path, data));
+    }
+
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        info!("writing an archive with {} files to '{}'", self.files.len(), path.to_string_lossy());
+
+        let header_len = Archive::MAGIC.len() + 4 + 4;
+        let toc_len : usize = self.files.iter()
+            .map(|(path, _)| 4 + path.len() + 8 + 8)
+            .sum();
+
+        let mut buffer = Vec::with_capacity(header_len + toc_len);
+        buffer.extend_from_slice(Archive::MAGIC);
+        buffer.extend_from_slice(&Archive::VERSION.to_le_bytes());
+        buffer.extend_from_slice(&(self.files.len() as u32).to_le_bytes());
+
+        let mut offset = (header_len + toc_len) as u64;
+        for (path, data) in self.files.iter() {
+            buffer.extend_from_slice(&(path.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(path.as_bytes());
+            buffer.extend_from_slice(&offset.to_le_bytes());
+            buffer.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            offset += data.len() as u64;
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(&buffer)?;
+        for (_, data) in self.files.iter() {
+            file.write_all(data)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+fn read_u32(file: &mut File) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    file.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+
+fn read_u64(file: &mut File) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    file.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn archive_roundtrip() {
+        let path = std::env::temp_dir().join(format!("butter-archive-{}.pack", std::process::id()));
+
+        let mut writer = ArchiveWriter::new();
+        writer.add("./scripts/player.lua", b"class_name = \"Player\"".to_vec());
+        writer.add("world.scene", b"[0]".to_vec());
+        writer.add("empty.txt", vec![]);
+        writer.write(&path).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.len(), 3);
+        assert!(archive.contains("scripts/player.lua"));
+        assert_eq!(archive.read("scripts/player.lua").unwrap(), b"class_name = \"Player\"");
+        assert_eq!(archive.read("world.scene").unwrap(), b"[0]");
+        assert_eq!(archive.read("empty.txt").unwrap(), b"");
+        assert!(archive.read("missing").is_err());
+
+        // reads stay inside of the entry
+        let mut reader = archive.open_entry("scripts/player.lua").unwrap();
+        reader.seek(SeekFrom::Start(13)).unwrap();
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"\"Player\"");
+        assert!(reader.seek(SeekFrom::Current(-30)).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+
+    #[test]
+    fn archive_rejects_garbage() {
+        let path = std::env::temp_dir().join(format!("butter-garbage-{}.pack", std::process::id()));
+        std::fs::write(&path, b"definitely not an archive").unwrap();
+
+        assert!(Archive::open(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+
+    #[test]
+    fn archive_rejects_broken_table_of_contents() {
+        let path = std::env::temp_dir().join(format!("butter-toc-{}.pack", std::process::id()));
+
+        let mut header = Archive::MAGIC.to_vec();
+        header.extend_from_slice(&Archive::VERSION.to_le_bytes());
+
+        // way more entries than the file has room for
+        let mut bytes = header.clone();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0; 32]);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(Archive::open(&path).is_err());
+
+        // a path that's longer than the rest of the file
+        let mut bytes = header.clone();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[b'a'; 24]);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(Archive::open(&path).is_err());
+
+        // a table of contents that's cut off
+        let mut writer = ArchiveWriter::new();
+        writer.add("world.scene", b"[0]".to_vec());
+        writer.write(&path).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 10]).unwrap();
+        assert!(Archive::open(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize("./character/character.lua"), "character/character.lua");
+        assert_eq!(normalize("pipe\\pipe.png"), "pipe/pipe.png");
+        assert_eq!(normalize("a/b/../c//d"), "a/c/d");
+    }
+}
@@ -0,0 +1,82 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use tracing::trace;
+
+use crate::engine::Engine;
+
+
+///
+/// Keeps track of the modification time of a set of
+/// files so that they can be reloaded once they change.
+///
+/// The watcher doesn't touch the file system on its own,
+/// `changed_files` has to be called to poll for changes.
+/// Files that live inside of archives never change.
+///
+#[derive(Debug)]
+pub struct FileWatcher {
+    files: HashMap<String, Option<SystemTime>>,
+    next_poll: f32,
+}
+
+
+impl FileWatcher {
+    /// How often, in seconds, the files are polled
+    pub const POLL_INTERVAL : f32 = 0.5;
+
+
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            next_poll: 0.0,
+        }
+    }
+
+
+    /// Starts watching `path`, using the current
+    /// modification time as the baseline
+    pub fn watch(&mut self, path: &str) {
+        trace!("watching '{path}'");
+        self.files.insert(path.to_string(), Self::modified(path));
+    }
+
+
+    pub fn unwatch(&mut self, path: &str) {
+        trace!("no longer watching '{path}'");
+        self.files.remove(path);
+    }
+
+
+    /// Returns the files that have been modified since
+    /// the last poll.
+    ///
+    /// `now` is the current time in seconds, if less than
+    /// `POLL_INTERVAL` has passed since the last poll
+    /// no files are checked.
+    pub fn changed_files(&mut self, now: f32) -> Vec<String> {
+        if now < self.next_poll { return vec![] }
+        self.next_poll = now + Self::POLL_INTERVAL;
+
+        let mut changed = vec![];
+        for (path, last_modified) in self.files.iter_mut() {
+            let modified = Self::modified(path);
+            if modified == *last_modified { continue }
+
+            *last_modified = modified;
+
+            // the file might be in the middle of being written
+            // to or it might've been removed, either way there's
+            // nothing to reload
+            if modified.is_none() { continue }
+
+            changed.push(path.clone());
+        }
+
+        changed
+    }
+
+
+    fn modified(path: &str) -> Option<SystemTime> {
+        Engine::file_system().modified(path)
+    }
+}
@@ -7,17 +7,24 @@ pub mod input_manager;
 pub mod event_manager;
 pub mod script_manager;
 pub mod asset_manager;
+pub mod audio_manager;
+pub mod async_loader;
 pub mod lua;
 pub mod physics;
 pub mod engine;
 pub mod deserialize;
+pub mod serialize;
 pub mod scene_manager;
 pub mod renderer;
+pub mod file_watcher;
+pub mod file_system;
+pub mod export;
 
 use core::str;
 use std::{ffi::CString, process::exit};
 
 use engine::Engine;
+use file_system::FileSystem;
 use math::vector::{Vec2, Vec3};
 use sokol::{app as sapp, debugtext::{self as sdtx}, gfx::{self as sg, ImageSampleType, ImageType, SamplerType, ShaderStage, UniformLayout}, glue as sglue, time as stime};
 use event_manager::{Event, Keycode, MouseButton};
@@ -25,16 +32,16 @@ use tracing::{error, info, warn};
 use settings::{engine_version::EngineVersion, ProjectSettings};
 
 
-const PROJECT_SETTINGS_FILE : &str = "project-settings.toml";
+pub const PROJECT_SETTINGS_FILE : &str = "project-settings.toml";
 
 
-pub fn start() -> ! {
+pub fn start(file_system: FileSystem) -> ! {
     let project_settings = {
         info!("reading project settings");
-        let project_settings = match std::fs::read_to_string(PROJECT_SETTINGS_FILE) {
+        let project_settings = match file_system.read_to_string(PROJECT_SETTINGS_FILE) {
             Ok(v) => v,
-            Err(_) => {
-                error!("unable to read '{PROJECT_SETTINGS_FILE}'");
+            Err(e) => {
+                error!("unable to read '{PROJECT_SETTINGS_FILE}': {e}");
                 exit(-2);
             },
         };
@@ -58,7 +65,7 @@ pub fn start() -> ! {
     }
 
 
-    Engine::new(project_settings.clone());
+    Engine::new(project_settings.clone(), file_system);
     info!("engine created");
 
     let title = to_cstring("window title", Engine::project_settings().window.title.clone());
@@ -67,6 +74,7 @@ pub fn start() -> ! {
         init_cb: Some(init),
         frame_cb: Some(frame),
         event_cb: Some(event),
+        cleanup_cb: Some(cleanup),
 
         window_title: title.as_ptr(),
         width: clamp_to_i32("window width", project_settings.window.width),
@@ -130,12 +138,21 @@ extern "C" fn init() {
 
     // set up the texture
     {
-        renderer.bind.samplers[0] = sg::make_sampler(&sg::SamplerDesc {
+        let mut sampler = sg::SamplerDesc {
             wrap_u: sg::Wrap::ClampToEdge,
             wrap_v: sg::Wrap::ClampToEdge,
             wrap_w: sg::Wrap::ClampToEdge,
+            min_filter: sg::Filter::Nearest,
+            mag_filter: sg::Filter::Nearest,
             ..Default::default()
-        });
+        };
+        renderer.nearest_sampler = sg::make_sampler(&sampler);
+
+        sampler.min_filter = sg::Filter::Linear;
+        sampler.mag_filter = sg::Filter::Linear;
+        renderer.linear_sampler = sg::make_sampler(&sampler);
+
+        renderer.bind.samplers[0] = renderer.nearest_sampler;
     }
 
     // set up the shader pipeline
@@ -158,6 +175,12 @@ extern "C" fn init() {
         };
         pipeline.label = c"pipeline".as_ptr();
         renderer.render_pip = sg::make_pipeline(&pipeline);
+
+        // the colour of premultiplied textures already
+        // has the alpha applied to it
+        pipeline.colors[0].blend.src_factor_rgb = sg::BlendFactor::One;
+        pipeline.label = c"premultiplied-pipeline".as_ptr();
+        renderer.premultiplied_pip = sg::make_pipeline(&pipeline);
     }
 
     // set background colour
@@ -184,6 +207,14 @@ extern "C" fn frame() {
 }
 
 
+extern "C" fn cleanup() {
+    let mut engine = Engine::generate();
+
+    Engine::shutdown(&mut engine);
+    sg::shutdown();
+}
+
+
 extern "C" fn event(event: *const sapp::Event) {
     let mut engine = Engine::generate();
     let event = unsafe { *event };
@@ -260,7 +291,7 @@ fn texcube_shader_desc(backend: sg::Backend) -> Option<sg::ShaderDesc> {
         desc.fragment_func.entry = c"fs_main".as_ptr();
         desc.uniform_blocks[0].stage = ShaderStage::Vertex; // vertex sahder
         desc.uniform_blocks[0].layout = UniformLayout::Std140; // align type
-        desc.uniform_blocks[0].size = 64; // f32x4x4
+        desc.uniform_blocks[0].size = 80; // f32x4x4 + f32x4
         desc.uniform_blocks[0].msl_buffer_n = 0; // no idea
         //fragment shader modulate uniform
         desc.uniform_blocks[1].stage = ShaderStage::Fragment; // fragment shader
@@ -2,16 +2,23 @@ pub mod time;
 pub mod math;
 pub mod input;
 pub mod texture;
+pub mod image;
 pub mod node;
 pub mod physics_server;
 pub mod draw;
 pub mod scene;
 pub mod engine;
+pub mod audio;
+pub mod music;
 
+use audio::Audio;
 use draw::Draw;
+use self::image::LuaImage;
 use input::Input;
 use math::Math;
 use mlua::{Function, Lua, UserData};
+use music::Music;
+use node::LuaNode;
 use physics_server::Physics;
 use scene::Scene;
 use texture::LuaTexture;
@@ -38,9 +45,13 @@ pub fn setup_lua_environment(lua: &Lua) {
     register(lua, "Math", Math);
     register(lua, "Input", Input);
     register(lua, "Texture", LuaTexture);
+    register(lua, "Image", LuaImage);
     register(lua, "PhysicsServer", Physics);
     register(lua, "Draw", Draw);
+    register(lua, "Node", LuaNode);
     register(lua, "SceneManager", Scene);
     register(lua, "Engine", engine::Engine);
+    register(lua, "Audio", Audio);
+    register(lua, "Music", Music);
 }
 
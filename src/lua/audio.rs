@@ -0,0 +1,305 @@
+use mlua::{Error, Table, Value};
+
+use crate::{audio_manager::{self, bus::{Bus, BusId}, effect::{Effect, EffectSettings}, mixer::{Mixer, PlayOptions, VoiceId}, stream::Stream, synth::{Tone, Waveform}, AudioManager}, engine::Engine, math::vector::Vec2};
+
+pub struct Audio;
+
+impl mlua::UserData for Audio {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function("play", |_, (path, options): (String, Option<Table>)| {
+            let mut engine = Engine::generate();
+            let mut engine = engine.get_mut();
+
+            let play = play_options(&engine.audio_manager, options.as_ref())?;
+            Ok(engine.audio_manager.play(Engine::file_system(), &path, play))
+        });
+
+
+        // generates a sound effect, `options` takes the play
+        // options along with the ones of the synth:
+        // `{ wave, frequency, slide, duty, duration, attack,
+        //    decay, sustain, release, seed }`
+        methods.add_function("tone", |_, options: Table| {
+            let tone = tone(&options)?;
+            let engine = Engine::generate();
+            let engine = engine.get();
+
+            let mut play = play_options(&engine.audio_manager, Some(&options))?;
+            // the volume is part of the tone
+            play.volume = 1.0;
+            play.looping = false;
+
+            Ok(engine.audio_manager.play_tone(&tone, play))
+        });
+
+
+        // the mono samples of a tone, to build
+        // upon before pushing them to a stream
+        methods.add_function("render_tone", |_, (options, sample_rate): (Table, Option<u32>)| {
+            let tone = tone(&options)?;
+            let sample_rate = sample_rate
+                .unwrap_or_else(|| Engine::generate().get().audio_manager.mixer().sample_rate());
+
+            if sample_rate == 0 || sample_rate > Tone::MAX_SAMPLE_RATE {
+                return Err(Error::runtime(format!("the sample rate has to be between 1 and {}hz",
+                                                  Tone::MAX_SAMPLE_RATE)));
+            }
+
+            Ok(tone.samples(sample_rate))
+        });
+
+
+        // a voice that plays the samples pushed into it with
+        // `push_samples`, `options` takes the play options
+        // along with `sample_rate` and `channels`
+        methods.add_function("create_stream", |_, options: Option<Table>| {
+            let engine = Engine::generate();
+            let engine = engine.get();
+            let audio = &engine.audio_manager;
+
+            let mut play = play_options(audio, options.as_ref())?;
+            play.looping = false;
+
+            let (sample_rate, channels) = match &options {
+                Some(options) => (options.get::<Option<u32>>("sample_rate")?,
+                                  options.get::<Option<usize>>("channels")?),
+                None => (None, None),
+            };
+
+            let sample_rate = sample_rate.unwrap_or_else(|| audio.mixer().sample_rate());
+            let (voice, _) = audio.play_queue(sample_rate, channels.unwrap_or(1), play)
+                .map_err(|e| Error::runtime(format!("unable to create the stream: {e}")))?;
+
+            Ok(voice)
+        });
+
+
+        // returns false if the stream has stopped
+        methods.add_function("push_samples", |_, (voice, samples): (VoiceId, Vec<f32>)| {
+            Ok(with_stream(voice, |stream| stream.push(&samples)).is_some())
+        });
+
+
+        // how many seconds of pushed samples are left to play
+        methods.add_function("queued", |_, voice: VoiceId| {
+            Ok(with_stream(voice, |stream| stream.queued()))
+        });
+
+
+        // lets the stream stop once it runs out of samples
+        methods.add_function("finish_stream", |_, voice: VoiceId| {
+            with_stream(voice, |stream| stream.finish());
+            Ok(())
+        });
+
+
+        methods.add_function("stop", |_, voice: VoiceId| {
+            Engine::generate().get().audio_manager.stop(voice);
+            Ok(())
+        });
+
+
+        methods.add_function("is_playing", |_, voice: VoiceId| {
+            Ok(Engine::generate().get().audio_manager.is_playing(voice))
+        });
+
+
+        methods.add_function("set_volume", |_, (voice, volume): (VoiceId, f32)| {
+            Engine::generate().get().audio_manager.set_volume(voice, volume);
+            Ok(())
+        });
+
+
+        methods.add_function("set_pan", |_, (voice, pan): (VoiceId, f32)| {
+            Engine::generate().get().audio_manager.set_pan(voice, pan);
+            Ok(())
+        });
+
+
+        // the volume multiplier and pan of a sound at
+        // `position`, as heard from the camera
+        methods.add_function("positional", |_, (position, max_distance, attenuation): (Vec2, f32, Option<f32>)| {
+            let engine = Engine::generate();
+            let engine = engine.get();
+
+            let camera = &engine.camera;
+            let listener = Vec2::new(camera.position.x, camera.position.y);
+            let half_width = camera.ortho * 0.5 * engine.renderer.aspect_ratio;
+
+            Ok(audio_manager::positional(listener, half_width, position,
+                                         max_distance, attenuation.unwrap_or(1.0)))
+        });
+
+
+        // kept from before there were buses
+        methods.add_function("set_master_volume", |_, volume: f32| {
+            let engine = Engine::generate();
+            let engine = engine.get();
+            engine.audio_manager.mixer().bus_mut(BusId::MASTER).volume = volume;
+            Ok(())
+        });
+
+
+        methods.add_function("buses", |_, _: ()| {
+            let engine = Engine::generate();
+            let engine = engine.get();
+            let names = engine.audio_manager.mixer().buses().iter()
+                .map(|bus| bus.name.clone())
+                .collect::<Vec<_>>();
+            Ok(names)
+        });
+
+
+        methods.add_function("get_bus_volume", |_, bus: String| {
+            with_bus(&bus, |bus| bus.volume)
+        });
+
+
+        methods.add_function("set_bus_volume", |_, (bus, volume): (String, f32)| {
+            with_bus(&bus, |bus| bus.volume = volume)
+        });
+
+
+        methods.add_function("is_bus_muted", |_, bus: String| {
+            with_bus(&bus, |bus| bus.muted)
+        });
+
+
+        methods.add_function("set_bus_muted", |_, (bus, muted): (String, bool)| {
+            with_bus(&bus, |bus| bus.muted = muted)
+        });
+
+
+        // returns the index of the effect on the bus
+        methods.add_function("add_bus_effect", |_, (bus, effect): (String, Table)| {
+            let effect = effect_settings(effect)?;
+            with_bus(&bus, |bus| {
+                bus.effects.push(Effect::new(effect));
+                bus.effects.len()
+            })
+        });
+
+
+        methods.add_function("set_bus_effect_enabled", |_, (bus, index, enabled): (String, usize, bool)| {
+            with_bus(&bus, |bus| {
+                let Some(effect) = index.checked_sub(1).and_then(|i| bus.effects.get_mut(i))
+                else { return Err(Error::runtime(format!("the bus '{}' has no effect {index}", bus.name))) };
+
+                effect.enabled = enabled;
+                Ok(())
+            })?
+        });
+
+
+        methods.add_function("clear_bus_effects", |_, bus: String| {
+            with_bus(&bus, |bus| bus.effects.clear())
+        });
+    }
+}
+
+
+fn play_options(audio: &AudioManager, options: Option<&Table>) -> mlua::Result<PlayOptions> {
+    let mut play = PlayOptions::default();
+    let Some(options) = options
+    else { return Ok(play) };
+
+    if let Some(volume) = options.get("volume")? { play.volume = volume }
+    if let Some(pitch) = options.get("pitch")? { play.pitch = pitch }
+    if let Some(pan) = options.get("pan")? { play.pan = pan }
+    if let Some(looping) = options.get("loop")? { play.looping = looping }
+    if let Some(bus) = options.get::<Option<String>>("bus")? {
+        play.bus = bus_id(&audio.mixer(), &bus)?;
+    }
+
+    Ok(play)
+}
+
+
+fn tone(options: &Table) -> mlua::Result<Tone> {
+    let mut tone = Tone::default();
+
+    if let Some(wave) = options.get::<Option<String>>("wave")? {
+        tone.waveform = Waveform::parse(&wave)
+            .ok_or_else(|| Error::runtime(format!("there's no waveform named '{wave}'")))?;
+    }
+
+    if let Some(attack) = options.get("attack")? { tone.envelope.attack = attack }
+    if let Some(decay) = options.get("decay")? { tone.envelope.decay = decay }
+    if let Some(sustain) = options.get("sustain")? { tone.envelope.sustain = sustain }
+    if let Some(release) = options.get("release")? { tone.envelope.release = release }
+
+    if let Some(frequency) = options.get("frequency")? { tone.frequency = frequency }
+    if let Some(duty) = options.get("duty")? { tone.duty = duty }
+    if let Some(duration) = options.get("duration")? { tone.duration = duration }
+    if let Some(volume) = options.get("volume")? { tone.volume = volume }
+    if let Some(seed) = options.get("seed")? { tone.seed = seed }
+    tone.slide = options.get("slide")?;
+
+    if tone.length() > Tone::MAX_LENGTH {
+        return Err(Error::runtime(format!("a tone can be at most {} seconds long including its release",
+                                          Tone::MAX_LENGTH)));
+    }
+
+    Ok(tone)
+}
+
+
+fn with_stream<T>(voice: VoiceId, f: impl FnOnce(&Stream) -> T) -> Option<T> {
+    let engine = Engine::generate();
+    let engine = engine.get();
+    let mixer = engine.audio_manager.mixer();
+    mixer.stream(voice).map(|stream| f(stream))
+}
+
+
+fn bus_id(mixer: &Mixer, name: &str) -> mlua::Result<BusId> {
+    mixer.bus_id(name)
+        .ok_or_else(|| Error::runtime(format!("there's no bus named '{name}'")))
+}
+
+
+fn with_bus<T>(name: &str, f: impl FnOnce(&mut Bus) -> T) -> mlua::Result<T> {
+    let engine = Engine::generate();
+    let engine = engine.get();
+    let mut mixer = engine.audio_manager.mixer();
+
+    let id = bus_id(&mixer, name)?;
+    Ok(f(mixer.bus_mut(id)))
+}
+
+
+/// Reads an effect from a table shaped like
+/// the ones in the project settings
+fn effect_settings(table: Table) -> mlua::Result<EffectSettings> {
+    let mut settings = toml::Table::new();
+    for pair in table.pairs::<String, Value>() {
+        let (key, value) = pair?;
+        let value = match value {
+            Value::Integer(v) => toml::Value::Float(v as f64),
+            Value::Number(v) => toml::Value::Float(v),
+            Value::String(v) => toml::Value::String(v.to_str()?.to_string()),
+            Value::Boolean(v) => toml::Value::Boolean(v),
+            _ => return Err(Error::runtime(format!("the effect option '{key}' can't be a {}", value.type_name()))),
+        };
+
+        settings.insert(key, value);
+    }
+
+    toml::Value::Table(settings).try_into()
+        .map_err(|e| Error::runtime(format!("invalid effect: {e}")))
+}
+
+
+impl mlua::UserData for VoiceId {}
+
+impl mlua::FromLua for VoiceId {
+    fn from_lua(value: Value, _: &mlua::Lua) -> mlua::Result<Self> {
+        let Value::UserData(data) = value
+        else { return Err(mlua::Error::RuntimeError(format!("'{value:?}' isn't a voice"))) };
+
+        let Ok(data) = data.borrow::<VoiceId>()
+        else { return Err(mlua::Error::RuntimeError(format!("'{data:?}' isn't a voice"))) };
+
+        Ok(*data)
+    }
+}
@@ -0,0 +1,156 @@
+use mlua::{AnyUserData, Error, UserData};
+
+use crate::{asset_manager::{pixels::PixelBuffer, texture::TextureUsage, TextureId}, engine::Engine, math::vector::Colour};
+
+pub struct LuaImage;
+
+
+/// The most memory `Image.new` hands out for one image, 16384x16384
+/// is the largest texture most GPUs support anyway
+const MAX_IMAGE_BYTES : usize = 16384 * 16384 * 4;
+
+
+///
+/// An image that lives on the CPU, scripts can edit it
+/// freely and turn it into a texture once they're done
+///
+pub struct Image {
+    pixels: PixelBuffer,
+    /// The dynamic texture that mirrors this image
+    texture: Option<TextureId>,
+    /// The frame the dynamic texture was last updated on
+    updated_at: Option<u64>,
+}
+
+
+impl UserData for LuaImage {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function("new", |_, (width, height): (usize, usize)| {
+            let size = width.checked_mul(height).and_then(|x| x.checked_mul(4));
+            if width == 0 || height == 0 || size.map(|x| x > MAX_IMAGE_BYTES).unwrap_or(true) {
+                return Err(Error::runtime(format!("an image can't be {width}x{height}")));
+            }
+
+            Ok(Image::new(PixelBuffer::new(width, height)))
+        });
+
+
+        methods.add_function("load", |_, path: String| {
+            let bytes = Engine::file_system().read(&path)
+                .map_err(|e| Error::runtime(format!("unable to read '{path}': {e}")))?;
+
+            let pixels = PixelBuffer::decode(&bytes)
+                .map_err(|e| Error::runtime(format!("unable to decode '{path}': {e}")))?;
+
+            Ok(Image::new(pixels))
+        });
+    }
+}
+
+
+impl Image {
+    fn new(pixels: PixelBuffer) -> Self {
+        Self { pixels, texture: None, updated_at: None }
+    }
+}
+
+
+impl UserData for Image {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("width", |_, this| Ok(this.pixels.width()));
+        fields.add_field_method_get("height", |_, this| Ok(this.pixels.height()));
+    }
+
+
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("get_pixel", |_, this, (x, y): (i64, i64)| {
+            Ok(this.pixels.get_pixel(x, y))
+        });
+
+
+        methods.add_method_mut("set_pixel", |_, this, (x, y, colour): (i64, i64, Colour)| {
+            this.pixels.set_pixel(x, y, colour);
+            Ok(())
+        });
+
+
+        methods.add_method_mut("fill", |_, this, colour: Colour| {
+            this.pixels.fill(colour);
+            Ok(())
+        });
+
+
+        methods.add_method_mut("blit", |_, this, (src, x, y): (AnyUserData, i64, i64)| {
+            let src = src.borrow::<Image>()
+                .map_err(|_| Error::runtime("the source of a blit must be another image"))?;
+
+            this.pixels.blit(&src.pixels, x, y);
+            Ok(())
+        });
+
+
+        methods.add_method("save", |_, this, path: String| {
+            let Some(real_path) = Engine::file_system().write_path(&path)
+            else { return Err(Error::runtime(format!("unable to save '{path}', no directory is mounted"))) };
+
+            let png = this.pixels.encode_png()
+                .map_err(|e| Error::runtime(format!("unable to encode '{path}': {e}")))?;
+
+            std::fs::write(real_path, png)
+                .map_err(|e| Error::runtime(format!("unable to save '{path}': {e}")))?;
+
+            Ok(())
+        });
+
+
+        // creates a new immutable texture every time, the
+        // texture is kept until `Texture.unload` is called
+        methods.add_method("to_texture", |_, this, _: ()| {
+            Engine::generate().with(|engine| {
+                let texture = engine.asset_manager.from_pixels("lua image", &this.pixels,
+                                                               TextureUsage::Immutable);
+                engine.asset_manager.acquire(texture);
+                Ok(texture)
+            })
+        });
+
+
+        // the texture is created once and then mirrors
+        // the image every time `update` is called
+        methods.add_method_mut("to_dynamic_texture", |_, this, _: ()| {
+            if let Some(texture) = this.texture {
+                return Ok(texture);
+            }
+
+            let texture = Engine::generate().with(|engine| {
+                let texture = engine.asset_manager.from_pixels("lua dynamic image", &this.pixels,
+                                                               TextureUsage::Dynamic);
+                engine.asset_manager.acquire(texture);
+
+                // creating it already uploaded the pixels
+                this.updated_at = Some(engine.last_frame);
+                texture
+            });
+
+            this.texture = Some(texture);
+            Ok(texture)
+        });
+
+
+        methods.add_method_mut("update", |_, this, _: ()| {
+            let Some(texture) = this.texture
+            else { return Err(Error::runtime("the image doesn't have a dynamic texture, \
+                                             call 'to_dynamic_texture' first")) };
+
+            Engine::generate().with(|engine| {
+                if this.updated_at == Some(engine.last_frame) {
+                    return Err(Error::runtime("a dynamic texture can only be updated once per frame"));
+                }
+
+                this.updated_at = Some(engine.last_frame);
+                engine.asset_manager.update_texture(texture, this.pixels.data());
+                Ok(())
+            })
+        });
+    }
+}
@@ -2,7 +2,7 @@ use mlua::{Error, IntoLua, UserData, Value, Vector};
 use rand::Rng;
 use tracing::warn;
 
-use crate::math::vector::{Vec2, Vec3, Vec4};
+use crate::math::{transform::Transform2D, vector::{Vec2, Vec3, Vec4}};
 
 pub(super) struct Math;
 
@@ -87,6 +87,11 @@ impl UserData for Math {
         methods.add_function("vec4", |_, (x, y, z, w): (f32, f32, f32, f32)| {
             Ok(Vec4::new(x, y, z, w))
         });
+        methods.add_function("transform", |_, (position, rotation, scale): (Option<Vec2>, Option<f32>, Option<Vec2>)| {
+            Ok(Transform2D::new(position.unwrap_or(Vec2::new(0.0, 0.0)),
+                                rotation.unwrap_or(0.0),
+                                scale.unwrap_or(Vec2::new(1.0, 1.0))))
+        });
 
     }
 }
@@ -105,6 +110,36 @@ impl mlua::FromLua for Vec4 {
 }
 
 
+impl UserData for Transform2D {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("x", |_, this| Ok(this.x));
+        fields.add_field_method_get("y", |_, this| Ok(this.y));
+        fields.add_field_method_get("origin", |_, this| Ok(this.origin));
+        fields.add_field_method_get("position", |_, this| Ok(this.position()));
+        fields.add_field_method_get("rotation", |_, this| Ok(this.rotation()));
+        fields.add_field_method_get("scale", |_, this| Ok(this.scale()));
+    }
+
+
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("apply", |_, this, point: Vec2| Ok(this.apply(point)));
+        methods.add_method("apply_basis", |_, this, v: Vec2| Ok(this.apply_basis(v)));
+        methods.add_method("inverse", |_, this, ()| Ok(this.inverse()));
+        methods.add_meta_method("__mul", |_, this, rhs: Transform2D| Ok(*this * rhs));
+    }
+}
+
+
+impl mlua::FromLua for Transform2D {
+    fn from_lua(value: mlua::Value, _: &mlua::Lua) -> mlua::Result<Self> {
+        let Value::UserData(transform) = value
+        else { return Err(mlua::Error::RuntimeError(format!("'{value:?}' can't be assigned to a transform"))) };
+
+        Ok(*transform.borrow::<Self>()?)
+    }
+}
+
+
 impl IntoLua for Vec3 {
     fn into_lua(self, _: &mlua::Lua) -> mlua::Result<mlua::Value> {
         Ok(Value::Vector(Vector::new(self.x, self.y, self.z)))
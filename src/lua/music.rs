@@ -0,0 +1,73 @@
+use mlua::Table;
+
+use crate::{audio_manager::{stream::LoopPoints, MusicOptions}, engine::Engine};
+
+pub struct Music;
+
+impl mlua::UserData for Music {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        // `options` is `{ volume, fade_in, loop, loop_start, loop_end }`
+        methods.add_function("play", |_, (path, options): (String, Option<Table>)| {
+            let options = music_options(options)?;
+            let mut engine = Engine::generate();
+            let mut engine = engine.get_mut();
+
+            // a new track cuts the current one
+            Ok(engine.audio_manager.play_music(Engine::file_system(), &path, options, 0.0))
+        });
+
+
+        // fades the current track out and `path` in over `duration` seconds
+        methods.add_function("crossfade", |_, (path, duration, options): (String, f32, Option<Table>)| {
+            let mut options = music_options(options)?;
+            options.fade_in = duration;
+
+            let mut engine = Engine::generate();
+            let mut engine = engine.get_mut();
+            Ok(engine.audio_manager.play_music(Engine::file_system(), &path, options, duration))
+        });
+
+
+        methods.add_function("stop", |_, fade_out: Option<f32>| {
+            Engine::generate().get_mut().audio_manager.stop_music(fade_out.unwrap_or(0.0));
+            Ok(())
+        });
+
+
+        // the playback position of the current track in seconds
+        methods.add_function("position", |_, _: ()| {
+            Ok(Engine::generate().get().audio_manager.music_position())
+        });
+
+
+        // the path of the current track
+        methods.add_function("current", |_, _: ()| {
+            Ok(Engine::generate().get().audio_manager.music().map(str::to_string))
+        });
+
+
+        methods.add_function("is_playing", |_, _: ()| {
+            Ok(Engine::generate().get().audio_manager.music().is_some())
+        });
+    }
+}
+
+
+fn music_options(options: Option<Table>) -> mlua::Result<MusicOptions> {
+    let mut music = MusicOptions::default();
+    let Some(options) = options
+    else { return Ok(music) };
+
+    if let Some(volume) = options.get("volume")? { music.volume = volume }
+    if let Some(fade_in) = options.get("fade_in")? { music.fade_in = fade_in }
+
+    music.looping = match options.get::<Option<bool>>("loop")?.unwrap_or(true) {
+        true => Some(LoopPoints {
+            start: options.get::<Option<f32>>("loop_start")?.unwrap_or(0.0),
+            end: options.get("loop_end")?,
+        }),
+        false => None,
+    };
+
+    Ok(music)
+}
@@ -1,94 +1,111 @@
-use mlua::{AnyUserData, Error, FromLua, Value, Vector};
-use rapier2d::{math::Rotation, na::Isometry2};
+use mlua::{AnyUserData, Error, FromLua, Table, Value, Vector};
+use sti::keyed::KVec;
 use tracing::info;
 
-use crate::{engine::Engine, math::vector::Vec3, scene_manager::{node::ComponentId, NodeId}, script_manager::fields::FieldValue};
+use crate::{asset_manager::TextureId, engine::Engine, math::{transform::Transform2D, vector::{Colour, Vec2, Vec3}}, scene_manager::{node::{ComponentId, NodeProperties}, scene_tree::SceneTree, NodeId, SceneManager}, script_manager::fields::FieldValue};
 
 #[derive(Debug, Clone, Copy)]
 pub struct NodeUserData(pub NodeId, pub ComponentId);
 
 
-impl<'a> mlua::UserData for NodeUserData {
-    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
-        fields.add_field_method_get("position", |_, NodeUserData(this, _)| Ok(Engine::generate().get().scene_manager.tree.get(*this).properties.position));
-        fields.add_field_method_get("scale", |_, NodeUserData(this, _)| Ok(Engine::generate().get().scene_manager.tree.get(*this).properties.scale));
-        fields.add_field_method_get("rotation", |_, NodeUserData(this, _)| Ok(Engine::generate().get().scene_manager.tree.get(*this).properties.rotation));
+impl NodeUserData {
+    /// Fails with a Lua error if the node has been freed
+    /// or the component has been removed from it
+    pub fn alive(self) -> mlua::Result<Self> {
+        let NodeUserData(node, comp) = self;
+        alive(node)?;
 
-        fields.add_field_method_set("position", |_, NodeUserData(this, _), ass| {
-            let mut engine = Engine::generate();
-            let mut engine = engine.get_mut();
-            let scene = &mut engine.scene_manager;
-            let node = scene.tree.get_mut(*this);
-            node.properties.position = ass;
+        let components = Engine::generate().get().scene_manager.tree.get(node).components.len();
+        if comp == ComponentId::STALE || comp.inner() as usize >= components {
+            return Err(Error::runtime(format!("the component has been removed from '{node:?}'")));
+        }
 
-            let mut stack = vec![];
-            stack.push(*this);
+        Ok(self)
+    }
 
-            while let Some(this) = stack.pop() {
-                let node = scene.tree.get(this);
-                stack.extend_from_slice(&node.children);
 
-                let Some(rb) = scene.physics.node_to_rigidbody.get(&this).copied()
-                else { continue };
+    /// Same as `alive` but only returns the node
+    pub fn node(&self) -> mlua::Result<NodeId> {
+        Ok(self.alive()?.0)
+    }
+}
 
-                let pos = node.global_position(&scene.tree);
-                let rot = node.global_rotation(&scene.tree);
-                let iso = Isometry2::new(pos.into(), rot);
-                scene.physics.get_rb_mut(rb).set_position(iso, true);
-            }
 
-            Ok(())
+impl<'a> mlua::UserData for NodeUserData {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("position", |_, this| {
+            let this = this.node()?;
+            Ok(Engine::generate().get().scene_manager.tree.get(this).properties.position)
+        });
+        fields.add_field_method_get("scale", |_, this| {
+            let this = this.node()?;
+            Ok(Engine::generate().get().scene_manager.tree.get(this).properties.scale)
+        });
+        fields.add_field_method_get("rotation", |_, this| {
+            let this = this.node()?;
+            Ok(Engine::generate().get().scene_manager.tree.get(this).properties.rotation)
         });
 
-
-        fields.add_field_method_set("rotation", |_, NodeUserData(this, _), ass| {
-            let mut engine = Engine::generate();
-            let mut engine = engine.get_mut();
-            let scene = &mut engine.scene_manager;
-            let node = scene.tree.get_mut(*this);
-            node.properties.rotation = ass;
-
-            let mut stack = vec![];
-            stack.push(*this);
-
-            while let Some(this) = stack.pop() {
-                let node = scene.tree.get(this);
-                stack.extend_from_slice(&node.children);
-
-                let Some(rb) = scene.physics.node_to_rigidbody.get(&this).copied()
-                else { continue };
-
-                let rot = node.global_rotation(&scene.tree);
-                scene.physics.get_rb_mut(rb).set_rotation(Rotation::from_angle(rot), true);
-            }
-
-            Ok(())
+        fields.add_field_method_set("position", |_, this, position: Vec2| {
+            let this = this.node()?;
+            move_node(this, |tree| tree.set_position(this, position))
+        });
+        fields.add_field_method_set("rotation", |_, this, rotation: f32| {
+            let this = this.node()?;
+            move_node(this, |tree| tree.set_rotation(this, rotation))
+        });
+        fields.add_field_method_set("scale", |_, this, scale: Vec2| {
+            let this = this.node()?;
+            move_node(this, |tree| tree.set_scale(this, scale))
+        });
+        fields.add_field_method_set("sprite", |_, this, ass| {
+            let this = this.node()?;
+            Ok(Engine::generate().get_mut().scene_manager.tree.get_mut(this).properties.texture = ass)
+        });
+        fields.add_field_method_set("modulate", |_, this, ass| {
+            let this = this.node()?;
+            Ok(Engine::generate().get_mut().scene_manager.tree.get_mut(this).properties.modulate = ass)
         });
 
 
+        // relative to the parent
+        fields.add_field_method_get("transform", |_, this| {
+            let this = this.node()?;
+            Ok(Engine::generate().get().scene_manager.tree.get(this).properties.transform())
+        });
+        fields.add_field_method_set("transform", |_, this, transform: Transform2D| {
+            let this = this.node()?;
+            move_node(this, |tree| tree.set_transform(this, transform))
+        });
 
-        fields.add_field_method_set("scale", |_, NodeUserData(this, _), ass| Ok(Engine::generate().get_mut().scene_manager.tree.get_mut(*this).properties.scale = ass));
-        fields.add_field_method_set("sprite", |_, NodeUserData(this, _), ass| Ok(Engine::generate().get_mut().scene_manager.tree.get_mut(*this).properties.texture = ass));
-        fields.add_field_method_set("modulate", |_, NodeUserData(this, _), ass| Ok(Engine::generate().get_mut().scene_manager.tree.get_mut(*this).properties.modulate = ass));
 
+        fields.add_field_method_get("global_transform", |_, this| Ok(global_transform(this.node()?)));
+        fields.add_field_method_get("global_position", |_, this| Ok(global_transform(this.node()?).position()));
+        fields.add_field_method_get("global_rotation", |_, this| Ok(global_transform(this.node()?).rotation()));
+        fields.add_field_method_get("global_scale", |_, this| Ok(global_transform(this.node()?).scale()));
 
-        fields.add_field_method_get("global_position", |_, NodeUserData(this, _)| {
-            let engine = Engine::generate();
-            let engine = engine.get();
-            Ok(engine.scene_manager.tree.get(*this).global_position(&engine.scene_manager.tree))
+        fields.add_field_method_set("global_transform", |_, this, transform: Transform2D| {
+            let this = this.node()?;
+            move_node(this, |tree| tree.set_global_transform(this, transform))
         });
-
-
-        fields.add_field_method_get("global_scale", |_, NodeUserData(this, _)| {
-            let engine = Engine::generate();
-            let engine = engine.get();
-            Ok(engine.scene_manager.tree.get(*this).global_scale(&engine.scene_manager.tree))
+        fields.add_field_method_set("global_position", |_, this, position: Vec2| {
+            let this = this.node()?;
+            move_node(this, |tree| tree.set_global_position(this, position))
+        });
+        fields.add_field_method_set("global_rotation", |_, this, rotation: f32| {
+            let this = this.node()?;
+            move_node(this, |tree| tree.set_global_rotation(this, rotation))
+        });
+        fields.add_field_method_set("global_scale", |_, this, scale: Vec2| {
+            let this = this.node()?;
+            move_node(this, |tree| tree.set_global_scale(this, scale))
         });
 
         fields.add_field_method_get("parent", |_, this| {
+            let this = this.node()?;
             let mut engine = Engine::generate();
             let mut engine = engine.get_mut();
-            let node = engine.scene_manager.tree.get(this.0);
+            let node = engine.scene_manager.tree.get(this);
             let parent = node.parent;
 
             Ok(match parent {
@@ -98,19 +115,30 @@ impl<'a> mlua::UserData for NodeUserData {
             })
         });
 
-        fields.add_field_method_set("parent", |_, this, parent: Option<NodeId>| {
-            Engine::generate().get_mut().scene_manager.tree.set_parent(this.0, parent);
-            Ok(())
+        fields.add_field_method_set("parent", |_, this, parent: Option<NodeId>| set_parent(this.node()?, parent));
+
+        fields.add_field_method_get("name", |_, this| Ok(name(this.node()?)));
+        fields.add_field_method_set("name", |_, this, name: Option<String>| set_name(this.node()?, name));
+
+        // persistent nodes move to the new scene on a scene change
+        fields.add_field_method_get("persistent", |_, this| {
+            let this = this.node()?;
+            Ok(Engine::generate().get().scene_manager.tree.get(this).persistent)
+        });
+        fields.add_field_method_set("persistent", |_, this, persistent: bool| {
+            let this = this.node()?;
+            Ok(Engine::generate().get_mut().scene_manager.tree.get_mut(this).persistent = persistent)
         });
     }
 
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_meta_method("__index", |_, NodeUserData(node, comp), name: String| {
+        methods.add_meta_method("__index", |_, this, name: String| {
+            let NodeUserData(node, comp) = this.alive()?;
             let engine = Engine::generate();
             let engine = engine.get();
 
-            let node = engine.scene_manager.tree.get(*node);
-            let comp = node.components.get(*comp);
+            let node = engine.scene_manager.tree.get(node);
+            let comp = node.components.get(comp);
             
             let script = engine.script_manager.script(comp.script);
 
@@ -122,10 +150,11 @@ impl<'a> mlua::UserData for NodeUserData {
         });
 
 
-        methods.add_meta_method("__newindex", |lua, NodeUserData(node, comp), (name, value): (String, mlua::Value)| {
+        methods.add_meta_method("__newindex", |lua, this, (name, value): (String, mlua::Value)| {
+            let NodeUserData(node, comp) = this.alive()?;
             Engine::generate().with(|engine| {
-                let node = engine.scene_manager.tree.get_mut(*node);
-                let comp = node.components.get_mut(*comp);
+                let node = engine.scene_manager.tree.get_mut(node);
+                let comp = node.components.get_mut(comp);
                 
                 let script = engine.script_manager.script(comp.script);
 
@@ -140,11 +169,12 @@ impl<'a> mlua::UserData for NodeUserData {
         });
 
         methods.add_method("get_component", |_, this, name: String| {
+            let this = this.node()?;
             let comp = 'b: {
                 let mut engine = Engine::generate();
                 let mut engine = engine.get_mut();
                 let engine = &mut *engine;
-                let node = engine.scene_manager.tree.get_mut(this.0);
+                let node = engine.scene_manager.tree.get_mut(this);
 
                 let mut comp_index = 0u32;
                 loop {
@@ -188,14 +218,17 @@ impl<'a> mlua::UserData for NodeUserData {
         });
 
         methods.add_method("get_child", |_, this, idx: usize| {
+            let this = this.node()?;
             let mut engine = Engine::generate();
             let mut engine = engine.get_mut();
-            let node = engine.scene_manager.tree.get(this.0);
+            let node = engine.scene_manager.tree.get(this);
 
             let target = node.children[idx];
             let target = &engine.scene_manager.tree.get_mut(target).userdata();
             Ok(target.clone())
         });
+
+        add_tree_methods(methods, |this| this.node());
     }
 
 }
@@ -203,17 +236,23 @@ impl<'a> mlua::UserData for NodeUserData {
 
 impl mlua::UserData for NodeId {
     fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
-        fields.add_field_method_get("scale", |_, this| Ok(Engine::generate().get().scene_manager.tree.get(*this).properties.scale));
+        fields.add_field_method_get("scale", |_, this| {
+            let this = alive(*this)?;
+            Ok(Engine::generate().get().scene_manager.tree.get(this).properties.scale)
+        });
+        fields.add_field_method_get("name", |_, this| Ok(name(alive(*this)?)));
+        fields.add_field_method_set("name", |_, this, name: Option<String>| set_name(alive(*this)?, name));
     }
 
 
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
         methods.add_method("get_component", |_, this, name: String| {
+            let this = alive(*this)?;
             let comp = 'b: {
                 let mut engine = Engine::generate();
                 let mut engine = engine.get_mut();
                 let engine = &mut *engine;
-                let node = engine.scene_manager.tree.get_mut(*this);
+                let node = engine.scene_manager.tree.get_mut(this);
 
                 let mut comp_index = 0u32;
                 loop {
@@ -256,8 +295,327 @@ impl mlua::UserData for NodeId {
             Ok(Value::UserData(comp.0))
         });
 
+        add_tree_methods(methods, |this| alive(*this));
+    }
+}
+
+
+/// The methods that look up and change the tree,
+/// shared by nodes and the components on them
+fn add_tree_methods<T: mlua::UserData + 'static, M: mlua::UserDataMethods<T>>(methods: &mut M, node_of: fn(&T) -> mlua::Result<NodeId>) {
+    // `path` is like "Pipe/Top" or "../Player"
+    methods.add_method("get_node", move |_, this, path: String| {
+        let node = node_of(this)?;
+        let mut engine = Engine::generate();
+        let mut engine = engine.get_mut();
+        let tree = &mut engine.scene_manager.tree;
+
+        let node = tree.get_node(node, &path);
+        Ok(node.map(|node| tree.get_mut(node).userdata()))
+    });
+
+
+    methods.add_method("find_child", move |_, this, (name, recursive): (String, Option<bool>)| {
+        let node = node_of(this)?;
+        let mut engine = Engine::generate();
+        let mut engine = engine.get_mut();
+        let tree = &mut engine.scene_manager.tree;
+
+        let node = tree.find_child(node, &name, recursive.unwrap_or(true));
+        Ok(node.map(|node| tree.get_mut(node).userdata()))
+    });
+
+
+    methods.add_method("get_path", move |_, this, _: ()| {
+        let node = node_of(this)?;
+        Ok(Engine::generate().get().scene_manager.tree.get_path(node))
+    });
+
+
+    methods.add_method("add_to_group", move |_, this, group: String| {
+        let node = node_of(this)?;
+        Engine::generate().get_mut().scene_manager.tree.add_to_group(node, &group);
+        Ok(())
+    });
+
+
+    methods.add_method("remove_from_group", move |_, this, group: String| {
+        let node = node_of(this)?;
+        Engine::generate().get_mut().scene_manager.tree.remove_from_group(node, &group);
+        Ok(())
+    });
+
+
+    methods.add_method("is_in_group", move |_, this, group: String| {
+        let node = node_of(this)?;
+        Ok(Engine::generate().get().scene_manager.tree.is_in_group(node, &group))
+    });
+
+
+    methods.add_method("get_groups", move |_, this, _: ()| {
+        let node = node_of(this)?;
+        Ok(Engine::generate().get().scene_manager.tree.get(node).groups.clone())
+    });
+
+
+    methods.add_method("add_child", move |_, this, child: NodeId| {
+        let node = node_of(this)?;
+        SceneManager::add_child(&mut Engine::generate(), node, child)
+            .map_err(Error::runtime)
+    });
+
+
+    methods.add_method("is_inside_tree", move |_, this, _: ()| {
+        let node = node_of(this)?;
+        Ok(Engine::generate().get().scene_manager.tree.is_inside_tree(node))
+    });
+
+
+    // `script` is a class name or a path, `fields`
+    // override the defaults of the script
+    methods.add_method("add_component", move |_, this, (script, fields): (String, Option<Table>)| {
+        let node = node_of(this)?;
+        add_component(&mut Engine::generate(), node, &script, fields)
+    });
+
+
+    // `component` is a class name or a component of the node.
+    //
+    // this isn't a method so that `self` isn't borrowed
+    // while the components after it are renumbered
+    methods.add_function("remove_component", move |_, (this, component): (AnyUserData, Value)| {
+        let node = node_of(&*this.borrow::<T>()?)?;
+        let mut engine = Engine::generate();
+
+        let comp = match component {
+            Value::String(name) => engine.with(|engine| {
+                let name = name.to_string_lossy();
+                let node = engine.scene_manager.tree.get(node);
+                node.components.iter().find(|comp| {
+                    let script = node.components.get(*comp).script;
+                    engine.script_manager.script(script).name == name
+                })
+            }),
+
+            _ => {
+                let NodeUserData(owner, comp) = NodeUserData::from_lua(component, Engine::lua())?;
+                if owner != node {
+                    return Err(Error::runtime("the component isn't on this node"));
+                }
+
+                Some(comp)
+            },
+        };
 
+        let Some(comp) = comp
+        else { return Ok(false) };
+
+        SceneManager::remove_component(&mut engine, node, comp);
+        Ok(true)
+    });
+
+
+    // the node is freed at the end of the frame
+    methods.add_method("queue_free", move |_, this, _: ()| {
+        let node = node_of(this)?;
+        SceneTree::queue_free(&mut Engine::generate(), node);
+        Ok(())
+    });
+}
+
+
+///
+/// The `Node` module, creates nodes from nothing:
+///
+/// ```lua
+/// local node = Node.new{
+///     name = "Bullet",
+///     position = Vec2.new(4, 2),
+///     groups = { "bullets" },
+///     components = { Bullet = { speed = 200 } },
+/// }
+/// self:add_child(node)
+/// ```
+///
+pub struct LuaNode;
+
+
+impl mlua::UserData for LuaNode {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        // the node is detached, its components get `_enter_tree`
+        // and `_ready` once it's added to the tree
+        methods.add_function("new", |_, props: Option<Table>| {
+            let mut engine = Engine::generate();
+            let node = engine.get_mut().scene_manager.tree.create(NodeProperties::identity());
+
+            if let Some(props) = props {
+                if let Err(e) = apply_props(&mut engine, node, &props) {
+                    engine.get_mut().scene_manager.tree.remove(node);
+                    return Err(e);
+                }
+            }
+
+            Ok(engine.get_mut().scene_manager.tree.get_mut(node).userdata())
+        });
+    }
+}
+
+
+///
+/// Sets the properties of `node` from a table like the one
+/// `Node.new` takes, every entry is optional:
+/// `name`, `position`, `scale`, `rotation`, `modulate`,
+/// `texture`, `groups` and `components`
+///
+pub fn apply_props(engine: &mut Engine, node: NodeId, props: &Table) -> mlua::Result<()> {
+    for pair in props.pairs::<String, Value>() {
+        let (key, _) = pair?;
+        if !matches!(key.as_str(), "name" | "position" | "scale" | "rotation"
+                                   | "modulate" | "texture" | "groups" | "components"
+                                   | "persistent") {
+            return Err(Error::runtime(format!("'{key}' isn't a property of a node")));
+        }
+    }
+
+    if let Some(name) = props.get::<Option<String>>("name")? {
+        set_name(node, Some(name))?;
+    }
+
+    let position = props.get::<Option<Vec2>>("position")?;
+    let scale = props.get::<Option<Vec2>>("scale")?;
+    let rotation = props.get::<Option<f32>>("rotation")?;
+    let modulate = props.get::<Option<Colour>>("modulate")?;
+    let texture = props.get::<Option<TextureId>>("texture")?;
+    let persistent = props.get::<Option<bool>>("persistent")?;
+
+    engine.with(|engine| {
+        let tree = &mut engine.scene_manager.tree;
+        if let Some(position) = position { tree.set_position(node, position) }
+        if let Some(scale) = scale { tree.set_scale(node, scale) }
+        if let Some(rotation) = rotation { tree.set_rotation(node, rotation) }
+
+        let properties = &mut tree.get_mut(node).properties;
+        if let Some(modulate) = modulate { properties.modulate = modulate }
+        if texture.is_some() { properties.texture = texture }
+        if let Some(persistent) = persistent { tree.get_mut(node).persistent = persistent }
+
+        engine.scene_manager.sync_rigidbodies(node);
+    });
+
+
+    if let Some(groups) = props.get::<Option<Vec<String>>>("groups")? {
+        let tree = &mut engine.get_mut().scene_manager.tree;
+        for group in groups {
+            tree.add_to_group(node, &group);
+        }
+    }
+
+
+    if let Some(components) = props.get::<Option<Table>>("components")? {
+        for pair in components.pairs::<String, Option<Table>>() {
+            let (script, fields) = pair?;
+            add_component(engine, node, &script, fields)?;
+        }
+    }
+
+    Ok(())
+}
+
+
+///
+/// Adds the component `script`, which is a class name or a path,
+/// to `node` with `fields` overriding the defaults of the script.
+///
+/// If the node is inside the tree the component enters it and
+/// is readied right away
+///
+fn add_component(engine: &mut Engine, node: NodeId, script: &str, fields: Option<Table>) -> mlua::Result<AnyUserData> {
+    let (comp, is_inside) = engine.with(|engine| {
+        let Some(script_id) = engine.script_manager.path_to_script.get(script).copied()
+        else { return Err(Error::runtime(format!("there's no script named '{script}'"))) };
+
+        let script = engine.script_manager.script(script_id);
+        let mut values = KVec::with_cap(script.default_fields.len());
+        for (_, field) in script.default_fields.iter() {
+            values.push(field.value.clone());
+        }
+
+        if let Some(fields) = fields {
+            for pair in fields.pairs::<String, Value>() {
+                let (name, value) = pair?;
+
+                let Some(field) = script.fields.get(&name)
+                else { return Err(Error::runtime(format!("field '{name}' doesn't exist in '{}'", script.name))) };
+
+                values[*field] = FieldValue::new(value);
+            }
+        }
+
+        let tree = &mut engine.scene_manager.tree;
+        let comp = tree.get_mut(node).components.push(script_id, values);
+        Ok((comp, tree.is_inside_tree(node)))
+    })?;
+
+    if is_inside {
+        SceneManager::call_enter_tree(engine, node, comp);
+        SceneManager::call_ready(engine, node);
     }
+
+    Ok(engine.get_mut().scene_manager.tree.get_mut(node).userdata_of(comp))
+}
+
+
+fn set_parent(node: NodeId, parent: Option<NodeId>) -> mlua::Result<()> {
+    let mut engine = Engine::generate();
+
+    match parent {
+        Some(parent) => SceneManager::add_child(&mut engine, parent, node).map_err(Error::runtime),
+        None => Ok(SceneManager::remove_child(&mut engine, node)),
+    }
+}
+
+
+/// Changes the transform of `node` through `f` and
+/// moves the rigidbodies under it to match
+fn move_node(node: NodeId, f: impl FnOnce(&mut SceneTree)) -> mlua::Result<()> {
+    Engine::generate().with(|engine| {
+        f(&mut engine.scene_manager.tree);
+        engine.scene_manager.sync_rigidbodies(node);
+    });
+
+    Ok(())
+}
+
+
+fn global_transform(node: NodeId) -> Transform2D {
+    Engine::generate().get().scene_manager.tree.global_transform(node)
+}
+
+
+/// Fails with a Lua error if `node` has been freed,
+/// Lua can hold on to a node for longer than it lives
+fn alive(node: NodeId) -> mlua::Result<NodeId> {
+    if !Engine::generate().get().scene_manager.tree.exists(node) {
+        return Err(Error::runtime(format!("the node '{node:?}' has been freed")));
+    }
+
+    Ok(node)
+}
+
+
+fn name(node: NodeId) -> Option<String> {
+    Engine::generate().get().scene_manager.tree.get(node).name.clone()
+}
+
+
+fn set_name(node: NodeId, name: Option<String>) -> mlua::Result<()> {
+    if !Engine::generate().get_mut().scene_manager.tree.set_name(node, name.clone()) {
+        return Err(Error::runtime(format!("'{}' isn't a valid name, names can't be \
+                                           empty, contain a '/' or be '.' or '..'",
+                                           name.unwrap_or_default())));
+    }
+
+    Ok(())
 }
 
 
@@ -271,10 +629,10 @@ impl FromLua for NodeId {
         };
 
         if let Ok(value) = value.borrow::<NodeUserData>() {
-            return Ok(value.0)
+            return value.node()
         }
 
-        value.borrow::<Self>().map(|x| *x)
+        alive(*value.borrow::<Self>()?)
     }
 }
 
@@ -287,6 +645,6 @@ impl FromLua for NodeUserData {
             return Err(Error::runtime(format!("expected a 'NodeId' found '{:?}'", value)));
         };
 
-        Ok(*value)
+        value.alive()
     }
 }
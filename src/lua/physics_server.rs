@@ -29,8 +29,9 @@ impl UserData for Physics {
             Ok(userdata)
         });
 
-        methods.add_function("create_static_rigidbody", |lua, _: ()| {
-            let userdata = Engine::generate().get_mut().scene_manager.physics.create_static_rigidbody(lua).1;
+        // the node is optional, with it the body is freed along with the node
+        methods.add_function("create_static_rigidbody", |lua, node: Option<NodeUserData>| {
+            let userdata = Engine::generate().get_mut().scene_manager.physics.create_static_rigidbody(lua, node.map(|x| x.0)).1;
             Ok(userdata)
         });
 
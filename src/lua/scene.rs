@@ -1,31 +1,201 @@
-use crate::{engine::Engine, scene_manager::{scene_template::TemplateScene, SceneManager, TemplateId}};
+use mlua::{Error, MultiValue, Table, Value};
+
+use crate::{async_loader::{AsyncLoader, LoadHandle, LoadState}, engine::Engine, scene_manager::{scene_template::TemplateScene, scene_tree::SceneTree, transition::{SceneChange, Transition}, NodeId, SceneManager, TemplateId}};
+
+use super::node::apply_props;
+
+/// The length of a transition when the script doesn't pick one
+const DEFAULT_TRANSITION_DURATION : f32 = 0.5;
+
 
 pub struct Scene;
 
 impl mlua::UserData for Scene {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        // persistent nodes don't survive a restart
         methods.add_function("restart_game", |_, _: ()| {
-            let mut engine = Engine::generate();
-            let entry = &Engine::project_settings().world.entry_scene;
-            Engine::change_scene(&mut engine, entry);
+            let entry = Engine::project_settings().world.entry_scene.clone();
+            let mut change = SceneChange::new(entry, Transition::None, 0.0);
+            change.keep_persistent = false;
+
+            Engine::generate().get_mut().scene_manager.queue_change(change);
+            Ok(())
+        });
+
+
+        // swaps to the scene at `path` at the end of the frame,
+        // `options` can pick a `transition` and its `duration`
+        methods.add_function("change_scene", |_, (path, options): (String, Option<Table>)| {
+            let mut transition = Transition::None;
+            let mut duration = DEFAULT_TRANSITION_DURATION;
+
+            if let Some(options) = options {
+                for pair in options.pairs::<String, Value>() {
+                    let (key, _) = pair?;
+                    if !matches!(key.as_str(), "transition" | "duration") {
+                        return Err(Error::runtime(format!("'{key}' isn't an option of a scene change")));
+                    }
+                }
+
+                if let Some(name) = options.get::<Option<String>>("transition")? {
+                    transition = Transition::from_name(&name)
+                        .ok_or_else(|| Error::runtime(format!("'{name}' isn't a transition, \
+                                                              expected 'none', 'fade' or 'wipe'")))?;
+                }
+
+                if let Some(value) = options.get::<Option<f32>>("duration")? {
+                    if value < 0.0 {
+                        return Err(Error::runtime(format!("a transition can't take '{value}' seconds")));
+                    }
+
+                    duration = value;
+                }
+            }
+
+            let change = SceneChange::new(path, transition, duration);
+            Engine::generate().get_mut().scene_manager.queue_change(change);
             Ok(())
         });
 
 
         methods.add_function("load", |_, name: String| {
-            let scene = SceneManager::template_from_file(&mut Engine::generate(),
-                                                         &name); 
+            let mut engine = Engine::generate();
+            let scene = SceneManager::template_from_file(&mut engine, &name); 
+            engine.get_mut().scene_manager.retain_template(scene);
             Ok(scene)
         });
+
+
+        // the template is kept loaded until it's unloaded, it's
+        // then evicted on the next scene change
+        methods.add_function("unload", |_, template: TemplateId| {
+            Engine::generate().get_mut().scene_manager.release_template(template);
+            Ok(())
+        });
+
+
+        methods.add_function("load_async", |_, name: String| {
+            let mut engine = Engine::generate();
+            let id = AsyncLoader::load_scene(&mut engine, &name);
+            Ok(engine.get().async_loader.handle(id))
+        });
+
+
+        methods.add_function("get_nodes_in_group", |_, group: String| {
+            Engine::generate().with(|engine| {
+                let tree = &mut engine.scene_manager.tree;
+                let nodes = tree.nodes_in_group(&group).into_iter()
+                    .map(|node| tree.get_mut(node).userdata())
+                    .collect::<Vec<_>>();
+                Ok(nodes)
+            })
+        });
+
+
+        // calls `method` on every component of every node in the
+        // group that has it, with the component and `args`
+        methods.add_function("call_group", |_, (group, method, args): (String, String, MultiValue)| {
+            let calls = Engine::generate().with(|engine| {
+                let tree = &mut engine.scene_manager.tree;
+                let mut calls = vec![];
+
+                for node in tree.nodes_in_group(&group) {
+                    let node = tree.get_mut(node);
+                    for comp in node.components.iter() {
+                        let component = node.components.get(comp);
+                        let script = engine.script_manager.script(component.script);
+
+                        let Some(field) = script.fields.get(&method)
+                        else { continue };
+
+                        let Value::Function(function) = component.fields[*field].value()
+                        else { continue };
+
+                        let function = function.clone();
+                        calls.push((function, node.userdata_of(comp)));
+                    }
+                }
+
+                calls
+            });
+
+            // the engine isn't borrowed so the
+            // methods can do whatever they want
+            for (function, userdata) in calls {
+                function.call::<()>((userdata, args.clone()))?;
+            }
+
+            Ok(())
+        });
+
+
+        // writes `node` and its children as a scene file
+        methods.add_function("save", |_, (node, path): (NodeId, String)| {
+            SceneManager::save(&Engine::generate(), node, &path)
+                .map_err(|e| Error::runtime(format!("unable to save '{path}': {e}")))
+        });
+    }
+}
+
+
+impl mlua::UserData for LoadHandle {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("progress", |_, this| {
+            Ok(Engine::generate().get().async_loader.job(this.id).progress())
+        });
+
+        fields.add_field_method_get("done", |_, this| {
+            Ok(Engine::generate().get().async_loader.job(this.id).state != LoadState::Loading)
+        });
+
+        fields.add_field_method_get("failed", |_, this| {
+            Ok(Engine::generate().get().async_loader.job(this.id).state == LoadState::Failed)
+        });
+
+        fields.add_field_method_get("scene", |_, this| {
+            match Engine::generate().get().async_loader.job(this.id).state {
+                LoadState::Done(template) => Ok(Some(template)),
+                _ => Ok(None),
+            }
+        });
     }
 }
 
 
 impl mlua::UserData for TemplateId {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method("instantiate", |_, this, _: ()| {
-            println!("instantiate");
-            Ok(TemplateScene::instantiate(&mut Engine::generate(), *this))
+        // `props` are applied to the root like in `Node.new`,
+        // without a `parent` the root is left detached
+        methods.add_method("instantiate", |_, this, (parent, props): (Option<NodeId>, Option<Table>)| {
+            let mut engine = Engine::generate();
+            let Some(root) = TemplateScene::instantiate(&mut engine, *this)
+            else { return Ok(None) };
+
+            if let Some(props) = props {
+                if let Err(e) = apply_props(&mut engine, root, &props) {
+                    SceneTree::queue_free(&mut engine, root);
+                    return Err(e);
+                }
+            }
+
+            if let Some(parent) = parent {
+                SceneManager::add_child(&mut engine, parent, root).map_err(Error::runtime)?;
+            }
+
+            Ok(Some(engine.get_mut().scene_manager.tree.get_mut(root).userdata()))
         });
     }
 }
+
+
+impl mlua::FromLua for TemplateId {
+    fn from_lua(value: mlua::Value, _: &mlua::Lua) -> mlua::Result<Self> {
+        let Value::UserData(data) = value
+        else { return Err(mlua::Error::RuntimeError(format!("'{value:?}' isn't a scene"))) };
+
+        let Ok(data) = data.borrow::<TemplateId>()
+        else { return Err(mlua::Error::RuntimeError(format!("'{data:?}' isn't a scene"))) };
+
+        Ok(*data)
+    }
+}
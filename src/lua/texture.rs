@@ -1,26 +1,95 @@
-use mlua::Value;
+use mlua::{Error, Table, Value};
 
-use crate::engine::Engine;
+use crate::{asset_manager::{import::{ImportSettings, TextureFilter}, split_frame, AssetManager, TextureId}, engine::Engine};
 
 pub struct LuaTexture;
 impl mlua::UserData for LuaTexture {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        // kept for older scripts, images are
+        // no longer loaded as RGBA32F
         methods.add_function("from_rgbaf32", |_, path: String| {
-            Ok(Engine::generate().get_mut().asset_manager.from_image(&path))
+            let mut engine = Engine::generate();
+            let mut engine = engine.get_mut();
+            let texture = engine.asset_manager.from_image(&path);
+            if let Some(texture) = texture { engine.asset_manager.acquire(texture) }
+            Ok(texture)
+        });
+
+
+        methods.add_function("load", |_, (path, options): (String, Option<Table>)| {
+            // the options override the import settings of the image
+            let (image_path, _) = split_frame(&path);
+            let mut import = ImportSettings::load(Engine::file_system(), image_path).texture();
+            if let Some(options) = options {
+                if let Some(premultiply_alpha) = options.get("premultiply_alpha")? {
+                    import.premultiply_alpha = premultiply_alpha;
+                }
+
+                if let Some(srgb) = options.get("srgb")? {
+                    import.srgb = srgb;
+                }
+
+                if let Some(filter) = options.get::<Option<String>>("filter")? {
+                    import.filter = match filter.as_str() {
+                        "nearest" => TextureFilter::Nearest,
+                        "linear" => TextureFilter::Linear,
+                        _ => return Err(Error::runtime(format!("'{filter}' isn't a filter, \
+                                                                 use either 'nearest' or 'linear'"))),
+                    };
+                }
+            }
+
+            let mut engine = Engine::generate();
+            let mut engine = engine.get_mut();
+            let texture = engine.asset_manager.from_image_with(&path, import);
+            if let Some(texture) = texture { engine.asset_manager.acquire(texture) }
+            Ok(texture)
+        });
+
+
+        methods.add_function("frames", |_, path: String| {
+            let mut engine = Engine::generate();
+            let mut engine = engine.get_mut();
+
+            let import = ImportSettings::load(Engine::file_system(), &path).texture();
+            let frames = engine.asset_manager.frames(&path, import).unwrap_or_default();
+            for frame in frames.iter() {
+                engine.asset_manager.acquire(*frame);
+            }
+
+            Ok(frames)
+        });
+
+
+        // keeps a texture that the script didn't load itself,
+        // like a node's sprite, loaded until it's unloaded
+        methods.add_function("acquire", |_, texture: TextureId| {
+            Engine::generate().get_mut().asset_manager.acquire(texture);
+            Ok(())
+        });
+
+
+        // textures loaded from scripts stay loaded
+        // until they're unloaded from a script
+        methods.add_function("unload", |_, texture: TextureId| {
+            let mut engine = Engine::generate();
+            engine.get_mut().asset_manager.release(texture);
+            AssetManager::collect_garbage(&mut engine);
+            Ok(())
         });
     }
 
 }
 
 
-impl mlua::UserData for crate::asset_manager::TextureId {}
+impl mlua::UserData for TextureId {}
 
-impl mlua::FromLua for crate::asset_manager::TextureId {
+impl mlua::FromLua for TextureId {
     fn from_lua(value: mlua::Value, _: &mlua::Lua) -> mlua::Result<Self> {
         let Value::UserData(data) = value
         else { return Err(mlua::Error::RuntimeError(format!("'{value:?}' can't be assigned to a texture"))) };
 
-        let Ok(data) = data.borrow::<crate::asset_manager::TextureId>()
+        let Ok(data) = data.borrow::<TextureId>()
         else { return Err(mlua::Error::RuntimeError(format!("'{data:?}' can't be assigned to a texture"))) };
 
         Ok(*data)
@@ -1,6 +1,6 @@
-use std::{env, process::ExitCode};
+use std::{env, path::Path, process::ExitCode};
 
-use butter::start;
+use butter::{asset_manager::atlas::Atlas, deserialize::scene_file::upgrade_scenes, export::{export, GAME_ARCHIVE}, file_system::FileSystem, start};
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
@@ -11,8 +11,89 @@ pub fn main() -> ExitCode {
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let dir = env::args().skip(1).next().unwrap();
-    env::set_current_dir(dir).unwrap();
+    let args = env::args().skip(1).collect::<Vec<_>>();
 
-    start()
+    match args.iter().map(|x| x.as_str()).collect::<Vec<_>>().as_slice() {
+        ["export", project, out] => {
+            return match export(Path::new(project), Path::new(out)) {
+                Ok(report) if report.missing.is_empty() => ExitCode::SUCCESS,
+                Ok(_) => ExitCode::FAILURE,
+                Err(e) => {
+                    eprintln!("export failed: {e}");
+                    ExitCode::FAILURE
+                },
+            };
+        },
+
+
+        ["export", ..] => {
+            eprintln!("usage: butter export <project directory> <output directory>");
+            return ExitCode::FAILURE;
+        },
+
+
+        ["atlas", project, folder] => {
+            let mut file_system = FileSystem::new();
+            file_system.mount_directory(project);
+
+            let atlas = Atlas::build(&file_system, folder)
+                .and_then(|atlas| atlas.write(Path::new(project)));
+
+            return match atlas {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("packing the atlas failed: {e}");
+                    ExitCode::FAILURE
+                },
+            };
+        },
+
+
+        ["atlas", ..] => {
+            eprintln!("usage: butter atlas <project directory> <folder in the project>");
+            return ExitCode::FAILURE;
+        },
+
+
+        ["upgrade", project] => {
+            return match upgrade_scenes(Path::new(project)) {
+                Ok(count) => {
+                    println!("upgraded {count} scenes");
+                    ExitCode::SUCCESS
+                },
+
+                Err(e) => {
+                    eprintln!("upgrading the scenes failed: {e}");
+                    ExitCode::FAILURE
+                },
+            };
+        },
+
+
+        ["upgrade", ..] => {
+            eprintln!("usage: butter upgrade <project directory>");
+            return ExitCode::FAILURE;
+        },
+
+
+        _ => (),
+    }
+
+
+    // the project can either be a directory or a packed archive,
+    // if none is given, look for the archive of an exported game
+    let path = match args.first() {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => {
+            let exe = env::current_exe().unwrap();
+            exe.with_file_name(GAME_ARCHIVE)
+        },
+    };
+
+    let mut file_system = FileSystem::new();
+    if file_system.mount(&path).is_err() {
+        return ExitCode::FAILURE;
+    }
+
+    start(file_system)
 }
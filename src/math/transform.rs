@@ -0,0 +1,167 @@
+use std::ops::Mul;
+
+use super::{matrix::{Matrix, Matrix4}, vector::Vec2};
+
+
+///
+/// A 2D affine transform, `x` and `y` are the axes
+/// of the basis and `origin` is the translation.
+///
+/// Built as translation * rotation * scale so a node
+/// is scaled first, then rotated and then moved
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform2D {
+    pub x: Vec2,
+    pub y: Vec2,
+    pub origin: Vec2,
+}
+
+
+impl Transform2D {
+    pub const IDENTITY : Self = Self {
+        x: Vec2 { x: 1.0, y: 0.0 },
+        y: Vec2 { x: 0.0, y: 1.0 },
+        origin: Vec2 { x: 0.0, y: 0.0 },
+    };
+
+
+    pub fn new(position: Vec2, rotation: f32, scale: Vec2) -> Self {
+        let (sin, cos) = rotation.sin_cos();
+        Self {
+            x: Vec2::new(cos * scale.x, sin * scale.x),
+            y: Vec2::new(-sin * scale.y, cos * scale.y),
+            origin: position,
+        }
+    }
+
+
+    pub fn position(&self) -> Vec2 {
+        self.origin
+    }
+
+
+    pub fn rotation(&self) -> f32 {
+        self.x.y.atan2(self.x.x)
+    }
+
+
+    /// A mirrored transform gets a negative y scale
+    pub fn scale(&self) -> Vec2 {
+        let sign = if self.determinant() < 0.0 { -1.0 } else { 1.0 };
+        Vec2::new(self.x.x.hypot(self.x.y), sign * self.y.x.hypot(self.y.y))
+    }
+
+
+    pub fn determinant(&self) -> f32 {
+        self.x.x * self.y.y - self.x.y * self.y.x
+    }
+
+
+    /// Returns the identity if the transform has a zero scale
+    pub fn inverse(&self) -> Self {
+        let det = self.determinant();
+        if det == 0.0 { return Self::IDENTITY }
+
+        let inv = 1.0 / det;
+        let x = Vec2::new(self.y.y * inv, -self.x.y * inv);
+        let y = Vec2::new(-self.y.x * inv, self.x.x * inv);
+        let origin = Vec2::new(
+            -(x.x * self.origin.x + y.x * self.origin.y),
+            -(x.y * self.origin.x + y.y * self.origin.y),
+        );
+
+        Self { x, y, origin }
+    }
+
+
+    /// Transforms a point
+    pub fn apply(&self, point: Vec2) -> Vec2 {
+        let v = self.apply_basis(point);
+        Vec2::new(v.x + self.origin.x, v.y + self.origin.y)
+    }
+
+
+    /// Transforms a direction, leaving out the translation
+    pub fn apply_basis(&self, v: Vec2) -> Vec2 {
+        Vec2::new(self.x.x * v.x + self.y.x * v.y,
+                  self.x.y * v.x + self.y.y * v.y)
+    }
+
+
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        Matrix::new([
+            [self.x.x, self.x.y, 0.0, 0.0],
+            [self.y.x, self.y.y, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [self.origin.x, self.origin.y, 0.0, 1.0],
+        ])
+    }
+}
+
+
+impl Mul for Transform2D {
+    type Output = Self;
+
+    /// `self * rhs` applies `rhs` first
+    fn mul(self, rhs: Self) -> Self {
+        Self {
+            x: self.apply_basis(rhs.x),
+            y: self.apply_basis(rhs.y),
+            origin: self.apply(rhs.origin),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+
+    fn assert_near(a: Vec2, b: Vec2) {
+        assert!((a.x - b.x).abs() < 1e-4 && (a.y - b.y).abs() < 1e-4, "{a} vs {b}");
+    }
+
+
+    #[test]
+    fn transform_decompose() {
+        let t = Transform2D::new(Vec2::new(3.0, -2.0), 0.75, Vec2::new(2.0, 0.5));
+        assert_near(t.position(), Vec2::new(3.0, -2.0));
+        assert!((t.rotation() - 0.75).abs() < 1e-4);
+        assert_near(t.scale(), Vec2::new(2.0, 0.5));
+
+        let mirrored = Transform2D::new(Vec2::new(0.0, 0.0), 0.0, Vec2::new(1.0, -3.0));
+        assert_near(mirrored.scale(), Vec2::new(1.0, -3.0));
+    }
+
+
+    #[test]
+    fn transform_hierarchy() {
+        // a child one unit to the right of a parent that's
+        // rotated a quarter turn ends up above the parent
+        let parent = Transform2D::new(Vec2::new(10.0, 0.0), FRAC_PI_2, Vec2::new(2.0, 2.0));
+        let child = Transform2D::new(Vec2::new(1.0, 0.0), 0.0, Vec2::new(1.0, 1.0));
+
+        let global = parent * child;
+        assert_near(global.position(), Vec2::new(10.0, 2.0));
+        assert!((global.rotation() - FRAC_PI_2).abs() < 1e-4);
+        assert_near(global.scale(), Vec2::new(2.0, 2.0));
+
+        let local = parent.inverse() * global;
+        assert_near(local.position(), child.position());
+        assert_near(local.apply(Vec2::new(3.0, 4.0)), child.apply(Vec2::new(3.0, 4.0)));
+    }
+
+
+    #[test]
+    fn transform_matrix() {
+        let t = Transform2D::new(Vec2::new(5.0, 6.0), 1.2, Vec2::new(3.0, 0.5));
+        let point = Vec2::new(2.0, -1.0);
+
+        let m = t.to_matrix() * Matrix::new([[point.x, point.y, 0.0, 1.0]]);
+        assert_near(Vec2::new(m[0][0], m[0][1]), t.apply(point));
+    }
+}
@@ -1,11 +1,10 @@
 use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
 
-use genmap::Handle;
 use mlua::{AnyUserData, Lua};
 use rapier2d::prelude::{ActiveEvents, CCDSolver, Collider, ColliderBuilder, ColliderHandle, ColliderSet, CollisionEvent, DefaultBroadPhase, ImpulseJointSet, IntegrationParameters, IslandManager, MultibodyJointSet, NarrowPhase, PhysicsPipeline, QueryPipeline, RigidBody, RigidBodyBuilder, RigidBodyHandle, RigidBodySet};
 use tracing::{error, info};
 
-use crate::{engine::{Engine, Timers}, lua::node::NodeUserData, math::vector::Vec2, scene_manager::{scene_tree::SceneTree, NodeId}};
+use crate::{engine::{Engine, Timers}, lua::node::NodeUserData, math::{transform::Transform2D, vector::Vec2}, scene_manager::{scene_tree::SceneTree, NodeId}};
 
 pub struct PhysicsServer {
     pub gravity: Vec2,
@@ -98,11 +97,16 @@ impl PhysicsServer {
     }
 
 
-    pub fn create_static_rigidbody(&mut self, lua: &Lua) -> (RigidBodyId, AnyUserData) {
+    /// Static bodies don't move their node, `owner` is
+    /// only used to release the body with the node
+    pub fn create_static_rigidbody(&mut self, lua: &Lua, owner: Option<NodeId>) -> (RigidBodyId, AnyUserData) {
         info!("creating a static rigid body");
         let id = RigidBodyId(self.rigid_body_set.insert(RigidBodyBuilder::new(rapier2d::prelude::RigidBodyType::Fixed).build()));
         let userdata = lua.create_userdata(id).unwrap();
         self.rigidbody_userdata.insert(id, userdata.clone());
+        if let Some(owner) = owner {
+            self.node_to_rigidbody.insert(owner, id);
+        }
         (id, userdata)
     }
 
@@ -120,18 +124,36 @@ impl PhysicsServer {
 
     
     pub fn delete_rb(&mut self, rbid: RigidBodyId) {
-        let rb = self.rigid_body_set.get(rbid.0).unwrap();
-        if !rb.is_fixed() {
-            let [gen, idx] = unsafe { core::mem::transmute(rb.user_data) };
-            let node = NodeId(Handle { gen, idx });
-            self.node_to_rigidbody.remove(&node);
+        if self.rigidbody_userdata.remove(&rbid).is_none() {
+            error!("'{rbid:?}' has already been deleted");
+            return;
         }
 
-        self.rigidbody_userdata.remove(&rbid);
+        self.node_to_rigidbody.retain(|_, rb| *rb != rbid);
         self.rigid_body_set.remove(rbid.0, &mut self.island_manager, &mut self.collider_set, &mut self.impulse_joint_set, &mut self.multibody_joint_set, false);
     }
 
 
+    /// Deletes the rigidbody and the colliders
+    /// that belong to a node that's being freed
+    pub fn release_node(&mut self, node: NodeId) {
+        if let Some(rb) = self.node_to_rigidbody.get(&node).copied() {
+            info!("releasing the rigidbody of freed node '{node:?}'");
+            self.delete_rb(rb);
+        }
+
+        let colliders = self.collider_userdata.iter()
+            .filter(|(_, data)| data.node.0 == node)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for collider in colliders {
+            info!("releasing a collider of freed node '{node:?}'");
+            self.delete_collider(collider);
+        }
+    }
+
+
     pub fn attach_collider_event(&mut self, cl: ColliderId, func: mlua::Function) {
         self.collider_userdata.get_mut(&cl).unwrap().events.push(func);
     }
@@ -215,6 +237,7 @@ impl PhysicsServer {
                                    but the node was freed without detaching from the \
                                    rigidbody. detaching.");
                             to_be_removed.push(*node_id);
+                            continue;
                         }
 
                         let rb = physics.rigid_body_set.get(rb.0).unwrap();
@@ -224,8 +247,9 @@ impl PhysicsServer {
                         let pos = Vec2::new(pos.translation.x, pos.translation.y);
                         let rot = rb.rotation().angle();
 
-                        tree.set_global_position(*node_id, pos);
-                        tree.set_global_rotation(*node_id, rot);
+                        // the body has no scale so the node keeps its own
+                        let scale = tree.global_transform(*node_id).scale();
+                        tree.set_global_transform(*node_id, Transform2D::new(pos, rot, scale));
                     }
 
 
@@ -1,13 +1,20 @@
-use sokol::{debugtext as sdtx, gfx::{self as sg, Bindings, PassAction, Pipeline}};
+use sokol::{debugtext as sdtx, gfx::{self as sg, Bindings, PassAction, Pipeline, Sampler}};
 use tracing::{trace, Level};
 
-use crate::{asset_manager::{AssetManager, TextureId}, math::{matrix::{Matrix, Matrix4}, vector::{Vec2, Vec3, Vec4}}, settings::ProjectSettings, Camera};
+use crate::{asset_manager::{import::TextureFilter, AssetManager, TextureId}, math::{matrix::{Matrix, Matrix4}, transform::Transform2D, vector::{Vec2, Vec3, Vec4}}, settings::ProjectSettings, Camera};
 
 #[derive(Debug)]
 pub struct Renderer {
     pub pass_action: PassAction,
     pub bind: Bindings,
     pub render_pip: Pipeline,
+    /// The same as `render_pip` but for textures
+    /// with premultiplied alpha
+    pub premultiplied_pip: Pipeline,
+    /// The pipeline that is currently applied
+    current_pip: Pipeline,
+    pub nearest_sampler: Sampler,
+    pub linear_sampler: Sampler,
 
     pub vp : Matrix4<f32>,
     pub aspect_ratio: f32,
@@ -24,6 +31,10 @@ impl Renderer {
             pass_action: PassAction::new(),
             bind: Bindings::new(),
             render_pip: Pipeline::new(),
+            premultiplied_pip: Pipeline::new(),
+            current_pip: Pipeline::new(),
+            nearest_sampler: Sampler::new(),
+            linear_sampler: Sampler::new(),
             vp: Matrix4::IDENTITY,
             draw_calls: 0,
             aspect_ratio: {
@@ -80,6 +91,7 @@ impl Renderer {
 
         trace!("apply pipeline");
         sg::apply_pipeline(self.render_pip);
+        self.current_pip = self.render_pip;
 
         let physical_height = sokol::app::heightf();
         let physical_width = sokol::app::widthf();
@@ -126,11 +138,23 @@ impl Renderer {
 }
 
 
+/// The uniforms of the vertex shader
+#[repr(C)]
+struct VsParams {
+    mvp: Matrix4<f32>,
+    /// The part of the texture that is drawn
+    /// as `(x, y, width, height)` in UV space
+    uv_rect: Vec4,
+}
+
+
 pub struct FrameQuad<'me> {
     renderer: &'me mut Renderer,
     pos: Vec2,
     scale: Vec2,
     rot: f32,
+    /// Overrides the position, scale and rotation when set
+    transform: Option<Transform2D>,
     texture: TextureId,
     modulate: Vec4,
 }
@@ -143,6 +167,7 @@ impl<'me> FrameQuad<'me> {
             pos: Vec2::new(0.0, 0.0),
             scale: Vec2::new(1.0, 1.0),
             rot: 0.0,
+            transform: None,
             texture: TextureId::WHITE,
             modulate: Vec4::new(1.0, 1.0, 1.0, 1.0)
         }
@@ -167,6 +192,12 @@ impl<'me> FrameQuad<'me> {
     }
 
 
+    pub fn transform(mut self, transform: Transform2D) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+
     pub fn modulate(mut self, modulate: Vec4) -> Self {
         self.modulate = modulate;
         self
@@ -180,8 +211,15 @@ impl<'me> FrameQuad<'me> {
 
 
     pub fn mvp(&self) -> Matrix4<f32> {
-        let model = Matrix::pos_scale_rot(self.pos, self.scale, self.rot);
-        self.renderer.vp * model
+        self.renderer.vp * self.model()
+    }
+
+
+    fn model(&self) -> Matrix4<f32> {
+        match self.transform {
+            Some(transform) => transform.to_matrix(),
+            None => Matrix::pos_scale_rot(self.pos, self.scale, self.rot),
+        }
     }
 
 
@@ -190,17 +228,41 @@ impl<'me> FrameQuad<'me> {
         trace!(" - position: {}", self.pos);
         trace!(" - scale   : {}", self.scale);
         trace!(" - rotation: {}", self.rot);
+        trace!(" - transform: {:?}", self.transform);
         trace!(" - modulate: {}", self.modulate);
         trace!(" - texture : {}", self.texture.inner());
 
-        let model = Matrix::pos_scale_rot(self.pos, self.scale, self.rot);
-        let mvp = self.renderer.vp * model;
+        let mvp = self.mvp();
+
+        let texture = asset_manager.texture(self.texture);
+        let pipeline = if texture.is_premultiplied() { self.renderer.premultiplied_pip }
+                       else { self.renderer.render_pip };
 
-        self.renderer.bind.images[0] = asset_manager.texture(self.texture).inner();
+        if pipeline.id != self.renderer.current_pip.id {
+            trace!("switching pipelines");
+            sg::apply_pipeline(pipeline);
+            self.renderer.current_pip = pipeline;
+        }
+
+        self.renderer.bind.images[0] = texture.inner();
+        self.renderer.bind.samplers[0] = match texture.filter() {
+            TextureFilter::Nearest => self.renderer.nearest_sampler,
+            TextureFilter::Linear => self.renderer.linear_sampler,
+        };
         sg::apply_bindings(&self.renderer.bind);
 
-        sg::apply_uniforms(0, &sg::Range { ptr: ((&mvp) as *const Matrix4<f32>).cast(), size: 64 });
-        sg::apply_uniforms(1, &sg::Range { ptr: ((&self.modulate) as *const Vec4).cast(), size: 16 });
+        let [x, y, w, h] = texture.uv();
+        let vs_params = VsParams { mvp, uv_rect: Vec4::new(x, y, w, h) };
+        sg::apply_uniforms(0, &sg::Range { ptr: ((&vs_params) as *const VsParams).cast(), size: size_of::<VsParams>() });
+        // with premultiplied blending the modulate colour
+        // has to be premultiplied as well for its alpha
+        // to have an effect
+        let modulate = if texture.is_premultiplied() {
+            let m = self.modulate;
+            Vec4::new(m.x * m.w, m.y * m.w, m.z * m.w, m.w)
+        } else { self.modulate };
+
+        sg::apply_uniforms(1, &sg::Range { ptr: ((&modulate) as *const Vec4).cast(), size: 16 });
 
         sg::draw(0, 6, 1);
         self.renderer.draw_calls += 1;
@@ -1,17 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use genmap::Handle;
+use rapier2d::na::Isometry2;
 use node::ComponentId;
 use scene_template::TemplateScene;
 use scene_tree::SceneTree;
+use transition::SceneChange;
 use sti::{define_key, keyed::KVec};
-use tracing::{error, info};
+use tracing::{error, info, trace, warn};
 
-use crate::{engine::Engine, math::vector::Vec2, physics::PhysicsServer};
+use crate::{asset_manager::TextureId, engine::Engine, file_watcher::FileWatcher, math::vector::Vec2, physics::PhysicsServer};
 
 pub mod node;
 pub mod scene_template;
 pub mod scene_tree;
+pub mod hot_reload;
+pub mod transition;
 
 
 define_key!(u32, pub TemplateId);
@@ -23,8 +27,21 @@ pub struct SceneManager {
     pub path_to_template: HashMap<String, TemplateId>,
     pub physics: PhysicsServer,
     pub tree: SceneTree,
-    pub queue_change: Option<TemplateScene>,
+    /// Swapped in at the end of the frame, see `process_change`
+    pub queue_change: Option<SceneChange>,
+    /// The path of the scene that was last changed to
+    pub current_scene: Option<String>,
+    /// How many times each template is held on to by scripts
+    /// or load jobs, these are never evicted on a scene change
+    pub retained_templates: HashMap<TemplateId, u32>,
+    pub watcher: FileWatcher,
+    /// The scenes that are being loaded, a scene that
+    /// instances one of these would never finish loading
+    pub loading_scenes: Vec<String>,
     initialized: InitState,
+    /// Templates whose ids were handed out before the scene
+    /// manager initialized and that haven't been loaded yet
+    reserved: HashSet<TemplateId>,
 }
 
 
@@ -47,19 +64,40 @@ impl SceneManager {
             templates: KVec::new(),
             tree: SceneTree::new(),
             queue_change: None,
+            current_scene: None,
+            retained_templates: HashMap::new(),
+            watcher: FileWatcher::new(),
+            loading_scenes: vec![],
             initialized: InitState::NotInitialized(KVec::new()),
+            reserved: HashSet::new(),
         }
     }
 
 
+    pub fn is_initialized(&self) -> bool {
+        matches!(self.initialized, InitState::Initialized)
+    }
+
+
+    /// Returns the template of the scene at `path`, loading it
+    /// if it isn't already cached
     pub fn template_from_file(engine: &mut Engine, path: &str) -> TemplateId {
         info!("loading template at '{path}'");
         {
             let mut engine = engine.get_mut();
-            match &mut engine.scene_manager.initialized {
+            let sm = &mut engine.scene_manager;
+
+            if let Some(template) = sm.path_to_template.get(path) {
+                info!("template is already loaded");
+                return *template;
+            }
+
+            match &mut sm.initialized {
                 InitState::NotInitialized(kvec) => {
                     info!("scene manager not initialized");
-                    return kvec.push(path.to_string());
+                    let id = kvec.push(path.to_string());
+                    sm.path_to_template.insert(path.to_string(), id);
+                    return id;
                 },
                 
                 _ => (),
@@ -68,7 +106,26 @@ impl SceneManager {
 
         info!("scene manager initialized");
         let scene = TemplateScene::from_file(engine, path);
-        engine.with(|engine| engine.scene_manager.templates.push(scene))
+        Self::insert_template(engine, path, scene)
+    }
+
+
+    /// Caches an already loaded template under `path`.
+    ///
+    /// If a template for `path` has been loaded in the
+    /// meantime that one is kept and returned instead
+    pub fn insert_template(engine: &mut Engine, path: &str, scene: TemplateScene) -> TemplateId {
+        engine.with(|engine| {
+            let sm = &mut engine.scene_manager;
+            if let Some(template) = sm.path_to_template.get(path) {
+                return *template;
+            }
+
+            let id = sm.templates.push(scene);
+            sm.path_to_template.insert(path.to_string(), id);
+            sm.watcher.watch(path);
+            id
+        })
     }
 
 
@@ -76,9 +133,9 @@ impl SceneManager {
         info!("initializing scene manager templates");
 
         let mut engine_ref = engine.get_mut();
+        let sm = &mut engine_ref.scene_manager;
 
-        let InitState::NotInitialized(kvec) = 
-            &mut engine_ref.scene_manager.initialized
+        let InitState::NotInitialized(kvec) = &mut sm.initialized
         else {
             error!("scene manager has already initialized templates");
             return;
@@ -86,27 +143,150 @@ impl SceneManager {
 
 
         let kvec = core::mem::replace(kvec, KVec::new());
+        sm.initialized = InitState::Initialized;
 
+        // every id has to be valid before anything loads as
+        // the scenes can instance each other in any order
+        assert!(sm.templates.is_empty());
+        for (id, _) in kvec.iter() {
+            let given_id = sm.templates.push(TemplateScene::new());
+            debug_assert_eq!(id, given_id);
+            sm.reserved.insert(id);
+        }
 
         drop(engine_ref);
 
-        let mut templates = KVec::new();
-
         for (id, path) in kvec.iter() {
-            let template = TemplateScene::from_file(engine, &path);
-            let given_id = templates.push(template);
+            // an earlier scene could have instanced it already
+            let is_reserved = engine.get().scene_manager.reserved.contains(&id);
+            if is_reserved {
+                Self::load_reserved(engine, id, path);
+            }
+        }
+    }
 
-            debug_assert_eq!(id, given_id);
+
+    /// Returns the template of the scene at `path` for another
+    /// scene to instance, loading it right away even if its
+    /// id was handed out before the scene manager initialized
+    pub fn instanced_template(engine: &mut Engine, path: &str) -> TemplateId {
+        let id = Self::template_from_file(engine, path);
+
+        // a template that failed to load stays empty,
+        // it isn't read again for every instance
+        let is_reserved = engine.get().scene_manager.reserved.contains(&id);
+        if is_reserved {
+            Self::load_reserved(engine, id, path);
         }
 
+        id
+    }
+
+
+    /// Loads the scene at `path` into an id that was
+    /// handed out before the scene manager initialized
+    fn load_reserved(engine: &mut Engine, id: TemplateId, path: &str) {
+        engine.get_mut().scene_manager.reserved.remove(&id);
+        let template = TemplateScene::from_file(engine, path);
+
         engine.with(|engine| {
-            assert!(engine.scene_manager.templates.is_empty());
-            engine.scene_manager.templates = templates;
-            engine.scene_manager.initialized = InitState::Initialized
+            let sm = &mut engine.scene_manager;
+            sm.templates[id] = template;
+            sm.watcher.watch(path);
         });
     }
 
 
+    /// Keeps the template loaded across scene changes until
+    /// every retain is matched by a release
+    pub fn retain_template(&mut self, template: TemplateId) {
+        let refs = self.retained_templates.entry(template).or_default();
+        *refs += 1;
+        trace!("retained '{template:?}', {refs} references");
+    }
+
+
+    /// Lets the template be evicted on the next scene change
+    /// once nothing else retains it, the instances of it that
+    /// are alive are kept
+    pub fn release_template(&mut self, template: TemplateId) {
+        let Some(refs) = self.retained_templates.get_mut(&template)
+        else {
+            warn!("released the template '{template:?}' more times than it was retained");
+            return;
+        };
+
+        *refs -= 1;
+        trace!("released '{template:?}', {refs} references");
+
+        if *refs == 0 {
+            self.retained_templates.remove(&template);
+        }
+    }
+
+
+    /// Unloads every cached template other than the current
+    /// scene and the ones that are retained.
+    ///
+    /// The ids of evicted templates stay valid but they
+    /// instantiate nothing, loading the same path again
+    /// gives a new id.
+    pub fn evict_templates(&mut self) {
+        let evicted = self.path_to_template.iter()
+            .filter(|(path, id)| Some(path.as_str()) != self.current_scene.as_deref()
+                                 && !self.retained_templates.contains_key(id))
+            .map(|(path, id)| (path.clone(), *id))
+            .collect::<Vec<_>>();
+
+        for (path, id) in evicted {
+            info!("evicting template '{path}'");
+            self.templates[id] = TemplateScene::new();
+            self.path_to_template.remove(&path);
+            self.watcher.unwatch(&path);
+        }
+    }
+
+
+    /// Returns every texture that is used by a node
+    /// in the tree or by a cached template
+    pub fn used_textures(&self) -> HashSet<TextureId> {
+        let mut used = HashSet::new();
+
+        for handle in self.tree.map.iter() {
+            let node = self.tree.map.get(handle).unwrap();
+            if node.queued_free { continue }
+
+            used.extend(node.properties.texture);
+        }
+
+        for (_, template) in self.templates.iter() {
+            for (_, node) in template.iter() {
+                used.extend(node.properties.texture);
+            }
+        }
+
+        used
+    }
+
+
+    /// Moves the rigidbodies of `node` and its descendants
+    /// to their global transforms after the node was moved
+    pub fn sync_rigidbodies(&mut self, node: NodeId) {
+        let mut stack = vec![node];
+
+        while let Some(node) = stack.pop() {
+            stack.extend_from_slice(&self.tree.get(node).children);
+
+            let Some(rb) = self.physics.node_to_rigidbody.get(&node).copied()
+            else { continue };
+
+            let transform = self.tree.global_transform(node);
+            let iso = Isometry2::new(transform.position().into(), transform.rotation());
+            self.physics.get_rb_mut(rb).set_position(iso, true);
+        }
+    }
+
+
     pub fn call_ready(engine: &mut Engine, root: NodeId) {
         info!("calling ready on '{root:?}'");
 
@@ -152,6 +332,178 @@ impl SceneManager {
     }
 
 
+    ///
+    /// Calls `_enter_tree` on every component of `root` and its
+    /// descendants, parents before their children, and then
+    /// `_ready` on the components that aren't ready yet.
+    ///
+    /// Called once a subtree becomes a part of the scene tree
+    ///
+    pub fn enter_tree(engine: &mut Engine, root: NodeId) {
+        Self::enter_tree_except(engine, root, &[]);
+    }
+
+
+    /// Same as `enter_tree` but the subtrees of `skip` don't get
+    /// `_enter_tree`, only `_ready` if they haven't had it yet
+    pub fn enter_tree_except(engine: &mut Engine, root: NodeId, skip: &[NodeId]) {
+        info!("'{root:?}' entered the tree");
+
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            if skip.contains(&node) { continue }
+
+            let comps = engine.with(|engine| {
+                let node = engine.scene_manager.tree.get(node);
+                stack.extend(node.children.iter().rev());
+                node.components.iter()
+            });
+
+            for comp in comps {
+                Self::call_enter_tree(engine, node, comp);
+            }
+        }
+
+        Self::call_ready(engine, root);
+    }
+
+
+    pub fn call_enter_tree(engine: &mut Engine, node: NodeId, comp: ComponentId) {
+        let (functions, userdata, path) = {
+            let mut engine = engine.get_mut();
+            let node = engine.scene_manager.tree.get_mut(node);
+            let userdata = node.userdata_of(comp);
+
+            let script = node.components.get(comp).script;
+            let script = engine.script_manager.script(script);
+
+            (
+                script.functions.clone(),
+                userdata,
+                script.path(),
+            )
+        };
+
+        functions.enter_tree(path, &userdata);
+    }
+
+
+    ///
+    /// Calls `_exit_tree` on every component of `root` and its
+    /// descendants, children before their parents.
+    ///
+    /// Called while the subtree is still a part of the scene tree,
+    /// right before it's detached or freed
+    ///
+    pub fn exit_tree(engine: &mut Engine, root: NodeId) {
+        info!("'{root:?}' is exiting the tree");
+
+        let nodes = engine.with(|engine| engine.scene_manager.tree.iter_vec(root));
+
+        for node in nodes {
+            let comps = engine.with(|engine| {
+                engine.scene_manager.tree.get(node).components.iter()
+            });
+
+            for comp in comps {
+                let (functions, userdata, path) = {
+                    let mut engine = engine.get_mut();
+                    let node = engine.scene_manager.tree.get_mut(node);
+                    let userdata = node.userdata_of(comp);
+
+                    let script = node.components.get(comp).script;
+                    let script = engine.script_manager.script(script);
+
+                    (
+                        script.functions.clone(),
+                        userdata,
+                        script.path(),
+                    )
+                };
+
+                functions.exit_tree(path, &userdata);
+            }
+        }
+    }
+
+
+    /// Calls `_queue_free` on the component so it can clean
+    /// up after itself and then takes it off `node`
+    pub fn remove_component(engine: &mut Engine, node: NodeId, comp: ComponentId) {
+        let (functions, userdata, path) = {
+            let mut engine = engine.get_mut();
+            let node = engine.scene_manager.tree.get_mut(node);
+            let userdata = node.userdata_of(comp);
+
+            let script = node.components.get(comp).script;
+            let script = engine.script_manager.script(script);
+
+            (
+                script.functions.clone(),
+                userdata,
+                script.path(),
+            )
+        };
+
+        functions.queue_free(path, userdata);
+
+        engine.get_mut().scene_manager.tree
+            .get_mut(node).components.remove(comp);
+    }
+
+
+    ///
+    /// Makes `child` a child of `parent`, if that moves it into
+    /// or out of the scene tree its subtree enters or exits it.
+    ///
+    /// Fails if `child` is the root or an ancestor of `parent`
+    ///
+    pub fn add_child(engine: &mut Engine, parent: NodeId, child: NodeId) -> Result<(), String> {
+        let (was_inside, will_be_inside) = engine.with(|engine| {
+            let tree = &engine.scene_manager.tree;
+
+            if tree.root() == Some(child) {
+                return Err(format!("'{child:?}' is the root of the scene, it can't have a parent"));
+            }
+
+            if tree.is_ancestor(child, parent) {
+                return Err(format!("'{child:?}' can't be a child of itself or of one of its descendants"));
+            }
+
+            Ok((tree.is_inside_tree(child), tree.is_inside_tree(parent)))
+        })?;
+
+        if was_inside && !will_be_inside {
+            Self::exit_tree(engine, child);
+        }
+
+        engine.get_mut().scene_manager.tree.set_parent(child, Some(parent));
+
+        if !was_inside && will_be_inside {
+            Self::enter_tree(engine, child);
+        }
+
+        Ok(())
+    }
+
+
+    /// Detaches `node` from its parent, if it was inside
+    /// the scene tree its subtree exits it first
+    pub fn remove_child(engine: &mut Engine, node: NodeId) {
+        let was_inside = engine.get().scene_manager.tree.is_inside_tree(node);
+        if was_inside && engine.get().scene_manager.tree.root() == Some(node) {
+            error!("'{node:?}' is the root of the scene, it can't be detached");
+            return;
+        }
+
+        if was_inside {
+            Self::exit_tree(engine, node);
+        }
+
+        engine.get_mut().scene_manager.tree.set_parent(node, None);
+    }
+
+
 }
 
 
@@ -167,3 +519,46 @@ impl NodeId {
 
     pub fn idx(&self) -> usize { self.0.idx }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn scene_manager_evict_templates() {
+        let mut sm = SceneManager::new(Vec2::new(0.0, 0.0));
+        let template = |sm: &mut SceneManager, path: &str| {
+            let id = sm.templates.push(TemplateScene::new());
+            sm.path_to_template.insert(path.to_string(), id);
+            id
+        };
+
+        template(&mut sm, "current.scn");
+        let retained = template(&mut sm, "retained.scn");
+        let released = template(&mut sm, "released.scn");
+        let shared = template(&mut sm, "shared.scn");
+        template(&mut sm, "other.scn");
+
+        sm.current_scene = Some("current.scn".to_string());
+        sm.retain_template(retained);
+        sm.retain_template(released);
+        sm.release_template(released);
+
+        // two holders, one of them lets go
+        sm.retain_template(shared);
+        sm.retain_template(shared);
+        sm.release_template(shared);
+
+        sm.evict_templates();
+
+        let mut paths = sm.path_to_template.keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, vec!["current.scn", "retained.scn", "shared.scn"]);
+
+        sm.release_template(shared);
+        sm.evict_templates();
+        assert!(!sm.path_to_template.contains_key("shared.scn"));
+    }
+}
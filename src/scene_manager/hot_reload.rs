@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use tracing::{error, info, warn};
+
+use crate::engine::Engine;
+
+use super::{node::NodeProperties, scene_template::{TemplateNodeId, TemplateScene}, scene_tree::SceneTree, NodeId, SceneManager, TemplateId};
+
+
+impl SceneManager {
+    /// Reloads every scene file that has changed on disk
+    /// since it was loaded and patches its live instances
+    pub fn hot_reload(engine: &mut Engine) {
+        let changed = engine.with(|engine| {
+            let now = engine.now;
+            engine.scene_manager.watcher.changed_files(now)
+        });
+
+        for path in changed {
+            let template = engine.get().scene_manager.path_to_template.get(&path).copied();
+            let Some(template) = template
+            else { continue };
+
+            info!("'{path}' has changed, reloading");
+            Self::reload_template(engine, template, &path);
+        }
+    }
+
+
+    /// Re-parses the scene at `path` into `template_id` and
+    /// updates the live instances of it.
+    ///
+    /// Properties and component fields are only overwritten if
+    /// the template changed them, so runtime state is kept
+    /// everywhere else. Added nodes are instantiated and removed
+    /// nodes are freed. If a node changed its parent or its
+    /// components the current scene is reloaded instead.
+    pub fn reload_template(engine: &mut Engine, template_id: TemplateId, path: &str) {
+        let span = tracing::span!(tracing::Level::ERROR, "reloading scene ", path);
+        let _handle = span.entered();
+
+        let template = TemplateScene::from_file(engine, path);
+        if template.len() == 0 {
+            error!("the scene failed to load, keeping the old version");
+            return;
+        }
+
+        let old = engine.with(|engine| {
+            core::mem::replace(&mut engine.scene_manager.templates[template_id], template)
+        });
+
+
+        let instances = engine.with(|engine| {
+            Self::live_instances(&engine.scene_manager.tree, template_id)
+        });
+
+        if instances.is_empty() {
+            info!("reloaded, there are no live instances to patch");
+            return;
+        }
+
+
+        let can_patch = engine.with(|engine| {
+            let new = &engine.scene_manager.templates[template_id];
+            Self::can_patch(&old, new)
+        });
+
+        if !can_patch {
+            warn!("the structure of the scene changed in a way that \
+                  can't be patched, reloading the current scene");
+
+            let current = engine.get().scene_manager.current_scene.clone();
+            if let Some(current) = current {
+                Engine::change_scene(engine, &current, true);
+            }
+
+            return;
+        }
+
+
+        let instance_count = instances.len();
+        let mut to_free = vec![];
+        let mut added = vec![];
+
+        engine.with(|engine| {
+            let sm = &mut engine.scene_manager;
+            let new = &sm.templates[template_id];
+
+            for (instance, mut nodes) in instances {
+                Self::patch_instance(&mut sm.tree, template_id, &old, new,
+                                     instance, &mut nodes, &mut to_free, &mut added);
+            }
+        });
+
+
+        for node in to_free {
+            SceneTree::queue_free(engine, node);
+        }
+
+        // nodes added to a detached instance enter the
+        // tree along with the rest of it, `enter_tree` goes
+        // down the subtree so only the top most ones start it
+        for node in added.iter().copied() {
+            let enters = engine.with(|engine| {
+                let tree = &engine.scene_manager.tree;
+                let parent = tree.get(node).parent;
+                tree.is_inside_tree(node) && !parent.is_some_and(|parent| added.contains(&parent))
+            });
+
+            if enters {
+                SceneManager::enter_tree(engine, node);
+            }
+        }
+
+        info!("reloaded, patched {instance_count} live instances");
+    }
+
+
+    /// Groups the live nodes that were instantiated from
+    /// `template_id` by the instance they belong to
+    fn live_instances(tree: &SceneTree, template_id: TemplateId)
+        -> HashMap<NodeId, HashMap<TemplateNodeId, NodeId>> {
+
+        let mut instances : HashMap<NodeId, HashMap<TemplateNodeId, NodeId>> = HashMap::new();
+
+        for handle in tree.map.iter() {
+            let node = tree.map.get(handle).unwrap();
+            if node.queued_free { continue }
+
+            let Some(origin) = node.origin
+            else { continue };
+
+            if origin.template != template_id { continue }
+
+            instances.entry(origin.instance)
+                .or_default()
+                .insert(origin.node, NodeId(handle));
+        }
+
+        instances
+    }
+
+
+    /// Checks if the live instances of `old` can be
+    /// turned into instances of `new` in place
+    fn can_patch(old: &TemplateScene, new: &TemplateScene) -> bool {
+        for (id, new_node) in new.iter() {
+            let Some(old_node) = old.get(id)
+            else { continue };
+
+            if old_node.parent != new_node.parent {
+                return false;
+            }
+
+            if old_node.components.len() != new_node.components.len() {
+                return false;
+            }
+
+            let same_scripts = old_node.components.iter()
+                .zip(new_node.components.iter())
+                .all(|(old, new)| old.1.script() == new.1.script());
+
+            if !same_scripts {
+                return false;
+            }
+        }
+
+        true
+    }
+
+
+    fn patch_instance(tree: &mut SceneTree,
+                      template_id: TemplateId,
+                      old: &TemplateScene,
+                      new: &TemplateScene,
+                      instance: NodeId,
+                      nodes: &mut HashMap<TemplateNodeId, NodeId>,
+                      to_free: &mut Vec<NodeId>,
+                      added: &mut Vec<NodeId>) {
+
+        // free the removed nodes, but only the top most ones
+        // as freeing a node frees its children too
+        for (template_node, live) in nodes.iter() {
+            if new.get(*template_node).is_some() { continue }
+
+            let parent = old.get(*template_node).map(|x| x.parent).flatten();
+            let parent_is_removed = parent.map(|x| new.get(x).is_none()).unwrap_or(false);
+            if parent_is_removed { continue }
+
+            to_free.push(*live);
+        }
+
+
+        for (id, new_node) in new.iter() {
+            let Some(old_node) = old.get(id)
+            else {
+                let parent = new_node.parent.map(|x| nodes.get(&x).copied()).flatten();
+                let Some(parent) = parent
+                else {
+                    warn!("the parent of the new node '{}' doesn't exist \
+                          in the live instance, skipping", id.inner());
+                    continue;
+                };
+
+                let node = TemplateScene::create_node(tree, template_id, id, new_node,
+                                                      Some(instance), Some(parent));
+                nodes.insert(id, node);
+                added.push(node);
+                continue;
+            };
+
+
+            // the node might've been freed at runtime
+            let Some(live) = nodes.get(&id)
+            else { continue };
+
+            if old_node.name != new_node.name {
+                tree.set_name(*live, new_node.name.clone());
+            }
+
+            for group in old_node.groups.iter().filter(|x| !new_node.groups.contains(x)) {
+                tree.remove_from_group(*live, group);
+            }
+
+            for group in new_node.groups.iter().filter(|x| !old_node.groups.contains(x)) {
+                tree.add_to_group(*live, group);
+            }
+
+            tree.invalidate_transform(*live);
+            let live = tree.get_mut(*live);
+            live.properties = patch_properties(live.properties,
+                                               old_node.properties,
+                                               new_node.properties);
+
+            let templates = old_node.components.iter().zip(new_node.components.iter());
+            for ((_, comp), ((_, old_comp), (_, new_comp))) in live.components.iter_mut().zip(templates) {
+                for (field_id, new_value) in new_comp.fields().iter() {
+                    let old_value = old_comp.fields().get(field_id);
+                    if old_value.map(|x| x.value()) == Some(new_value.value()) { continue }
+
+                    let Some(field) = comp.fields.get_mut(field_id)
+                    else { continue };
+
+                    *field = new_value.clone();
+                }
+            }
+        }
+    }
+}
+
+
+/// Applies the properties that changed between
+/// `old` and `new` to `live`
+fn patch_properties(mut live: NodeProperties, old: NodeProperties, new: NodeProperties) -> NodeProperties {
+    if old.position != new.position { live.position = new.position }
+    if old.modulate != new.modulate { live.modulate = new.modulate }
+    if old.scale != new.scale { live.scale = new.scale }
+    if old.rotation != new.rotation { live.rotation = new.rotation }
+    if old.texture != new.texture { live.texture = new.texture }
+    live
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sti::keyed::KVec;
+
+    use crate::{engine::{test_dir, test_engine}, math::vector::Vec2, scene_manager::scene_template::{TemplateComponent, TemplateComponents, TemplateNode}, script_manager::{ScriptId, ScriptManager}};
+
+    use super::*;
+
+
+    fn scene(nodes: &[(Option<u32>, &[ScriptId])]) -> TemplateScene {
+        let mut scene = TemplateScene::new();
+        for (parent, scripts) in nodes {
+            let mut components = KVec::new();
+            for script in scripts.iter() {
+                components.push(TemplateComponent::new(*script, KVec::new()));
+            }
+
+            scene.inner_mut().push(TemplateNode {
+                name: None,
+                groups: vec![],
+                properties: NodeProperties::identity(),
+                parent: parent.map(TemplateNodeId::new_unck),
+                components: TemplateComponents::new(components),
+            });
+        }
+
+        scene
+    }
+
+
+    #[test]
+    fn hot_reload_can_patch() {
+        let script = ScriptId::new_unck(1);
+        let old = scene(&[(None, &[]), (Some(0), &[script])]);
+
+        // added nodes and changed properties can be patched
+        let mut new = scene(&[(None, &[]), (Some(0), &[script]), (Some(1), &[])]);
+        new.get_mut(TemplateNodeId::new_unck(0)).unwrap().properties.position = Vec2::new(1.0, 2.0);
+        assert!(SceneManager::can_patch(&old, &new));
+
+        let reparented = scene(&[(None, &[]), (None, &[script])]);
+        assert!(!SceneManager::can_patch(&old, &reparented));
+
+        let new_component = scene(&[(None, &[]), (Some(0), &[script, script])]);
+        assert!(!SceneManager::can_patch(&old, &new_component));
+
+        let other_script = scene(&[(None, &[]), (Some(0), &[ScriptId::EMPTY])]);
+        assert!(!SceneManager::can_patch(&old, &other_script));
+    }
+
+
+    #[test]
+    fn hot_reload_patch_properties() {
+        let old = NodeProperties::identity();
+
+        let mut new = old;
+        new.position = Vec2::new(5.0, 5.0);
+        new.rotation = 1.0;
+
+        // the scale was changed at runtime and the rotation
+        // both at runtime and in the template
+        let mut live = old;
+        live.scale = Vec2::new(3.0, 3.0);
+        live.rotation = 2.0;
+
+        let patched = patch_properties(live, old, new);
+        assert_eq!(patched.position, Vec2::new(5.0, 5.0));
+        assert_eq!(patched.rotation, 1.0);
+        assert_eq!(patched.scale, Vec2::new(3.0, 3.0));
+        assert_eq!(patched.modulate, old.modulate);
+    }
+
+
+    #[test]
+    fn hot_reload_enter_added_subtree() {
+        let (_guard, mut engine) = test_engine();
+        ScriptManager::from_lua(&mut engine, "tracker.lua", b"
+            class_name = \"Tracker\"
+            entered = 0
+            function _enter_tree(self) self.entered = self.entered + 1 end
+        ");
+
+        let path = "reload/added.scene";
+        std::fs::create_dir_all(test_dir().join("reload")).unwrap();
+        std::fs::write(test_dir().join(path), "version = 2\n\n[root]\nname = \"Level\"").unwrap();
+        Engine::change_scene(&mut engine, path, true);
+
+        std::fs::write(test_dir().join(path), r#"
+            version = 2
+
+            [root]
+            name = "Level"
+
+            [root.children.Parent]
+            components = { Tracker = {} }
+
+            [root.children.Parent.children.Child]
+            components = { Tracker = {} }
+        "#).unwrap();
+
+        let template = engine.get().scene_manager.path_to_template[path];
+        SceneManager::reload_template(&mut engine, template, path);
+
+        let engine = engine.get();
+        let tree = &engine.scene_manager.tree;
+        let root = tree.root().unwrap();
+        let tracker = engine.script_manager.path_to_script["Tracker"];
+        let entered = engine.script_manager.script(tracker).fields["entered"];
+
+        for path in ["Parent", "Parent/Child"] {
+            let node = tree.get_node(root, path).unwrap();
+            let component = tree.get(node).components.get_index(0);
+            assert_eq!(component.fields[entered].value().as_f64(), Some(1.0), "{path}");
+        }
+    }
+}
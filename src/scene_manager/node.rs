@@ -1,22 +1,49 @@
+use std::cell::Cell;
+
 use mlua::AnyUserData;
 use sti::{define_key, keyed::{KIterMut, KVec}};
-use tracing::error;
+use tracing::{error, warn};
 
-use crate::{asset_manager::{texture::TextureLoadType, AssetManager, TextureId}, engine::Engine, lua::node::NodeUserData, math::vector::{Colour, Vec2, Vec4}, script_manager::{fields::{FieldId, FieldValue}, ScriptId}};
+use crate::{asset_manager::{texture::TextureLoadType, AssetManager, TextureId}, engine::Engine, lua::node::NodeUserData, math::{transform::Transform2D, vector::{Colour, Vec2, Vec4}}, script_manager::{fields::{FieldId, FieldValue}, ScriptId}};
 
-use super::{NodeId, scene_tree::SceneTree};
+use super::{NodeId, scene_template::TemplateNodeId, TemplateId};
 
 define_key!(u32, pub ComponentId);
 
 #[derive(Debug, Clone)]
 pub struct Node {
     pub node_id: NodeId,
+    /// Unique among the siblings of the node, see `SceneTree::set_name`
+    pub name: Option<String>,
+    /// Changed through `SceneTree::add_to_group` and
+    /// `remove_from_group` to keep the tree's index right
+    pub groups: Vec<String>,
     pub properties: NodeProperties,
     pub children: Vec<NodeId>,
     pub parent: Option<NodeId>,
     pub components: Components,
     pub queued_free: bool,
     pub userdata: Option<AnyUserData>,
+    pub origin: Option<TemplateOrigin>,
+    /// Cached by `SceneTree::global_transform`, `None` when the
+    /// node or one of its parents has moved since
+    pub global_transform: Cell<Option<Transform2D>>,
+    /// Moved over to the new scene on a scene change
+    /// instead of being freed with the old one
+    pub persistent: bool,
+}
+
+
+///
+/// Where a node was instantiated from, used to
+/// patch live nodes when their template changes
+///
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateOrigin {
+    pub template: TemplateId,
+    pub node: TemplateNodeId,
+    /// The root node of the instance this node belongs to
+    pub instance: NodeId,
 }
 
 
@@ -70,56 +97,6 @@ impl Node {
     }
 
 
-    pub fn global_position(&self, nodes: &SceneTree) -> Vec2 {
-        let mut target_parent = self.parent;
-        let mut pos = self.properties.position;
-
-        while let Some(parent) = target_parent {
-            let this = nodes.get(parent);
-
-            pos.x *= this.properties.scale.x;
-            pos.y *= this.properties.scale.y;
-            pos.x += this.properties.position.x;
-            pos.y += this.properties.position.y;
-
-            target_parent = this.parent;
-        }
-
-        pos
-    }
-
-
-    pub fn global_rotation(&self, nodes: &SceneTree) -> f32 {
-        let mut target_parent = self.parent;
-        let mut rot = self.properties.rotation;
-
-        while let Some(parent) = target_parent {
-            let this = nodes.get(parent);
-            rot += this.properties.rotation;
-            target_parent = this.parent;
-        }
-
-        rot
-    }
-
-
-    pub fn global_scale(&self, nodes: &SceneTree) -> Vec2 {
-        let mut target_parent = self.parent;
-        let mut scale = self.properties.scale;
-
-        while let Some(parent) = target_parent {
-            let this = nodes.get(parent);
-
-            scale.x *= this.properties.scale.x;
-            scale.y *= this.properties.scale.y;
-
-            target_parent = this.parent;
-        }
-
-        scale
-    }
-
-
     pub fn userdata(&mut self) -> AnyUserData {
         if let Some(userdata) = &self.userdata {
             return userdata.clone();
@@ -139,6 +116,13 @@ impl Node {
         comp.userdata = Some(Engine::lua().create_userdata(NodeUserData(self.node_id, comp.comp_id)).unwrap());
         comp.userdata.as_ref().unwrap().clone()
     }
+
+
+    /// The userdata of every component, unlike the ids they
+    /// keep pointing at their components if one is removed
+    pub fn components_userdata(&mut self) -> Vec<AnyUserData> {
+        self.components.iter().map(|comp| self.userdata_of(comp)).collect()
+    }
 }
 
 
@@ -178,6 +162,42 @@ impl Components {
     }
 
 
+    /// Adds a component with `fields` to the end
+    pub fn push(&mut self, script: ScriptId, fields: KVec<FieldId, FieldValue>) -> ComponentId {
+        let comp_id = ComponentId::new_unck(self.vec.len() as u32);
+        self.vec.push(Component::new(comp_id, script, fields))
+    }
+
+
+    ///
+    /// Takes the component out, the ones after it move down
+    /// to fill the gap and the userdata Lua holds of them is
+    /// updated to the new ids.
+    ///
+    /// The userdata of the removed component is pointed at
+    /// `ComponentId::STALE` so it can't alias another one
+    ///
+    pub fn remove(&mut self, key: ComponentId) -> Component {
+        let old = core::mem::replace(&mut self.vec, KVec::with_cap(self.vec.len()));
+        let mut removed = None;
+
+        for (comp_id, comp) in old.iter() {
+            if comp_id == key {
+                removed = Some(comp.clone());
+                continue;
+            }
+
+            let mut comp = comp.clone();
+            comp.set_id(ComponentId::new_unck(self.vec.len() as u32));
+            self.vec.push(comp);
+        }
+
+        let mut removed = removed.unwrap();
+        removed.set_id(ComponentId::STALE);
+        removed
+    }
+
+
     pub fn iter(&self) -> ComponentIter {
         ComponentIter { curr: 0, max: self.vec.len() as u32 }
     }
@@ -189,6 +209,12 @@ impl Components {
 }
 
 
+impl ComponentId {
+    /// The id of a component that has been removed from its node
+    pub const STALE : Self = Self(u32::MAX);
+}
+
+
 impl Component {
     pub fn new(comp_id: ComponentId, script: ScriptId, fields: KVec<FieldId, FieldValue>) -> Self {
         Self {
@@ -199,6 +225,19 @@ impl Component {
             comp_id,
         }
     }
+
+
+    fn set_id(&mut self, comp_id: ComponentId) {
+        self.comp_id = comp_id;
+
+        let Some(userdata) = &self.userdata
+        else { return };
+
+        match userdata.borrow_mut::<NodeUserData>() {
+            Ok(mut userdata) => userdata.1 = comp_id,
+            Err(e) => error!("unable to update the userdata of a moved component: {e}"),
+        }
+    }
 }
 
 
@@ -208,18 +247,18 @@ impl NodeProperties {
     }
 
 
-    pub fn merge(mut self, oth: NodeProperties) -> Self {
-        self.scale.x *= oth.scale.x;
-        self.scale.y *= oth.scale.y;
+    /// The transform relative to the parent
+    pub fn transform(&self) -> Transform2D {
+        Transform2D::new(self.position, self.rotation, self.scale)
+    }
 
-        self.rotation += oth.rotation;
-        self.modulate = self.modulate * oth.modulate;
-        self.position.x *= oth.scale.x;
-        self.position.y *= oth.scale.y;
-        self.position.x += oth.position.x;
-        self.position.y += oth.position.y;
 
-        self
+    /// Any skew in `transform` is lost as the properties
+    /// only hold a position, rotation and scale
+    pub fn set_transform(&mut self, transform: Transform2D) {
+        self.position = transform.position();
+        self.rotation = transform.rotation();
+        self.scale = transform.scale();
     }
 }
 
@@ -257,36 +296,8 @@ impl NodeProperties {
         let rotation = rotation as f32;
 
 
-        let texture = table.get("texture").map(|texture| {
-            match texture.as_str() {
-                Some(v) => Some(v),
-                None => {
-                    error!("failed to read 'texture' in '{parent_name}', texture must be a path string");
-                    None
-                },
-            }
-        }).flatten();
-
-        let texture = texture.map(|texture| {
-            let Some((ty, path)) = texture.split_once(':')
-            else {
-                error!("failed to read 'texture' in '{parent_name}', \
-                       unable to parse the texture string, format must be '<type>:<path>'");
-                return None;
-            };
-
-            match ty {
-                "image" => engine.get_mut().asset_manager.from_image(path),
-                "script" => AssetManager::from_script(engine, path),
-
-                _ => {
-                    error!("failed to read 'texture' in '{parent_name}', texture's type must be \
-                           either 'image' or 'script' but it is '{ty}'");
-                    None
-                }
-            }
-
-        }).flatten();
+        let texture = table.get("texture")
+            .and_then(|texture| Self::texture_from_toml(engine, parent_name, texture));
 
         Some(Self {
             position,
@@ -298,21 +309,67 @@ impl NodeProperties {
     }
 
 
-    fn _to_table(self, asset_manager: &mut AssetManager) -> toml::Table {
+    /// Reads a texture string, '<type>:<path>' where the
+    /// type is either 'image' or 'script'
+    pub fn texture_from_toml(engine: &mut Engine, parent_name: &str,
+                             texture: &toml::Value) -> Option<TextureId> {
+        let Some(texture) = texture.as_str()
+        else {
+            error!("failed to read 'texture' in '{parent_name}', texture must be a path string");
+            return None;
+        };
+
+        Self::texture_from_str(engine, parent_name, texture)
+    }
+
+
+    /// Same as `texture_from_toml` but for a string
+    /// that's already known to be one
+    pub fn texture_from_str(engine: &mut Engine, parent_name: &str, texture: &str) -> Option<TextureId> {
+        let Some((ty, path)) = texture.split_once(':')
+        else {
+            error!("failed to read 'texture' in '{parent_name}', \
+                   unable to parse the texture string, format must be '<type>:<path>'");
+            return None;
+        };
+
+        match ty {
+            "image" => engine.get_mut().asset_manager.from_image(path),
+            "script" => AssetManager::from_script(engine, path),
+
+            _ => {
+                error!("failed to read 'texture' in '{parent_name}', texture's type must be \
+                       either 'image' or 'script' but it is '{ty}'");
+                None
+            }
+        }
+    }
+
+
+    /// Writes the properties in the format `from_table` reads.
+    ///
+    /// Textures created at runtime have no path to
+    /// point to so they're left out
+    pub fn to_table(self, asset_manager: &AssetManager) -> toml::Table {
         let mut table = toml::Table::new();
         table.insert("position".to_string(), self.position.to_table().into());
         table.insert("modulate".to_string(), self.modulate.to_table().into());
         table.insert("scale".to_string(), self.scale.to_table().into());
         table.insert("rotation".to_string(), self.rotation.into());
-        if let Some(texture) = self.texture {
-            let texture = asset_manager.texture(texture);
+        if let Some(id) = self.texture {
+            let texture = asset_manager.texture(id);
             let script = match texture.load_type() {
-                TextureLoadType::Image(v) => format!("image:{v}"),
-                TextureLoadType::Script(v) => format!("script:{v}"),
-                TextureLoadType::Runtime => unreachable!(),
+                TextureLoadType::Image(v) => Some(format!("image:{v}")),
+                TextureLoadType::Script(v) => Some(format!("script:{v}")),
+                TextureLoadType::Runtime => {
+                    warn!("the texture '{id:?}' was created at runtime, it can't be saved");
+                    None
+                },
             };
 
-            table.insert("texture".to_string(), script.into());
+            if let Some(script) = script {
+                table.insert("texture".to_string(), script.into());
+            }
         }
         table
     }
@@ -1,11 +1,11 @@
-use std::collections::HashMap;
+use std::{cell::Cell, collections::HashMap};
 
 use sti::{define_key, keyed::KVec};
 use tracing::info;
 
-use crate::{engine::Engine, scene_manager::node::{Components, Node}, script_manager::{fields::{FieldId, FieldValue}, ScriptId}};
+use crate::{engine::Engine, scene_manager::node::{Components, Node}, script_manager::{fields::{FieldId, FieldValue}, Script, ScriptId}};
 
-use super::{node::{Component, ComponentId, NodeProperties}, NodeId, SceneManager, TemplateId};
+use super::{node::{Component, ComponentId, NodeProperties, TemplateOrigin}, scene_tree::SceneTree, NodeId, TemplateId};
 
 
 define_key!(u32, pub TemplateNodeId);
@@ -17,21 +17,23 @@ pub struct TemplateScene {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TemplateNode {
+    pub name: Option<String>,
+    pub groups: Vec<String>,
     pub properties: NodeProperties,
     pub parent: Option<TemplateNodeId>,
     pub components: TemplateComponents,
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TemplateComponents {
     map: KVec<TemplateComponentId, TemplateComponent>,
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TemplateComponent {
     script: ScriptId,
     fields: KVec<FieldId, FieldValue>,
@@ -54,6 +56,55 @@ impl TemplateScene {
     }
 
 
+    pub fn get(&self, node: TemplateNodeId) -> Option<&TemplateNode> {
+        self.nodes.get(node)
+    }
+
+
+    pub fn get_mut(&mut self, node: TemplateNodeId) -> Option<&mut TemplateNode> {
+        self.nodes.get_mut(node)
+    }
+
+
+    pub fn iter(&self) -> impl Iterator<Item=(TemplateNodeId, &TemplateNode)> {
+        self.nodes.iter()
+    }
+
+
+    /// Finds the node at `path` relative to `from`, paths
+    /// work like they do in `SceneTree::get_node` except
+    /// that they can't leave the template
+    pub fn get_node(&self, from: TemplateNodeId, path: &str) -> Option<TemplateNodeId> {
+        let mut current = from;
+
+        for segment in path.split('/') {
+            current = match segment {
+                "" | "." => current,
+                ".." => self.get(current)?.parent?,
+                _ => {
+                    let children = self.nodes.iter()
+                        .filter(|(_, node)| node.parent == Some(current))
+                        .collect::<Vec<_>>();
+
+                    let by_name = children.iter()
+                        .find(|(_, node)| node.name.as_deref() == Some(segment));
+
+                    let by_index = || segment.strip_prefix('#')
+                        .and_then(|x| x.parse::<usize>().ok())
+                        .and_then(|x| children.get(x));
+
+                    by_name.or_else(by_index)?.0
+                },
+            };
+        }
+
+        Some(current)
+    }
+
+
+    /// Creates the nodes of the template, the root is left detached
+    /// so nothing is ready until it's added to the tree, see
+    /// `SceneManager::add_child`
     pub fn instantiate(engine: &mut Engine, template_id: TemplateId) -> Option<NodeId> {
         info!("instantiating template scene {template_id:?}");
         let mut hashmap = HashMap::new();
@@ -64,59 +115,103 @@ impl TemplateScene {
 
         let mut root = None;
         for (node_id, template_node) in this.nodes.iter() {
+            // this will NEVER return 'None', this is because
+            // this loop is stack based and the parent will be
+            // inserted into the hashmap before the children
+            let parent = template_node.parent
+                .map(|parent| *hashmap.get(&parent).unwrap());
+
+            let insert_id = Self::create_node(&mut sm.tree, template_id, node_id,
+                                              template_node, root, parent);
+
             if root == None {
-                root = Some(node_id);
+                root = Some(insert_id);
             }
-            
-            let components = {
-                let mut vec = KVec::with_cap(template_node.components.map.len());
-
-                for (i, comp) in template_node.components.map.iter().enumerate() {
-                    let comp_id = ComponentId::new_unck(i as u32);
-                    vec.push(Component::new(comp_id,
-                                            comp.1.script,
-                                            comp.1.fields.clone()));
-                }
-
-                Components::new(vec)
-            };
 
+            hashmap.insert(node_id, insert_id);
+        }
 
-            let insert_node = Node {
-                node_id: NodeId::PLACEHOLDER,
-                properties: template_node.properties,
-                children: vec![],
-                parent: None,
-                components,
-                userdata: None,
-                queued_free: false,
-            };
 
-            let insert_id = sm.tree.insert(insert_node);
+        root
+    }
 
-            hashmap.insert(node_id, insert_id);
 
-            if let Some(parent) = template_node.parent {
-                // this will NEVER return 'None', this is because
-                // this loop is stack based and the parent will be
-                // inserted into the hashmap before the children
-                let parent_id = hashmap.get(&parent).unwrap();
-                sm.tree.set_parent(insert_id, Some(*parent_id));
+    /// Inserts a single node of a template into the tree
+    /// as a part of the instance rooted at `instance`.
+    ///
+    /// If `instance` is `None` the new node is the root of
+    /// the instance
+    pub fn create_node(tree: &mut SceneTree,
+                       template_id: TemplateId,
+                       node_id: TemplateNodeId,
+                       template_node: &TemplateNode,
+                       instance: Option<NodeId>,
+                       parent: Option<NodeId>) -> NodeId {
+
+        let components = {
+            let mut vec = KVec::with_cap(template_node.components.map.len());
+
+            for (i, comp) in template_node.components.map.iter().enumerate() {
+                let comp_id = ComponentId::new_unck(i as u32);
+                vec.push(Component::new(comp_id,
+                                        comp.1.script,
+                                        comp.1.fields.clone()));
             }
 
-
-            let insert_node = sm.tree.get_mut(insert_id);
-            insert_node.node_id = insert_id;
+            Components::new(vec)
+        };
+
+
+        let insert_node = Node {
+            node_id: NodeId::PLACEHOLDER,
+            name: template_node.name.clone(),
+            groups: template_node.groups.clone(),
+            properties: template_node.properties,
+            children: vec![],
+            parent: None,
+            components,
+            userdata: None,
+            queued_free: false,
+            origin: None,
+            global_transform: Cell::new(None),
+            persistent: false,
+        };
+
+        let insert_id = tree.insert(insert_node);
+
+        if let Some(parent) = parent {
+            tree.set_parent(insert_id, Some(parent));
         }
 
 
-        drop(engine_ref);
+        let insert_node = tree.get_mut(insert_id);
+        insert_node.node_id = insert_id;
+        insert_node.origin = Some(TemplateOrigin {
+            template: template_id,
+            node: node_id,
+            instance: instance.unwrap_or(insert_id),
+        });
+
+        insert_id
+    }
 
-        let root = root?;
-        let root = *hashmap.get(&root).unwrap();
-        SceneManager::call_ready(engine, root);
 
-        Some(root)
+    /// Migrates the field values of every component that uses
+    /// `script_id` after the script was hot swapped from `old`
+    /// to `script`.
+    ///
+    /// Only values that the scene overrides are kept, the rest
+    /// pick up the new defaults of the script.
+    pub fn migrate_script_fields(&mut self, script_id: ScriptId, script: &Script, old: &Script) {
+        for (_, node) in self.nodes.iter_mut() {
+            for (_, comp) in node.components.map.iter_mut() {
+                if comp.script != script_id { continue }
+
+                comp.fields = script.migrate_fields(old, &comp.fields, |value, old_default| {
+                    value.value() != old_default.value.value()
+                });
+            }
+        }
     }
 }
 
@@ -125,6 +220,21 @@ impl TemplateComponents {
     pub fn new(map: KVec<TemplateComponentId, TemplateComponent>) -> Self {
         Self { map }
     }
+
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+
+    pub fn iter(&self) -> impl Iterator<Item=(TemplateComponentId, &TemplateComponent)> {
+        self.map.iter()
+    }
+
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=(TemplateComponentId, &mut TemplateComponent)> {
+        self.map.iter_mut()
+    }
 }
 
 
@@ -132,4 +242,53 @@ impl TemplateComponent {
     pub fn new(script: ScriptId, fields: KVec<FieldId, FieldValue>) -> Self {
         Self { script, fields }
     }
+
+
+    pub fn script(&self) -> ScriptId {
+        self.script
+    }
+
+
+    pub fn fields(&self) -> &KVec<FieldId, FieldValue> {
+        &self.fields
+    }
+
+
+    pub fn fields_mut(&mut self) -> &mut KVec<FieldId, FieldValue> {
+        &mut self.fields
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn template_get_node() {
+        let mut scene = TemplateScene::new();
+        let mut node = |name: Option<&str>, parent: Option<u32>| {
+            scene.inner_mut().push(TemplateNode {
+                name: name.map(String::from),
+                groups: vec![],
+                properties: NodeProperties::identity(),
+                parent: parent.map(TemplateNodeId::new_unck),
+                components: TemplateComponents::new(KVec::new()),
+            });
+        };
+
+        node(Some("Pipe"), None);
+        node(Some("Bottom"), Some(0));
+        node(None, Some(0));
+        node(Some("Top"), Some(0));
+
+        let root = TemplateNodeId::new_unck(0);
+        assert_eq!(scene.get_node(root, "."), Some(root));
+        assert_eq!(scene.get_node(root, "Top"), Some(TemplateNodeId::new_unck(3)));
+        assert_eq!(scene.get_node(root, "#1"), Some(TemplateNodeId::new_unck(2)));
+        assert_eq!(scene.get_node(root, "Top/../Bottom"), Some(TemplateNodeId::new_unck(1)));
+        assert_eq!(scene.get_node(root, ".."), None);
+        assert_eq!(scene.get_node(root, "Middle"), None);
+    }
 }
@@ -1,42 +1,150 @@
+use std::{cell::Cell, collections::{HashMap, VecDeque}};
+
 use genmap::GenMap;
 use tracing::{info, trace};
 
-use crate::{engine::Engine, math::vector::Vec2};
+use crate::{engine::Engine, math::{transform::Transform2D, vector::Vec2}};
 
-use super::{node::Node, NodeId};
+use super::{node::{Components, Node, NodeProperties}, NodeId, SceneManager};
 
 #[derive(Clone, Debug)]
 pub struct SceneTree {
     pub map: GenMap<Node>,
     root: Option<NodeId>,
+    /// The nodes in every group, in the order they joined it
+    groups: HashMap<String, Vec<NodeId>>,
+    /// The roots of the subtrees that `free_queued` removes
+    to_free: Vec<NodeId>,
 }
 
 
 impl SceneTree {
     pub fn new() -> Self {
-        Self { map: GenMap::with_capacity(0), root: None }
+        Self { map: GenMap::with_capacity(0), root: None, groups: HashMap::new(), to_free: vec![] }
     }
 
 
+    /// Inserts `node` and adds it to the groups it's in
     pub fn insert(&mut self, node: Node) -> NodeId {
-        NodeId(self.map.insert(node))
+        let groups = node.groups.clone();
+        let id = NodeId(self.map.insert(node));
+
+        for group in groups {
+            self.groups.entry(group).or_default().push(id);
+        }
+
+        id
+    }
+
+
+    /// Inserts an empty node that isn't a part of the
+    /// tree until it's given a parent
+    pub fn create(&mut self, properties: NodeProperties) -> NodeId {
+        let id = self.insert(Node {
+            node_id: NodeId::PLACEHOLDER,
+            name: None,
+            groups: vec![],
+            properties,
+            children: vec![],
+            parent: None,
+            components: Components::empty(),
+            queued_free: false,
+            userdata: None,
+            origin: None,
+            global_transform: Cell::new(None),
+            persistent: false,
+        });
+
+        self.get_mut(id).node_id = id;
+        id
+    }
+
+
+    /// Takes `node` out of the map and out of its groups,
+    /// its parent and children are left untouched
+    pub fn remove(&mut self, node: NodeId) -> Option<Node> {
+        let removed = self.map.remove(node.0)?;
+
+        for group in removed.groups.iter() {
+            let Some(nodes) = self.groups.get_mut(group)
+            else { continue };
+
+            nodes.retain(|x| *x != node);
+            if nodes.is_empty() {
+                self.groups.remove(group);
+            }
+        }
+
+        Some(removed)
+    }
+
+
+    pub fn add_to_group(&mut self, node: NodeId, group: &str) {
+        let groups = &mut self.get_mut(node).groups;
+        if groups.iter().any(|x| x == group) { return }
+
+        groups.push(group.to_string());
+        self.groups.entry(group.to_string()).or_default().push(node);
+    }
+
+
+    pub fn remove_from_group(&mut self, node: NodeId, group: &str) {
+        self.get_mut(node).groups.retain(|x| x != group);
+
+        let Some(nodes) = self.groups.get_mut(group)
+        else { return };
+
+        nodes.retain(|x| *x != node);
+        if nodes.is_empty() {
+            self.groups.remove(group);
+        }
+    }
+
+
+    pub fn is_in_group(&self, node: NodeId, group: &str) -> bool {
+        self.get(node).groups.iter().any(|x| x == group)
+    }
+
+
+    /// The nodes in `group` in the order they joined it, leaving
+    /// out the ones that are queued to be freed or that aren't
+    /// inside the tree
+    pub fn nodes_in_group(&self, group: &str) -> Vec<NodeId> {
+        let Some(nodes) = self.groups.get(group)
+        else { return vec![] };
+
+        nodes.iter()
+            .copied()
+            .filter(|x| !self.get(*x).queued_free && self.is_inside_tree(*x))
+            .collect()
     }
 
 
     pub fn len(&self) -> usize { self.map.inner_unck().len() }
 
 
+    ///
+    /// Marks `node` and its descendants to be freed at the end
+    /// of the frame by `free_queued` and calls `_queue_free` on
+    /// the ones that weren't marked already
+    ///
     pub fn queue_free(engine: &mut Engine, node: NodeId) {
         info!("calling queue free on {node:?}");
 
         // call free on everything
         let nodes = engine.with(|engine| {
-            engine.scene_manager.tree.iter_vec(node)
+            let tree = &mut engine.scene_manager.tree;
+            if !tree.get(node).queued_free {
+                tree.to_free.push(node);
+            }
+
+            tree.iter_vec(node)
         });
 
         for node in nodes.iter().copied() {
-            engine.get_mut().scene_manager.tree
-                .get_mut(node).queued_free = true;
+            let was_queued = core::mem::replace(&mut engine.get_mut().scene_manager.tree
+                                                .get_mut(node).queued_free, true);
+            if was_queued { continue }
 
             let comps = {
                 let mut engine = engine.get_mut();
@@ -72,6 +180,51 @@ impl SceneTree {
     }
 
 
+    ///
+    /// Frees the subtrees that were queued with `queue_free`.
+    ///
+    /// Each subtree is detached from its parent, if it was inside
+    /// the tree it gets `_exit_tree`, and then its nodes are removed
+    /// children first along with the physics objects they own.
+    ///
+    /// Lua may still hold userdata of the freed nodes, using it
+    /// raises an error instead of touching whatever reuses the slot
+    ///
+    pub fn free_queued(engine: &mut Engine) {
+        loop {
+            let to_free = core::mem::take(&mut engine.get_mut().scene_manager.tree.to_free);
+            if to_free.is_empty() { break }
+
+            for root in to_free {
+                if !engine.get().scene_manager.tree.exists(root) { continue }
+
+                // children added after the node was queued
+                // haven't been through `_queue_free` yet
+                Self::queue_free(engine, root);
+
+                if engine.get().scene_manager.tree.is_inside_tree(root) {
+                    SceneManager::exit_tree(engine, root);
+                }
+
+                engine.with(|engine| {
+                    let tree = &mut engine.scene_manager.tree;
+                    if !tree.exists(root) { return }
+
+                    tree.set_parent(root, None);
+                    if tree.root == Some(root) {
+                        tree.root = None;
+                    }
+
+                    for node in tree.iter_vec(root) {
+                        engine.scene_manager.physics.release_node(node);
+                        tree.remove(node);
+                    }
+                });
+            }
+        }
+    }
+
+
     pub fn exists(&self, handle: NodeId) -> bool {
         self.map.get(handle.0).is_some()
     }
@@ -87,43 +240,98 @@ impl SceneTree {
     }
 
 
-    pub fn set_global_position(&mut self, of: NodeId, mut pos: Vec2) {
-        trace!("set global position of '{of:?}' to {pos}");
+    /// The transform of `node` relative to the world, cached
+    /// until the node or one of its parents moves
+    pub fn global_transform(&self, node: NodeId) -> Transform2D {
+        let this = self.get(node);
+        if let Some(transform) = this.global_transform.get() {
+            return transform;
+        }
+
+        let local = this.properties.transform();
+        let transform = match this.parent {
+            Some(parent) => self.global_transform(parent) * local,
+            None => local,
+        };
+
+        this.global_transform.set(Some(transform));
+        transform
+    }
+
 
-        let node = self.get(of);
-        let mut target_parent = node.parent;
+    /// Drops the cached global transforms of `node` and its
+    /// descendants, called whenever the node moves
+    pub fn invalidate_transform(&self, node: NodeId) {
+        let mut stack = vec![node];
 
-        while let Some(parent) = target_parent {
-            let this = self.get(parent);
+        while let Some(node) = stack.pop() {
+            let node = self.get(node);
 
-            pos.x /= this.properties.scale.x;
-            pos.y /= this.properties.scale.y;
-            pos.x -= this.properties.position.x;
-            pos.y -= this.properties.position.y;
+            // a node is only cached if its parents are
+            // so the children of this one aren't either
+            if node.global_transform.take().is_none() { continue }
 
-            target_parent = this.parent;
+            stack.extend_from_slice(&node.children);
         }
+    }
 
-        self.get_mut(of).properties.position = pos;
+
+    pub fn set_position(&mut self, of: NodeId, position: Vec2) {
+        self.get_mut(of).properties.position = position;
+        self.invalidate_transform(of);
     }
 
 
+    pub fn set_rotation(&mut self, of: NodeId, rotation: f32) {
+        self.get_mut(of).properties.rotation = rotation;
+        self.invalidate_transform(of);
+    }
 
-    pub fn set_global_rotation(&mut self, of: NodeId, mut rot: f32) {
-        trace!("set global rotation of '{of:?}' to {rot}");
 
-        let node = self.get(of);
-        let mut target_parent = node.parent;
+    pub fn set_scale(&mut self, of: NodeId, scale: Vec2) {
+        self.get_mut(of).properties.scale = scale;
+        self.invalidate_transform(of);
+    }
 
-        while let Some(parent) = target_parent {
-            let this = self.get(parent);
 
-            rot -= this.properties.rotation;
+    /// Sets the transform of `of` relative to its parent,
+    /// see `NodeProperties::set_transform`
+    pub fn set_transform(&mut self, of: NodeId, transform: Transform2D) {
+        self.get_mut(of).properties.set_transform(transform);
+        self.invalidate_transform(of);
+    }
+
+
+    /// Moves `of` so that its global transform becomes
+    /// `transform`, see `NodeProperties::set_transform`
+    pub fn set_global_transform(&mut self, of: NodeId, transform: Transform2D) {
+        trace!("set global transform of '{of:?}' to {transform:?}");
+
+        let local = match self.get(of).parent {
+            Some(parent) => self.global_transform(parent).inverse() * transform,
+            None => transform,
+        };
+
+        self.get_mut(of).properties.set_transform(local);
+        self.invalidate_transform(of);
+    }
+
+
+    pub fn set_global_position(&mut self, of: NodeId, position: Vec2) {
+        let transform = self.global_transform(of);
+        self.set_global_transform(of, Transform2D { origin: position, ..transform });
+    }
 
-            target_parent = this.parent;
-        }
 
-        self.get_mut(of).properties.rotation = rot;
+    pub fn set_global_rotation(&mut self, of: NodeId, rotation: f32) {
+        let transform = self.global_transform(of);
+        self.set_global_transform(of, Transform2D::new(transform.position(), rotation, transform.scale()));
+    }
+
+
+    pub fn set_global_scale(&mut self, of: NodeId, scale: Vec2) {
+        let transform = self.global_transform(of);
+        self.set_global_transform(of, Transform2D::new(transform.position(), transform.rotation(), scale));
     }
 
 
@@ -158,6 +366,50 @@ impl SceneTree {
     }
 
 
+    /// Whether `node` is the root or one of its descendants
+    pub fn is_inside_tree(&self, node: NodeId) -> bool {
+        let Some(root) = self.root
+        else { return false };
+
+        self.is_ancestor(root, node)
+    }
+
+
+    /// Whether `ancestor` is `node` or one of its parents
+    pub fn is_ancestor(&self, ancestor: NodeId, node: NodeId) -> bool {
+        let mut current = Some(node);
+
+        while let Some(node) = current {
+            if node == ancestor { return true }
+            current = self.get(node).parent;
+        }
+
+        false
+    }
+
+
+    /// The persistent nodes under `root` that don't have a
+    /// persistent parent, their children move along with them
+    pub fn persistent_nodes(&self, root: NodeId) -> Vec<NodeId> {
+        let mut nodes = vec![];
+        let mut stack = self.get(root).children.clone();
+
+        while let Some(node) = stack.pop() {
+            let this = self.get(node);
+            if this.queued_free { continue }
+
+            if this.persistent {
+                nodes.push(node);
+                continue;
+            }
+
+            stack.extend_from_slice(&this.children);
+        }
+
+        nodes
+    }
+
+
     pub fn set_root(engine: &mut Engine, node: NodeId) {
         info!("set current scene root to {node:?}");
 
@@ -167,12 +419,140 @@ impl SceneTree {
 
         if let Some(root) = root {
             Self::queue_free(engine, root);
+            SceneManager::exit_tree(engine, root);
         }
 
         engine.get_mut().scene_manager.tree.root = Some(node);
     }
 
 
+    ///
+    /// Renames `node`, adding a number to the end of
+    /// the name if a sibling already has it.
+    ///
+    /// Returns false if the name isn't valid, names can't be
+    /// empty, contain a '/' or be '.' or '..'
+    ///
+    pub fn set_name(&mut self, node: NodeId, name: Option<String>) -> bool {
+        if let Some(name) = &name {
+            if !is_valid_name(name) {
+                return false;
+            }
+        }
+
+        self.get_mut(node).name = name;
+        self.make_name_unique(node);
+        true
+    }
+
+
+    /// Appends the lowest number that makes the name of
+    /// `node` unique among its siblings
+    fn make_name_unique(&mut self, node: NodeId) {
+        let this = self.get(node);
+        let (Some(name), Some(parent)) = (&this.name, this.parent)
+        else { return };
+
+        let is_taken = |name: &str| self.get(parent).children.iter()
+            .any(|x| *x != node && self.get(*x).name.as_deref() == Some(name));
+
+        if !is_taken(name) { return }
+
+        let unique = (2..).map(|i| format!("{name}{i}"))
+            .find(|x| !is_taken(x))
+            .unwrap();
+
+        trace!("the name '{name}' is taken, renaming {node:?} to '{unique}'");
+        self.get_mut(node).name = Some(unique);
+    }
+
+
+    ///
+    /// Finds the node at `path` relative to `from`.
+    ///
+    /// Paths are node names separated by '/', '..' is the
+    /// parent and '.' the node itself. A path that starts with
+    /// '/' is relative to the root of the tree instead. Nodes
+    /// without a name can be reached by their index in their
+    /// parent's children, written as '#0'.
+    ///
+    pub fn get_node(&self, from: NodeId, path: &str) -> Option<NodeId> {
+        let mut current = match path.starts_with('/') {
+            true => self.root?,
+            false => from,
+        };
+
+        for segment in path.split('/') {
+            current = match segment {
+                "" | "." => current,
+                ".." => self.get(current).parent?,
+                _ => {
+                    let children = &self.get(current).children;
+                    let by_name = children.iter()
+                        .find(|x| self.get(**x).name.as_deref() == Some(segment));
+
+                    let by_index = || segment.strip_prefix('#')
+                        .and_then(|x| x.parse::<usize>().ok())
+                        .and_then(|x| children.get(x));
+
+                    *by_name.or_else(by_index)?
+                },
+            };
+        }
+
+        Some(current)
+    }
+
+
+    /// Finds the first child of `of` named `name`, searching
+    /// breadth first through every descendant if `recursive`
+    pub fn find_child(&self, of: NodeId, name: &str, recursive: bool) -> Option<NodeId> {
+        let mut queue = VecDeque::from([of]);
+
+        while let Some(node) = queue.pop_front() {
+            for child in self.get(node).children.iter() {
+                if self.get(*child).name.as_deref() == Some(name) {
+                    return Some(*child);
+                }
+
+                if recursive {
+                    queue.push_back(*child);
+                }
+            }
+        }
+
+        None
+    }
+
+
+    /// The absolute path of `node`, see `get_node`. The root
+    /// of the tree is `/` and nodes outside of it have none
+    pub fn get_path(&self, node: NodeId) -> Option<String> {
+        if !self.is_inside_tree(node) { return None }
+
+        let mut segments = vec![];
+        let mut current = node;
+
+        while let Some(parent) = self.get(current).parent {
+            let segment = match &self.get(current).name {
+                Some(name) => name.clone(),
+                None => {
+                    let index = self.get(parent).children.iter()
+                        .position(|x| *x == current)
+                        .unwrap();
+                    format!("#{index}")
+                },
+            };
+
+            segments.push(segment);
+            current = parent;
+        }
+
+        segments.reverse();
+        Some(format!("/{}", segments.join("/")))
+    }
+
+
     pub fn set_parent(&mut self, of: NodeId, to: Option<NodeId>) {
         let of_node = self.get_mut(of);
         let old_parent_id = of_node.parent;
@@ -194,6 +574,314 @@ impl SceneTree {
             let to_parent = self.get_mut(to);
             to_parent.children.push(of);
         }
+
+        self.make_name_unique(of);
+        self.invalidate_transform(of);
     }
 }
 
+
+/// Whether `name` can be used as the name of a node
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use sti::keyed::KVec;
+
+    use crate::{engine::test_engine, lua::node::NodeUserData, scene_manager::node::{ComponentId, NodeProperties}, script_manager::ScriptId};
+
+    use super::*;
+
+
+    fn node(tree: &mut SceneTree, name: Option<&str>, parent: Option<NodeId>) -> NodeId {
+        let id = tree.create(NodeProperties::identity());
+        tree.set_name(id, name.map(String::from));
+        if parent.is_some() { tree.set_parent(id, parent) }
+        id
+    }
+
+
+    #[test]
+    fn scene_tree_paths() {
+        let mut tree = SceneTree::new();
+        let root = node(&mut tree, Some("World"), None);
+        tree.root = Some(root);
+
+        let pipe = node(&mut tree, Some("Pipe"), Some(root));
+        let top = node(&mut tree, Some("Top"), Some(pipe));
+        let unnamed = node(&mut tree, None, Some(pipe));
+        let player = node(&mut tree, Some("Player"), Some(root));
+
+        assert_eq!(tree.get_node(root, "Pipe/Top"), Some(top));
+        assert_eq!(tree.get_node(top, "../../Player"), Some(player));
+        assert_eq!(tree.get_node(top, "/Player"), Some(player));
+        assert_eq!(tree.get_node(top, "/"), Some(root));
+        assert_eq!(tree.get_node(pipe, "./#1"), Some(unnamed));
+        assert_eq!(tree.get_node(root, "Pipe/Bottom"), None);
+        assert_eq!(tree.get_node(root, ".."), None);
+
+        assert_eq!(tree.get_path(top).as_deref(), Some("/Pipe/Top"));
+        assert_eq!(tree.get_path(unnamed).as_deref(), Some("/Pipe/#1"));
+        assert_eq!(tree.get_path(root).as_deref(), Some("/"));
+        assert_eq!(tree.get_node(player, &tree.get_path(unnamed).unwrap()), Some(unnamed));
+
+        // a node outside of the tree has no absolute path
+        tree.set_parent(pipe, None);
+        assert_eq!(tree.get_path(top), None);
+
+        assert_eq!(tree.find_child(root, "Top", false), None);
+        assert_eq!(tree.find_child(root, "Top", true), Some(top));
+    }
+
+
+    #[test]
+    fn scene_tree_unique_names() {
+        let mut tree = SceneTree::new();
+        let root = node(&mut tree, None, None);
+        let first = node(&mut tree, Some("Pipe"), Some(root));
+        let second = node(&mut tree, Some("Pipe"), Some(root));
+        assert_eq!(tree.get(first).name.as_deref(), Some("Pipe"));
+        assert_eq!(tree.get(second).name.as_deref(), Some("Pipe2"));
+
+        let other = node(&mut tree, Some("Other"), Some(root));
+        assert!(tree.set_name(other, Some("Pipe".to_string())));
+        assert_eq!(tree.get(other).name.as_deref(), Some("Pipe3"));
+
+        // renaming to its own name keeps it
+        assert!(tree.set_name(second, Some("Pipe2".to_string())));
+        assert_eq!(tree.get(second).name.as_deref(), Some("Pipe2"));
+
+        assert!(!tree.set_name(other, Some("a/b".to_string())));
+        assert!(!tree.set_name(other, Some("..".to_string())));
+        assert_eq!(tree.get(other).name.as_deref(), Some("Pipe3"));
+    }
+
+
+    #[test]
+    fn scene_tree_groups() {
+        let mut tree = SceneTree::new();
+        let root = node(&mut tree, None, None);
+        tree.root = Some(root);
+
+        let a = node(&mut tree, Some("A"), Some(root));
+        let b = node(&mut tree, Some("B"), Some(root));
+
+        tree.add_to_group(b, "enemies");
+        tree.add_to_group(a, "enemies");
+        tree.add_to_group(a, "enemies");
+        tree.add_to_group(a, "pipes");
+        assert_eq!(tree.nodes_in_group("enemies"), vec![b, a]);
+        assert!(tree.is_in_group(a, "pipes"));
+
+        tree.remove_from_group(a, "pipes");
+        assert!(!tree.is_in_group(a, "pipes"));
+        assert!(tree.nodes_in_group("pipes").is_empty());
+
+        tree.get_mut(b).queued_free = true;
+        assert_eq!(tree.nodes_in_group("enemies"), vec![a]);
+
+        tree.remove(b);
+        tree.remove(a);
+        assert!(tree.groups.is_empty());
+
+        // nodes join their groups as they're inserted
+        let mut c = tree.get(root).clone();
+        c.groups = vec!["pipes".to_string()];
+        c.children = vec![];
+        let c = tree.insert(c);
+        tree.set_parent(c, Some(root));
+        assert_eq!(tree.nodes_in_group("pipes"), vec![c]);
+    }
+
+
+    #[test]
+    fn scene_tree_groups_outside_tree() {
+        let mut tree = SceneTree::new();
+        let root = node(&mut tree, None, None);
+        tree.root = Some(root);
+
+        let detached = node(&mut tree, None, None);
+        let child = node(&mut tree, Some("Child"), Some(detached));
+        tree.add_to_group(child, "enemies");
+
+        assert!(tree.is_in_group(child, "enemies"));
+        assert!(tree.nodes_in_group("enemies").is_empty());
+
+        tree.set_parent(detached, Some(root));
+        assert_eq!(tree.nodes_in_group("enemies"), vec![child]);
+
+        tree.set_parent(detached, None);
+        assert!(tree.nodes_in_group("enemies").is_empty());
+    }
+
+
+    #[test]
+    fn scene_tree_inside_tree() {
+        let mut tree = SceneTree::new();
+        let root = node(&mut tree, None, None);
+        tree.root = Some(root);
+
+        let pipe = node(&mut tree, Some("Pipe"), Some(root));
+        let detached = tree.create(NodeProperties::identity());
+        let child = node(&mut tree, Some("Child"), Some(detached));
+
+        assert!(tree.is_inside_tree(pipe));
+        assert!(!tree.is_inside_tree(child));
+        assert!(tree.is_ancestor(detached, child));
+        assert!(!tree.is_ancestor(child, detached));
+
+        tree.set_parent(detached, Some(pipe));
+        assert!(tree.is_inside_tree(child));
+        assert!(tree.is_ancestor(root, child));
+    }
+
+
+    #[test]
+    fn scene_tree_remove_component() {
+        let mut tree = SceneTree::new();
+        let node = tree.create(NodeProperties::identity());
+        let components = &mut tree.get_mut(node).components;
+
+        let a = components.push(ScriptId::EMPTY, KVec::new());
+        let b = components.push(ScriptId::EMPTY, KVec::new());
+        let c = components.push(ScriptId::EMPTY, KVec::new());
+        assert_eq!(c.inner(), 2);
+
+        let removed = components.remove(a);
+        assert_eq!(removed.comp_id, ComponentId::STALE);
+        assert_eq!(components.len(), 2);
+
+        // the ones after it move down
+        assert_eq!(components.get(a).comp_id, a);
+        assert_eq!(components.get(b).comp_id, b);
+    }
+
+
+    #[test]
+    fn scene_tree_global_transform() {
+        let mut tree = SceneTree::new();
+        let root = node(&mut tree, None, None);
+        let child = node(&mut tree, Some("Child"), Some(root));
+
+        tree.set_scale(root, Vec2::new(2.0, 2.0));
+        tree.set_position(child, Vec2::new(3.0, 0.0));
+        assert_eq!(tree.global_transform(child).position(), Vec2::new(6.0, 0.0));
+
+        // moving the parent drops the child's cached transform
+        tree.set_position(root, Vec2::new(1.0, 1.0));
+        assert_eq!(tree.global_transform(child).position(), Vec2::new(7.0, 1.0));
+
+        tree.set_global_position(child, Vec2::new(5.0, 5.0));
+        assert_eq!(tree.get(child).properties.position, Vec2::new(2.0, 2.0));
+        assert_eq!(tree.global_transform(child).position(), Vec2::new(5.0, 5.0));
+    }
+
+
+    #[test]
+    fn scene_tree_persistent_nodes() {
+        let mut tree = SceneTree::new();
+        let root = node(&mut tree, None, None);
+        let player = node(&mut tree, Some("Player"), Some(root));
+        let hat = node(&mut tree, Some("Hat"), Some(player));
+        let level = node(&mut tree, Some("Level"), Some(root));
+        let music = node(&mut tree, Some("Music"), Some(level));
+
+        tree.get_mut(root).persistent = true;
+        tree.get_mut(player).persistent = true;
+        tree.get_mut(hat).persistent = true;
+        tree.get_mut(music).persistent = true;
+
+        // the root can't move and the hat goes with the player
+        let nodes = tree.persistent_nodes(root);
+        assert_eq!(nodes.len(), 2);
+        assert!(nodes.contains(&player) && nodes.contains(&music));
+    }
+
+
+    /// A root in the tree with a `Level` child
+    /// that has an `Enemy` child of its own
+    fn level_tree(engine: &mut Engine) -> (NodeId, NodeId, NodeId) {
+        engine.with(|engine| {
+            let tree = &mut engine.scene_manager.tree;
+            let root = node(tree, None, None);
+            tree.root = Some(root);
+
+            let level = node(tree, Some("Level"), Some(root));
+            let enemy = node(tree, Some("Enemy"), Some(level));
+            (root, level, enemy)
+        })
+    }
+
+
+    #[test]
+    fn scene_tree_free_detaches() {
+        let (_guard, mut engine) = test_engine();
+        let (root, level, enemy) = level_tree(&mut engine);
+
+        SceneTree::queue_free(&mut engine, level);
+        SceneTree::free_queued(&mut engine);
+
+        let engine = engine.get();
+        let tree = &engine.scene_manager.tree;
+        assert!(tree.get(root).children.is_empty());
+        assert!(!tree.exists(level));
+        assert!(!tree.exists(enemy));
+        assert!(tree.to_free.is_empty());
+    }
+
+
+    #[test]
+    fn scene_tree_free_nested() {
+        let (_guard, mut engine) = test_engine();
+        let (root, level, enemy) = level_tree(&mut engine);
+
+        // the child is queued on its own before its parent
+        SceneTree::queue_free(&mut engine, enemy);
+        SceneTree::queue_free(&mut engine, level);
+        assert_eq!(engine.get().scene_manager.tree.to_free, vec![enemy, level]);
+
+        SceneTree::free_queued(&mut engine);
+
+        let engine = engine.get();
+        let tree = &engine.scene_manager.tree;
+        assert!(tree.get(root).children.is_empty());
+        assert!(!tree.exists(level));
+        assert!(!tree.exists(enemy));
+        assert_eq!(tree.len(), 1);
+    }
+
+
+    #[test]
+    fn scene_tree_free_deduplicates() {
+        let (_guard, mut engine) = test_engine();
+        let (_, level, enemy) = level_tree(&mut engine);
+
+        SceneTree::queue_free(&mut engine, level);
+        SceneTree::queue_free(&mut engine, level);
+        // already marked along with its parent
+        SceneTree::queue_free(&mut engine, enemy);
+
+        assert_eq!(engine.get().scene_manager.tree.to_free, vec![level]);
+    }
+
+
+    #[test]
+    fn scene_tree_alive() {
+        let (_guard, mut engine) = test_engine();
+        let (_, level, enemy) = level_tree(&mut engine);
+        let comp = engine.get_mut().scene_manager.tree.get_mut(enemy).components.push(ScriptId::EMPTY, KVec::new());
+
+        assert!(NodeUserData(enemy, comp).alive().is_ok());
+        assert!(NodeUserData(enemy, ComponentId::STALE).alive().is_err());
+
+        SceneTree::queue_free(&mut engine, level);
+        SceneTree::free_queued(&mut engine);
+
+        assert!(NodeUserData(enemy, comp).alive().is_err());
+        assert!(NodeUserData(level, comp).node().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use tracing::info;
+
+use crate::{asset_manager::AssetManager, engine::Engine, math::{matrix::Matrix4, vector::{Colour, Vec2}}, renderer::Renderer};
+
+use super::SceneManager;
+
+
+/// How the screen is covered while the scene changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    /// Swaps the scenes without covering the screen
+    None,
+    /// Fades to black and back
+    Fade,
+    /// Sweeps a black panel over the screen from the
+    /// left and pulls it off towards the right
+    Wipe,
+}
+
+
+impl Transition {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "fade" => Some(Self::Fade),
+            "wipe" => Some(Self::Wipe),
+            _ => None,
+        }
+    }
+}
+
+
+/// A scene change that's waiting for the end of
+/// the frame or is in the middle of its transition
+#[derive(Debug)]
+pub struct SceneChange {
+    pub path: String,
+    pub transition: Transition,
+    /// The length of the whole transition, the scenes are
+    /// swapped halfway through once the screen is covered
+    pub duration: f32,
+    pub elapsed: f32,
+    pub swapped: bool,
+    /// Whether the persistent nodes move to the new scene
+    /// or are freed along with the old one
+    pub keep_persistent: bool,
+}
+
+
+impl SceneChange {
+    /// The longest step a transition takes in one frame so
+    /// the slow frame that loads the new scene doesn't skip
+    /// most of it
+    const MAX_STEP : f32 = 1.0 / 30.0;
+
+
+    pub fn new(path: String, transition: Transition, duration: f32) -> Self {
+        let duration = if transition == Transition::None { 0.0 } else { duration.max(0.0) };
+
+        Self {
+            path,
+            transition,
+            duration,
+            elapsed: 0.0,
+            swapped: false,
+            keep_persistent: true,
+        }
+    }
+
+
+    /// How much of the screen is covered, from 0 to 1
+    pub fn cover(&self) -> f32 {
+        if self.duration <= 0.0 { return 0.0 }
+
+        let half = self.duration * 0.5;
+        let cover = if self.swapped { 1.0 - (self.elapsed - half) / half }
+                    else { self.elapsed / half };
+
+        cover.clamp(0.0, 1.0)
+    }
+
+
+    pub fn draw(&self, renderer: &mut Renderer, asset_manager: &AssetManager) {
+        let cover = self.cover();
+        if cover == 0.0 { return }
+
+        // the quad spans from `position - scale` to
+        // `position + scale` on the screen
+        let (position, scale, alpha) = match self.transition {
+            Transition::None => return,
+            Transition::Fade => (Vec2::new(0.0, 0.0), Vec2::new(1.0, 1.0), cover),
+            Transition::Wipe => {
+                let x = if self.swapped { 1.0 - cover } else { cover - 1.0 };
+                (Vec2::new(x, 0.0), Vec2::new(cover, 1.0), 1.0)
+            },
+        };
+
+        let vp = renderer.vp;
+        renderer.vp = Matrix4::IDENTITY;
+
+        renderer.draw_quad()
+            .position(position)
+            .scale(scale)
+            .modulate(Colour::new(0.0, 0.0, 0.0, alpha))
+            .commit(asset_manager);
+
+        renderer.vp = vp;
+    }
+}
+
+
+impl SceneManager {
+    /// Replaces any change that's already queued, a new change
+    /// picks up from however much of the screen is covered
+    pub fn queue_change(&mut self, mut change: SceneChange) {
+        info!("queued a scene change to '{}'", change.path);
+
+        if let Some(old) = &self.queue_change {
+            change.elapsed = old.cover() * change.duration * 0.5;
+        }
+
+        self.queue_change = Some(change);
+    }
+
+
+    /// Advances the queued scene change and swaps the scenes
+    /// once the screen is covered.
+    ///
+    /// Called at the end of the frame, before the freed nodes
+    /// are removed, so the old scene is gone by the next one
+    pub fn process_change(engine: &mut Engine) {
+        let swap = engine.with(|engine| {
+            let dt = engine.dt.min(SceneChange::MAX_STEP);
+            let change = engine.scene_manager.queue_change.as_mut()?;
+            change.elapsed += dt;
+
+            if change.swapped || change.elapsed < change.duration * 0.5 { return None }
+
+            change.swapped = true;
+            Some((change.path.clone(), change.keep_persistent))
+        });
+
+        if let Some((path, keep_persistent)) = swap {
+            Engine::change_scene(engine, &path, keep_persistent);
+        }
+
+        engine.with(|engine| {
+            let sm = &mut engine.scene_manager;
+
+            // a new scene could have queued
+            // another change in its `_ready`
+            if sm.queue_change.as_ref().is_some_and(|change| change.swapped
+                                                    && change.elapsed >= change.duration) {
+                sm.queue_change = None;
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn transition_cover() {
+        let mut change = SceneChange::new("a.scn".to_string(), Transition::Fade, 1.0);
+        assert_eq!(change.cover(), 0.0);
+
+        change.elapsed = 0.25;
+        assert_eq!(change.cover(), 0.5);
+
+        change.elapsed = 0.5;
+        assert_eq!(change.cover(), 1.0);
+
+        change.swapped = true;
+        change.elapsed = 0.75;
+        assert_eq!(change.cover(), 0.5);
+
+        change.elapsed = 2.0;
+        assert_eq!(change.cover(), 0.0);
+
+        let instant = SceneChange::new("a.scn".to_string(), Transition::None, 1.0);
+        assert_eq!(instant.duration, 0.0);
+        assert_eq!(instant.cover(), 0.0);
+    }
+}
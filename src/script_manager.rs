@@ -1,6 +1,6 @@
 pub mod fields;
 
-use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use std::collections::HashMap;
 
 use fields::{Field, FieldId, FieldValue};
 use mlua::AnyUserData;
@@ -8,15 +8,23 @@ use sokol::app::get_num_dropped_files;
 use sti::{define_key, keyed::KVec};
 use tracing::{error, info, trace, warn};
 
-use crate::{asset_manager::TextureId, engine::Engine};
+use crate::{asset_manager::TextureId, engine::Engine, file_watcher::FileWatcher};
 
 define_key!(u32, pub ScriptId);
 
 
+/// The scripts that ship with the engine as `(path, source)`,
+/// they're loaded before the scripts of the project
+pub const BUILTIN_SCRIPTS : &[(&str, &str)] = &[
+    ("<builtin>/audio_player.lua", include_str!("script_manager/builtin/audio_player.lua")),
+];
+
+
 #[derive(Debug)]
 pub struct ScriptManager {
     pub scripts: KVec<ScriptId, Script>,
     pub path_to_script: HashMap<String, ScriptId>,
+    pub watcher: FileWatcher,
 }
 
 
@@ -33,6 +41,8 @@ pub struct Script {
 #[derive(Debug, Clone, Default)]
 pub struct ScriptFunctions {
     ready : Option<mlua::Function>,
+    enter_tree: Option<mlua::Function>,
+    exit_tree: Option<mlua::Function>,
     update: Option<mlua::Function>,
     physics_update: Option<mlua::Function>,
     texture: Option<mlua::Function>,
@@ -58,59 +68,27 @@ impl ScriptManager {
         Self {
             scripts,
             path_to_script: HashMap::new(),
+            watcher: FileWatcher::new(),
         }
    }
 
 
-    pub fn load_current_dir(engine: &mut Engine) {
-        info!("loading current directory scripts");
+    /// Loads every '.lua' file in the file system
+    pub fn load_all(engine: &mut Engine) {
+        info!("loading builtin scripts");
 
-        let mut stack = vec![];
-        stack.push(PathBuf::from_str("./").unwrap());
-        while let Some(dir) = stack.pop() {
-            let span = tracing::span!(tracing::Level::INFO, "searching dir ", path = dir.to_string_lossy().to_string());
-            let _handle = span.entered();
-
-            let read_dir = match dir.read_dir() {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("unable to read directory '{}': {}", dir.to_string_lossy(), e);
-                    continue;
-                },
-            };
+        for (path, source) in BUILTIN_SCRIPTS {
+            Self::from_lua(engine, path, source.as_bytes());
+        }
 
-            for item in read_dir {
-                let item = match item {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!("unable to read an item: {}", e);
-                        continue;
-                    },
-                }; 
-
-                let path = item.path();
-                trace!("found file: {}", path.to_string_lossy());
-
-                let metadata = match item.metadata() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!("unable to retrieve metadata of '{}': {}", path.to_string_lossy(), e);
-                        continue;
-                    },
-                };
-
-                if metadata.file_type().is_dir() {
-                    stack.push(path);
-                    continue
-                }
+        info!("loading all scripts");
 
-                let Some(ext) = path.extension()
-                else { continue };
+        for path in Engine::file_system().files() {
+            trace!("found file: {path}");
 
-                if ext.to_str() == Some("lua") {
-                    Self::from_path(engine, path.to_str().unwrap());
-                }
-            };
+            if path.ends_with(".lua") {
+                Self::from_path(engine, &path);
+            }
         }
 
         info!("loaded all scripts");
@@ -120,6 +98,29 @@ impl ScriptManager {
     pub fn script(&self, script: ScriptId) -> &Script {
         &self.scripts[script]
     }
+
+
+    /// Reloads every script that has changed on disk since
+    /// the last time it was loaded
+    pub fn hot_reload(engine: &mut Engine) {
+        let changed = engine.with(|engine| {
+            let now = engine.now;
+            engine.script_manager.watcher.changed_files(now)
+        });
+
+        for path in changed {
+            info!("'{path}' has changed, reloading");
+
+            let is_loaded = engine.get().script_manager.path_to_script.contains_key(&path);
+            if is_loaded {
+                Self::reload(engine, &path);
+            } else {
+                // the script failed to load the first time
+                // so there's nothing to patch
+                Self::from_path(engine, &path);
+            }
+        }
+    }
 }
 
 
@@ -127,6 +128,13 @@ impl Script {
     pub fn new(path: String,
                fields: HashMap<String, FieldId>,
                default_fields: KVec<FieldId, Field>) -> Self {
+        Self::from_parts(path.leak(), fields, default_fields)
+    }
+
+
+    fn from_parts(path: &'static str,
+                  fields: HashMap<String, FieldId>,
+                  default_fields: KVec<FieldId, Field>) -> Self {
 
         let name = fields.get("class_name")
             .map(|name| default_fields[*name].value.value().as_string_lossy())
@@ -162,6 +170,8 @@ impl Script {
 
         let funcs = ScriptFunctions {
             ready: get_func("_ready"),
+            enter_tree: get_func("_enter_tree"),
+            exit_tree: get_func("_exit_tree"),
             update: get_func("_update"),
             physics_update: get_func("_physics_update"),
             texture: get_func("_create_texture"),
@@ -170,7 +180,7 @@ impl Script {
         };
 
         Self {
-            path: path.leak(),
+            path,
             name,
             fields,
             default_fields,
@@ -179,6 +189,53 @@ impl Script {
     }
 
 
+    /// Replaces the fields and functions of the script with
+    /// the ones of a re-executed version of it.
+    ///
+    /// Returns the script as it was before the swap so that
+    /// existing field values can be migrated with `migrate_fields`
+    pub fn hot_swap(&mut self,
+                    fields: HashMap<String, FieldId>,
+                    default_fields: KVec<FieldId, Field>) -> Script {
+        let script = Self::from_parts(self.path, fields, default_fields);
+        core::mem::replace(self, script)
+    }
+
+
+    /// Converts `values`, which are laid out according to `old`,
+    /// into the field layout of `self`.
+    ///
+    /// - fields that no longer exist are dropped
+    /// - fields that didn't exist use their new default value
+    /// - fields holding functions always use the new function
+    /// - otherwise `keep` decides whether the old value is kept
+    ///   or replaced with the new default value
+    pub fn migrate_fields(&self,
+                          old: &Script,
+                          values: &KVec<FieldId, FieldValue>,
+                          keep: impl Fn(&FieldValue, &Field) -> bool) -> KVec<FieldId, FieldValue> {
+
+        let mut migrated = KVec::with_cap(self.default_fields.len());
+
+        for (_, field) in self.default_fields.iter() {
+            let is_function = field.value.value().as_function().is_some();
+            let old_value = old.fields.get(&field.name)
+                .map(|id| (&values[*id], &old.default_fields[*id]));
+
+            let value = match old_value {
+                Some((value, old_default))
+                    if !is_function && keep(value, old_default) => value.clone(),
+
+                _ => field.value.clone(),
+            };
+
+            migrated.push(value);
+        }
+
+        migrated
+    }
+
+
     pub fn path(&self) -> &'static str {
         &self.path
     }
@@ -206,6 +263,26 @@ impl ScriptFunctions {
     }
 
 
+    pub fn enter_tree(&self, path: &str, user_data: &AnyUserData) {
+        let Some(enter_tree) = &self.enter_tree
+        else { return };
+
+        if let Err(e) = enter_tree.call::<()>(user_data) {
+            error!("on enter tree of '{}': \n{e}", path);
+        }
+    }
+
+
+    pub fn exit_tree(&self, path: &str, user_data: &AnyUserData) {
+        let Some(exit_tree) = &self.exit_tree
+        else { return };
+
+        if let Err(e) = exit_tree.call::<()>(user_data) {
+            error!("on exit tree of '{}': \n{e}", path);
+        }
+    }
+
+
     pub fn draw(&self, path: &str, user_data: AnyUserData) {
         let Some(draw) = &self.draw
         else { return };
@@ -277,3 +354,50 @@ impl Default for Script {
 impl ScriptId {
     pub const EMPTY : Self = Self(0);
 }
+
+
+#[cfg(test)]
+mod tests {
+    use mlua::Value;
+
+    use super::*;
+
+
+    fn script(fields: &[(&str, Value)]) -> Script {
+        let mut names = HashMap::new();
+        let mut default_fields = KVec::new();
+
+        for (name, value) in fields {
+            let id = default_fields.push(Field::new(name.to_string(), FieldValue::new(value.clone())));
+            names.insert(name.to_string(), id);
+        }
+
+        Script::from_parts("test.lua", names, default_fields)
+    }
+
+
+    #[test]
+    fn script_migrate_fields() {
+        let old = script(&[("speed", Value::Integer(1)),
+                           ("removed", Value::Integer(2)),
+                           ("health", Value::Integer(3))]);
+
+        let new = script(&[("health", Value::Integer(30)),
+                           ("speed", Value::Integer(10)),
+                           ("added", Value::Integer(4))]);
+
+        // the live values, `speed` was changed at runtime
+        let mut values = KVec::new();
+        values.push(FieldValue::new(Value::Integer(5)));
+        values.push(FieldValue::new(Value::Integer(2)));
+        values.push(FieldValue::new(Value::Integer(3)));
+
+        // only values that differ from the old default are kept
+        let migrated = new.migrate_fields(&old, &values, |value, old_default| {
+            value.value() != old_default.value.value()
+        });
+
+        let migrated = migrated.iter().map(|(_, v)| v.value().as_integer()).collect::<Vec<_>>();
+        assert_eq!(migrated, vec![Some(30), Some(5), Some(4)]);
+    }
+}
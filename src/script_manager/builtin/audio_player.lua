@@ -0,0 +1,122 @@
+class_name = "AudioPlayer"
+
+-- the '.wav' or '.ogg' file to play
+stream = ""
+autoplay = false
+volume = 1.0
+pitch = 1.0
+loop = false
+bus = "sfx"
+
+-- plays the sound every time the 'Collider'
+-- of the node starts touching something
+play_on_collision = false
+
+-- how far from the camera the sound can still be heard,
+-- the sound isn't positional if this is 0
+max_distance = 0.0
+-- how the volume fades with the distance, 1 is
+-- linear and higher values fade out faster
+attenuation = 1.0
+
+-- called with the player, set them from other scripts
+on_play = not_set
+on_stop = not_set
+on_finished = not_set
+
+voice = not_set
+
+
+local function positional(self)
+    return Audio.positional(self.global_position, self.max_distance, self.attenuation)
+end
+
+
+function play(self)
+    if self.voice ~= not_set then
+        Audio.stop(self.voice)
+        self.voice = not_set
+    end
+
+    local gain, pan = positional(self)
+    local voice = Audio.play(self.stream, {
+        volume = self.volume * gain,
+        pitch = self.pitch,
+        pan = pan,
+        loop = self.loop,
+        bus = self.bus,
+    })
+
+    if voice == nil then return end
+    self.voice = voice
+
+    if self.on_play ~= not_set then
+        self.on_play(self)
+    end
+end
+
+
+function stop(self)
+    if self.voice == not_set then return end
+
+    Audio.stop(self.voice)
+    self.voice = not_set
+
+    if self.on_stop ~= not_set then
+        self.on_stop(self)
+    end
+end
+
+
+function is_playing(self)
+    return self.voice ~= not_set and Audio.is_playing(self.voice)
+end
+
+
+function _ready(self)
+    if self.play_on_collision then
+        local collider = self:get_component("Collider")
+        if collider == nil then
+            error("'play_on_collision' is set but the node has no collider")
+        end
+
+        PhysicsServer.attach_collider_event(collider.physics_collider, function()
+            self:play()
+        end)
+    end
+
+    if self.autoplay then
+        self:play()
+    end
+end
+
+
+function _update(self)
+    if self.voice == not_set then return end
+
+    if not Audio.is_playing(self.voice) then
+        self.voice = not_set
+
+        if self.on_finished ~= not_set then
+            self.on_finished(self)
+        end
+
+        return
+    end
+
+    if self.max_distance > 0 then
+        local gain, pan = positional(self)
+        Audio.set_volume(self.voice, self.volume * gain)
+        Audio.set_pan(self.voice, pan)
+    end
+end
+
+
+-- one shot sounds are left to finish so that a sound played
+-- right before the node is freed, like a hit that restarts
+-- the game, is still heard
+function _queue_free(self)
+    if self.voice ~= not_set and self.loop then
+        Audio.stop(self.voice)
+    end
+end
@@ -0,0 +1,259 @@
+use sti::keyed::KVec;
+use tracing::{error, info, warn, Level};
+
+use crate::{asset_manager::AssetManager, deserialize::scene_file::{self, FlatNode}, engine::Engine, scene_manager::{node::NodeProperties, scene_template::TemplateScene, scene_tree::SceneTree, NodeId, SceneManager}, script_manager::{fields::{FieldId, FieldValue}, ScriptId, ScriptManager}};
+
+
+impl SceneManager {
+    /// Writes `root` and everything under it to `path` as a
+    /// `.scene` file, `root` becomes the root of the scene
+    pub fn save(engine: &Engine, root: NodeId, path: &str) -> Result<(), String> {
+        let span = tracing::span!(Level::ERROR, "serialize ", path);
+        let _handle = span.entered();
+
+        info!("saving {root:?} as a scene");
+
+        let Some(real_path) = Engine::file_system().write_path(path)
+        else { return Err(String::from("no directory is mounted")) };
+
+        let root = {
+            let engine = engine.get();
+            if !engine.scene_manager.tree.exists(root) {
+                return Err(format!("the node '{root:?}' doesn't exist"));
+            }
+
+            engine.scene_manager.tree.to_table(root, &engine.asset_manager, &engine.script_manager)
+        };
+
+        let contents = scene_file::to_text(&root);
+
+        if let Some(dir) = real_path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+
+        std::fs::write(real_path, contents).map_err(|e| e.to_string())
+    }
+}
+
+
+impl SceneTree {
+    ///
+    /// Turns `root` and its descendants into the root table
+    /// of a version 2 scene, see `scene_file::SceneFile`.
+    ///
+    /// Nodes that are queued to be freed are left out and
+    /// the ones without a name get one, as children are
+    /// keyed by their name.
+    ///
+    pub fn to_table(&self, root: NodeId, asset_manager: &AssetManager,
+                    script_manager: &ScriptManager) -> toml::Table {
+        let mut nodes = vec![];
+        let mut stack = vec![(root, None)];
+
+        while let Some((node_id, parent)) = stack.pop() {
+            let node = self.get(node_id);
+            if node.queued_free { continue }
+
+            let index = nodes.len();
+            let components = node.components.iter()
+                .map(|comp| node.components.get(comp))
+                .map(|comp| (comp.script, &comp.fields));
+
+            let table = node_table(node.name.as_deref(), &node.groups, node.properties,
+                                   components, asset_manager, script_manager);
+            nodes.push(FlatNode { parent, table });
+
+            // reversed so the children keep their order
+            stack.extend(node.children.iter().rev().map(|child| (*child, Some(index))));
+        }
+
+        scene_file::nest(nodes)
+    }
+}
+
+
+impl TemplateScene {
+    /// Turns the template back into the root table of a
+    /// scene it could have been loaded from, instances are
+    /// written out in full, see `SceneTree::to_table`
+    pub fn to_table(&self, asset_manager: &AssetManager,
+                    script_manager: &ScriptManager) -> toml::Table {
+        let mut nodes = vec![];
+
+        for (_, node) in self.iter() {
+            let components = node.components.iter()
+                .map(|(_, comp)| (comp.script(), comp.fields()));
+
+            let parent = node.parent.map(|x| x.inner() as usize);
+            let table = node_table(node.name.as_deref(), &node.groups, node.properties,
+                                   components, asset_manager, script_manager);
+            nodes.push(FlatNode { parent, table });
+        }
+
+        scene_file::nest(nodes)
+    }
+}
+
+
+impl FieldValue {
+    /// The toml value `from_toml` reads back into this value,
+    /// `None` for values that can't be stored in a scene file
+    pub fn to_toml(&self) -> Option<toml::Value> {
+        Some(match self.value() {
+            mlua::Value::String(v) => toml::Value::String(v.to_str().ok()?.to_string()),
+            mlua::Value::Integer(v) => toml::Value::Integer(*v as i64),
+            mlua::Value::Number(v) => toml::Value::Float(*v),
+            mlua::Value::Boolean(v) => toml::Value::Boolean(*v),
+
+            mlua::Value::Vector(v) => {
+                let mut table = toml::Table::new();
+                table.insert("x".to_string(), v.x().into());
+                table.insert("y".to_string(), v.y().into());
+                table.insert("z".to_string(), v.z().into());
+                toml::Value::Table(table)
+            },
+
+            _ => return None,
+        })
+    }
+}
+
+
+fn node_table<'a>(name: Option<&str>, groups: &[String], properties: NodeProperties,
+                  components: impl Iterator<Item=(ScriptId, &'a KVec<FieldId, FieldValue>)>,
+                  asset_manager: &AssetManager, script_manager: &ScriptManager) -> toml::Table {
+    let mut table = properties.to_table(asset_manager);
+    scene_file::strip_defaults(&mut table);
+
+    if let Some(name) = name {
+        table.insert("name".to_string(), name.into());
+    }
+
+    if !groups.is_empty() {
+        table.insert("groups".to_string(), groups.to_vec().into());
+    }
+
+    let mut components_table = toml::Table::new();
+    for (script_id, fields) in components {
+        let script = script_manager.script(script_id);
+
+        // scenes refer to scripts by their class name
+        // unless another script took the name
+        let name = match script_manager.path_to_script.get(&script.name) {
+            Some(id) if *id == script_id => script.name.clone(),
+            _ => script.path().to_string(),
+        };
+
+        let mut fields_table = toml::Table::new();
+        for (field_id, field) in script.default_fields.iter() {
+            let value = &fields[field_id];
+
+            // only what the scene overrides is written so that
+            // the rest picks up changes to the script's defaults
+            if value.value() == field.value.value() { continue }
+
+            match value.to_toml() {
+                Some(v) => { fields_table.insert(field.name.clone(), v); },
+                None => warn!("the field '{}' of '{name}' is a {}, it can't be saved",
+                              field.name, value.value().type_name()),
+            }
+        }
+
+        if components_table.insert(name.clone(), fields_table.into()).is_some() {
+            error!("the node has more than one '{name}' component, only the last one is saved");
+        }
+    }
+
+    if !components_table.is_empty() {
+        table.insert("components".to_string(), components_table.into());
+    }
+
+    table
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deserialize::scene_file::SceneSource, engine::test_engine, math::vector::Vec2, scene_manager::scene_template::TemplateNodeId};
+
+
+    #[test]
+    fn serialize_field_values() {
+        let lua = mlua::Lua::new();
+        let value = |v: mlua::Value| FieldValue::new(v).to_toml();
+
+        assert_eq!(value(mlua::Value::Boolean(true)), Some(toml::Value::Boolean(true)));
+        assert_eq!(value(mlua::Value::Integer(3)), Some(toml::Value::Integer(3)));
+        assert_eq!(value(mlua::Value::Number(0.5)), Some(toml::Value::Float(0.5)));
+
+        let string = lua.create_string("character/hit.wav").unwrap();
+        assert_eq!(value(mlua::Value::String(string)), Some(toml::Value::String("character/hit.wav".to_string())));
+
+        let vector = value(mlua::Value::Vector(mlua::Vector::new(1.0, 2.0, 0.5))).unwrap();
+        assert_eq!(vector, toml::Value::Table(toml::from_str("x = 1.0\ny = 2.0\nz = 0.5").unwrap()));
+
+        assert_eq!(value(mlua::Value::Nil), None);
+        assert_eq!(value(mlua::Value::Table(lua.create_table().unwrap())), None);
+    }
+
+
+    #[test]
+    fn serialize_round_trip() {
+        let (_guard, mut engine) = test_engine();
+        ScriptManager::from_lua(&mut engine, "spinner.lua", b"class_name = \"Spinner\"\nspeed = 1.0\nlabel = \"spin\"");
+
+        let (texture, source) = engine.with(|engine| {
+            let spinner = engine.script_manager.path_to_script["Spinner"];
+            let script = engine.script_manager.script(spinner);
+            let mut fields = KVec::new();
+            for (_, field) in script.default_fields.iter() {
+                fields.push(field.value.clone());
+            }
+            fields[script.fields["speed"]] = FieldValue::new(mlua::Value::Number(3.0));
+
+            let texture = engine.asset_manager.placeholder_image("player.png");
+
+            let tree = &mut engine.scene_manager.tree;
+            let node = |tree: &mut SceneTree, name: &str, parent: Option<NodeId>, properties: NodeProperties| {
+                let id = tree.create(properties);
+                tree.set_name(id, Some(name.to_string()));
+                tree.set_parent(id, parent);
+                id
+            };
+
+            let root = node(tree, "level", None, NodeProperties { position: Vec2::new(1.0, 2.0), ..NodeProperties::identity() });
+            let player = node(tree, "player", Some(root), NodeProperties { texture: Some(texture), ..NodeProperties::identity() });
+            node(tree, "sword", Some(player), NodeProperties::identity());
+            node(tree, "enemy", Some(root), NodeProperties { rotation: 0.5, ..NodeProperties::identity() });
+            let ghost = node(tree, "ghost", Some(root), NodeProperties::identity());
+
+            tree.add_to_group(player, "players");
+            tree.get_mut(player).components.push(spinner, fields);
+            tree.get_mut(ghost).queued_free = true;
+
+            let table = tree.to_table(root, &engine.asset_manager, &engine.script_manager);
+            (texture, SceneSource::from_bytes(scene_file::to_text(&table).into_bytes()).unwrap())
+        });
+
+        let template = TemplateScene::from_source(&mut engine, "level.scene", &source);
+        let engine = engine.get();
+
+        let nodes = template.iter().map(|(id, node)| (id.inner(), node.name.as_deref().unwrap(), node.parent.map(|x| x.inner())))
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, vec![(0, "level", None), (1, "player", Some(0)), (2, "sword", Some(1)), (3, "enemy", Some(0))]);
+
+        let node = |index: u32| template.get(TemplateNodeId::new_unck(index)).unwrap();
+        assert_eq!(node(0).properties.position, Vec2::new(1.0, 2.0));
+        assert_eq!(node(3).properties.rotation, 0.5);
+        assert_eq!(node(1).properties.texture, Some(texture));
+        assert_eq!(node(1).groups, vec!["players"]);
+
+        let spinner = engine.script_manager.path_to_script["Spinner"];
+        let script = engine.script_manager.script(spinner);
+        let (_, component) = node(1).components.iter().next().unwrap();
+        assert_eq!(component.script(), spinner);
+        assert_eq!(component.fields()[script.fields["speed"]].value(), &mlua::Value::Number(3.0));
+        assert_eq!(component.fields()[script.fields["label"]].value().as_string_lossy().as_deref(), Some("spin"));
+    }
+}
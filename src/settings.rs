@@ -1,22 +1,30 @@
 pub mod engine_version;
 
+use std::collections::BTreeMap;
+
 use engine_version::EngineVersion;
 use tracing::info;
 use serde::{Deserialize, Serialize};
 
-use crate::math::vector::Vec2;
+use crate::{audio_manager::effect::EffectSettings, math::vector::Vec2};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ProjectSettings {
     pub engine: EngineSettings,
     pub window: WindowSettings,
     pub world : WorldSettings,
+    #[serde(default)]
+    pub assets: AssetSettings,
+    #[serde(default)]
+    pub audio: AudioSettings,
 }
 
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct EngineSettings {
     pub version: EngineVersion,
+    #[serde(default = "default_hot_reload")]
+    pub hot_reload: bool,
 }
 
 
@@ -49,6 +57,51 @@ pub struct WorldSettings {
 }
 
 
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct AssetSettings {
+    /// Folders whose images are packed into
+    /// an atlas when the project is exported
+    #[serde(default)]
+    pub atlases: Vec<String>,
+}
+
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct AudioSettings {
+    /// The buses sounds are mixed into, `master`, `music`,
+    /// `sfx` and `ui` exist even if they aren't declared
+    #[serde(default)]
+    pub buses: BTreeMap<String, BusSettings>,
+}
+
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BusSettings {
+    #[serde(default = "default_volume")]
+    pub volume: f32,
+    #[serde(default)]
+    pub muted: bool,
+    /// The bus this one is mixed into, `master` by default
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Applied in order before the volume
+    #[serde(default)]
+    pub effects: Vec<EffectSettings>,
+}
+
+
+impl Default for BusSettings {
+    fn default() -> Self {
+        Self {
+            volume: default_volume(),
+            muted: false,
+            parent: None,
+            effects: vec![],
+        }
+    }
+}
+
+
 impl ProjectSettings {
     pub fn new(file: &str) -> Result<Self, toml::de::Error> {
         info!("parsing project settings");
@@ -56,6 +109,7 @@ impl ProjectSettings {
 
         info!("project settings:");
         info!("- engine.version: '{}' (current: '{}')", settings.engine.version, EngineVersion::CURRENT);
+        info!("- engine.hot_reload: {}", settings.engine.hot_reload);
         info!("- window.title: '{}'", settings.window.title);
         info!("- window.width: {}", settings.window.width);
         info!("- window.height: {}", settings.window.height);
@@ -64,6 +118,8 @@ impl ProjectSettings {
         info!("- window.fullscreen: {}", settings.window.fullscreen);
         info!("- window.allow_transparency: {}", settings.window.allow_transparency);
         info!("- world.entry_scene: {}", settings.world.entry_scene);
+        info!("- assets.atlases: {:?}", settings.assets.atlases);
+        info!("- audio.buses: {:?}", settings.audio.buses.keys().collect::<Vec<_>>());
         Ok(settings)
     }
 }
@@ -72,7 +128,10 @@ impl ProjectSettings {
 impl core::default::Default for ProjectSettings {
     fn default() -> Self {
         Self {
-            engine: EngineSettings { version: EngineVersion::CURRENT },
+            engine: EngineSettings {
+                version: EngineVersion::CURRENT,
+                hot_reload: default_hot_reload(),
+            },
             window: WindowSettings {
                 title: "untitled project".to_string(),
                 width: 800,
@@ -87,6 +146,8 @@ impl core::default::Default for ProjectSettings {
                 gravity: Vec2::new(0.0, -9.8),
                 physics_framerate: 240,
             },
+            assets: AssetSettings::default(),
+            audio: AudioSettings::default(),
         }
     }
 }
@@ -120,3 +181,13 @@ fn default_gravity() -> Vec2 {
 fn default_msaa_sample_count() -> usize {
     4
 }
+
+
+fn default_volume() -> f32 {
+    1.0
+}
+
+
+fn default_hot_reload() -> bool {
+    cfg!(debug_assertions)
+}